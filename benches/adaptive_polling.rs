@@ -0,0 +1,101 @@
+// Compares the default fixed polling with a fast fixed interval and `ServerBuilder::adaptive_polling`: how long a burst
+// of new connections takes to get its first response, and how much CPU the server burns holding idle connections.
+// Run with `cargo bench --bench adaptive_polling`.
+use embedded_recruitment_task::{
+    codec,
+    message::{client_message, ClientMessage, EchoMessage},
+    server::Server,
+};
+use std::{
+    io::Write,
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+const CONNECTIONS: usize = 200; // Connections in the burst, each opened after the previous one was answered
+const IDLE_CONNECTIONS: usize = 50;
+const IDLE_PERIOD: Duration = Duration::from_secs(3);
+
+/// Opens connections one after another, sending an echo on each, and returns the mean time to its response
+fn connect_latency(addr: SocketAddr) -> Duration {
+    let started = Instant::now();
+    for index in 0..CONNECTIONS {
+        let mut stream = TcpStream::connect(addr).expect("Failed to connect to the server");
+        let request = ClientMessage {
+            message: Some(client_message::Message::EchoMessage(EchoMessage {
+                content: format!("request {}", index),
+            })),
+        };
+        stream.write_all(&codec::encode_frame(&request)).expect("Failed to send request");
+        codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing response");
+    }
+    started.elapsed() / CONNECTIONS as u32
+}
+
+/// User plus system CPU time the process has used so far
+#[cfg(target_os = "linux")]
+fn cpu_time() -> Option<Duration> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: `getrusage` fills the struct it is given and reports failure through its return value
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let usage = unsafe { usage.assume_init() };
+    let time = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+    Some(time(usage.ru_utime) + time(usage.ru_stime))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cpu_time() -> Option<Duration> {
+    None
+}
+
+/// CPU the process uses while the server holds idle connections for `IDLE_PERIOD`
+fn idle_cpu(addr: SocketAddr) -> Option<Duration> {
+    let connections: Vec<TcpStream> = (0..IDLE_CONNECTIONS)
+        .map(|_| TcpStream::connect(addr).expect("Failed to connect to the server"))
+        .collect();
+    thread::sleep(Duration::from_secs(1)); // Let the polls back off before measuring
+    let before = cpu_time()?;
+    thread::sleep(IDLE_PERIOD);
+    let used = cpu_time()? - before;
+    drop(connections);
+    Some(used)
+}
+
+fn run(polling: Option<(Duration, Duration)>) -> (Duration, Option<Duration>) {
+    let mut builder = Server::builder("127.0.0.1:0");
+    if let Some((min, max)) = polling {
+        builder = builder.adaptive_polling(min, max);
+    }
+    let server = Arc::new(builder.build().expect("Failed to start server"));
+    let addr = server.local_addr().expect("Server has no address");
+    let handle = {
+        let server = Arc::clone(&server);
+        thread::spawn(move || server.run().expect("Server encountered an error"))
+    };
+    thread::sleep(Duration::from_secs(1)); // Start from an idle accept loop, as a burst after a quiet spell would
+    let latency = connect_latency(addr);
+    let cpu = idle_cpu(addr);
+    server.stop();
+    handle.join().expect("Server thread panicked");
+    (latency, cpu)
+}
+
+fn main() {
+    let configs = [
+        ("fixed 100ms (default)", None),
+        ("fixed 1ms", Some((Duration::from_millis(1), Duration::from_millis(1)))),
+        ("adaptive 1ms..1s", Some((Duration::from_millis(1), Duration::from_secs(1)))),
+    ];
+    for (label, polling) in configs {
+        let (latency, cpu) = run(polling);
+        let cpu = cpu.map_or_else(|| "unavailable".to_string(), |cpu| format!("{:?}", cpu));
+        println!(
+            "{:<22} {:?} per new connection under load, {} CPU over {:?} with {} idle connections",
+            label, latency, cpu, IDLE_PERIOD, IDLE_CONNECTIONS
+        );
+    }
+}
@@ -0,0 +1,75 @@
+// Compares socket writes and wall time for a pipelining client with and without `ServerBuilder::write_buffer`.
+// Run with `cargo bench --bench write_buffering`.
+use embedded_recruitment_task::{
+    codec,
+    message::{client_message, ClientMessage, EchoMessage},
+    server::Server,
+    stats::ConnectionStats,
+};
+use std::{
+    io::Write,
+    net::TcpStream,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+const REQUESTS: usize = 20_000;
+
+/// Pipelines every request on one connection and returns the server's statistics for it with the elapsed time
+fn run(write_buffer: Option<usize>) -> (ConnectionStats, Duration) {
+    let (sender, receiver) = mpsc::channel();
+    let sender = Mutex::new(sender); // The callback must be `Sync`
+    let mut builder = Server::builder("127.0.0.1:0").on_disconnect(move |stats| {
+        let _ = sender.lock().unwrap().send(stats.clone());
+    });
+    if let Some(capacity) = write_buffer {
+        builder = builder.write_buffer(capacity);
+    }
+    let server = Arc::new(builder.build().expect("Failed to start server"));
+    let addr = server.local_addr().expect("Server has no address");
+    let handle = {
+        let server = Arc::clone(&server);
+        thread::spawn(move || server.run().expect("Server encountered an error"))
+    };
+
+    let mut stream = TcpStream::connect(addr).expect("Failed to connect to the server");
+    let mut writer = stream.try_clone().expect("Failed to clone stream");
+    let started = Instant::now();
+    let sending = thread::spawn(move || {
+        let mut batch = Vec::new();
+        for index in 0..REQUESTS {
+            batch.extend(codec::encode_frame(&ClientMessage {
+                message: Some(client_message::Message::EchoMessage(EchoMessage {
+                    content: format!("request {}", index),
+                })),
+            }));
+        }
+        writer.write_all(&batch).expect("Failed to send requests");
+    });
+    for _ in 0..REQUESTS {
+        codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing response");
+    }
+    let elapsed = started.elapsed();
+    sending.join().expect("Sender thread panicked");
+    drop(stream);
+
+    let stats = receiver.recv_timeout(Duration::from_secs(5)).expect("on_disconnect was not called");
+    server.stop();
+    handle.join().expect("Server thread panicked");
+    (stats, elapsed)
+}
+
+fn main() {
+    for (label, write_buffer) in [("unbuffered", None), ("write_buffer(64 KiB)", Some(64 * 1024))] {
+        let (stats, elapsed) = run(write_buffer);
+        println!(
+            "{:<22} {} responses in {} writes ({:.1} per write), {:?}",
+            label,
+            REQUESTS,
+            stats.writes,
+            REQUESTS as f64 / stats.writes as f64,
+            elapsed
+        );
+    }
+}
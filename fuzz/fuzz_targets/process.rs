@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Feeds arbitrary bytes through framing, decoding and dispatch; any panic is a crash
+fuzz_target!(|data: &[u8]| {
+    let _ = embedded_recruitment_task::server::process(data);
+});
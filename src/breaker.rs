@@ -0,0 +1,61 @@
+use log::warn;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Called with the server's total decode errors so far when they spike
+pub(crate) type SpikeCallback = Box<dyn Fn(u64) + Send + Sync>;
+
+/// Counts undecodable frames across every connection of a server and, once more than `threshold` of them arrive within
+/// `window`, calls back: many clients failing to decode at once points at a protocol mismatch or an attack rather than
+/// one broken client.
+pub(crate) struct DecodeErrorBreaker {
+    total: AtomicU64,
+    spike: Option<(u32, Duration, SpikeCallback)>, // Threshold, window and callback, when `on_decode_error_spike` is set
+    recent: Mutex<VecDeque<Instant>>, // When the errors still within the window arrived, oldest first
+}
+
+impl DecodeErrorBreaker {
+    pub(crate) fn new(spike: Option<(u32, Duration, SpikeCallback)>) -> Self {
+        DecodeErrorBreaker {
+            total: AtomicU64::new(0),
+            spike,
+            recent: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Decode errors counted since the server was built
+    pub(crate) fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// Counts one decode error, returning true if it took the errors within the window past the threshold. The
+    /// callback has run by then, and the window starts over empty, so a spike that goes on is reported again only
+    /// once it crosses the threshold anew.
+    pub(crate) fn record(&self) -> bool {
+        let total = self.total.fetch_add(1, Ordering::Relaxed) + 1;
+        let Some((threshold, window, callback)) = &self.spike else {
+            return false;
+        };
+        let now = Instant::now();
+        {
+            let mut recent = self.recent.lock().unwrap();
+            while recent.front().is_some_and(|at| now.duration_since(*at) >= *window) {
+                recent.pop_front();
+            }
+            recent.push_back(now);
+            if recent.len() <= *threshold as usize {
+                return false;
+            }
+            recent.clear();
+        }
+        warn!("More than {} decode errors within {:?} across all connections, {} in total.", threshold, window, total);
+        callback(total); // Outside the lock, so a slow callback doesn't hold up other connections' errors
+        true
+    }
+}
@@ -0,0 +1,281 @@
+use prost::Message;
+use std::{
+    error::Error,
+    fmt,
+    io::{self, Read, Write},
+    sync::Arc,
+};
+
+/// Version of the wire protocol, framing and message set, spoken by this crate
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Every frame starts with the payload length as a 4-byte big-endian integer
+pub const HEADER_LEN: usize = 4;
+
+/// Set in the length prefix when a 4-byte big-endian stream id follows it; frames without it belong to stream 0
+pub const STREAM_ID_FLAG: u32 = 1 << 31;
+
+/// Size of the optional stream id that follows a flagged length prefix
+pub const STREAM_ID_LEN: usize = 4;
+
+/// Largest payload length the prefix can carry next to the stream id flag
+pub const MAX_PAYLOAD_LEN: usize = (STREAM_ID_FLAG - 1) as usize;
+
+/// Default upper bound for a single frame's payload
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Longest header a frame can have: the length prefix plus a stream id
+pub const MAX_HEADER_LEN: usize = HEADER_LEN + STREAM_ID_LEN;
+
+/// One payload and the logical stream it travels on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub stream_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// The frame as sent on the wire, header included
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(MAX_HEADER_LEN + self.payload.len());
+        push_header(&mut bytes, self.stream_id, self.payload.len());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+}
+
+/// Encodes the message and prefixes it with its length, on stream 0
+pub fn encode_frame<M: Message>(message: &M) -> Vec<u8> {
+    encode_stream_frame(0, message)
+}
+
+/// Encodes the message for the given stream; stream 0 uses the plain 4-byte header understood by every client
+pub fn encode_stream_frame<M: Message>(stream_id: u32, message: &M) -> Vec<u8> {
+    let payload_len = message.encoded_len();
+    debug_assert!(payload_len <= MAX_PAYLOAD_LEN, "payload too large to frame");
+    let mut frame = Vec::with_capacity(MAX_HEADER_LEN + payload_len);
+    push_header(&mut frame, stream_id, payload_len);
+    message
+        .encode(&mut frame)
+        .expect("a Vec grows to fit any message"); // Encoding only fails when the buffer is out of room
+    frame
+}
+
+/// Appends the header of a frame on `stream_id` carrying `payload_len` bytes
+fn push_header(buffer: &mut Vec<u8>, stream_id: u32, payload_len: usize) {
+    if stream_id == 0 {
+        buffer.extend_from_slice(&(payload_len as u32).to_be_bytes());
+    } else {
+        buffer.extend_from_slice(&(payload_len as u32 | STREAM_ID_FLAG).to_be_bytes());
+        buffer.extend_from_slice(&stream_id.to_be_bytes());
+    }
+}
+
+/// Writes one framed message and flushes the writer
+pub fn write_frame<W: Write, M: Message>(writer: &mut W, message: &M) -> io::Result<()> {
+    writer.write_all(&encode_frame(message))?;
+    writer.flush()
+}
+
+/// Blocks until one whole frame has been read
+pub fn read_frame<R: Read>(reader: &mut R, max_frame_size: usize) -> io::Result<Frame> {
+    let mut prefix = [0u8; HEADER_LEN];
+    reader.read_exact(&mut prefix)?;
+    let (declared, has_stream_id) = parse_prefix(prefix);
+    if declared > max_frame_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            FrameError::TooLarge {
+                declared,
+                max: max_frame_size,
+            },
+        ));
+    }
+    let mut stream_id = 0;
+    if has_stream_id {
+        let mut id = [0u8; STREAM_ID_LEN];
+        reader.read_exact(&mut id)?;
+        stream_id = u32::from_be_bytes(id);
+    }
+    let mut payload = vec![0u8; declared];
+    reader.read_exact(&mut payload)?;
+    Ok(Frame { stream_id, payload })
+}
+
+/// Splits a length prefix into the payload length and whether a stream id follows
+fn parse_prefix(prefix: [u8; HEADER_LEN]) -> (usize, bool) {
+    let prefix = u32::from_be_bytes(prefix);
+    ((prefix & !STREAM_ID_FLAG) as usize, prefix & STREAM_ID_FLAG != 0)
+}
+
+/// How frames are delimited on the wire, picked with `ServerBuilder::framer` or offered with `upgrade_framer`. The
+/// server reads into a buffer and asks the framer for frames as bytes arrive, so `read_frame` works on whatever has
+/// been received so far.
+pub trait Framer: Send + Sync {
+    /// Decodes the frame at the start of `bytes`, returning it with the number of bytes it took, or `None` until more
+    /// bytes arrive. Fails for a frame whose payload is over `max_payload`, or that is malformed; either way the
+    /// connection is closed, since the stream can't be resynchronized.
+    fn read_frame(&self, bytes: &[u8], max_payload: usize) -> Result<Option<(Frame, usize)>, FrameError>;
+
+    /// Appends `frame` to `out` as it is sent on the wire. Framings without streams may ignore `frame.stream_id`.
+    fn write_frame(&self, frame: &Frame, out: &mut Vec<u8>);
+
+    /// Longest a frame carrying at most `max_payload` bytes can be on the wire; the server buffers at least this much
+    fn max_frame_len(&self, max_payload: usize) -> usize {
+        max_payload + MAX_HEADER_LEN
+    }
+
+    /// Whether `bytes`, the start of an incomplete frame, hold enough of it to tell a frame is under way, which starts
+    /// the clock of `ServerBuilder::frame_timeout`. Any byte does by default.
+    fn has_header(&self, bytes: &[u8]) -> bool {
+        !bytes.is_empty()
+    }
+}
+
+/// The default framing: a 4-byte big-endian payload length, flagged with `STREAM_ID_FLAG` when a stream id follows
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LengthPrefixed;
+
+impl Framer for LengthPrefixed {
+    fn read_frame(&self, bytes: &[u8], max_payload: usize) -> Result<Option<(Frame, usize)>, FrameError> {
+        if bytes.len() < HEADER_LEN {
+            return Ok(None); // The header itself may arrive over several reads
+        }
+        let mut prefix = [0u8; HEADER_LEN];
+        prefix.copy_from_slice(&bytes[..HEADER_LEN]);
+        let (declared, has_stream_id) = parse_prefix(prefix);
+        if declared > max_payload {
+            return Err(FrameError::TooLarge {
+                declared,
+                max: max_payload,
+            });
+        }
+        let header_len = if has_stream_id {
+            MAX_HEADER_LEN
+        } else {
+            HEADER_LEN
+        };
+        if bytes.len() < header_len + declared {
+            return Ok(None);
+        }
+        let mut stream_id = 0;
+        if has_stream_id {
+            let mut id = [0u8; STREAM_ID_LEN];
+            id.copy_from_slice(&bytes[HEADER_LEN..header_len]);
+            stream_id = u32::from_be_bytes(id);
+        }
+        let payload = bytes[header_len..header_len + declared].to_vec();
+        Ok(Some((Frame { stream_id, payload }, header_len + declared)))
+    }
+
+    fn write_frame(&self, frame: &Frame, out: &mut Vec<u8>) {
+        push_header(out, frame.stream_id, frame.payload.len());
+        out.extend_from_slice(&frame.payload);
+    }
+
+    fn has_header(&self, bytes: &[u8]) -> bool {
+        if bytes.len() < HEADER_LEN {
+            return false;
+        }
+        let mut prefix = [0u8; HEADER_LEN];
+        prefix.copy_from_slice(&bytes[..HEADER_LEN]);
+        let (_, has_stream_id) = parse_prefix(prefix);
+        bytes.len() >= if has_stream_id { MAX_HEADER_LEN } else { HEADER_LEN }
+    }
+}
+
+/// A frame that cannot be accepted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// The header declares a payload larger than the configured maximum
+    TooLarge { declared: usize, max: usize },
+    /// A custom `Framer` found bytes that don't follow its framing
+    Malformed(String),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge { declared, max } => {
+                write!(f, "declared length {} exceeds max {}", declared, max)
+            }
+            FrameError::Malformed(reason) => write!(f, "malformed frame: {}", reason),
+        }
+    }
+}
+
+impl Error for FrameError {}
+
+/// Collects bytes as they are read from a stream and splits them into complete frames
+pub struct FrameBuffer {
+    buffer: Vec<u8>,
+    max_frame_size: usize,
+    framer: Arc<dyn Framer>,
+}
+
+impl FrameBuffer {
+    /// Splits length-prefixed frames
+    pub fn new(max_frame_size: usize) -> Self {
+        FrameBuffer::with_framer(max_frame_size, Arc::new(LengthPrefixed))
+    }
+
+    /// Splits frames the way `framer` delimits them
+    pub fn with_framer(max_frame_size: usize, framer: Arc<dyn Framer>) -> Self {
+        FrameBuffer {
+            buffer: Vec::new(),
+            max_frame_size,
+            framer,
+        }
+    }
+
+    /// Splits what arrives from now on the way `framer` delimits frames, as after a connection upgrade
+    pub fn set_framer(&mut self, framer: Arc<dyn Framer>) {
+        self.framer = framer;
+    }
+
+    /// Number of bytes received but not yet returned as a frame
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Whether the next frame's header, stream id included, has fully arrived while its payload hasn't yet; see
+    /// `Framer::has_header` for framings without a header
+    pub fn has_header(&self) -> bool {
+        self.framer.has_header(&self.buffer)
+    }
+
+    /// Appends freshly read bytes
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Removes the complete frames `pick` selects, out of turn, and returns how many; the other frames and any partial
+    /// one stay buffered in order. Stops at a frame that can't be accepted, leaving it for `next_frame` to report.
+    pub fn remove_frames(&mut self, mut pick: impl FnMut(&Frame) -> bool) -> usize {
+        let mut kept = Vec::with_capacity(self.buffer.len());
+        let mut removed = 0;
+        while let Ok(Some(frame)) = self.next_frame() {
+            if pick(&frame) {
+                removed += 1;
+            } else {
+                self.framer.write_frame(&frame, &mut kept);
+            }
+        }
+        kept.extend_from_slice(&self.buffer); // The partial or invalid rest
+        self.buffer = kept;
+        removed
+    }
+
+    /// Removes and returns the next complete frame, or `None` until more bytes arrive
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, FrameError> {
+        let Some((frame, consumed)) = self.framer.read_frame(&self.buffer, self.max_frame_size)? else {
+            return Ok(None);
+        };
+        self.buffer.drain(..consumed);
+        Ok(Some(frame))
+    }
+}
@@ -0,0 +1,186 @@
+use crate::message::{CloseNotice, ErrorResponse};
+use std::{error::Error, fmt, io};
+
+/// Why the server answered with an `ErrorResponse`, carried on the wire as `ErrorResponse::code`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u32)]
+pub enum ErrorCode {
+    /// A frame didn't decode as a `ClientMessage`
+    DecodeFailed = 1,
+    /// The request is valid but not supported by this server
+    Unsupported = 2,
+    /// An arithmetic result doesn't fit its field
+    Overflow = 3,
+    /// The client sent requests faster than allowed
+    RateLimited = 4,
+    /// The server is too busy to take the connection or request
+    AtCapacity = 5,
+    /// The request took longer than allowed
+    Timeout = 6,
+    /// The client used up its allowance, such as the bytes a connection may send
+    QuotaExceeded = 7,
+    /// The `ClientMessage` has no message set at all
+    EmptyMessage = 8,
+    /// The server is shutting down and no longer serves new connections
+    ShuttingDown = 9,
+    /// A value in the request is outside the range the server allows
+    OutOfRange = 10,
+    /// The client cancelled the request with a `CancelRequest` before it was answered
+    Cancelled = 11,
+    /// The server failed to produce a response, such as a handler returning none for a request that needs one
+    Internal = 12,
+}
+
+impl ErrorCode {
+    /// Every code, in wire order
+    pub const ALL: [ErrorCode; 12] = [
+        ErrorCode::DecodeFailed,
+        ErrorCode::Unsupported,
+        ErrorCode::Overflow,
+        ErrorCode::RateLimited,
+        ErrorCode::AtCapacity,
+        ErrorCode::Timeout,
+        ErrorCode::QuotaExceeded,
+        ErrorCode::EmptyMessage,
+        ErrorCode::ShuttingDown,
+        ErrorCode::OutOfRange,
+        ErrorCode::Cancelled,
+        ErrorCode::Internal,
+    ];
+
+    /// The value sent in `ErrorResponse::code`
+    pub fn code(self) -> u32 {
+        self as u32
+    }
+
+    /// Looks up a wire value, `None` for codes this version doesn't know
+    pub fn from_code(code: u32) -> Option<Self> {
+        ErrorCode::ALL.into_iter().find(|known| known.code() == code)
+    }
+}
+
+impl From<ErrorCode> for u32 {
+    fn from(code: ErrorCode) -> Self {
+        code.code()
+    }
+}
+
+impl TryFrom<u32> for ErrorCode {
+    type Error = u32; // The unknown code
+
+    fn try_from(code: u32) -> Result<Self, Self::Error> {
+        ErrorCode::from_code(code).ok_or(code)
+    }
+}
+
+impl ErrorResponse {
+    /// Builds a response with a typed code
+    pub fn new(code: ErrorCode, message: &str) -> Self {
+        ErrorResponse {
+            code: code.code(),
+            message: message.to_string(),
+            detail: String::new(),
+        }
+    }
+
+    /// Adds diagnostic context to the response, such as the values that broke a limit; clients should branch on the
+    /// code, not on the detail
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = detail.into();
+        self
+    }
+
+    /// The typed code, `None` if the server sent one this version doesn't know
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        ErrorCode::from_code(self.code)
+    }
+}
+
+/// Why the server closed a connection, sent in the `CloseNotice` it writes last when `close_notices` is set. Codes
+/// that have a WebSocket counterpart use its number; the rest are in WebSocket's range for private use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u32)]
+pub enum CloseCode {
+    /// The connection ended as intended, such as a handler asking to close after its response
+    NormalClose = 1000,
+    /// The server is shutting down
+    ServerShutdown = 1001,
+    /// The client sent something that doesn't follow the protocol, such as frames that don't decode
+    ProtocolError = 1002,
+    /// The client broke a limit the server enforces, such as the bytes a connection may send
+    PolicyViolation = 1008,
+    /// The client sent a frame over the size limit
+    TooBig = 1009,
+    /// The client sent nothing, or not a whole frame, for too long
+    Timeout = 4000,
+}
+
+impl CloseCode {
+    /// Every code, in wire order
+    pub const ALL: [CloseCode; 6] = [
+        CloseCode::NormalClose,
+        CloseCode::ServerShutdown,
+        CloseCode::ProtocolError,
+        CloseCode::PolicyViolation,
+        CloseCode::TooBig,
+        CloseCode::Timeout,
+    ];
+
+    /// The value sent in `CloseNotice::code`
+    pub fn code(self) -> u32 {
+        self as u32
+    }
+
+    /// Looks up a wire value, `None` for codes this version doesn't know
+    pub fn from_code(code: u32) -> Option<Self> {
+        CloseCode::ALL.into_iter().find(|known| known.code() == code)
+    }
+}
+
+impl CloseNotice {
+    /// Builds a notice with a typed code
+    pub fn new(code: CloseCode, reason: &str) -> Self {
+        CloseNotice {
+            code: code.code(),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// The typed code, `None` if the server sent one this version doesn't know
+    pub fn close_code(&self) -> Option<CloseCode> {
+        CloseCode::from_code(self.code)
+    }
+}
+
+/// Why a server could not be created
+#[derive(Debug)]
+pub enum ServerError {
+    /// A setting is missing, malformed or out of range
+    Config(String),
+    /// Binding the listener or another socket operation failed
+    Io(io::Error),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Config(message) => write!(f, "invalid configuration: {}", message),
+            ServerError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for ServerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ServerError::Config(_) => None,
+            ServerError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> Self {
+        ServerError::Io(e)
+    }
+}
@@ -0,0 +1,120 @@
+use crate::handler::Priority;
+use crate::pool::worker_thread;
+use log::error;
+use std::{
+    collections::VecDeque,
+    io,
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Condvar, Mutex},
+    thread::JoinHandle,
+};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed set of threads that run message handlers for every connection, so CPU-heavy handlers are bounded by
+/// the executor size instead of the number of connections
+pub(crate) struct Executor {
+    queue: Arc<Queue>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+struct Queue {
+    state: Mutex<QueueState>,
+    available: Condvar, // Signalled when a job is queued or the queue closes
+}
+
+struct QueueState {
+    high: VecDeque<Job>, // Always drained before `normal`
+    normal: VecDeque<Job>,
+    closed: bool,
+}
+
+impl Executor {
+    pub(crate) fn new(threads: usize, stack_size: Option<usize>) -> io::Result<Self> {
+        let queue = Arc::new(Queue {
+            state: Mutex::new(QueueState {
+                high: VecDeque::new(),
+                normal: VecDeque::new(),
+                closed: false,
+            }),
+            available: Condvar::new(),
+        });
+        let mut executor = Executor {
+            queue,
+            workers: Vec::with_capacity(threads),
+        };
+        for index in 0..threads {
+            let queue = Arc::clone(&executor.queue);
+            let worker = worker_thread(format!("handler-{}", index), stack_size)
+                .spawn(move || {
+                    while let Some(job) = queue.next_job() {
+                        job();
+                    }
+                })?; // Dropping `executor` on error closes the queue and stops the threads already started
+            executor.workers.push(worker);
+        }
+        Ok(executor)
+    }
+
+    /// Queues `task` to run on an executor thread, ahead of any queued tasks of lower `priority`; its result arrives on
+    /// the receiver, which disconnects instead if the task panicked
+    pub(crate) fn submit<R, F>(&self, priority: Priority, task: F) -> mpsc::Receiver<R>
+    where
+        R: Send + 'static,
+        F: FnOnce() -> R + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        self.queue.push(priority, Box::new(move || {
+            // Keep the thread alive if the task panics; the dropped sender tells the caller
+            match panic::catch_unwind(AssertUnwindSafe(task)) {
+                Ok(result) => {
+                    let _ = sender.send(result);
+                }
+                Err(_) => error!("A message handler panicked."),
+            }
+        }));
+        receiver
+    }
+
+    /// Tasks waiting for a thread
+    pub(crate) fn queued(&self) -> usize {
+        let state = self.queue.state.lock().unwrap();
+        state.high.len() + state.normal.len()
+    }
+}
+
+impl Drop for Executor {
+    fn drop(&mut self) {
+        self.queue.state.lock().unwrap().closed = true;
+        self.queue.available.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join(); // Task panics are caught inside the worker
+        }
+    }
+}
+
+impl Queue {
+    fn push(&self, priority: Priority, job: Job) {
+        let mut state = self.state.lock().unwrap();
+        match priority {
+            Priority::High => state.high.push_back(job),
+            Priority::Normal => state.normal.push_back(job),
+        }
+        drop(state);
+        self.available.notify_one();
+    }
+
+    /// Blocks until a job is queued, returning `None` once the queue is closed and empty
+    fn next_job(&self) -> Option<Job> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(job) = state.high.pop_front().or_else(|| state.normal.pop_front()) {
+                return Some(job);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.available.wait(state).unwrap();
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use crate::error::ErrorCode;
+use crate::message::*;
+use crate::metrics::MessageType;
+use log::{info, warn};
+use std::{
+    io::{self, Read},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+/// Whether the connection stays open once a response has been written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CloseAfter {
+    /// Keep serving the client
+    #[default]
+    No,
+    /// Flush the response, then close the connection
+    Yes,
+}
+
+/// Order in which queued requests reach the handler when it runs on `handler_threads`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    /// Queued behind every waiting high-priority request
+    #[default]
+    Normal,
+    /// Taken ahead of normal requests, for control messages that must stay responsive under load
+    High,
+}
+
+/// Whether the server answers this kind of message; one-way messages like `LogEvent` never get a response, so
+/// clients must not wait for one. An upload is answered once, after its `last` chunk, and a `CancelRequest` only
+/// through the response of the request it cancels. An `IdempotentRequest` or a `DeadlineRequest` is answered if the
+/// request it wraps is.
+pub fn expects_response(message: &client_message::Message) -> bool {
+    match message {
+        client_message::Message::IdempotentRequest(request) => {
+            request.request.as_ref().and_then(|inner| inner.message.as_ref()).is_none_or(expects_response) // An empty one gets an error
+        }
+        client_message::Message::DeadlineRequest(request) => {
+            request.request.as_ref().and_then(|inner| inner.message.as_ref()).is_none_or(expects_response)
+        }
+        client_message::Message::LogEvent(_)
+        | client_message::Message::UploadStart(_)
+        | client_message::Message::CancelRequest(_) => false,
+        client_message::Message::UploadChunk(chunk) => chunk.last,
+        _ => true,
+    }
+}
+
+/// Set once the client sends a `CancelRequest` for the request being handled, once the server stops, or once the
+/// deadline of a `DeadlineRequest` passes; cloned tokens share the flag. It also tells the handler which connection
+/// sent the request.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    running: Option<Arc<AtomicBool>>, // The server's running flag, cleared by `Server::stop`; `None` outside a server
+    deadline: Option<SystemTime>, // When the client stops waiting, `None` if it didn't say
+    connection_id: Option<u64>, // The connection the request came from, `None` outside a server
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    /// A token that is also cancelled once `running` is cleared
+    pub(crate) fn until_stopped(running: Arc<AtomicBool>) -> Self {
+        CancellationToken {
+            cancelled: Arc::default(),
+            running: Some(running),
+            deadline: None,
+            connection_id: None,
+        }
+    }
+
+    /// The same token, also cancelled once `deadline` passes
+    pub(crate) fn with_deadline(mut self, deadline: Option<SystemTime>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// The same token, for a request from the connection with the given id
+    pub(crate) fn for_connection(mut self, connection_id: u64) -> Self {
+        self.connection_id = Some(connection_id);
+        self
+    }
+
+    /// The id of the connection that sent the request, as in the server's `[connection N]` log lines; `None` for a
+    /// token made outside a server
+    pub fn connection_id(&self) -> Option<u64> {
+        self.connection_id
+    }
+
+    /// Whether the request was cancelled by the client, the server is stopping or the deadline passed; a handler
+    /// checking this may give up early
+    pub fn is_cancelled(&self) -> bool {
+        self.is_cancelled_by_client() || self.is_shutting_down() || self.is_past_deadline()
+    }
+
+    /// When the client stops waiting for the response, if it sent the request in a `DeadlineRequest`; handlers waiting
+    /// on something else can bound the wait by it
+    pub fn deadline(&self) -> Option<SystemTime> {
+        self.deadline
+    }
+
+    /// Whether the deadline passed; the client gets an `ErrorResponse` with `ErrorCode::Timeout` whatever the handler
+    /// returns
+    pub fn is_past_deadline(&self) -> bool {
+        self.deadline.is_some_and(|deadline| SystemTime::now() >= deadline)
+    }
+
+    /// Whether the server is stopping, telling a shutdown apart from a `CancelRequest`
+    pub fn is_shutting_down(&self) -> bool {
+        self.running.as_ref().is_some_and(|running| !running.load(Ordering::SeqCst))
+    }
+
+    /// Whether the client sent a `CancelRequest` for the request; its response is discarded
+    pub(crate) fn is_cancelled_by_client(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Application logic run for every request the server itself doesn't answer
+pub trait MessageHandler: Send + Sync {
+    /// Handles one request; `None` sends no response
+    fn handle(&self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)>;
+
+    /// Handles one request that the client may cancel while it runs; by default `cancel` is ignored and the request
+    /// goes to `handle`. Slow handlers override this to stop once `cancel.is_cancelled()`: the client gets an
+    /// `ErrorResponse` with `ErrorCode::Cancelled` either way. A `CancelRequest` is only seen while handlers run on
+    /// `handler_threads`, since otherwise the connection doesn't read while its handler runs.
+    ///
+    /// The token is cancelled too once the server stops. A connection can't close until its handler returns, so `run`
+    /// only returns as fast as handlers notice: one that gives up on `cancel.is_shutting_down()` may return `None`, and
+    /// the client gets an `ErrorResponse` with `ErrorCode::ShuttingDown`. Handlers that never check hold up shutdown
+    /// until they finish.
+    fn handle_cancellable(&self, message: client_message::Message, _cancel: &CancellationToken) -> Option<(ServerMessage, CloseAfter)> {
+        self.handle(message)
+    }
+
+    /// The message types `handle` answers or accepts, listed in every `CapabilitiesResponse` next to the ones the
+    /// server handles itself. None by default, since the server can't tell what a handler does; override it so clients
+    /// can discover them.
+    fn message_types(&self) -> Vec<MessageType> {
+        Vec::new()
+    }
+
+    /// Picks the queue position of a request when handlers run on `handler_threads`; everything is `Normal`,
+    /// first come first served, unless overridden. Ignored when handlers run on the connection threads.
+    fn priority(&self, _message: &client_message::Message) -> Priority {
+        Priority::Normal
+    }
+
+    /// Whether the response to `message` depends on nothing but the message itself, so that with `result_cache` set a
+    /// repeat of it may be answered from the cache without running `handle` or any middleware. False unless overridden.
+    fn is_pure(&self, _message: &client_message::Message) -> bool {
+        false
+    }
+
+    /// Handles an upload announced by `start`; `body` reads the data of its `UploadChunk`s as they arrive, so at most
+    /// one chunk is held in memory. Runs on the connection's own thread, outside middleware, and chunks the handler
+    /// leaves unread are discarded. By default the data is counted and dropped, answering with an `UploadResponse`.
+    fn upload(&self, start: UploadStart, body: &mut dyn Read) -> Option<(ServerMessage, CloseAfter)> {
+        let bytes_received = io::copy(body, &mut io::sink()).ok()?; // A failed body closes the connection anyway
+        info!("Received upload {:?}: {} bytes", start.name, bytes_received);
+        let response = ServerMessage {
+            message: Some(server_message::Message::UploadResponse(UploadResponse { bytes_received })),
+            load: None,
+        };
+        Some((response, CloseAfter::No))
+    }
+}
+
+/// A layer run around the handler for every request it would see, for cross-cutting concerns like authentication,
+/// logging or metrics. It may answer by itself, change the request, or pass it on with `next.run`.
+pub trait Middleware: Send + Sync {
+    fn call(&self, message: client_message::Message, next: Next<'_>) -> Option<(ServerMessage, CloseAfter)>;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(client_message::Message, Next<'_>) -> Option<(ServerMessage, CloseAfter)> + Send + Sync,
+{
+    fn call(&self, message: client_message::Message, next: Next<'_>) -> Option<(ServerMessage, CloseAfter)> {
+        self(message, next)
+    }
+}
+
+/// The rest of the chain after a middleware: the later middleware, then the handler
+pub struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware>],
+    handler: &'a dyn MessageHandler,
+    cancel: &'a CancellationToken,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(middleware: &'a [Box<dyn Middleware>], handler: &'a dyn MessageHandler, cancel: &'a CancellationToken) -> Self {
+        Next { middleware, handler, cancel }
+    }
+
+    /// The token telling whether the client cancelled this request
+    pub fn cancellation(&self) -> &CancellationToken {
+        self.cancel
+    }
+
+    /// Passes the request on and returns the response of the rest of the chain
+    pub fn run(self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)> {
+        match self.middleware.split_first() {
+            Some((layer, rest)) => layer.call(message, Next::new(rest, self.handler, self.cancel)),
+            None => self.handler.handle_cancellable(message, self.cancel),
+        }
+    }
+}
+
+/// What the built-in add does with a sum that doesn't fit an `i32`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Answer with an `Overflow` error
+    #[default]
+    Error,
+    /// Clamp the sum to `i32::MIN` or `i32::MAX`
+    Saturate,
+    /// Wrap around, as two's complement addition does
+    Wrap,
+}
+
+impl OverflowPolicy {
+    /// `a + b` under this policy, `None` if it is an error
+    pub fn add(self, a: i32, b: i32) -> Option<i32> {
+        match self {
+            OverflowPolicy::Error => a.checked_add(b),
+            OverflowPolicy::Saturate => Some(a.saturating_add(b)),
+            OverflowPolicy::Wrap => Some(a.wrapping_add(b)),
+        }
+    }
+}
+
+/// The built-in add and echo handlers; every connection stays open
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultHandler;
+
+impl MessageHandler for DefaultHandler {
+    fn message_types(&self) -> Vec<MessageType> {
+        BuiltinHandler::default().message_types()
+    }
+
+    fn handle(&self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)> {
+        BuiltinHandler::default().handle(message)
+    }
+
+    fn handle_cancellable(&self, message: client_message::Message, cancel: &CancellationToken) -> Option<(ServerMessage, CloseAfter)> {
+        BuiltinHandler::default().handle_cancellable(message, cancel)
+    }
+
+    fn is_pure(&self, message: &client_message::Message) -> bool {
+        BuiltinHandler::default().is_pure(message)
+    }
+}
+
+/// `DefaultHandler` with a choice of what happens to sums that don't fit an `i32`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BuiltinHandler {
+    pub overflow: OverflowPolicy,
+}
+
+impl BuiltinHandler {
+    pub fn new(overflow: OverflowPolicy) -> Self {
+        BuiltinHandler { overflow }
+    }
+}
+
+impl MessageHandler for BuiltinHandler {
+    fn message_types(&self) -> Vec<MessageType> {
+        vec![MessageType::AddRequest, MessageType::EchoMessage, MessageType::BinaryEchoMessage, MessageType::LogEvent]
+    }
+
+    /// Sums only: echoes are as cheap to answer as to look up, and logging has to happen every time
+    fn is_pure(&self, message: &client_message::Message) -> bool {
+        matches!(message, client_message::Message::AddRequest(_))
+    }
+
+    fn handle(&self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)> {
+        self.answer(message, None)
+    }
+
+    /// Logs under the connection the token names, like the server's own lines about it
+    fn handle_cancellable(&self, message: client_message::Message, cancel: &CancellationToken) -> Option<(ServerMessage, CloseAfter)> {
+        self.answer(message, cancel.connection_id())
+    }
+}
+
+impl BuiltinHandler {
+    /// Answers `message`, prefixing the log lines with `connection` when the server said which one sent it
+    fn answer(&self, message: client_message::Message, connection: Option<u64>) -> Option<(ServerMessage, CloseAfter)> {
+        let prefix = connection.map(|id| format!("[connection {}] ", id)).unwrap_or_default();
+        let response = match message {
+            client_message::Message::AddRequest(add_request) => {
+                // Handle AddRequest messages
+                info!("{}Received AddRequest: a={}, b={}", prefix, add_request.a, add_request.b); // Log the request
+                // Perform the addition operation; a sum that doesn't fit an i32 never panics, the policy decides what it becomes
+                match self.overflow.add(add_request.a, add_request.b) {
+                    Some(result) => server_message::Message::AddResponse(AddResponse { result }), // Create the response with the result
+                    None => server_message::Message::ErrorResponse(
+                        ErrorResponse::new(ErrorCode::Overflow, "sum overflows i32").with_detail(format!(
+                            "{} + {} = {} is outside {}..={}",
+                            add_request.a,
+                            add_request.b,
+                            add_request.a as i64 + add_request.b as i64,
+                            i32::MIN,
+                            i32::MAX
+                        )),
+                    ),
+                }
+            }
+            // Handle EchoMessage messages
+            client_message::Message::EchoMessage(echo_message) => {
+                // Process EchoMessage
+                info!("{}Received EchoMessage: {}", prefix, echo_message.content); // Log the received message
+                // Create the echo response, echoing back the same content
+                server_message::Message::EchoMessage(echo_message)
+            }
+            // Handle BinaryEchoMessage messages
+            client_message::Message::BinaryEchoMessage(binary_echo) => {
+                info!("{}Received BinaryEchoMessage: {} bytes", prefix, binary_echo.payload.len()); // Log the payload size, the bytes may not be printable
+                // Echo the raw bytes back untouched
+                server_message::Message::BinaryEchoMessage(binary_echo)
+            }
+            // One-way events are only logged; replying would cost the client a round trip it doesn't wait for
+            client_message::Message::LogEvent(event) => {
+                info!("{}Received LogEvent: {}", prefix, event.message);
+                return None;
+            }
+            // Requests the server answers before handlers see them
+            other => {
+                warn!("{}No handler for message: {:?}", prefix, other);
+                return None;
+            }
+        };
+        Some((
+            ServerMessage {
+                message: Some(response),
+                load: None,
+            },
+            CloseAfter::No,
+        ))
+    }
+}
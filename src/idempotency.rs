@@ -0,0 +1,83 @@
+use crate::handler::CloseAfter;
+use crate::message::ServerMessage;
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    hash::Hash,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// A stored result, `None` for a request that was left unanswered
+type Handled = Option<(ServerMessage, CloseAfter)>;
+
+struct Entry {
+    handled: Handled,
+    stored_at: Instant, // Expires `ttl` after this
+    last_used: u64, // Tick of the last lookup or store, the smallest is evicted first
+}
+
+/// Results of recent requests by key, shared by every connection of a server: `IdempotentRequest`s by their idempotency
+/// key, and with `result_cache` requests to a pure handler by their encoding. Holds at most `capacity` entries,
+/// evicting the least recently used, and forgets each one `ttl` after it was stored.
+pub(crate) struct IdempotencyCache<K = String> {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<(HashMap<K, Entry>, u64)>, // With the tick counter, so both change under one lock
+}
+
+impl<K: Eq + Hash + Clone> IdempotencyCache<K> {
+    pub(crate) fn new(capacity: usize, ttl: Duration) -> Self {
+        IdempotencyCache {
+            capacity,
+            ttl,
+            entries: Mutex::new((HashMap::new(), 0)),
+        }
+    }
+
+    /// The stored result for `key`, if one is stored and hasn't expired
+    pub(crate) fn get<Q>(&self, key: &Q) -> Option<Handled>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let mut guard = self.entries.lock().unwrap();
+        let (entries, tick) = &mut *guard;
+        let entry = entries.get_mut(key)?;
+        if entry.stored_at.elapsed() >= self.ttl {
+            entries.remove(key);
+            return None;
+        }
+        *tick += 1;
+        entry.last_used = *tick;
+        Some(entry.handled.clone())
+    }
+
+    /// Stores the result for `key`, replacing any older one; makes room first by dropping expired entries, then the
+    /// least recently used
+    pub(crate) fn insert(&self, key: K, handled: Handled) {
+        let mut guard = self.entries.lock().unwrap();
+        let (entries, tick) = &mut *guard;
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            entries.retain(|_, entry| entry.stored_at.elapsed() < self.ttl);
+            if entries.len() >= self.capacity {
+                let oldest = entries.iter().min_by_key(|(_, entry)| entry.last_used).map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        *tick += 1;
+        let entry = Entry {
+            handled,
+            stored_at: Instant::now(),
+            last_used: *tick,
+        };
+        entries.insert(key, entry);
+    }
+
+    /// Forgets every stored result
+    pub(crate) fn clear(&self) {
+        self.entries.lock().unwrap().0.clear();
+    }
+}
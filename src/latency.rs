@@ -0,0 +1,52 @@
+use hdrhistogram::Histogram;
+use std::{sync::Mutex, time::Duration};
+
+const SHARDS: u64 = 16; // Connections are spread over this many histograms so they rarely contend for a lock
+const MAX_TRACKED_MICROS: u64 = 60_000_000; // Latencies above one minute are clamped
+
+/// Latency percentiles over everything recorded so far, such as every request handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub count: u64,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// Sharded latency histograms, written by the client workers and merged on demand
+pub(crate) struct LatencyRecorder {
+    shards: Vec<Mutex<Histogram<u64>>>,
+}
+
+impl LatencyRecorder {
+    pub(crate) fn new() -> Self {
+        LatencyRecorder {
+            shards: (0..SHARDS).map(|_| Mutex::new(new_histogram())).collect(),
+        }
+    }
+
+    /// Records one request; the connection id picks the shard, so a connection always hits the same lock
+    pub(crate) fn record(&self, connection_id: u64, elapsed: Duration) {
+        let micros = (elapsed.as_micros() as u64).clamp(1, MAX_TRACKED_MICROS);
+        let mut shard = self.shards[(connection_id % SHARDS) as usize].lock().unwrap();
+        shard.saturating_record(micros);
+    }
+
+    /// Merges the shards and reads the percentiles
+    pub(crate) fn stats(&self) -> LatencyStats {
+        let mut merged = new_histogram();
+        for shard in &self.shards {
+            let _ = merged.add(&*shard.lock().unwrap()); // Every shard shares the merged histogram's bounds
+        }
+        LatencyStats {
+            count: merged.len(),
+            p50: Duration::from_micros(merged.value_at_quantile(0.50)),
+            p99: Duration::from_micros(merged.value_at_quantile(0.99)),
+            max: Duration::from_micros(merged.max()),
+        }
+    }
+}
+
+fn new_histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, MAX_TRACKED_MICROS, 3).expect("histogram bounds are valid") // 3 significant digits
+}
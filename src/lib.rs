@@ -1,4 +1,22 @@
+mod breaker;
+pub mod codec;
+pub mod error;
+mod executor;
+pub mod handler;
+mod idempotency;
+#[cfg(feature = "latency")]
+pub mod latency;
+pub mod metrics;
+mod pool;
+mod rate_limit;
+#[cfg(feature = "recording")]
+pub mod recording;
+pub mod redact;
+#[cfg(feature = "request-log")]
+mod request_log;
+mod semaphore;
 pub mod server;
+pub mod stats;
 
 pub mod message {
     include!(concat!(env!("OUT_DIR"), "/messages.rs"));
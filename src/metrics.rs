@@ -0,0 +1,151 @@
+use crate::message::{client_message, server_message, ServerMessage};
+use std::{collections::HashMap, mem, sync::Mutex};
+
+/// Kind of request a metric is counted under
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum MessageType {
+    EchoMessage,
+    AddRequest,
+    BinaryEchoMessage,
+    VersionRequest,
+    HealthRequest,
+    LogEvent,
+    UploadStart,
+    UploadChunk,
+    SetIdleTimeoutRequest,
+    CancelRequest,
+    CapabilitiesRequest,
+    IdempotentRequest,
+    EchoMetadataRequest,
+    DeadlineRequest,
+    UpgradeRequest,
+    /// A `ClientMessage` with no message set, or only one this version doesn't know
+    Empty,
+    /// A frame that didn't decode as a `ClientMessage`
+    Undecodable,
+}
+
+impl MessageType {
+    /// The type of a decoded request
+    pub fn of(message: Option<&client_message::Message>) -> Self {
+        match message {
+            Some(client_message::Message::EchoMessage(_)) => MessageType::EchoMessage,
+            Some(client_message::Message::AddRequest(_)) => MessageType::AddRequest,
+            Some(client_message::Message::BinaryEchoMessage(_)) => MessageType::BinaryEchoMessage,
+            Some(client_message::Message::VersionRequest(_)) => MessageType::VersionRequest,
+            Some(client_message::Message::HealthRequest(_)) => MessageType::HealthRequest,
+            Some(client_message::Message::LogEvent(_)) => MessageType::LogEvent,
+            Some(client_message::Message::UploadStart(_)) => MessageType::UploadStart,
+            Some(client_message::Message::UploadChunk(_)) => MessageType::UploadChunk,
+            Some(client_message::Message::SetIdleTimeoutRequest(_)) => MessageType::SetIdleTimeoutRequest,
+            Some(client_message::Message::CancelRequest(_)) => MessageType::CancelRequest,
+            Some(client_message::Message::CapabilitiesRequest(_)) => MessageType::CapabilitiesRequest,
+            Some(client_message::Message::IdempotentRequest(_)) => MessageType::IdempotentRequest,
+            Some(client_message::Message::EchoMetadataRequest(_)) => MessageType::EchoMetadataRequest,
+            Some(client_message::Message::DeadlineRequest(_)) => MessageType::DeadlineRequest,
+            Some(client_message::Message::UpgradeRequest(_)) => MessageType::UpgradeRequest,
+            None => MessageType::Empty,
+        }
+    }
+
+    /// The name of the `ClientMessage` field type, as listed in a `CapabilitiesResponse`
+    pub fn name(&self) -> &'static str {
+        match self {
+            MessageType::EchoMessage => "EchoMessage",
+            MessageType::AddRequest => "AddRequest",
+            MessageType::BinaryEchoMessage => "BinaryEchoMessage",
+            MessageType::VersionRequest => "VersionRequest",
+            MessageType::HealthRequest => "HealthRequest",
+            MessageType::LogEvent => "LogEvent",
+            MessageType::UploadStart => "UploadStart",
+            MessageType::UploadChunk => "UploadChunk",
+            MessageType::SetIdleTimeoutRequest => "SetIdleTimeoutRequest",
+            MessageType::CancelRequest => "CancelRequest",
+            MessageType::CapabilitiesRequest => "CapabilitiesRequest",
+            MessageType::IdempotentRequest => "IdempotentRequest",
+            MessageType::EchoMetadataRequest => "EchoMetadataRequest",
+            MessageType::DeadlineRequest => "DeadlineRequest",
+            MessageType::UpgradeRequest => "UpgradeRequest",
+            MessageType::Empty => "Empty",
+            MessageType::Undecodable => "Undecodable",
+        }
+    }
+}
+
+/// How a request ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Outcome {
+    /// Answered with anything but an `ErrorResponse`, or left unanswered by the handler
+    Ok,
+    /// Answered with an `ErrorResponse`, failed to decode, or broke off the connection (an interrupted upload)
+    Error,
+}
+
+impl Outcome {
+    /// The outcome of a request that got `response`
+    pub fn of(response: Option<&ServerMessage>) -> Self {
+        match response.and_then(|response| response.message.as_ref()) {
+            Some(server_message::Message::ErrorResponse(_)) => Outcome::Error,
+            _ => Outcome::Ok,
+        }
+    }
+}
+
+/// Request counters by message type and outcome, shared by every connection of a server
+#[derive(Debug, Default)]
+pub struct Metrics {
+    counts: Mutex<HashMap<(MessageType, Outcome), u64>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// Counts one request
+    pub(crate) fn record(&self, message_type: MessageType, outcome: Outcome) {
+        *self.counts.lock().unwrap().entry((message_type, outcome)).or_insert(0) += 1;
+    }
+
+    /// How many requests of `message_type` ended with `outcome`
+    pub fn count(&self, message_type: MessageType, outcome: Outcome) -> u64 {
+        self.counts.lock().unwrap().get(&(message_type, outcome)).copied().unwrap_or(0)
+    }
+
+    /// Copies every non-zero counter
+    pub fn snapshot(&self) -> HashMap<(MessageType, Outcome), u64> {
+        self.counts.lock().unwrap().clone()
+    }
+
+    /// Takes every counter and resets them to zero in one step, so each request is in exactly one drain
+    pub fn drain(&self) -> MetricsSnapshot {
+        let counts = mem::take(&mut *self.counts.lock().unwrap());
+        MetricsSnapshot {
+            counts: counts.into_iter().map(|((message_type, outcome), count)| (message_type, outcome, count)).collect(),
+        }
+    }
+}
+
+/// Request counters taken out of `Metrics` by `Server::drain_metrics`, covering the requests since the previous drain
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct MetricsSnapshot {
+    pub counts: Vec<(MessageType, Outcome, u64)>, // Every non-zero counter, in no particular order
+}
+
+impl MetricsSnapshot {
+    /// How many requests of `message_type` ended with `outcome` in this window
+    pub fn count(&self, message_type: MessageType, outcome: Outcome) -> u64 {
+        self.counts
+            .iter()
+            .find(|(counted_type, counted_outcome, _)| *counted_type == message_type && *counted_outcome == outcome)
+            .map_or(0, |(_, _, count)| *count)
+    }
+
+    /// How many requests of any type and outcome are in this window
+    pub fn total(&self) -> u64 {
+        self.counts.iter().map(|(_, _, count)| count).sum()
+    }
+}
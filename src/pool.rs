@@ -0,0 +1,106 @@
+use log::error;
+use std::{
+    io,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// A fixed set of worker threads fed through a bounded queue
+pub(crate) struct WorkerPool<T> {
+    sender: Option<SyncSender<T>>, // Dropped on `join` so idle workers see the queue close
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    /// Starts `size` workers that run `job` for every queued item; at most `queue_capacity` items wait at once.
+    /// `connection_id` names the connection an item serves in the log line of a job that panics.
+    pub(crate) fn new<F>(
+        size: usize,
+        queue_capacity: usize,
+        stack_size: Option<usize>,
+        connection_id: fn(&T) -> u64,
+        job: F,
+    ) -> io::Result<Self>
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(queue_capacity);
+        let receiver = Arc::new(Mutex::new(receiver)); // Shared so any idle worker can take the next item
+        let job = Arc::new(job);
+        let mut pool = WorkerPool {
+            sender: Some(sender),
+            workers: Vec::with_capacity(size),
+        };
+        for index in 0..size {
+            let receiver = Arc::clone(&receiver);
+            let job = Arc::clone(&job);
+            let worker = worker_thread(format!("pool-worker-{}", index), stack_size)
+                .spawn(move || {
+                    while let Some(item) = next_item(&receiver) {
+                        let id = connection_id(&item);
+                        // Keep the worker alive if a job panics, otherwise the pool would shrink for good
+                        if panic::catch_unwind(AssertUnwindSafe(|| job(item))).is_err() {
+                            error!("[connection {}] A pool job panicked.", id);
+                        }
+                    }
+                })?; // Dropping `pool` on error closes the queue and stops the workers already started
+            pool.workers.push(worker);
+        }
+        Ok(pool)
+    }
+
+    /// Queues the item without waiting, handing it back if the queue is full
+    pub(crate) fn try_submit(&self, item: T) -> Result<(), T> {
+        match self.sender.as_ref() {
+            Some(sender) => sender.try_send(item).map_err(|e| match e {
+                TrySendError::Full(item) | TrySendError::Disconnected(item) => item,
+            }),
+            None => Err(item),
+        }
+    }
+
+    /// Queues the item, waiting for room if the queue is full; the item is handed back only if no worker is left
+    pub(crate) fn submit(&self, item: T) -> Result<(), T> {
+        match self.sender.as_ref() {
+            Some(sender) => sender.send(item).map_err(|e| e.0),
+            None => Err(item),
+        }
+    }
+
+    /// Closes the queue and waits for the workers to finish everything already queued
+    pub(crate) fn join(mut self) {
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join(); // Job panics are caught inside the worker
+        }
+    }
+}
+
+impl<T> Drop for WorkerPool<T> {
+    fn drop(&mut self) {
+        self.sender.take(); // Let detached workers exit if the pool is dropped without `join`
+    }
+}
+
+/// A builder for a named thread that runs connections or handlers, with the configured stack size if any
+pub(crate) fn worker_thread(name: String, stack_size: Option<usize>) -> thread::Builder {
+    let builder = thread::Builder::new().name(name);
+    match stack_size {
+        Some(size) => builder.stack_size(size),
+        None => builder, // The standard library default, 2 MiB unless `RUST_MIN_STACK` says otherwise
+    }
+}
+
+/// Blocks until an item is queued, returning `None` once the queue is closed and empty
+fn next_item<T>(receiver: &Mutex<Receiver<T>>) -> Option<T> {
+    let receiver = match receiver.lock() {
+        Ok(receiver) => receiver,
+        Err(poisoned) => poisoned.into_inner(), // A panicking job never holds the lock, so the queue is still intact
+    };
+    receiver.recv().ok()
+}
+
@@ -0,0 +1,55 @@
+use crate::metrics::MessageType;
+use std::{collections::HashMap, time::Instant};
+
+/// A token bucket: holds up to `burst` tokens, refilled at `per_second`, one taken per request
+struct Bucket {
+    per_second: u32,
+    burst: u32,
+    tokens: f64,
+    refilled: Instant, // When `tokens` was last brought up to date
+}
+
+impl Bucket {
+    fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let earned = now.duration_since(self.refilled).as_secs_f64() * self.per_second as f64;
+        self.tokens = (self.tokens + earned).min(self.burst as f64);
+        self.refilled = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// The rate limits of one connection, a bucket per limited message type; every bucket starts full
+pub(crate) struct RateLimiter {
+    buckets: HashMap<MessageType, Bucket>,
+}
+
+impl RateLimiter {
+    /// Buckets for `limits`, which maps each limited type to its rate per second and burst
+    pub(crate) fn new(limits: &HashMap<MessageType, (u32, u32)>) -> Self {
+        let now = Instant::now();
+        let buckets = limits
+            .iter()
+            .map(|(&message_type, &(per_second, burst))| {
+                let bucket = Bucket {
+                    per_second,
+                    burst,
+                    tokens: burst as f64,
+                    refilled: now,
+                };
+                (message_type, bucket)
+            })
+            .collect();
+        RateLimiter { buckets }
+    }
+
+    /// Counts a request of `message_type`, returning false if it is over its type's limit; types without a limit
+    /// always pass
+    pub(crate) fn try_acquire(&mut self, message_type: MessageType) -> bool {
+        self.buckets.get_mut(&message_type).is_none_or(Bucket::try_take)
+    }
+}
@@ -0,0 +1,104 @@
+use crate::codec::{self, Frame};
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
+    net::{Shutdown, TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    thread,
+};
+
+const INBOUND: u8 = 0; // Marks a frame the client sent
+const OUTBOUND: u8 = 1; // Marks a frame the server sent
+
+/// Which way a recorded frame travelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Sent by the client
+    Inbound,
+    /// Sent by the server
+    Outbound,
+}
+
+/// Writes the frames of one connection to its log as they pass. The log is a sequence of entries, each a direction
+/// byte (0 inbound, 1 outbound) followed by the frame exactly as framed on the wire.
+pub(crate) struct Recorder {
+    writer: BufWriter<File>,
+}
+
+impl Recorder {
+    /// Creates (or truncates) the log of connection `connection_id` in `dir`
+    pub(crate) fn create(dir: &Path, connection_id: u64) -> io::Result<Self> {
+        let file = File::create(log_path(dir, connection_id))?;
+        Ok(Recorder { writer: BufWriter::new(file) })
+    }
+
+    /// Appends one encoded frame; buffered, and flushed when the recorder is dropped with its connection
+    pub(crate) fn record(&mut self, direction: Direction, frame: &[u8]) -> io::Result<()> {
+        let marker = match direction {
+            Direction::Inbound => INBOUND,
+            Direction::Outbound => OUTBOUND,
+        };
+        self.writer.write_all(&[marker])?;
+        self.writer.write_all(frame)
+    }
+}
+
+/// Where `ServerBuilder::record_to(dir)` writes the log of connection `connection_id`
+pub fn log_path(dir: &Path, connection_id: u64) -> PathBuf {
+    dir.join(format!("connection-{}.log", connection_id))
+}
+
+/// Reads every frame of a connection log, in the order they passed
+pub fn read_log(path: impl AsRef<Path>) -> io::Result<Vec<(Direction, Frame)>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+    loop {
+        let mut marker = [0u8; 1];
+        match reader.read_exact(&mut marker) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(entries), // Between entries, so the log is whole
+            Err(e) => return Err(e),
+        }
+        let direction = match marker[0] {
+            INBOUND => Direction::Inbound,
+            OUTBOUND => Direction::Outbound,
+            other => return Err(io::Error::new(ErrorKind::InvalidData, format!("unknown direction marker {}", other))),
+        };
+        entries.push((direction, codec::read_frame(&mut reader, codec::MAX_PAYLOAD_LEN)?));
+    }
+}
+
+/// Connects to `addr`, sends every frame the client sent in the recorded log, in order and on its original stream,
+/// then closes the sending side and returns every frame the server answers with until it closes the connection.
+/// Comparing them with the log's outbound frames shows whether the server still behaves as recorded. Responses are
+/// read while the requests are sent, so a long log can't fill both socket buffers and stall the two sides.
+pub fn replay(path: impl AsRef<Path>, addr: impl ToSocketAddrs) -> io::Result<Vec<Frame>> {
+    let requests: Vec<Frame> = read_log(path)?
+        .into_iter()
+        .filter(|(direction, _)| *direction == Direction::Inbound)
+        .map(|(_, frame)| frame)
+        .collect();
+    let mut stream = TcpStream::connect(addr)?;
+    let mut reader = stream.try_clone()?;
+    let receiver = thread::spawn(move || {
+        let mut responses = Vec::new();
+        loop {
+            match codec::read_frame(&mut reader, codec::MAX_PAYLOAD_LEN) {
+                Ok(frame) => responses.push(frame),
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(responses),
+                Err(e) => return Err(e),
+            }
+        }
+    });
+    let sent = requests
+        .iter()
+        .try_for_each(|frame| stream.write_all(&frame.encode()))
+        .and_then(|_| stream.flush())
+        .and_then(|_| stream.shutdown(Shutdown::Write)); // The server answers everything, then sees the client close
+    if sent.is_err() {
+        let _ = stream.shutdown(Shutdown::Both); // Ends the receiver's read, the replay has failed anyway
+    }
+    let responses = receiver.join().map_err(|_| io::Error::other("response reader panicked"))?;
+    sent?;
+    responses
+}
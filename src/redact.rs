@@ -0,0 +1,117 @@
+use crate::message::*;
+
+/// How a payload field is written to the debug log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Redaction {
+    /// Log the whole value
+    Show,
+    /// Log the first `n` bytes and how many were left out
+    Truncate(usize),
+    /// Log only the length
+    Hide,
+}
+
+/// Redaction rules for `ServerBuilder::log_payloads`, one per message type with a free-form payload; numeric
+/// fields and server-level messages are always logged in full, upload data only by its length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadLogging {
+    pub echo: Redaction,        // `EchoMessage::content`, both ways
+    pub binary_echo: Redaction, // `BinaryEchoMessage::payload`, both ways, logged as hex
+    pub log_event: Redaction,   // `LogEvent::message`
+    pub error: Redaction,       // `ErrorResponse::message`
+}
+
+impl Default for PayloadLogging {
+    fn default() -> Self {
+        PayloadLogging {
+            echo: Redaction::Truncate(64),
+            binary_echo: Redaction::Truncate(32),
+            log_event: Redaction::Truncate(64),
+            error: Redaction::Show,
+        }
+    }
+}
+
+impl PayloadLogging {
+    /// Formats a request with the rules applied
+    pub fn describe_request(&self, message: &client_message::Message) -> String {
+        match message {
+            client_message::Message::EchoMessage(echo) => {
+                format!("EchoMessage {{ content: {} }}", redact_text(&echo.content, self.echo))
+            }
+            client_message::Message::BinaryEchoMessage(binary) => {
+                format!("BinaryEchoMessage {{ payload: {} }}", redact_bytes(&binary.payload, self.binary_echo))
+            }
+            client_message::Message::LogEvent(event) => {
+                format!("LogEvent {{ message: {} }}", redact_text(&event.message, self.log_event))
+            }
+            client_message::Message::UploadChunk(chunk) => {
+                format!("UploadChunk {{ data: <{} bytes>, last: {} }}", chunk.data.len(), chunk.last) // Never the data itself
+            }
+            client_message::Message::IdempotentRequest(request) => {
+                match request.request.as_ref().and_then(|inner| inner.message.as_ref()) {
+                    Some(inner) => format!("IdempotentRequest {{ key: {:?}, request: {} }}", request.key, self.describe_request(inner)),
+                    None => format!("IdempotentRequest {{ key: {:?}, request: none }}", request.key),
+                }
+            }
+            client_message::Message::DeadlineRequest(request) => {
+                match request.request.as_ref().and_then(|inner| inner.message.as_ref()) {
+                    Some(inner) => format!(
+                        "DeadlineRequest {{ deadline_unix_ms: {}, request: {} }}",
+                        request.deadline_unix_ms,
+                        self.describe_request(inner)
+                    ),
+                    None => format!("DeadlineRequest {{ deadline_unix_ms: {}, request: none }}", request.deadline_unix_ms),
+                }
+            }
+            other => format!("{:?}", other),
+        }
+    }
+
+    /// Formats a response with the rules applied
+    pub fn describe_response(&self, message: &ServerMessage) -> String {
+        match &message.message {
+            Some(server_message::Message::EchoMessage(echo)) => {
+                format!("EchoMessage {{ content: {} }}", redact_text(&echo.content, self.echo))
+            }
+            Some(server_message::Message::BinaryEchoMessage(binary)) => {
+                format!("BinaryEchoMessage {{ payload: {} }}", redact_bytes(&binary.payload, self.binary_echo))
+            }
+            Some(server_message::Message::ErrorResponse(error)) => format!(
+                "ErrorResponse {{ code: {}, message: {}, detail: {} }}",
+                error.code,
+                redact_text(&error.message, self.error),
+                redact_text(&error.detail, self.error)
+            ),
+            Some(other) => format!("{:?}", other),
+            None => "empty".to_string(),
+        }
+    }
+}
+
+fn redact_text(text: &str, redaction: Redaction) -> String {
+    match redaction {
+        Redaction::Show => format!("{:?}", text),
+        Redaction::Truncate(limit) if text.len() > limit => {
+            let mut end = limit;
+            while !text.is_char_boundary(end) {
+                end -= 1; // Never split a character
+            }
+            format!("{:?}... ({} more bytes)", &text[..end], text.len() - end)
+        }
+        Redaction::Truncate(_) => format!("{:?}", text),
+        Redaction::Hide => format!("<{} bytes redacted>", text.len()),
+    }
+}
+
+fn redact_bytes(bytes: &[u8], redaction: Redaction) -> String {
+    let hex = |bytes: &[u8]| bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+    match redaction {
+        Redaction::Show => hex(bytes),
+        Redaction::Truncate(limit) if bytes.len() > limit => {
+            format!("{}... ({} more bytes)", hex(&bytes[..limit]), bytes.len() - limit)
+        }
+        Redaction::Truncate(_) => hex(bytes),
+        Redaction::Hide => format!("<{} bytes redacted>", bytes.len()),
+    }
+}
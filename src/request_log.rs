@@ -0,0 +1,88 @@
+use crate::metrics::{MessageType, Outcome};
+use log::{error, warn};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender, TryRecvError, TrySendError},
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+const QUEUE_CAPACITY: usize = 4096; // Lines waiting for the writer thread; past this they are dropped rather than stall a connection
+
+/// Append-only JSON-lines log of every request, written by a background thread so connections never wait on the file
+pub(crate) struct RequestLog {
+    sender: SyncSender<String>, // Dropped with the server, which lets the writer flush and exit
+    dropped: AtomicU64, // Lines lost because the writer fell behind
+}
+
+impl RequestLog {
+    /// Opens `path` for appending, creating it if needed, and starts the writer thread
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+        thread::Builder::new()
+            .name("request-log".to_string())
+            .spawn(move || write_lines(BufWriter::new(file), receiver))?;
+        Ok(RequestLog {
+            sender,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Queues the line of one request without waiting; `latency` runs from its frame being read to its response being ready
+    pub(crate) fn write(&self, connection_id: u64, peer: SocketAddr, message_type: MessageType, outcome: Outcome, latency: Duration) {
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        // Every value is a number or a name without quotes or backslashes, so nothing needs escaping
+        let line = format!(
+            "{{\"timestamp_ms\":{},\"connection_id\":{},\"peer\":\"{}\",\"message_type\":\"{:?}\",\"outcome\":\"{:?}\",\"latency_us\":{}}}\n",
+            timestamp.as_millis(),
+            connection_id,
+            peer,
+            message_type,
+            outcome,
+            latency.as_micros()
+        );
+        match self.sender.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                    warn!("Request log can't keep up, dropping lines."); // Once, or it would flood the log it's behind
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => {} // The writer hit an error and already reported it
+        }
+    }
+}
+
+/// Writes queued lines until the log is dropped, flushing whenever the queue runs empty
+fn write_lines(mut writer: BufWriter<File>, receiver: Receiver<String>) {
+    loop {
+        let line = match receiver.try_recv() {
+            Ok(line) => line,
+            Err(TryRecvError::Empty) => {
+                if let Err(e) = writer.flush() {
+                    error!("Failed to write the request log, no more lines are logged: {}", e);
+                    return;
+                }
+                match receiver.recv() {
+                    Ok(line) => line,
+                    Err(_) => return, // Flushed above
+                }
+            }
+            Err(TryRecvError::Disconnected) => break,
+        };
+        if let Err(e) = writer.write_all(line.as_bytes()) {
+            error!("Failed to write the request log, no more lines are logged: {}", e);
+            return;
+        }
+    }
+    if let Err(e) = writer.flush() {
+        error!("Failed to flush the request log: {}", e);
+    }
+}
@@ -0,0 +1,46 @@
+use std::{
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Counts permits for a limited resource, such as requests in flight across every connection
+pub(crate) struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar, // Signalled whenever a permit is returned
+}
+
+/// A held permit, returned to its semaphore when dropped
+pub(crate) struct Permit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+    pub(crate) fn new(permits: usize) -> Self {
+        Semaphore {
+            available: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Takes a permit, waiting up to `timeout` for one to be released; `None` if none was free in time
+    pub(crate) fn acquire_timeout(&self, timeout: Duration) -> Option<Permit<'_>> {
+        let deadline = Instant::now() + timeout;
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            available = self.released.wait_timeout(available, deadline - now).unwrap().0; // Spurious wakeups loop again
+        }
+        *available -= 1;
+        Some(Permit { semaphore: self })
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.semaphore.available.lock().unwrap() += 1;
+        self.semaphore.released.notify_one();
+    }
+}
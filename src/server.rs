@@ -1,184 +1,3483 @@
+#[cfg(feature = "latency")]
+use crate::latency::{LatencyRecorder, LatencyStats};
+use crate::breaker::{DecodeErrorBreaker, SpikeCallback};
+use crate::codec::{self, Frame, FrameBuffer, FrameError, Framer, LengthPrefixed, DEFAULT_MAX_FRAME_SIZE};
+use crate::error::{CloseCode, ErrorCode, ServerError};
+use crate::executor::Executor;
+use crate::idempotency::IdempotencyCache;
+use crate::handler::{expects_response, CancellationToken, CloseAfter, DefaultHandler, MessageHandler, Middleware, Next};
 use crate::message::*; // Import the module containing messages
-use log::{error, info, warn};
+use crate::metrics::{MessageType, Metrics, MetricsSnapshot, Outcome};
+use crate::pool::{worker_thread, WorkerPool};
+use crate::rate_limit::RateLimiter;
+#[cfg(feature = "recording")]
+use crate::recording::{Direction, Recorder};
+use crate::redact::PayloadLogging;
+#[cfg(feature = "request-log")]
+use crate::request_log::RequestLog;
+use crate::semaphore::{Permit, Semaphore};
+use crate::stats::{
+    ConnectionCounters, ConnectionSnapshot, ConnectionStats, DisconnectLog, DisconnectReason, DisconnectRecord, ServerSnapshot,
+    ShutdownReason, SlowWrite,
+};
+use log::{debug, error, info, log_enabled, warn, Level};
 use prost::Message;
+use signal_hook::{consts::SIGTERM, flag};
+use socket2::SockRef;
+#[cfg(any(feature = "recording", feature = "request-log"))]
+use std::path::PathBuf;
 use std::{
+    collections::HashMap,
+    env,
     io::{self, ErrorKind, Read, Write},
-    net::{TcpListener, TcpStream},
+    str::FromStr,
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
         Arc,
         Mutex, // Mutual exclusion
+        RwLock,
     },
-    thread,
-    time::Duration,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
 };
 
+const POLL_INTERVAL: Duration = Duration::from_millis(100); // How long the accept and read loops wait before re-checking `is_running`, unless `adaptive_polling` is set
+const ACCEPT_TIMEOUT: Duration = Duration::from_millis(25); // How long a blocking `accept` waits for a client before re-checking `is_running`, on Linux
+const WARMUP_STEP: Duration = Duration::from_millis(10); // How long the accept loop waits for the next accept `warmup` allows
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(10); // How often a connection waiting for its handler checks for a `CancelRequest`
+const FD_EXHAUSTION_BACKOFF: Duration = Duration::from_millis(500); // How long `accept` rests once the process is out of file descriptors
+const BUFFER_BUDGET_WAIT: Duration = Duration::from_millis(10); // How long a connection paused by `max_total_buffered_bytes` waits before asking again
+const NO_LEADER: u64 = u64::MAX; // `Shared::buffer_leader` while no connection leads, never a connection id
+const MAX_ECHO_LEN: usize = 16 * 1024 * 1024; // Longest echo content answered unless `max_echo_len` says otherwise
+const DEFAULT_ADDR: &str = "127.0.0.1:8080"; // Bind address used by `Server::from_env` when `SERVER_ADDR` is unset
+
+/// Server state every client needs to see, created once per server
+struct Shared {
+    config: ServerConfig,
+    tunables: RwLock<Tunables>, // Settings read as they are used, so `Server::reload_config` takes effect on running connections
+    #[cfg(feature = "latency")]
+    latency: LatencyRecorder, // Written by each client, read through `Server::latency_stats`
+    #[cfg(feature = "latency")]
+    queue_latency: LatencyRecorder, // How long connections waited for a pool worker, read through `Server::queue_latency_stats`
+    disconnects: DisconnectLog, // Recent disconnect reasons, read through `Server::recent_disconnects`
+    metrics: Arc<Metrics>, // Requests by type and outcome, read through `Server::metrics`
+    shared_metrics: Option<Arc<Metrics>>, // Also counts every request, together with other servers, when `shared_metrics` is set
+    handler: RwLock<Arc<dyn MessageHandler>>, // Application logic for every request the server doesn't answer itself, swapped by `Server::set_handler`
+    framer: Arc<dyn Framer>, // Splits what clients send into frames and frames every response
+    upgrades: HashMap<String, Arc<dyn Framer>>, // Framings a client may switch to with an `UpgradeRequest`, by name
+    active_clients: AtomicUsize, // Connections accepted and not yet closed, reported by health checks
+    on_disconnect: Option<DisconnectCallback>, // Called with the final statistics of every connection
+    executor: Option<Executor>, // Runs handlers off the connection threads when `handler_threads` is set
+    connections: Mutex<HashMap<u64, ConnectionHandle>>, // Open connections by id, for `Server::disconnect`
+    connections_per_ip: Mutex<HashMap<IpAddr, usize>>, // Open connections by peer IP, counted only when `max_connections_per_ip` is set
+    middleware: Arc<Vec<Box<dyn Middleware>>>, // Run around the handler, outermost first; shared with executor tasks
+    validation: Vec<ValidationRule>, // Checked against every request before it is handled, in the order added
+    in_flight: Option<Semaphore>, // Permits for handler runs across every connection, when `max_in_flight` is set
+    idempotency: Option<IdempotencyCache>, // Results of recent `IdempotentRequest`s, when `idempotency_cache` is set
+    results: Option<IdempotencyCache<Vec<u8>>>, // Responses of a pure handler by encoded request, when `result_cache` is set
+    started: Mutex<Option<(Instant, SystemTime)>>, // When the current `run` marked the server running, `None` outside `run`
+    is_paused: AtomicBool, // While set, new connections are accepted and closed right away
+    decode_errors: DecodeErrorBreaker, // Undecodable frames across every connection, for `on_decode_error_spike`
+    buffered_total: AtomicUsize, // Bytes held against `max_total_buffered_bytes` across every connection
+    buffer_leader: AtomicU64, // Connection that may read into the last `max_frame_len` bytes of the budget, `NO_LEADER` if none
+    queued_connections: AtomicUsize, // Connections handed to the worker pool that no worker has taken yet
+    #[cfg(feature = "request-log")]
+    request_log: Option<RequestLog>, // Gets a line per request when `request_log` is set
+}
+
+impl Shared {
+    /// The current `Tunables`, as last set by `build` or `Server::reload_config`
+    fn tunables(&self) -> Tunables {
+        *self.tunables.read().unwrap()
+    }
+
+    /// Takes a permit to run the handler if `max_in_flight` is set, or the response telling the client it wasn't granted
+    fn acquire_in_flight(&self, id: u64) -> Result<Option<Permit<'_>>, ServerMessage> {
+        let (Some(semaphore), Some((limit, wait))) = (&self.in_flight, self.config.max_in_flight) else {
+            return Ok(None);
+        };
+        match semaphore.acquire_timeout(wait) {
+            Some(permit) => Ok(Some(permit)),
+            None => {
+                warn!("[connection {}] {} requests already in flight, turning the request away.", id, limit);
+                let detail = format!("{} requests in flight, none finished within {:?}", limit, wait);
+                Err(detailed_error(ErrorCode::AtCapacity, "too many requests in flight", detail))
+            }
+        }
+    }
+
+    /// Counts an undecodable frame towards the server-wide total, pausing the server if that sets off
+    /// `on_decode_error_spike` and `pause_on_decode_error_spike` is set
+    fn count_decode_error(&self) {
+        if self.decode_errors.record() && self.config.pause_on_decode_error_spike && !self.is_paused.swap(true, Ordering::SeqCst) {
+            warn!("Server paused by the decode error spike, new connections are closed until `resume`.");
+        }
+    }
+
+    /// The load `report_load` adds to every response, as it is now
+    fn load(&self) -> ServerLoad {
+        let active = self.active_clients.load(Ordering::Relaxed);
+        let queued = self.queued_connections.load(Ordering::Relaxed);
+        let pool_size = self.config.workers.unwrap_or(0);
+        ServerLoad {
+            active_connections: active as u32,
+            pool_size: pool_size as u32,
+            busy_workers: active.saturating_sub(queued).min(pool_size) as u32, // Every open connection not queued has a worker
+            queued_connections: queued as u32,
+            queued_requests: self.executor.as_ref().map_or(0, Executor::queued) as u32,
+        }
+    }
+
+    /// Takes up to `wanted` bytes of `budget` for a read by connection `id` holding `held` bytes, returning how many
+    /// were granted. Once less than `wanted` is left, only connections holding less than their fair share of the
+    /// budget get the rest, so the largest consumers are the first to stop reading; 0 pauses the connection.
+    ///
+    /// Partial frames could otherwise take the whole budget, leaving none of them room to complete. So the last
+    /// `max_frame_len` bytes are held back for one connection at a time, the leader: the first to be refused takes the
+    /// role, and reads on until it has completed a frame.
+    fn reserve_buffered(&self, id: u64, wanted: usize, held: usize, budget: usize) -> usize {
+        let leading = self.buffer_leader.load(Ordering::SeqCst) == id;
+        let shared_budget = budget - self.config.max_frame_len; // `build` made sure the budget holds a largest frame
+        let fair_share = shared_budget / self.active_clients.load(Ordering::Relaxed).max(1);
+        let mut granted = 0;
+        let _ = self.buffered_total.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |total| {
+            let available = shared_budget.saturating_sub(total);
+            granted = if leading {
+                budget.saturating_sub(total).min(wanted)
+            } else if available >= wanted {
+                wanted
+            } else {
+                available.min(fair_share.saturating_sub(held))
+            };
+            (granted > 0).then_some(total + granted)
+        });
+        if granted == 0 && !leading && self.buffer_leader.compare_exchange(NO_LEADER, id, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            return self.reserve_buffered(id, wanted, held, budget);
+        }
+        granted
+    }
+
+    /// Gives up the lead of connection `id` over the held back bytes of `budget`, once the others can do without them
+    fn yield_buffer_lead(&self, id: u64, budget: usize) {
+        if self.buffered_total.load(Ordering::SeqCst) <= budget - self.config.max_frame_len {
+            let _ = self.buffer_leader.compare_exchange(id, NO_LEADER, Ordering::SeqCst, Ordering::SeqCst);
+        }
+    }
+
+    /// Counts a new connection from `ip`, or returns false if that IP already has `max_connections_per_ip` open
+    fn admit_peer(&self, ip: IpAddr) -> bool {
+        let Some(limit) = self.config.max_connections_per_ip else {
+            return true;
+        };
+        let mut counts = self.connections_per_ip.lock().unwrap();
+        let count = counts.entry(ip).or_insert(0);
+        if *count >= limit {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    /// Uncounts a connection from `ip` that `admit_peer` let in
+    fn release_peer(&self, ip: IpAddr) {
+        if self.config.max_connections_per_ip.is_none() {
+            return;
+        }
+        let mut counts = self.connections_per_ip.lock().unwrap();
+        if let Some(count) = counts.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&ip); // Keeps the map to the IPs with connections open
+            }
+        }
+    }
+
+    /// How long the current `run` has been going, zero outside `run`
+    fn uptime(&self) -> Duration {
+        self.started.lock().unwrap().map_or(Duration::ZERO, |(instant, _)| instant.elapsed())
+    }
+}
+
+/// What `Server::disconnect` needs to close a connection from outside its worker
+struct ConnectionHandle {
+    addr: SocketAddr,
+    stream: Option<TcpStream>, // A clone of the client's stream, shut down to wake the worker's read; `None` if cloning failed
+    kicked: Arc<AtomicBool>, // Tells the worker why its read ended
+    counters: Arc<ConnectionCounters>, // Kept up to date by the worker, for `Server::snapshot`
+    connected_at: Instant,
+}
+
+/// Observer registered through `ServerBuilder::on_disconnect`
+type DisconnectCallback = Box<dyn Fn(&ConnectionStats) + Send + Sync>;
+
+/// Check registered through `ServerBuilder::validate`, refusing a request with the `ErrorResponse` it returns
+type ValidationRule = Box<dyn Fn(&client_message::Message) -> Result<(), ErrorResponse> + Send + Sync>;
+
+/// Runner registered through `ServerBuilder::spawn_with`
+type Spawner = Box<dyn Fn(Connection) + Send + Sync>;
+
+/// An accepted connection handed to the `ServerBuilder::spawn_with` callback, to be served on a thread of the
+/// caller's choosing
+pub struct Connection {
+    client: Client,
+}
+
+impl Connection {
+    /// The connection id, matching the `[connection N]` log prefix
+    pub fn id(&self) -> u64 {
+        self.client.id
+    }
+
+    /// The client's address
+    pub fn peer(&self) -> SocketAddr {
+        self.client.addr
+    }
+
+    /// The client's socket, for inspecting its options; reading or writing it would break the framing
+    pub fn stream(&self) -> &TcpStream {
+        &self.client.stream
+    }
+
+    /// Serves the client until the connection ends, blocking the calling thread; returns why it ended.
+    /// Dropping the connection without running it closes it.
+    pub fn run(mut self) -> DisconnectReason {
+        self.client.handle()
+    }
+}
+
 struct Client {
     stream: TcpStream,
-    is_running: Arc<Mutex<AtomicBool>>, // Reference to the server's is_running flag wrapped in Arc<Mutex>
+    addr: SocketAddr, // Peer address, recorded when the connection ends
+    is_running: Arc<AtomicBool>, // The server's is_running flag, shared with every client
+    id: u64, // Connection id assigned at accept, prefixed to every log line about this connection
+    shared: Arc<Shared>,
+    decode_errors: u32, // Consecutive frames that failed to decode
+    connected_at: Instant, // When the connection was accepted
+    counters: Arc<ConnectionCounters>, // Bytes, writes and requests so far, shared with `Server::snapshot`
+    pending: Vec<u8>, // Responses held back by `write_buffer` until the end of the batch
+    kicked: Arc<AtomicBool>, // Set by `Server::disconnect`
+    last_echo: Option<String>, // Content of the previous request if it was an echo, for `suppress_duplicate_echoes`
+    idle_timeout: Option<Duration>, // Picked by the client with a `SetIdleTimeoutRequest`, `None` follows the server's
+    echoed_bytes: u64, // Echo content sent back so far, for `max_echo_bytes_per_connection`
+    echo_exhausted: bool, // An echo went over `max_echo_bytes_per_connection`; no more are answered
+    deadline: Option<SystemTime>, // Deadline of the `DeadlineRequest` being handled, if any
+    rate_limiter: RateLimiter, // This connection's allowance under `rate_limit`
+    buffered: usize, // Bytes this connection holds of `max_total_buffered_bytes`, 0 when no budget is set
+    read_ahead_end: Option<DisconnectReason>, // Why reading ahead for a `CancelRequest` ended the connection, acted on after the response
+    framer: Arc<dyn Framer>, // The server's framing until an `UpgradeRequest` switches it
+    upgrade: Option<Arc<dyn Framer>>, // Framing agreed to by an `UpgradeRequest`, switched to once its response is sent
+    close_code: Option<CloseCode>, // Sent instead of the reason's own `close_code`, such as `TooBig` for an oversized frame
+    #[cfg(feature = "recording")]
+    recorder: Option<Recorder>, // Logs every frame in and out when `record_to` is set
 }
 
 impl Client {
-    pub fn new(stream: TcpStream, is_running: Arc<Mutex<AtomicBool>>) -> Self {
-        Client { stream, is_running  } // Initialize with the TCP stream and the shared is_running flag
+    pub fn new(stream: TcpStream, addr: SocketAddr, is_running: Arc<AtomicBool>, id: u64, shared: Arc<Shared>) -> Self {
+        shared.active_clients.fetch_add(1, Ordering::Relaxed); // Released in `drop`, however the connection ends
+        let kicked = Arc::new(AtomicBool::new(false));
+        // Cloning needs a new descriptor and can fail under fd pressure; the connection is served either way
+        let handle = stream
+            .try_clone()
+            .inspect_err(|e| warn!("[connection {}] Failed to clone stream, disconnecting it waits for its next read timeout: {}", id, e))
+            .ok();
+        let counters = Arc::new(ConnectionCounters::default());
+        let connected_at = Instant::now();
+        let handle = ConnectionHandle {
+            addr,
+            stream: handle,
+            kicked: Arc::clone(&kicked),
+            counters: Arc::clone(&counters),
+            connected_at,
+        };
+        shared.connections.lock().unwrap().insert(id, handle); // Removed in `drop`
+        #[cfg(feature = "recording")]
+        let recorder = shared.config.record_dir.as_deref().and_then(|dir| {
+            Recorder::create(dir, id)
+                .inspect_err(|e| warn!("[connection {}] Failed to create the recording in {}: {}", id, dir.display(), e))
+                .ok() // Served without a recording
+        });
+        let rate_limiter = RateLimiter::new(&shared.config.rate_limits);
+        let framer = Arc::clone(&shared.framer);
+        Client {
+            stream,
+            addr,
+            is_running,
+            id,
+            shared,
+            decode_errors: 0,
+            connected_at,
+            counters,
+            pending: Vec::new(),
+            kicked,
+            last_echo: None,
+            idle_timeout: None,
+            echoed_bytes: 0,
+            echo_exhausted: false,
+            deadline: None,
+            rate_limiter,
+            buffered: 0,
+            read_ahead_end: None,
+            framer,
+            upgrade: None,
+            close_code: None,
+            #[cfg(feature = "recording")]
+            recorder,
+        } // Initialize with the TCP stream, the shared is_running flag and the server state
+    }
+
+    pub fn handle(&mut self) -> DisconnectReason {
+        let reason = self.serve();
+        // Remember why the connection ended for `Server::recent_disconnects`; recorded before the close below, which
+        // may wait on the client
+        self.shared.disconnects.push(self.id, self.addr, reason);
+        // A zero `linger` asks for a reset, which an orderly close would only delay
+        if reason.is_graceful() && self.shared.config.linger != Some(Duration::ZERO) {
+            self.send_close_notice(reason);
+            self.finish_writes();
+        }
+        info!("[connection {}] Connection closed ({:?}).", self.id, reason);
+        // `serve` returns on every way a connection can end, so the callback sees each connection exactly once
+        if let Some(on_disconnect) = &self.shared.on_disconnect {
+            let counters = &self.counters;
+            on_disconnect(&ConnectionStats {
+                connection_id: self.id,
+                peer: self.addr,
+                bytes_in: counters.bytes_in.load(Ordering::Relaxed),
+                bytes_out: counters.bytes_out.load(Ordering::Relaxed),
+                writes: counters.writes.load(Ordering::Relaxed),
+                messages_handled: counters.messages_handled.load(Ordering::Relaxed),
+                peak_buffered_bytes: counters.peak_buffered_bytes.load(Ordering::Relaxed),
+                duration: self.connected_at.elapsed(),
+                reason,
+            });
+        }
+        reason
+    }
+
+    /// Writes a `CloseNotice` saying why the connection ends, if `close_notices` is set and the reason has a code
+    fn send_close_notice(&mut self, reason: DisconnectReason) {
+        if !self.shared.config.close_notices {
+            return;
+        }
+        let Some(code) = self.close_code.or(reason.close_code()) else {
+            return;
+        };
+        let notice = ServerMessage {
+            message: Some(server_message::Message::CloseNotice(CloseNotice::new(code, &format!("{:?}", reason)))),
+            load: None,
+        };
+        if let Err(e) = self.send_response(0, &notice) {
+            debug!("[connection {}] Failed to send the close notice: {}", self.id, e); // Closing anyway
+        }
+    }
+
+    /// Flushes what was written and sends the end of stream after it, so the client reads every response before it
+    /// sees the connection close. Dropping the stream alone would still deliver them, unless unread requests are left
+    /// in the socket, in which case the OS resets the connection and the client may lose the last response.
+    fn finish_writes(&mut self) {
+        // `write_out` already flushed everything else; a failed flush doesn't hold back the end of stream
+        let finished = self.flush_pending().and_then(|_| self.stream.shutdown(std::net::Shutdown::Write));
+        if let Err(e) = finished {
+            debug!("[connection {}] Failed to finish writes on close: {}", self.id, e); // The client may have gone already
+            return;
+        }
+        if let Some(timeout) = self.shared.tunables().graceful_close_timeout {
+            self.await_client_close(timeout);
+        }
+    }
+
+    /// Waits up to `timeout` for the client to close its side once ours is shut down, discarding anything it still
+    /// sends, so the OS has no unread requests to reset the connection over. A client that doesn't close in time gets a
+    /// reset when the stream is dropped, so a dead peer can't hold the socket in the kernel.
+    fn await_client_close(&mut self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let mut buffer = [0; 512];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            if let Err(e) = self.stream.set_read_timeout(Some(remaining)) {
+                debug!("[connection {}] Failed to wait for the client to close: {}", self.id, e);
+                return; // Closed the usual way
+            }
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return, // Closed by the client
+                Ok(_) => {} // Requests sent after the server closed, which will never be answered
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => break,
+                Err(_) => return, // Reset by the client, nothing left to close
+            }
+        }
+        warn!("[connection {}] Client didn't close within {:?} of the server closing. Resetting connection.", self.id, timeout);
+        if let Err(e) = SockRef::from(&self.stream).set_linger(Some(Duration::ZERO)) {
+            debug!("[connection {}] Failed to reset the connection: {}", self.id, e); // Closed the usual way instead
+        }
+    }
+
+    /// Gives back the budget of bytes handled since the last call, `held` being what is still buffered
+    fn release_buffered(&mut self, held: usize) {
+        if held < self.buffered {
+            self.shared.buffered_total.fetch_sub(self.buffered - held, Ordering::SeqCst);
+            self.buffered = held;
+        }
+        if let Some(budget) = self.shared.config.max_total_buffered_bytes {
+            self.shared.yield_buffer_lead(self.id, budget);
+        }
+    }
+
+    /// When bytes last arrived, to the millisecond; reads made during an upload included
+    fn last_read_at(&self) -> Instant {
+        self.connected_at + Duration::from_millis(self.counters.last_read_ms.load(Ordering::Relaxed))
     }
 
-    pub fn handle(&mut self) {
+    /// Runs the read loop until the connection ends, returning why it ended
+    fn serve(&mut self) -> DisconnectReason {
         let mut buffer = [0; 512]; // Create a buffer to store incoming data
+        // Reassembles frames split across reads
+        let mut frames = FrameBuffer::with_framer(self.shared.config.max_frame_size, Arc::clone(&self.framer));
+        let max_buffered = self.shared.config.max_buffered_bytes();
+        // A connection accepted just as the server stopped learns why it won't be served
+        if !self.is_running.load(Ordering::SeqCst) {
+            info!("[connection {}] Server is shutting down. Turning away new client.", self.id);
+            self.reject(ErrorCode::ShuttingDown, "server shutting down");
+            return DisconnectReason::Shutdown;
+        }
+        let shared = Arc::clone(&self.shared);
+        // Send the configured banner before reading anything, so clients learn about the server without asking
+        if let Some(greeting) = &shared.config.greeting {
+            if let Err(e) = self.send_response(0, greeting).and_then(|_| self.flush_pending()) {
+                return DisconnectReason::from_write_error(&e);
+            }
+        }
+        let mut last_read = Instant::now(); // For `idle_timeout`
+        let mut frame_started: Option<Instant> = None; // When the header of the partial frame arrived, for `frame_timeout`
+        let mut backoff = Backoff::new(shared.tunables().poll_interval); // Paces the read timeout, see `adaptive_polling`
         // Enter a loop to continuously handle client messages
         loop{
             // Check if the server is still running
-            {
-                let is_running = self.is_running.lock().unwrap(); // Lock the `is_running` flag to check its status
-                if !is_running.load(Ordering::SeqCst) {  // If the server is shutting down, exit the loop
-                    info!("Server is shutting down. Closing client connection.");
-                    break;
-                }
-            }   
+            if !self.is_running.load(Ordering::SeqCst) {  // If the server is shutting down, exit the loop
+                info!("[connection {}] Server is shutting down. Closing client connection.", self.id);
+                return DisconnectReason::Shutdown;
+            }
+            if backoff.reconfigure(self.shared.tunables().poll_interval) {
+                self.set_read_timeout(backoff.current()); // `Server::reload_config` changed the poll interval
+            }
+
+            // Handle the frames already buffered; when the cap is hit, re-check `is_running` before handling the rest
+            // Responses buffered by `write_buffer` go out once the batch is handled, before waiting for more requests
+            let buffered = frames.len();
+            let processed = self.process_frames(&mut frames);
+            self.release_buffered(frames.len());
+            if frames.len() < buffered {
+                frame_started = None; // A frame was taken, so a header left in the buffer is the next frame's
+            }
+            last_read = last_read.max(self.last_read_at()); // An upload reads from the stream itself
+            if let Err(e) = self.flush_pending() {
+                return processed.err().unwrap_or_else(|| DisconnectReason::from_write_error(&e));
+            }
+            match processed {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(reason) => return reason,
+            }
+
+            if frames.has_header() {
+                frame_started.get_or_insert_with(Instant::now);
+            }
+            if let Some(reason) = self.stalled(&frames, last_read, frame_started) {
+                return reason;
+            }
+
+            // Read no more than the backlog has room for. A full backlog always holds a complete frame, so the next
+            // iteration shrinks it; meanwhile unread bytes stay in the socket and TCP flow control slows the sender down
+            let room = max_buffered.saturating_sub(frames.len()).min(buffer.len());
+            if room == 0 {
+                continue;
+            }
 
             // Attempt to read data from the client's stream         
-            match self.stream.read(&mut buffer) {
+            let Some(read) = self.read_budgeted(&mut buffer[..room]) else {
+                thread::sleep(BUFFER_BUDGET_WAIT); // Unread bytes stay in the socket, slowing the client down
+                continue;
+            };
+            if self.kicked.load(Ordering::SeqCst) {
+                info!("[connection {}] Disconnected by the server.", self.id); // The shutdown ended the read, whatever it returned
+                return DisconnectReason::Kicked;
+            }
+            if self.counters.reaped.load(Ordering::SeqCst) {
+                return self.idle_reason(&frames, last_read.elapsed()); // The reaper already shut the stream down
+            }
+            match read {
+                Ok(0) if !frames.is_empty() => {
+                    // Every complete frame was handled above, so what is left is the start of one the client never finished
+                    warn!("[connection {}] Client disconnected mid-frame, {} bytes discarded.", self.id, frames.len());
+                    return DisconnectReason::ClosedMidFrame;
+                }
                 Ok(0) => {
-                    info!("Client disconnected."); // If 0 bytes are read, the client has disconnected,  so exit the loop  
-                    break;
+                    info!("[connection {}] Client disconnected.", self.id); // If 0 bytes are read, the client has disconnected,  so exit the loop  
+                    return DisconnectReason::ClientClosed;
                 }
                 Ok(bytes_read) => {
-                    // Decode the incoming message from the buffer
-                    match ClientMessage::decode(&buffer[..bytes_read]) {
-                        Ok(ClientMessage {
-                            message: Some(client_message::Message::AddRequest(add_request)),
-                        }) => {
-                            // Handle AddRequest messages
-                            info!("Received AddRequest: a={}, b={}",add_request.a, add_request.b); // Log the request
-                            let result = add_request.a + add_request.b; // Perform the addition operation
-                            // Create the response with the result
-                            let response = ServerMessage {
-                                message: Some(server_message::Message::AddResponse(AddResponse {
-                                    result, 
-                                })),
-                            };
-                             // Encode the response and send it back to the client
-                            let payload = response.encode_to_vec();
-                            if let Err(e) = self.stream.write_all(&payload) { // Handle any write errors
-                                error!("Error sending response: {}", e);
-                                break;
-                            }
-                            if let Err(e) = self.stream.flush() { // Ensure the data is flushed to the stream
-                                error!("Error flushing stream: {}", e);
-                                break;
-                            }
-                        }
-                        // Handle EchoMessage messages
-                        Ok(ClientMessage {
-                            message: Some(client_message::Message::EchoMessage(echo_message)),
-                        }) => {
-                            // Process EchoMessage
-                            info!("Received EchoMessage: {}", echo_message.content); // Log the received message
-                             // Create the echo response
-                            let response = ServerMessage {
-                                message: Some(server_message::Message::EchoMessage(EchoMessage {
-                                    content: echo_message.content.clone(), // Echo back the same content
-                                })),
-                            };
-
-                            // Encode the response and send it back to the client
-                            let payload = response.encode_to_vec();
-                            if let Err(e) = self.stream.write_all(&payload) { // Handle any write errors
-                                error!("Error sending response: {}", e);
-                                break;
-                            }
-                            if let Err(e) = self.stream.flush() { // Ensure the data is flushed to the stream
-                                error!("Error flushing stream: {}", e);
-                                break;
-                            }
-                        }
-                        // Log and ignore unknown message types
-                        Ok(_) => {
-                            warn!("Received unknown message type.");
-                        }
-                        // Handle decoding errors
-                        Err(e) => {
-                            error!("Failed to decode message: {}", e);
-                        }
+                    let bytes_in = self.counters.read(bytes_read);
+                    last_read = Instant::now();
+                    self.counters.last_read_ms.store(self.connected_at.elapsed().as_millis() as u64, Ordering::Relaxed);
+                    if backoff.busy() {
+                        self.set_read_timeout(backoff.current());
+                    }
+                    if self.exceeds_quota(bytes_in) {
+                        return DisconnectReason::QuotaExceeded;
                     }
+                    frames.extend(&buffer[..bytes_read]); // The frames it completes are handled at the top of the loop
+                    self.counters.buffered(frames.len());
                 }
-                 // Handle cases where no data is available yet
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    // No data available, just return and retry later
-                    thread::sleep(Duration::from_millis(100)); // Sleep briefly to avoid busy waiting
+                 // Handle cases where no data arrived within the read timeout (`TimedOut` on Windows)
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                    // No data available; the read timeout already paced the loop, so go back and re-check `is_running`
+                    if backoff.idle() {
+                        self.set_read_timeout(backoff.current());
+                    }
                 }
                 // Handle unexpected errors while reading from the stream
                 Err(e) => {
-                    error!("Unexpected error while reading: {}", e);
-                    break;
+                    error!("[connection {}] Unexpected error while reading: {}", self.id, e);
+                    return DisconnectReason::ReadError;
                 }
             }
         }
     }
-}
 
-pub struct Server {
-    listener: TcpListener,
-   is_running: Arc<Mutex<AtomicBool>>, // Wrap `AtomicBool` in a `Mutex` so you can lock it for safe access across threads
-}
+    /// Reads into `buffer`, holding what it reads against `max_total_buffered_bytes` when that is set. Waits for data
+    /// without reading it first, so an idle connection doesn't hold on to budget while it waits; `None` when the
+    /// budget has no room for this connection yet, leaving the bytes in the socket.
+    fn read_budgeted(&mut self, buffer: &mut [u8]) -> Option<io::Result<usize>> {
+        let Some(budget) = self.shared.config.max_total_buffered_bytes else {
+            return Some(self.stream.read(buffer));
+        };
+        match self.stream.peek(&mut buffer[..1]) {
+            Ok(0) => Some(Ok(0)),
+            Ok(_) => {
+                let granted = self.shared.reserve_buffered(self.id, buffer.len(), self.buffered, budget);
+                if granted == 0 {
+                    return None;
+                }
+                let read = self.stream.read(&mut buffer[..granted]);
+                let kept = *read.as_ref().unwrap_or(&0);
+                self.shared.buffered_total.fetch_sub(granted - kept, Ordering::SeqCst); // Give back what wasn't read
+                self.buffered += kept;
+                Some(read)
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
 
-impl Server {
-    /// Creates a new server instance
-    pub fn new(addr: &str) -> io::Result<Self> {
-        let listener = TcpListener::bind(addr)?;
-        let is_running = Arc::new(Mutex::new(AtomicBool::new(false))); // Initialize the is_running flag with a Mutex
-        Ok(Server {
-            listener,
-            is_running,
-        })
+    /// Whether `bytes_in` received so far is over `max_bytes_per_connection`; if so the client is told before the
+    /// connection closes
+    fn exceeds_quota(&mut self, bytes_in: u64) -> bool {
+        let Some(quota) = self.shared.tunables().max_bytes_per_connection.filter(|&quota| bytes_in > quota) else {
+            return false;
+        };
+        warn!("[connection {}] Client sent more than its {} byte quota. Closing connection.", self.id, quota);
+        let detail = format!("{} bytes received, quota is {}", bytes_in, quota);
+        let _ = self.send_response(0, &detailed_error(ErrorCode::QuotaExceeded, "quota exceeded", detail));
+        true
     }
 
-    /// Runs the server, listening for incoming connections and handling them
-    pub fn run(&self) -> io::Result<()> {
-        {
-            let is_running = self.is_running.lock().unwrap(); // Lock the Mutex to access is_running
-            is_running.store(true, Ordering::SeqCst); // Mark the server as running
+    /// Changes how long a read waits for data before the loop re-checks `is_running`
+    fn set_read_timeout(&self, timeout: Duration) {
+        if let Err(e) = self.stream.set_read_timeout(Some(timeout)) {
+            warn!("[connection {}] Failed to set the read timeout to {:?}: {}", self.id, timeout, e); // The old one keeps pacing the loop
         }
-        info!("Server is running on {}", self.listener.local_addr()?);
-        
-        self.listener.set_nonblocking(true)?; // Set the listener to non-blocking mode
+    }
 
-        while {
-            let is_running = self.is_running.lock().unwrap(); // Lock the Mutex to check is_running
-            is_running.load(Ordering::SeqCst) // Read the value inside the Mutex to continue the loop if the server is running
-        } {
-            match self.listener.accept() {
-                Ok((stream, addr)) => {
-                    info!("New client connected: {}", addr); // log the new client address
-                    let is_running_clone = Arc::clone(&self.is_running); // Clone the `is_running` Arc to pass a reference to the new thread safely
-                    // Spawn a new thread to handle the client independently
-                    thread::spawn(move || {
-                        let mut client = Client::new(stream, is_running_clone);  // Create a new Client instance, passing the stream and the cloned `is_running` reference
-                        client.handle(); // Call the `handle` method to process the client's requests in the separate thread
-                    });
+    /// Whether the client has stalled past `frame_timeout` or `idle_timeout`, and if so why; `frames` holds what it sent
+    /// but hasn't been handled, `frame_started` is when the header of the partial frame in it arrived
+    fn stalled(&self, frames: &FrameBuffer, last_read: Instant, frame_started: Option<Instant>) -> Option<DisconnectReason> {
+        let tunables = self.shared.tunables();
+        if let (Some(timeout), Some(started)) = (tunables.frame_timeout, frame_started) {
+            if started.elapsed() >= timeout {
+                warn!("[connection {}] Frame incomplete {:?} after its header, {} bytes discarded. Closing connection.", self.id, timeout, frames.len());
+                return Some(DisconnectReason::FrameTimeout);
+            }
+        }
+        if self.shared.config.idle_reaper.is_some() {
+            return None; // The reaper watches idle connections instead
+        }
+        let timeout = self.idle_timeout.or(tunables.idle_timeout)?;
+        if last_read.elapsed() < timeout {
+            return None;
+        }
+        Some(self.idle_reason(frames, timeout))
+    }
+
+    /// Why a client idle for `idle` is disconnected: stalled partway through a frame header, or just quiet
+    fn idle_reason(&self, frames: &FrameBuffer, idle: Duration) -> DisconnectReason {
+        if frames.is_empty() || frames.has_header() {
+            info!("[connection {}] Client idle for {:?}. Closing connection.", self.id, idle);
+            DisconnectReason::IdleTimeout
+        } else {
+            warn!("[connection {}] Frame header incomplete after {:?} idle, {} bytes discarded. Closing connection.", self.id, idle, frames.len());
+            DisconnectReason::HeaderTimeout
+        }
+    }
+
+    /// Handles up to `max_frames_per_iteration` buffered frames, returning `true` if the cap was reached, or why the
+    /// connection must end. A single read may complete many pipelined frames; the cap keeps such a batch from delaying shutdown.
+    fn process_frames(&mut self, frames: &mut FrameBuffer) -> Result<bool, DisconnectReason> {
+        for _ in 0..self.shared.tunables().max_frames_per_iteration {
+            let frame = match frames.next_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(false), // Wait for the rest of the frame
+                Err(e) => {
+                    error!("[connection {}] Invalid frame from client: {}. Closing connection.", self.id, e); // The stream can't be resynchronized
+                    let _ = self.send_response(0, &invalid_frame_response(&e));
+                    self.close_code = frame_close_code(&e);
+                    return Err(DisconnectReason::InvalidFrame);
                 }
-                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                    // No incoming connections, sleep briefly to reduce CPU usage
-                    thread::sleep(Duration::from_millis(100));
+            };
+            #[cfg(feature = "recording")]
+            self.record(Direction::Inbound, &frame.encode());
+            let frame_complete = Instant::now(); // Request latency is measured from here until the response is written
+            let message = match ClientMessage::decode(frame.payload.as_slice()) {
+                Ok(message) => {
+                    self.decode_errors = 0; // Only consecutive failures count against the client
+                    self.counters.messages_handled.fetch_add(1, Ordering::Relaxed);
+                    message
                 }
+                // Handle decoding errors
                 Err(e) => {
-                    error!("Error accepting connection: {}", e);
+                    error!("[connection {}] Failed to decode message: {}", self.id, e);
+                    self.finish_request(MessageType::Undecodable, Outcome::Error, frame_complete);
+                    self.decode_errors += 1;
+                    self.shared.count_decode_error();
+                    let limit = self.shared.tunables().max_decode_errors;
+                    if limit > 0 && self.decode_errors >= limit {
+                        // The peer is most likely not speaking this protocol at all; stop spending cycles on it
+                        error!("[connection {}] {} consecutive decode errors. Closing connection.", self.id, self.decode_errors);
+                        let response = decode_errors_response(self.decode_errors, frame.stream_id, &e);
+                        let _ = self.send_response(frame.stream_id, &response);
+                        return Err(DisconnectReason::TooManyDecodeErrors);
+                    }
+                    continue;
+                }
+            };
+            let payload_logging = self.shared.config.payload_logging.filter(|_| log_enabled!(Level::Debug)); // Skip the formatting when it would be dropped
+            if let (Some(logging), Some(request)) = (payload_logging, &message.message) {
+                debug!("[connection {}] Request on stream {}: {}", self.id, frame.stream_id, logging.describe_request(request));
+            }
+            let message_type = MessageType::of(message.message.as_ref());
+            if self.shared.config.suppress_duplicate_echoes && self.is_duplicate_echo(&message) {
+                debug!("[connection {}] Suppressing duplicate echo.", self.id);
+                let response = ServerMessage {
+                    message: Some(server_message::Message::DuplicateSuppressed(DuplicateSuppressed {})),
+                    load: None,
+                };
+                if let Err(e) = self.send_response(frame.stream_id, &response) {
+                    return Err(DisconnectReason::from_write_error(&e));
+                }
+                self.finish_request(message_type, Outcome::Ok, frame_complete);
+                continue;
+            }
+            // An upload reads its chunks straight from the connection, so it is handled here rather than dispatched
+            let handled = if let Some(client_message::Message::UploadStart(start)) = message.message {
+                self.upload(start, frames)
+                    .inspect_err(|_| self.finish_request(message_type, Outcome::Error, frame_complete))?
+            } else {
+                self.dispatch(message, &frame, frames)
+            };
+            let handled = self.limit_echo(handled);
+            let handled = self.limit_response_size(handled);
+            self.finish_request(message_type, Outcome::of(handled.as_ref().map(|(response, _)| response)), frame_complete);
+            if let Some((response, close_after)) = handled {
+                if let Some(logging) = payload_logging {
+                    debug!("[connection {}] Response on stream {}: {}", self.id, frame.stream_id, logging.describe_response(&response));
+                }
+                // Encode the response and send it back to the client, on the stream the request came from
+                if let Err(e) = self.send_response(frame.stream_id, &response) {
+                    return Err(DisconnectReason::from_write_error(&e));
                 }
+                #[cfg(feature = "latency")]
+                self.shared.latency.record(self.id, frame_complete.elapsed());
+                if close_after == CloseAfter::Yes {
+                    info!("[connection {}] Handler asked to close the connection after its response.", self.id);
+                    return Err(DisconnectReason::ClosedByHandler); // The response is already flushed
+                }
+            }
+            if let Some(reason) = self.read_ahead_end.take() {
+                return Err(reason); // The client was already told why
+            }
+            if let Some(framer) = self.upgrade.take() {
+                frames.set_framer(Arc::clone(&framer)); // The next frame is the first in the new framing
+                self.framer = framer;
             }
         }
+        Ok(true)
+    }
 
-        info!("Server stopped.");
-        Ok(())
+    /// Counts a request in the metrics and, with `request_log` set, logs it; `started` is when its frame was complete
+    #[cfg_attr(not(feature = "request-log"), allow(unused_variables))]
+    fn finish_request(&self, message_type: MessageType, outcome: Outcome, started: Instant) {
+        self.shared.metrics.record(message_type, outcome);
+        if let Some(metrics) = &self.shared.shared_metrics {
+            metrics.record(message_type, outcome);
+        }
+        #[cfg(feature = "request-log")]
+        if let Some(log) = &self.shared.request_log {
+            log.write(self.id, self.addr, message_type, outcome, started.elapsed());
+        }
     }
 
-    /// Stops the server by setting the `is_running` flag to `false`
-    pub fn stop(&self) {
-        let is_running = self.is_running.lock().unwrap(); // Acquire a lock on the Mutex to safely access the `is_running` flag
-        if is_running.load(Ordering::SeqCst) {
-            is_running.store(false, Ordering::SeqCst);
-            info!("Shutdown signal sent.");
-        } else {
-            warn!("Server was already stopped or not running.");
+    /// Replaces a response whose payload is over `max_frame_size` with an `ErrorResponse`, since clients enforcing the
+    /// same limit couldn't receive it; an echo is answered at the size it was sent, but handlers may answer with more
+    fn limit_response_size(&self, handled: Option<(ServerMessage, CloseAfter)>) -> Option<(ServerMessage, CloseAfter)> {
+        let max = self.shared.config.max_frame_size;
+        let (response, close_after) = handled?;
+        let size = response.encoded_len();
+        if size <= max {
+            return Some((response, close_after));
+        }
+        warn!("[connection {}] Response of {} bytes is over the {} byte frame limit, sending an error instead.", self.id, size, max);
+        let detail = format!("response of {} bytes exceeds max frame size {}", size, max);
+        Some((detailed_error(ErrorCode::OutOfRange, "response too large", detail), close_after))
+    }
+
+    /// The response to an echo of `len` bytes, over `max_echo_len`
+    fn echo_too_long(&self, len: usize) -> ServerMessage {
+        let max = self.shared.config.max_echo_len;
+        warn!("[connection {}] Echo of {} bytes is over the {} byte limit, refusing it.", self.id, len, max);
+        detailed_error(ErrorCode::OutOfRange, "echo too long", format!("echo of {} bytes exceeds max echo length {}", len, max))
+    }
+
+    /// Counts the content of an echo response against `max_echo_bytes_per_connection`, replacing it with an
+    /// `ErrorResponse` once it would go over; from then on every echo is refused, whatever its size
+    fn limit_echo(&mut self, handled: Option<(ServerMessage, CloseAfter)>) -> Option<(ServerMessage, CloseAfter)> {
+        let echoed = match handled.as_ref().and_then(|(response, _)| response.message.as_ref()) {
+            Some(server_message::Message::EchoMessage(echo)) => echo.content.len() as u64,
+            Some(server_message::Message::BinaryEchoMessage(binary)) => binary.payload.len() as u64,
+            _ => return handled,
+        };
+        let Some(cap) = self.shared.tunables().max_echo_bytes else {
+            self.echoed_bytes += echoed; // Still counted, so a cap set later by `reload_config` includes it
+            return handled;
+        };
+        if !self.echo_exhausted && self.echoed_bytes + echoed <= cap {
+            self.echoed_bytes += echoed;
+            return handled;
+        }
+        if !self.echo_exhausted {
+            warn!("[connection {}] Client used up its {} echo bytes. Refusing further echoes.", self.id, cap);
+            self.echo_exhausted = true;
+        }
+        let detail = format!("{} of {} echo bytes used, this response has {}", self.echoed_bytes, cap, echoed);
+        handled.map(|(_, close_after)| (detailed_error(ErrorCode::QuotaExceeded, "echo quota exceeded", detail), close_after))
+    }
+
+    /// Whether `message` is an echo with the same content as the request just before it; remembers it for the next one
+    fn is_duplicate_echo(&mut self, message: &ClientMessage) -> bool {
+        match &message.message {
+            Some(client_message::Message::EchoMessage(echo)) => {
+                if self.last_echo.as_deref() == Some(echo.content.as_str()) {
+                    return true;
+                }
+                self.last_echo = Some(echo.content.clone());
+                false
+            }
+            _ => {
+                self.last_echo = None; // Any other request ends the run of duplicates
+                false
+            }
+        }
+    }
+
+    /// Builds the response to a request, if any, and whether to close the connection after sending it; `frame` carried
+    /// the request, `frames` holds what the client sent after it
+    fn dispatch(&mut self, message: ClientMessage, frame: &Frame, frames: &mut FrameBuffer) -> Option<(ServerMessage, CloseAfter)> {
+        // Checked here rather than on the frame, so the request inside an `IdempotentRequest` or `DeadlineRequest` counts too
+        let message_type = MessageType::of(message.message.as_ref());
+        if !self.rate_limiter.try_acquire(message_type) {
+            let (per_second, burst) = self.shared.config.rate_limits[&message_type];
+            warn!("[connection {}] {:?} over its rate limit, refusing it.", self.id, message_type);
+            let detail = format!("{} allows {} per second, in bursts of up to {}", message_type.name(), per_second, burst);
+            let response = detailed_error(ErrorCode::RateLimited, "rate limited", detail);
+            return message.message.as_ref().is_none_or(expects_response).then_some((response, CloseAfter::No));
+        }
+        if let Some(request) = &message.message {
+            if let Some(refusal) = self.shared.validation.iter().find_map(|rule| rule(request).err()) {
+                warn!("[connection {}] {:?} failed validation: {}.", self.id, message_type, refusal.message);
+                let response = ServerMessage {
+                    message: Some(server_message::Message::ErrorResponse(refusal)),
+                    load: None,
+                };
+                return expects_response(request).then_some((response, CloseAfter::No));
+            }
+        }
+        match message.message {
+            // Report the crate and protocol versions so clients can decide whether they are compatible
+            Some(client_message::Message::VersionRequest(_)) => {
+                info!("[connection {}] Received VersionRequest", self.id);
+                Some((version_response(), CloseAfter::No))
+            }
+            // Answer health probes from the server's own state; load balancers only need to know it is serving
+            Some(client_message::Message::HealthRequest(_)) => {
+                let client_count = self.shared.active_clients.load(Ordering::Relaxed);
+                let started = *self.shared.started.lock().unwrap();
+                Some((health_response(client_count, started), CloseAfter::No))
+            }
+            // Read-only view of this connection, for clients diagnosing their own connectivity
+            Some(client_message::Message::EchoMetadataRequest(_)) => {
+                let counters = &self.counters;
+                let metadata = EchoMetadataResponse {
+                    peer: self.addr.to_string(),
+                    connection_id: self.id,
+                    connected_ms: self.connected_at.elapsed().as_millis() as u64,
+                    bytes_in: counters.bytes_in.load(Ordering::Relaxed),
+                    bytes_out: counters.bytes_out.load(Ordering::Relaxed),
+                    messages_handled: counters.messages_handled.load(Ordering::Relaxed),
+                };
+                Some((echo_metadata_response(metadata), CloseAfter::No))
+            }
+            Some(client_message::Message::SetIdleTimeoutRequest(request)) => {
+                let response = match idle_timeout_for(self.shared.config.idle_timeout_bounds, &request) {
+                    Ok(timeout) => {
+                        info!("[connection {}] Client set its idle timeout to {:?}.", self.id, timeout);
+                        self.idle_timeout = Some(timeout);
+                        self.counters.idle_timeout_ms.store(timeout.as_millis().max(1) as u64, Ordering::Relaxed);
+                        idle_timeout_response(timeout)
+                    }
+                    Err(response) => response,
+                };
+                Some((response, CloseAfter::No))
+            }
+            // Listed from what this server answers, so it stays right whatever handler is installed
+            Some(client_message::Message::CapabilitiesRequest(_)) => {
+                let handler = Arc::clone(&self.shared.handler.read().unwrap());
+                Some((capabilities_response(&*handler, &self.shared.config, &self.shared.upgrades), CloseAfter::No))
+            }
+            Some(client_message::Message::IdempotentRequest(request)) => self.idempotent(*request, frame, frames),
+            Some(client_message::Message::DeadlineRequest(request)) => self.with_deadline(*request, frame, frames),
+            Some(client_message::Message::UpgradeRequest(request)) => Some((self.upgrade(request, frames), CloseAfter::No)),
+            // Requests are handled one at a time, so the one to cancel has been answered by now
+            Some(client_message::Message::CancelRequest(cancel)) => {
+                debug!("[connection {}] Nothing in flight on stream {} to cancel.", self.id, cancel.request_id);
+                None
+            }
+            // Checked here so a pathological payload never reaches the handler, nor gets encoded into a response
+            Some(client_message::Message::EchoMessage(echo)) if echo.content.len() > self.shared.config.max_echo_len => {
+                Some((self.echo_too_long(echo.content.len()), CloseAfter::No))
+            }
+            Some(client_message::Message::BinaryEchoMessage(binary)) if binary.payload.len() > self.shared.config.max_echo_len => {
+                Some((self.echo_too_long(binary.payload.len()), CloseAfter::No))
+            }
+            // Everything else is application logic
+            Some(message) => {
+                let shared = Arc::clone(&self.shared); // The permit borrows it while waiting for the handler borrows the client
+                // Take a reference and release the lock, so a swap never waits for a request and the request keeps the
+                // handler it started with
+                let handler = Arc::clone(&shared.handler.read().unwrap());
+                // Keyed by the request itself rather than the frame, so the request inside a wrapper matches a bare one
+                let cache_key = match &shared.results {
+                    Some(results) if handler.is_pure(&message) => {
+                        let mut key = Vec::with_capacity(message.encoded_len());
+                        message.encode(&mut key);
+                        if let Some(handled) = results.get(&key) {
+                            debug!("[connection {}] Answered the request on stream {} from the result cache.", self.id, frame.stream_id);
+                            return handled;
+                        }
+                        Some(key)
+                    }
+                    _ => None,
+                };
+                let _permit = match shared.acquire_in_flight(self.id) {
+                    Ok(permit) => permit, // Held until the handler is done
+                    Err(response) => return Some((response, CloseAfter::No)),
+                };
+                let middleware = Arc::clone(&shared.middleware);
+                // Lets handlers give up on `stop` or once the client stops waiting
+                let cancel = CancellationToken::until_stopped(Arc::clone(&self.is_running))
+                    .with_deadline(self.deadline)
+                    .for_connection(self.id);
+                let message_type = MessageType::of(Some(&message));
+                let expects_response = expects_response(&message);
+                let handled = match &shared.executor {
+                    // Waiting for the result keeps this connection's responses in request order
+                    Some(executor) => {
+                        let priority = handler.priority(&message);
+                        let token = cancel.clone();
+                        let task = move || Next::new(&middleware, &*handler, &token).run(message);
+                        let handled = self.await_handler(executor.submit(priority, task), frame.stream_id, &cancel, frames);
+                        if cancel.is_cancelled_by_client() {
+                            info!("[connection {}] Request on stream {} cancelled.", self.id, frame.stream_id);
+                            let detail = format!("CancelRequest for stream {} arrived while its handler ran", frame.stream_id);
+                            return Some((detailed_error(ErrorCode::Cancelled, "request cancelled", detail), CloseAfter::No));
+                        }
+                        let Some(handled) = handled else {
+                            return None; // The handler panicked, which sends nothing whatever the policy
+                        };
+                        handled
+                    }
+                    None => Next::new(&middleware, &*handler, &cancel).run(message),
+                };
+                if expects_response && cancel.is_past_deadline() {
+                    info!("[connection {}] Request on stream {} finished past its deadline.", self.id, frame.stream_id);
+                    return Some((deadline_exceeded(message_type), CloseAfter::No));
+                }
+                if handled.is_none() && expects_response {
+                    if cancel.is_shutting_down() {
+                        info!("[connection {}] Handler gave up on stream {} for shutdown.", self.id, frame.stream_id);
+                        let detail = format!("handler for the {} on stream {} stopped for the shutdown", message_type.name(), frame.stream_id);
+                        return Some((detailed_error(ErrorCode::ShuttingDown, "server shutting down", detail), CloseAfter::Yes));
+                    }
+                    return self.missing_response(message_type);
+                }
+                if let (Some(results), Some(key)) = (&shared.results, cache_key) {
+                    // Errors aren't stored, as with `idempotency_cache`, so one that was passing goes away on the next try
+                    if Outcome::of(handled.as_ref().map(|(response, _)| response)) == Outcome::Ok && !cancel.is_cancelled() {
+                        results.insert(key, handled.clone());
+                    }
+                }
+                handled
+            }
+            None => {
+                warn!("[connection {}] Received a message with no known message set.", self.id);
+                Some((unset_message_response(&frame.payload), CloseAfter::No))
+            }
+        }
+    }
+
+    /// Handles the request a `DeadlineRequest` wraps, unless the deadline has passed already; handlers see it through
+    /// their `CancellationToken`. A deadline wrapped in another one only ever shortens it. One-way requests past their
+    /// deadline are dropped without an answer.
+    fn with_deadline(&mut self, request: DeadlineRequest, frame: &Frame, frames: &mut FrameBuffer) -> Option<(ServerMessage, CloseAfter)> {
+        let (deadline, inner) = match unwrap_deadline(request) {
+            Ok(unwrapped) => unwrapped,
+            Err(response) => return Some((response, CloseAfter::No)),
+        };
+        let message_type = MessageType::of(inner.message.as_ref());
+        if SystemTime::now() >= deadline {
+            info!("[connection {}] {} on stream {} arrived past its deadline, not handling it.", self.id, message_type.name(), frame.stream_id);
+            return expects_response(inner.message.as_ref()?).then(|| (deadline_exceeded(message_type), CloseAfter::No));
+        }
+        let outer = self.deadline;
+        self.deadline = Some(outer.map_or(deadline, |outer| outer.min(deadline)));
+        let handled = self.dispatch(inner, frame, frames);
+        self.deadline = outer;
+        handled
+    }
+
+    /// Agrees to switch the connection to the framing an `UpgradeRequest` names, one added with `upgrade_framer`. The
+    /// `UpgradeResponse` is the last frame in the old framing; bytes already received after the request would be split
+    /// the old way, so the upgrade is refused while any are buffered.
+    fn upgrade(&mut self, request: UpgradeRequest, frames: &FrameBuffer) -> ServerMessage {
+        let Some(framer) = self.shared.upgrades.get(&request.framing) else {
+            return unknown_framing(&request.framing, &self.shared.upgrades);
+        };
+        if !frames.is_empty() {
+            let detail = format!("{} bytes arrived after the UpgradeRequest", frames.len());
+            return detailed_error(ErrorCode::Unsupported, "upgrade must be the last frame sent", detail);
+        }
+        info!("[connection {}] Upgrading to the {:?} framing.", self.id, request.framing);
+        self.upgrade = Some(Arc::clone(framer));
+        ServerMessage {
+            message: Some(server_message::Message::UpgradeResponse(UpgradeResponse { framing: request.framing })),
+            load: None,
+        }
+    }
+
+    /// Handles the request an `IdempotentRequest` wraps. With `idempotency_cache` set, a retry is answered with the
+    /// result stored for its key instead of running again; only results other than an `ErrorResponse` are stored, so
+    /// a request that failed can be retried for real.
+    fn idempotent(&mut self, request: IdempotentRequest, frame: &Frame, frames: &mut FrameBuffer) -> Option<(ServerMessage, CloseAfter)> {
+        let (key, inner) = match unwrap_idempotent(request) {
+            Ok(unwrapped) => unwrapped,
+            Err(response) => return Some((response, CloseAfter::No)),
+        };
+        let shared = Arc::clone(&self.shared); // The cache borrows it while dispatching borrows the client
+        let Some(cache) = &shared.idempotency else {
+            return self.dispatch(inner, frame, frames);
+        };
+        if let Some(handled) = cache.get(&key) {
+            info!("[connection {}] Answering retried request {:?} with its stored result.", self.id, key);
+            return handled;
+        }
+        let handled = self.dispatch(inner, frame, frames);
+        if Outcome::of(handled.as_ref().map(|(response, _)| response)) == Outcome::Ok {
+            cache.insert(key, handled.clone());
+        }
+        handled
+    }
+
+    /// Waits for the handler's result, meanwhile reading ahead so a `CancelRequest` for `request_id` cancels it while it
+    /// runs. Other frames read stay buffered, in order, for the read loop; so does a closed or failed socket, which the
+    /// read loop finds on its next read. Returns `None` if the handler panicked.
+    fn await_handler(
+        &mut self,
+        pending: Receiver<Option<(ServerMessage, CloseAfter)>>,
+        request_id: u32,
+        cancel: &CancellationToken,
+        frames: &mut FrameBuffer,
+    ) -> Option<Option<(ServerMessage, CloseAfter)>> {
+        let mut buffer = [0; 512];
+        let mut reading = true;
+        let mut nonblocking = false; // Set on the first read ahead, so a handler that answers quickly costs no syscalls
+        let result = loop {
+            match pending.recv_timeout(CANCEL_POLL_INTERVAL) {
+                Ok(handled) => break Some(handled),
+                Err(RecvTimeoutError::Disconnected) => break None,
+                Err(RecvTimeoutError::Timeout) if !reading || cancel.is_cancelled_by_client() => {}
+                Err(RecvTimeoutError::Timeout) => {
+                    // Don't block on the read, the result may arrive meanwhile
+                    if !nonblocking {
+                        nonblocking = true;
+                        if let Err(e) = self.stream.set_nonblocking(true) {
+                            warn!("[connection {}] Can't read ahead for a CancelRequest: {}", self.id, e);
+                            reading = false;
+                            continue;
+                        }
+                    }
+                    // Read the way the main loop does, within the backlog cap, the buffer budget and the byte quota
+                    let room = self.shared.config.max_buffered_bytes().saturating_sub(frames.len()).min(buffer.len());
+                    let read = if room == 0 { None } else { self.read_budgeted(&mut buffer[..room]) };
+                    match read {
+                        None => {} // No room for now; the bytes wait in the socket
+                        Some(Ok(0)) => reading = false,
+                        Some(Ok(bytes_read)) => {
+                            let bytes_in = self.counters.read(bytes_read);
+                            if self.exceeds_quota(bytes_in) {
+                                self.read_ahead_end = Some(DisconnectReason::QuotaExceeded);
+                                reading = false;
+                            } else {
+                                frames.extend(&buffer[..bytes_read]);
+                                self.counters.buffered(frames.len());
+                            }
+                        }
+                        Some(Err(ref e)) if e.kind() == ErrorKind::WouldBlock => {}
+                        Some(Err(_)) => reading = false,
+                    }
+                    let cancels = frames.remove_frames(|frame| {
+                        let picked = is_cancel_for(frame, request_id);
+                        #[cfg(feature = "recording")]
+                        if picked {
+                            self.record(Direction::Inbound, &frame.encode());
+                        }
+                        picked
+                    });
+                    for _ in 0..cancels {
+                        self.counters.messages_handled.fetch_add(1, Ordering::Relaxed);
+                        self.finish_request(MessageType::CancelRequest, Outcome::Ok, Instant::now());
+                    }
+                    if cancels > 0 {
+                        cancel.cancel();
+                    }
+                }
+            }
+        };
+        if nonblocking {
+            if let Err(e) = self.stream.set_nonblocking(false) {
+                warn!("[connection {}] Failed to restore blocking reads: {}", self.id, e); // The next read reports it
+            }
+        }
+        result
+    }
+
+    /// Applies `missing_response` to a request of `message_type` the handler left unanswered
+    fn missing_response(&self, message_type: MessageType) -> Option<(ServerMessage, CloseAfter)> {
+        let policy = self.shared.config.missing_response;
+        if policy != MissingResponsePolicy::Ignore {
+            warn!("[connection {}] Handler returned no response to a {}.", self.id, message_type.name());
+        }
+        (policy == MissingResponsePolicy::Error)
+            .then(|| {
+                let detail = format!("handler returned nothing for a {}", message_type.name());
+                (detailed_error(ErrorCode::Internal, "handler returned no response", detail), CloseAfter::No)
+            })
+    }
+
+    /// Hands the chunks following `start` to the handler as a reader, returning its response once the upload is over or
+    /// why the connection must end. The handler sees data as it arrives, so memory stays bounded by the frame buffer.
+    fn upload(&mut self, start: UploadStart, frames: &mut FrameBuffer) -> Result<Option<(ServerMessage, CloseAfter)>, DisconnectReason> {
+        info!("[connection {}] Upload {:?} started, {} bytes announced.", self.id, start.name, start.size);
+        let shared = Arc::clone(&self.shared); // The permit borrows it while the body borrows the client
+        let _permit = match shared.acquire_in_flight(self.id) {
+            Ok(permit) => permit,
+            Err(response) => {
+                // Turned away before the handler saw anything; skip the chunks so the stream stays in sync
+                let mut body = UploadBody::new(self, frames);
+                let _ = io::copy(&mut body, &mut io::sink());
+                return match body.failure {
+                    Some(reason) => Err(reason),
+                    None => Ok(Some((response, CloseAfter::No))),
+                };
+            }
+        };
+        let handler = Arc::clone(&self.shared.handler.read().unwrap());
+        let mut body = UploadBody::new(self, frames);
+        let response = handler.upload(start, &mut body);
+        // Discard what the handler left unread, so the next frame read is the one after the last chunk
+        if body.failure.is_none() && !body.finished {
+            let _ = io::copy(&mut body, &mut io::sink());
+        }
+        match body.failure {
+            Some(reason) => Err(reason),
+            None => Ok(response),
+        }
+    }
+
+    /// Tells the client why it is being turned away; the connection closes when the client is dropped
+    fn reject(&mut self, code: ErrorCode, message: &str) {
+        // Failures are already logged, and the connection is closing anyway
+        let _ = self.send_response(0, &error_response(code, message)).and_then(|_| self.flush_pending());
+    }
+
+    /// Encodes the response as a frame on the given stream and writes it to the client, logging any failure. With
+    /// `write_buffer` set the frame is only queued until the buffer fills up or `flush_pending` is called.
+    fn send_response(&mut self, stream_id: u32, response: &ServerMessage) -> io::Result<()> {
+        let mut payload = response.encode_to_vec();
+        if self.shared.config.report_load {
+            // Decoding merges fields encoded one after the other, so appending the load spares cloning the response
+            let load = ServerMessage {
+                message: None,
+                load: Some(self.shared.load()),
+            };
+            load.encode(&mut payload).expect("a Vec grows to fit any message");
+        }
+        let frame = Frame { stream_id, payload };
+        #[cfg(feature = "recording")]
+        self.record(Direction::Outbound, &frame.encode()); // Recordings keep length prefixes whatever the framer
+        match self.shared.config.write_buffer {
+            Some(capacity) => {
+                self.framer.write_frame(&frame, &mut self.pending);
+                if self.pending.len() >= capacity {
+                    self.flush_pending()?;
+                }
+                Ok(())
+            }
+            None => {
+                let mut bytes = Vec::with_capacity(self.framer.max_frame_len(frame.payload.len()));
+                self.framer.write_frame(&frame, &mut bytes);
+                self.write_out(&bytes)
+            }
+        }
+    }
+
+    /// Appends a frame to the connection's recording, if `record_to` is set; a failed write stops the recording
+    #[cfg(feature = "recording")]
+    fn record(&mut self, direction: Direction, frame: &[u8]) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.record(direction, frame) {
+                warn!("[connection {}] Failed to record a frame, recording stopped: {}", self.id, e);
+                self.recorder = None;
+            }
+        }
+    }
+
+    /// Writes every response queued by `write_buffer` in one go
+    fn flush_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let pending = std::mem::take(&mut self.pending);
+        let written = self.write_out(&pending);
+        self.pending = pending; // Reuse the allocation for the next batch
+        self.pending.clear();
+        written
+    }
+
+    /// Writes encoded frames to the client and flushes the stream, logging any failure. Only a failed write ends the
+    /// connection: once `write_all` returns the bytes are with the transport, and a failed flush doesn't take them back.
+    /// On a `TcpStream` flushing is a no-op, since the kernel sends on its own schedule; a buffering transport
+    /// wrapped around the socket would report a real failure again on its next write, which does end the connection.
+    fn write_out(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.counters.writes.fetch_add(1, Ordering::Relaxed);
+        let tunables = self.shared.tunables();
+        let written = match self.shared.config.fragment_writes {
+            Some((chunk_len, delay)) => self.write_fragmented(bytes, chunk_len, delay, tunables),
+            None => self.write_whole(bytes, tunables),
+        };
+        written.inspect_err(|e| match e.kind() {
+            _ if DisconnectReason::from_write_error(e) == DisconnectReason::SlowWrite => error!(
+                "[connection {}] Slow client write: {} bytes not written within {:?}. Closing connection.",
+                self.id,
+                bytes.len(),
+                tunables.write_progress_timeout.unwrap_or_default()
+            ),
+            // The write timeout expired: the client keeps sending requests but stopped reading the responses,
+            // so both socket buffers are full and waiting any longer would deadlock this worker
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => error!(
+                "[connection {}] Client is not reading responses (write timed out after {:?}). Closing connection.",
+                self.id,
+                tunables.write_timeout.unwrap_or_default()
+            ),
+            _ => error!("[connection {}] Error sending response: {}", self.id, e), // Handle any write errors
+        })?;
+        self.counters.bytes_out.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        flush_written(self.id, &mut self.stream)
+    }
+
+    /// Writes all of `bytes`, bounded by `write_progress_timeout` or else `write_timeout`
+    fn write_whole(&mut self, bytes: &[u8], tunables: Tunables) -> io::Result<()> {
+        match tunables.write_progress_timeout {
+            Some(limit) => self.write_within(bytes, limit),
+            // Set on every write, as `write_within` changes it and `Server::reload_config` may have
+            None => self.stream.set_write_timeout(tunables.write_timeout).and_then(|_| self.stream.write_all(bytes)),
+        }
+    }
+
+    /// Writes `bytes` in pieces of `chunk_len`, each flushed and `delay` after the previous one, for `fragment_writes`
+    fn write_fragmented(&mut self, bytes: &[u8], chunk_len: usize, delay: Duration, tunables: Tunables) -> io::Result<()> {
+        for (index, chunk) in bytes.chunks(chunk_len).enumerate() {
+            if index > 0 {
+                thread::sleep(delay);
+            }
+            self.write_whole(chunk, tunables)?;
+            flush_written(self.id, &mut self.stream)?; // Never fails, as in `write_out`
         }
+        Ok(())
+    }
+
+    /// Writes `bytes` like `write_all`, but gives up with a `SlowWrite` error once `limit` has passed, however steadily
+    /// the client reads. Each write waits no longer than what is left of `limit`, nor than `write_timeout`.
+    fn write_within(&mut self, mut bytes: &[u8], limit: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + limit;
+        while !bytes.is_empty() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::new(ErrorKind::TimedOut, SlowWrite));
+            }
+            let wait = self.shared.tunables().write_timeout.map_or(remaining, |timeout| timeout.min(remaining));
+            self.stream.set_write_timeout(Some(wait))?;
+            match self.stream.write(bytes) {
+                Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+                Ok(written) => bytes = &bytes[written..],
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(ref e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) && Instant::now() >= deadline => {
+                    return Err(io::Error::new(ErrorKind::TimedOut, SlowWrite));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The data of an upload's chunks, read from the connection as the handler asks for it
+struct UploadBody<'a> {
+    client: &'a mut Client,
+    frames: &'a mut FrameBuffer, // Frames after the last chunk stay here for the read loop
+    chunk: Vec<u8>, // Data of the current chunk
+    position: usize, // How much of `chunk` has been read
+    finished: bool, // The `last` chunk has been received
+    failure: Option<DisconnectReason>, // Why the upload, and with it the connection, broke off
+    last_read: Instant, // For `idle_timeout`, as in the read loop
+    frame_started: Option<Instant>, // When the header of the partial chunk arrived, for `frame_timeout`
+}
+
+impl<'a> UploadBody<'a> {
+    /// The body of an upload whose `UploadStart` was just taken from `frames`
+    fn new(client: &'a mut Client, frames: &'a mut FrameBuffer) -> Self {
+        UploadBody {
+            client,
+            frames,
+            chunk: Vec::new(),
+            position: 0,
+            finished: false,
+            failure: None,
+            last_read: Instant::now(), // The `UploadStart` has just been read
+            frame_started: None,
+        }
+    }
+
+    /// Replaces the exhausted chunk with the next one, reading from the socket until it is complete
+    fn next_chunk(&mut self) -> Result<(), DisconnectReason> {
+        let id = self.client.id;
+        let mut buffer = [0; 16 * 1024];
+        loop {
+            let next = self.frames.next_frame();
+            self.client.release_buffered(self.frames.len()); // A chunk taken gives its share of the budget back
+            match next {
+                Ok(Some(frame)) => {
+                    #[cfg(feature = "recording")]
+                    self.client.record(Direction::Inbound, &frame.encode());
+                    return match ClientMessage::decode(frame.payload.as_slice()) {
+                        Ok(ClientMessage { message: Some(client_message::Message::UploadChunk(chunk)) }) => {
+                            self.frame_started = None; // A header left in the buffer is the next chunk's
+                            self.chunk = chunk.data;
+                            self.position = 0;
+                            self.finished = chunk.last;
+                            Ok(())
+                        }
+                        Ok(_) => {
+                            error!("[connection {}] Upload interrupted by another message. Closing connection.", id);
+                            Err(DisconnectReason::InvalidUpload)
+                        }
+                        Err(e) => {
+                            error!("[connection {}] Failed to decode upload chunk: {}. Closing connection.", id, e);
+                            Err(DisconnectReason::InvalidUpload)
+                        }
+                    };
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    error!("[connection {}] Invalid frame from client: {}. Closing connection.", id, e);
+                    self.client.close_code = frame_close_code(&e);
+                    return Err(DisconnectReason::InvalidFrame);
+                }
+            }
+
+            // A client that goes quiet or trickles a chunk in mid-upload is disconnected like one between requests
+            if self.frames.has_header() {
+                self.frame_started.get_or_insert_with(Instant::now);
+            }
+            if let Some(reason) = self.client.stalled(self.frames, self.last_read, self.frame_started) {
+                return Err(reason);
+            }
+
+            // The same backlog cap and buffer budget as the read loop; a full backlog always holds a complete frame
+            let room = self.client.shared.config.max_buffered_bytes().saturating_sub(self.frames.len()).min(buffer.len());
+            if room == 0 {
+                continue;
+            }
+            let Some(read) = self.client.read_budgeted(&mut buffer[..room]) else {
+                thread::sleep(BUFFER_BUDGET_WAIT); // Unread bytes stay in the socket, slowing the client down
+                continue;
+            };
+            if self.client.kicked.load(Ordering::SeqCst) {
+                info!("[connection {}] Disconnected by the server during an upload.", id);
+                return Err(DisconnectReason::Kicked);
+            }
+            if !self.client.is_running.load(Ordering::SeqCst) {
+                info!("[connection {}] Server is shutting down. Abandoning upload.", id);
+                return Err(DisconnectReason::Shutdown);
+            }
+            if self.client.counters.reaped.load(Ordering::SeqCst) {
+                return Err(self.client.idle_reason(self.frames, self.last_read.elapsed())); // The reaper already shut the stream down
+            }
+            match read {
+                Ok(0) => {
+                    warn!("[connection {}] Client disconnected mid-upload.", id);
+                    return Err(DisconnectReason::ClosedMidFrame);
+                }
+                Ok(bytes_read) => {
+                    let bytes_in = self.client.counters.read(bytes_read);
+                    self.last_read = Instant::now();
+                    self.client.counters.last_read_ms.store(self.client.connected_at.elapsed().as_millis() as u64, Ordering::Relaxed);
+                    if self.client.exceeds_quota(bytes_in) {
+                        return Err(DisconnectReason::QuotaExceeded);
+                    }
+                    self.frames.extend(&buffer[..bytes_read]);
+                    self.client.counters.buffered(self.frames.len());
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {} // Re-check for shutdown
+                Err(e) => {
+                    error!("[connection {}] Unexpected error while reading an upload: {}", id, e);
+                    return Err(DisconnectReason::ReadError);
+                }
+            }
+        }
+    }
+}
+
+impl Read for UploadBody<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            if let Some(reason) = self.failure {
+                return Err(io::Error::new(ErrorKind::ConnectionAborted, format!("upload broke off: {:?}", reason)));
+            }
+            if self.finished {
+                return Ok(0);
+            }
+            if let Err(reason) = self.next_chunk() {
+                self.failure = Some(reason); // Reported by `Client::upload` once the handler returns
+            }
+        }
+        let len = buf.len().min(self.chunk.len() - self.position);
+        buf[..len].copy_from_slice(&self.chunk[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.shared.active_clients.fetch_sub(1, Ordering::Relaxed); // The stream closes along with the client
+        self.shared.connections.lock().unwrap().remove(&self.id);
+        self.shared.release_peer(self.addr.ip());
+        self.release_buffered(0);
+    }
+}
+
+/// Flushes `stream` once a response has been written to it. A failure is only logged, never returned: the bytes are
+/// already with the transport, so it doesn't end the connection (see `Client::write_out`).
+fn flush_written(id: u64, stream: &mut impl Write) -> io::Result<()> {
+    if let Err(e) = stream.flush() {
+        warn!("[connection {}] Error flushing stream, the response was already written: {}", id, e);
+    }
+    Ok(())
+}
+
+/// Builds an `ErrorResponse` message
+fn error_response(code: ErrorCode, message: &str) -> ServerMessage {
+    ServerMessage {
+        message: Some(server_message::Message::ErrorResponse(ErrorResponse::new(code, message))),
+        load: None,
+    }
+}
+
+/// Builds an `ErrorResponse` message with diagnostic context
+fn detailed_error(code: ErrorCode, message: &str, detail: String) -> ServerMessage {
+    ServerMessage {
+        message: Some(server_message::Message::ErrorResponse(ErrorResponse::new(code, message).with_detail(detail))),
+        load: None,
+    }
+}
+
+/// Builds the error closing a connection that sent a frame it can't be resynchronized after
+fn invalid_frame_response(error: &FrameError) -> ServerMessage {
+    detailed_error(ErrorCode::DecodeFailed, "invalid frame", error.to_string())
+}
+
+/// Builds the error closing a connection after `count` consecutive frames failed to decode, the last on `stream_id`
+fn decode_errors_response(count: u32, stream_id: u32, last: &prost::DecodeError) -> ServerMessage {
+    let detail = format!("{} consecutive frames failed to decode, the last on stream {}: {}", count, stream_id, last);
+    detailed_error(ErrorCode::DecodeFailed, "too many decode errors", detail)
+}
+
+/// Builds the error for a `ClientMessage` that decoded with no message set: an empty payload means the client set
+/// none, anything else is a message this version doesn't know, which prost skips while decoding
+fn unset_message_response(payload: &[u8]) -> ServerMessage {
+    if payload.is_empty() {
+        detailed_error(ErrorCode::EmptyMessage, "empty oneof / no message set", "payload is empty".to_string())
+    } else {
+        let detail = format!("{} byte payload has no field this server knows", payload.len());
+        detailed_error(ErrorCode::Unsupported, "unknown message type", detail)
+    }
+}
+
+/// Builds the error refusing an `UpgradeRequest` for a framing the server doesn't offer
+fn unknown_framing(framing: &str, upgrades: &HashMap<String, Arc<dyn Framer>>) -> ServerMessage {
+    let mut offered: Vec<&str> = upgrades.keys().map(String::as_str).collect();
+    offered.sort_unstable();
+    let detail = format!("{:?} is not offered, the server offers [{}]", framing, offered.join(", "));
+    detailed_error(ErrorCode::Unsupported, "unknown framing", detail)
+}
+
+/// Builds the `VersionResponse` with the crate and protocol versions
+fn version_response() -> ServerMessage {
+    ServerMessage {
+        message: Some(server_message::Message::VersionResponse(VersionResponse {
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol_version: codec::PROTOCOL_VERSION,
+        })),
+        load: None,
+    }
+}
+
+/// Builds the `CapabilitiesResponse` of a server running `handler`: the types the server answers itself, those an
+/// upload is made of, and those the handler lists, sorted by name
+fn capabilities_response(handler: &dyn MessageHandler, config: &ServerConfig, upgrades: &HashMap<String, Arc<dyn Framer>>) -> ServerMessage {
+    let mut types = vec![
+        MessageType::VersionRequest,
+        MessageType::HealthRequest,
+        MessageType::CapabilitiesRequest,
+        MessageType::EchoMetadataRequest,
+        MessageType::CancelRequest,
+        MessageType::IdempotentRequest, // Deduplicated only with `idempotency_cache`, but always handled
+        MessageType::DeadlineRequest,
+        MessageType::UploadStart, // Every handler takes uploads, through `MessageHandler::upload`
+        MessageType::UploadChunk,
+    ];
+    if config.idle_timeout_bounds.is_some() {
+        types.push(MessageType::SetIdleTimeoutRequest); // Otherwise it is only ever refused
+    }
+    if !upgrades.is_empty() {
+        types.push(MessageType::UpgradeRequest);
+    }
+    types.extend(handler.message_types());
+    let mut message_types: Vec<String> = types.iter().map(|message_type| message_type.name().to_string()).collect();
+    message_types.sort();
+    message_types.dedup();
+    ServerMessage {
+        message: Some(server_message::Message::CapabilitiesResponse(CapabilitiesResponse { message_types })),
+        load: None,
+    }
+}
+
+/// The key and request of an `IdempotentRequest`, or the error answering one that can't be handled: without a key or
+/// a request, or wrapping a request the server only takes unwrapped (uploads, which span frames, cancels and wrappers)
+fn unwrap_idempotent(request: IdempotentRequest) -> Result<(String, ClientMessage), ServerMessage> {
+    if request.key.is_empty() {
+        return Err(error_response(ErrorCode::OutOfRange, "idempotency key must not be empty"));
+    }
+    let inner = match request.request {
+        Some(inner) => *inner,
+        None => ClientMessage::default(),
+    };
+    match inner.message {
+        None => Err(error_response(ErrorCode::EmptyMessage, "idempotent request without a request")),
+        Some(
+            ref wrapped @ (client_message::Message::UploadStart(_)
+            | client_message::Message::UploadChunk(_)
+            | client_message::Message::CancelRequest(_)
+            | client_message::Message::UpgradeRequest(_)
+            | client_message::Message::IdempotentRequest(_)),
+        ) => {
+            let detail = format!("a {} can only be sent unwrapped", MessageType::of(Some(wrapped)).name());
+            Err(detailed_error(ErrorCode::Unsupported, "request can't be made idempotent", detail))
+        }
+        Some(_) => Ok((request.key, inner)),
+    }
+}
+
+/// The deadline and request of a `DeadlineRequest`, or the error answering one that can't be handled: without a
+/// request, or wrapping a request the server only takes unwrapped (uploads, which span frames, cancels, upgrades and
+/// deadlines)
+fn unwrap_deadline(request: DeadlineRequest) -> Result<(SystemTime, ClientMessage), ServerMessage> {
+    let deadline = SystemTime::UNIX_EPOCH + Duration::from_millis(request.deadline_unix_ms);
+    let inner = match request.request {
+        Some(inner) => *inner,
+        None => ClientMessage::default(),
+    };
+    match inner.message {
+        None => Err(error_response(ErrorCode::EmptyMessage, "deadline request without a request")),
+        Some(
+            ref wrapped @ (client_message::Message::UploadStart(_)
+            | client_message::Message::UploadChunk(_)
+            | client_message::Message::CancelRequest(_)
+            | client_message::Message::UpgradeRequest(_)
+            | client_message::Message::DeadlineRequest(_)),
+        ) => {
+            let detail = format!("a {} can only be sent unwrapped", MessageType::of(Some(wrapped)).name());
+            Err(detailed_error(ErrorCode::Unsupported, "request can't be given a deadline", detail))
+        }
+        Some(_) => Ok((deadline, inner)),
+    }
+}
+
+/// Builds the error answering a request of `message_type` whose deadline passed before it was answered
+fn deadline_exceeded(message_type: MessageType) -> ServerMessage {
+    let detail = format!("the client's deadline for the {} passed", message_type.name());
+    detailed_error(ErrorCode::Timeout, "deadline exceeded", detail)
+}
+
+/// Builds the `HealthResponse` of a serving server; `started` is when its `run` began, if it is running
+fn health_response(client_count: usize, started: Option<(Instant, SystemTime)>) -> ServerMessage {
+    let (uptime, started_at) = match started {
+        Some((instant, at)) => (instant.elapsed(), at.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default()),
+        None => (Duration::ZERO, Duration::ZERO),
+    };
+    ServerMessage {
+        message: Some(server_message::Message::HealthResponse(HealthResponse {
+            ok: true,
+            client_count: client_count as u32,
+            uptime_ms: uptime.as_millis() as u64,
+            started_at_ms: started_at.as_millis() as u64,
+        })),
+        load: None,
+    }
+}
+
+/// Wraps the view of a connection in a `ServerMessage`
+fn echo_metadata_response(metadata: EchoMetadataResponse) -> ServerMessage {
+    ServerMessage {
+        message: Some(server_message::Message::EchoMetadataResponse(metadata)),
+        load: None,
+    }
+}
+
+/// Whether `frame` holds a `CancelRequest` for the request on stream `request_id`
+fn is_cancel_for(frame: &Frame, request_id: u32) -> bool {
+    matches!(
+        ClientMessage::decode(frame.payload.as_slice()),
+        Ok(ClientMessage { message: Some(client_message::Message::CancelRequest(cancel)) }) if cancel.request_id == request_id
+    )
+}
+
+/// Checks the idle timeout a client asked for against `bounds`, returning it or the `ErrorResponse` refusing it
+fn idle_timeout_for(bounds: Option<(Duration, Duration)>, request: &SetIdleTimeoutRequest) -> Result<Duration, ServerMessage> {
+    let Some((min, max)) = bounds else {
+        return Err(error_response(ErrorCode::Unsupported, "idle timeout can't be changed"));
+    };
+    let timeout = Duration::from_millis(request.timeout_ms);
+    if timeout < min || timeout > max {
+        let message = format!("idle timeout must be between {} and {} ms", min.as_millis(), max.as_millis());
+        return Err(error_response(ErrorCode::OutOfRange, &message));
+    }
+    Ok(timeout)
+}
+
+/// Builds the `SetIdleTimeoutResponse` confirming the connection's new idle timeout
+fn idle_timeout_response(timeout: Duration) -> ServerMessage {
+    ServerMessage {
+        message: Some(server_message::Message::SetIdleTimeoutResponse(SetIdleTimeoutResponse {
+            timeout_ms: timeout.as_millis() as u64,
+        })),
+        load: None,
+    }
+}
+
+/// Handles the frames in `bytes` the way a connection with the default configuration and handler would, without
+/// sockets or server state, returning the encoded responses or `None` if nothing is answered. Meant for fuzzing:
+/// malformed input gets an `ErrorResponse` or nothing, never a panic.
+///
+/// Processing stops where a connection would close, and a trailing partial frame is ignored. Health checks report no
+/// clients and no uptime, connection metadata is all empty, and an upload is answered as if it had no chunks; chunks
+/// reach the handler like any other message.
+pub fn process(bytes: &[u8]) -> Option<Vec<u8>> {
+    let defaults = ServerConfig::default();
+    let mut frames = FrameBuffer::new(defaults.max_frame_size);
+    frames.extend(bytes);
+    let mut output = Vec::new();
+    let mut decode_errors = 0;
+    loop {
+        let frame = match frames.next_frame() {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            // An invalid frame can't be resynchronized, so the connection would close after saying why
+            Err(e) => {
+                output.extend(codec::encode_stream_frame(0, &invalid_frame_response(&e)));
+                break;
+            }
+        };
+        let message = match ClientMessage::decode(frame.payload.as_slice()) {
+            Ok(message) => {
+                decode_errors = 0;
+                message
+            }
+            Err(e) => {
+                decode_errors += 1;
+                if decode_errors >= defaults.tunables.max_decode_errors {
+                    let response = decode_errors_response(decode_errors, frame.stream_id, &e);
+                    output.extend(codec::encode_stream_frame(frame.stream_id, &response));
+                    break;
+                }
+                continue;
+            }
+        };
+        let handled = match message.message {
+            Some(message) => process_message(message, &defaults),
+            None => Some((unset_message_response(&frame.payload), CloseAfter::No)),
+        };
+        if let Some((response, close_after)) = handled {
+            output.extend(codec::encode_stream_frame(frame.stream_id, &response));
+            if close_after == CloseAfter::Yes {
+                break;
+            }
+        }
+    }
+    (!output.is_empty()).then_some(output)
+}
+
+/// Answers one request for `process` as a server with `defaults` and the default handler would; nothing is cached
+fn process_message(message: client_message::Message, defaults: &ServerConfig) -> Option<(ServerMessage, CloseAfter)> {
+    match message {
+        client_message::Message::VersionRequest(_) => Some((version_response(), CloseAfter::No)),
+        client_message::Message::HealthRequest(_) => Some((health_response(0, None), CloseAfter::No)),
+        client_message::Message::EchoMetadataRequest(_) => Some((echo_metadata_response(EchoMetadataResponse::default()), CloseAfter::No)),
+        client_message::Message::SetIdleTimeoutRequest(request) => {
+            let response = idle_timeout_for(defaults.idle_timeout_bounds, &request).map_or_else(|e| e, idle_timeout_response);
+            Some((response, CloseAfter::No))
+        }
+        client_message::Message::CancelRequest(_) => None, // Nothing is ever in flight
+        client_message::Message::UpgradeRequest(request) => Some((unknown_framing(&request.framing, &HashMap::new()), CloseAfter::No)),
+        client_message::Message::CapabilitiesRequest(_) => Some((capabilities_response(&DefaultHandler, defaults, &HashMap::new()), CloseAfter::No)),
+        client_message::Message::IdempotentRequest(request) => match unwrap_idempotent(*request) {
+            Ok((_, inner)) => inner.message.and_then(|message| process_message(message, defaults)),
+            Err(response) => Some((response, CloseAfter::No)),
+        },
+        client_message::Message::DeadlineRequest(request) => match unwrap_deadline(*request) {
+            Ok((deadline, inner)) if SystemTime::now() >= deadline => {
+                let message = inner.message?; // Never empty once unwrapped
+                expects_response(&message).then(|| (deadline_exceeded(MessageType::of(Some(&message))), CloseAfter::No))
+            }
+            Ok((_, inner)) => inner.message.and_then(|message| process_message(message, defaults)),
+            Err(response) => Some((response, CloseAfter::No)),
+        },
+        client_message::Message::UploadStart(start) => DefaultHandler.upload(start, &mut io::empty()),
+        message => DefaultHandler.handle(message),
+    }
+}
+
+/// What the acceptor does with a new connection when every pool worker is busy and the queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SaturationPolicy {
+    /// Wait for room in the queue, pausing `accept` until a worker frees up
+    #[default]
+    Block,
+    /// Reply with an `ErrorResponse` and close the connection
+    Reject,
+    /// Close the connection without a reply
+    Close,
+}
+
+/// What the server does when the handler returns no response for a request that always gets one (see
+/// `handler::expects_response`), which usually means a bug in a custom handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MissingResponsePolicy {
+    /// Send nothing, as the handler asked; the client waits for a response that never comes
+    #[default]
+    Ignore,
+    /// Send nothing, but log a warning naming the request
+    Warn,
+    /// Log a warning and answer with an `ErrorResponse` carrying `ErrorCode::Internal`
+    Error,
+}
+
+#[derive(Debug, Clone)]
+struct ServerConfig {
+    workers: Option<usize>, // `None` spawns one thread per connection
+    queue_capacity: usize,  // Connections allowed to wait for a pool worker
+    max_connections_per_ip: Option<usize>, // Connections a single peer IP may have open at once, `None` for no limit
+    saturation_policy: SaturationPolicy,
+    missing_response: MissingResponsePolicy, // What to do when the handler doesn't answer a request
+    max_frame_size: usize, // Largest payload a client may declare in a frame header
+    tunables: Tunables, // What `Server::reload_config` can change; a running server reads them from `Shared::tunables`
+    idle_timeout_bounds: Option<(Duration, Duration)>, // Idle timeouts a client may pick for itself, `None` if it can't
+    greeting: Option<ServerMessage>, // Sent to every client as soon as it connects
+    disconnect_history: usize, // How many disconnect records `recent_disconnects` keeps
+    linger: Option<Duration>, // `SO_LINGER` applied to accepted streams, `None` keeps the OS default
+    recv_buffer_size: Option<usize>, // `SO_RCVBUF` applied to the listener and accepted streams, `None` keeps the OS default
+    send_buffer_size: Option<usize>, // `SO_SNDBUF` applied to accepted streams, `None` keeps the OS default
+    warmup: Option<(Duration, u32)>, // How long accepts ramp up after `run` starts, and to what rate per second
+    max_buffered_bytes: Option<usize>, // Undecoded bytes held per connection before reading pauses, `None` for one largest frame
+    max_frame_len: usize, // Longest frame of `max_frame_size` on the wire with any of the server's framers, set by `build`
+    handler_threads: Option<usize>, // `None` runs handlers on the connection's own thread
+    payload_logging: Option<PayloadLogging>, // Redaction rules for logging every request and response, `None` logs none
+    acceptors: usize, // Threads calling `accept` on the shared listener
+    nodelay: bool, // `TCP_NODELAY` applied to accepted streams, changed per connection through `Server::set_nodelay`
+    stack_size: Option<usize>, // Stack of connection, pool and handler threads, `None` for the standard library default
+    max_in_flight: Option<(usize, Duration)>, // Handler runs allowed at once across connections, and how long a request waits for one
+    idempotency_cache: Option<(usize, Duration)>, // Results kept for `IdempotentRequest` retries, and for how long
+    result_cache: Option<(usize, Duration)>, // Responses kept for repeats of pure requests, and for how long
+    write_buffer: Option<usize>, // Response bytes batched per connection before a write, `None` writes every response at once
+    fragment_writes: Option<(usize, Duration)>, // Bytes per write and the pause between writes, for testing clients' reassembly
+    suppress_duplicate_echoes: bool, // Answer a repeated echo with `DuplicateSuppressed` instead of the content
+    max_echo_len: usize, // Longest content, text or binary, a single echo may have
+    rate_limits: HashMap<MessageType, (u32, u32)>, // Requests per second and burst each connection may send, by type
+    max_total_buffered_bytes: Option<usize>, // Received but unhandled bytes across every connection, `None` for no limit
+    close_notices: bool, // Send a `CloseNotice` before the server closes a connection
+    report_load: bool, // Add the server's `ServerLoad` to every response
+    idle_reaper: Option<Duration>, // How often the reaper thread scans for idle connections, `None` leaves it to each worker
+    pause_on_decode_error_spike: bool, // Pause the server when `on_decode_error_spike` fires
+    #[cfg(feature = "recording")]
+    record_dir: Option<PathBuf>, // Directory each connection's frames are logged to, `None` records nothing
+    #[cfg(feature = "request-log")]
+    request_log: Option<PathBuf>, // File that gets a JSON line per request, `None` logs none
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            workers: None,
+            queue_capacity: 64,
+            max_connections_per_ip: None,
+            saturation_policy: SaturationPolicy::default(),
+            missing_response: MissingResponsePolicy::default(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            tunables: Tunables::default(),
+            idle_timeout_bounds: None,
+            greeting: None,
+            disconnect_history: 32,
+            linger: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            warmup: None,
+            max_buffered_bytes: None,
+            max_frame_len: DEFAULT_MAX_FRAME_SIZE + codec::MAX_HEADER_LEN,
+            handler_threads: None,
+            payload_logging: None,
+            acceptors: 1,
+            nodelay: true,
+            stack_size: None,
+            max_in_flight: None,
+            write_buffer: None,
+            fragment_writes: None,
+            idempotency_cache: None,
+            result_cache: None,
+            suppress_duplicate_echoes: false,
+            max_echo_len: MAX_ECHO_LEN,
+            idle_reaper: None,
+            close_notices: false,
+            report_load: false,
+            max_total_buffered_bytes: None,
+            rate_limits: HashMap::new(),
+            pause_on_decode_error_spike: false,
+            #[cfg(feature = "recording")]
+            record_dir: None,
+            #[cfg(feature = "request-log")]
+            request_log: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// The backlog cap, never less than one largest frame so every frame can complete
+    fn max_buffered_bytes(&self) -> usize {
+        self.max_buffered_bytes.unwrap_or(self.max_frame_len)
+    }
+}
+
+/// The settings a running server can change with `Server::reload_config`; each is set the same way by the
+/// `ServerBuilder` method of the same name. Everything else, the address the server is bound to included, is fixed
+/// once `build` returns. Connections read these as they use them, so a change reaches open connections too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tunables {
+    pub write_timeout: Option<Duration>, // Applies from the next response written
+    pub write_progress_timeout: Option<Duration>,
+    pub graceful_close_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>, // Except on connections that picked their own with a `SetIdleTimeoutRequest`
+    pub frame_timeout: Option<Duration>,
+    pub max_frames_per_iteration: usize,
+    pub max_decode_errors: u32,
+    pub max_bytes_per_connection: Option<u64>, // Counts what connections already received
+    pub max_echo_bytes: Option<u64>, // `max_echo_bytes_per_connection`, counting what connections were already sent
+    pub poll_interval: (Duration, Duration), // `adaptive_polling`, the same value twice for a fixed interval
+}
+
+impl Default for Tunables {
+    fn default() -> Self {
+        Tunables {
+            write_timeout: Some(Duration::from_secs(5)),
+            write_progress_timeout: None,
+            graceful_close_timeout: Some(Duration::from_secs(2)),
+            idle_timeout: None,
+            frame_timeout: None,
+            max_frames_per_iteration: 32,
+            max_decode_errors: 10,
+            max_bytes_per_connection: None,
+            max_echo_bytes: None,
+            poll_interval: (POLL_INTERVAL, POLL_INTERVAL),
+        }
+    }
+}
+
+impl Tunables {
+    /// Rejects the values `build` and `Server::reload_config` refuse, with `ErrorKind::InvalidInput`
+    fn validate(&self) -> io::Result<()> {
+        if self.max_frames_per_iteration == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "at least one frame must be handled per iteration",
+            ));
+        }
+        if self.write_progress_timeout == Some(Duration::ZERO) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "write progress timeout must be non-zero",
+            ));
+        }
+        if self.graceful_close_timeout == Some(Duration::ZERO) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "graceful close timeout must be non-zero, use None to close without waiting",
+            ));
+        }
+        if self.write_timeout == Some(Duration::ZERO) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "write timeout must be non-zero, use None to wait forever",
+            ));
+        }
+        if self.idle_timeout == Some(Duration::ZERO) || self.frame_timeout == Some(Duration::ZERO) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "idle and frame timeouts must be non-zero",
+            ));
+        }
+        let (min, max) = self.poll_interval;
+        if min.is_zero() || min > max {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "poll intervals must be non-zero with min no greater than max",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Configures and creates a `Server`
+pub struct ServerBuilder {
+    addr: String,
+    config: ServerConfig,
+    handler: Arc<dyn MessageHandler>,
+    framer: Arc<dyn Framer>,
+    upgrades: HashMap<String, Arc<dyn Framer>>,
+    on_disconnect: Option<DisconnectCallback>,
+    middleware: Vec<Box<dyn Middleware>>,
+    validation: Vec<ValidationRule>,
+    spawner: Option<Spawner>,
+    shared_metrics: Option<Arc<Metrics>>,
+    on_decode_error_spike: Option<(u32, Duration, SpikeCallback)>,
+}
+
+impl ServerBuilder {
+    /// Handles connections on a fixed pool of `workers` threads instead of one thread per connection
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.config.workers = Some(workers);
+        self
+    }
+
+    /// Sets how many accepted connections may wait for a free pool worker (64 by default)
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.config.queue_capacity = capacity;
+        self
+    }
+
+    /// Sets what happens to new connections once the pool and its queue are full (`Block` by default)
+    pub fn saturation_policy(mut self, policy: SaturationPolicy) -> Self {
+        self.config.saturation_policy = policy;
+        self
+    }
+
+    /// Sets the largest frame payload accepted from a client (64 KiB by default, at most `codec::MAX_PAYLOAD_LEN`);
+    /// larger frames close the connection. Responses are held to the same limit: a larger one is replaced with an
+    /// `ErrorResponse` with `ErrorCode::OutOfRange`.
+    pub fn max_frame_size(mut self, size: usize) -> Self {
+        self.config.max_frame_size = size;
+        self
+    }
+
+    /// Sets how long writing a response may stall before the connection is closed (5 seconds by default, `None` waits forever).
+    ///
+    /// A client may pipeline requests without reading the responses only as long as the unread responses fit in the
+    /// socket buffers on both ends, typically a few hundred KiB each, which is thousands of small echo or add responses.
+    /// Past that depth the server's writes stall while the client's writes stall too; instead of deadlocking the worker,
+    /// the server gives up on the connection once this timeout expires.
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.tunables.write_timeout = timeout;
+        self
+    }
+
+    /// Closes the connection, as `DisconnectReason::SlowWrite`, when a single response takes longer than `timeout` to
+    /// write in full (no limit by default). Unlike `write_timeout`, which only notices a client that stops reading, this
+    /// catches one reading so slowly that every write makes a little progress, such as a large response over a
+    /// throttled link. It is logged as a slow client write, and the response is left partly sent.
+    pub fn write_progress_timeout(mut self, timeout: Duration) -> Self {
+        self.config.tunables.write_progress_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how long a connection the server closes gracefully, such as on shutdown or an idle timeout, waits for the
+    /// client to close its side (2 seconds by default, `None` closes right away). The server flushes its responses and
+    /// shuts down its side first, so a client that reads to the end and closes loses nothing; one that doesn't close in
+    /// time, most likely dead, has the connection reset. The wait holds up the connection's worker, and so `run`
+    /// returning on shutdown. A zero `linger` skips it and resets every connection right away.
+    pub fn graceful_close_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.tunables.graceful_close_timeout = timeout;
+        self
+    }
+
+    /// Disconnects a client that sends nothing for `timeout` (no limit by default). A client that stalls partway
+    /// through a frame header is disconnected as `DisconnectReason::HeaderTimeout`, any other as `IdleTimeout`.
+    /// Counted from the last byte received, so time spent handling its requests counts too.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.tunables.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Sends every client a `CloseNotice` as the last frame before the server closes its connection (off by default),
+    /// with a `CloseCode` that says why: `NormalClose` after a handler's `CloseAfter::Yes`, `ServerShutdown`,
+    /// `ProtocolError`, `PolicyViolation` for a broken quota, `TooBig` for an oversized frame or `Timeout`. Nothing is
+    /// sent when the client closed first or the socket failed, nor for `Server::disconnect`, which shuts the socket
+    /// down at once.
+    pub fn close_notices(mut self) -> Self {
+        self.config.close_notices = true;
+        self
+    }
+
+    /// Adds the server's current load to every message sent (off by default): open connections, busy and queued pool
+    /// workers, and requests waiting for a handler thread. Clients can use it to back off or to pick a less loaded
+    /// server. Each message costs a few more bytes and a look at the handler queue.
+    pub fn report_load(mut self) -> Self {
+        self.config.report_load = true;
+        self
+    }
+
+    /// Enforces `idle_timeout` from one reaper thread that scans every connection each `interval`, instead of each
+    /// worker checking its own (off by default). The reaper shuts a stale connection's socket down, which ends its
+    /// worker's read at once, however long the poll interval; it is recorded as `DisconnectReason::IdleTimeout`, or
+    /// `HeaderTimeout` if the client stalled partway through a frame header. A connection may go up to `interval`
+    /// past its timeout before it is reaped.
+    pub fn idle_reaper(mut self, interval: Duration) -> Self {
+        self.config.idle_reaper = Some(interval);
+        self
+    }
+
+    /// Limits how many requests of `message_type` each connection may send to `per_second` on average, in bursts of
+    /// up to `burst` (unlimited by default). Each type is limited separately, so throttling an expensive request
+    /// leaves the others alone; setting a type again replaces its limit. A request over its limit is answered with
+    /// `ErrorCode::RateLimited` without being handled, and the connection stays open. A request inside an
+    /// `IdempotentRequest` or `DeadlineRequest` counts against its own type as well as the wrapper's. Uploads can't
+    /// be limited.
+    pub fn rate_limit(mut self, message_type: MessageType, per_second: u32, burst: u32) -> Self {
+        self.config.rate_limits.insert(message_type, (per_second, burst));
+        self
+    }
+
+    /// Lets each client pick its own idle timeout, between `min` and `max` inclusive, with a `SetIdleTimeoutRequest`
+    /// (not allowed by default, such requests are answered with `ErrorCode::Unsupported`). The choice replaces
+    /// `idle_timeout` for that connection only; values out of bounds get `ErrorCode::OutOfRange` and change nothing.
+    pub fn idle_timeout_bounds(mut self, min: Duration, max: Duration) -> Self {
+        self.config.idle_timeout_bounds = Some((min, max));
+        self
+    }
+
+    /// Disconnects a client whose frame hasn't fully arrived `timeout` after its header did (no limit by default),
+    /// as `DisconnectReason::FrameTimeout`. Guards against slow-loris clients that trickle a body in a byte at a
+    /// time: unlike `idle_timeout`, bytes arriving don't restart the clock.
+    pub fn frame_timeout(mut self, timeout: Duration) -> Self {
+        self.config.tunables.frame_timeout = Some(timeout);
+        self
+    }
+
+    /// Lets the accept loop and every connection adapt how often they poll (a fixed 100ms by default). While work
+    /// keeps arriving they wait only `min` between checks; each poll that finds nothing doubles the wait, up to `max`,
+    /// and the next connection or byte drops it back to `min`. A small `min` accepts bursts of connections with little
+    /// delay; a large `max` lets an idle server sleep. The wait also bounds how long `stop`, `idle_timeout` and
+    /// `frame_timeout` take to notice, so an idle connection may close up to `max` late. On Linux the accept loop
+    /// doesn't poll: it blocks in `accept`, waking at least every 25ms to check for `stop`.
+    pub fn adaptive_polling(mut self, min: Duration, max: Duration) -> Self {
+        self.config.tunables.poll_interval = (min, max);
+        self
+    }
+
+    /// Sets what happens when the handler returns `None` for a request that expects a response (`Ignore` by default).
+    /// One-way messages like `LogEvent` are never affected, and neither is a handler that panicked.
+    pub fn missing_response(mut self, policy: MissingResponsePolicy) -> Self {
+        self.config.missing_response = policy;
+        self
+    }
+
+    /// Sends `greeting` to every client right after it connects, before any request is read (no greeting by default)
+    pub fn greeting(mut self, greeting: ServerMessage) -> Self {
+        self.config.greeting = Some(greeting);
+        self
+    }
+
+    /// Remembers the results of up to `capacity` `IdempotentRequest`s for `ttl` each (off by default, which handles
+    /// every such request anew). A retry with a key already answered, on any connection, then gets the stored result
+    /// instead of running again, so a client that resends after a lost response doesn't apply a change twice. Results
+    /// that are an `ErrorResponse` aren't stored. A retry arriving while the first request still runs is run as well.
+    pub fn idempotency_cache(mut self, capacity: usize, ttl: Duration) -> Self {
+        self.config.idempotency_cache = Some((capacity, ttl));
+        self
+    }
+
+    /// Remembers the responses to up to `capacity` distinct requests the handler calls pure through
+    /// `MessageHandler::is_pure`, for `ttl` each (off by default). A repeat of such a request, byte for byte and on any
+    /// connection, is then answered from the cache before it reaches the middleware or the handler, which saves the
+    /// work of an expensive computation asked for again and again. Responses that are an `ErrorResponse` aren't stored,
+    /// and replacing the handler forgets every response.
+    pub fn result_cache(mut self, capacity: usize, ttl: Duration) -> Self {
+        self.config.result_cache = Some((capacity, ttl));
+        self
+    }
+
+    /// Batches responses into writes of up to `capacity` bytes instead of writing each one as it is ready (off by
+    /// default). A client pipelining many small requests then costs a few large writes instead of one per response.
+    /// Responses are still written as soon as every request already received has been handled, before the server
+    /// waits for more, so a client sending one request at a time sees no added latency. A connection that ends
+    /// abruptly, such as on a read error, loses the responses still buffered.
+    pub fn write_buffer(mut self, capacity: usize) -> Self {
+        self.config.write_buffer = Some(capacity);
+        self
+    }
+
+    /// For testing clients only: splits everything written to a connection into writes of at most `chunk_len` bytes,
+    /// each flushed and `delay` after the previous one (off by default). A client then receives its responses in
+    /// fragments, showing whether it reassembles frames from partial reads. Combine with `nodelay` so the fragments
+    /// leave as separate segments. Slows every response down, so never use it in production.
+    pub fn fragment_writes(mut self, chunk_len: usize, delay: Duration) -> Self {
+        self.config.fragment_writes = Some((chunk_len, delay));
+        self
+    }
+
+    /// Answers an echo whose content is the same as the echo just before it on the connection with a
+    /// `DuplicateSuppressed` message instead of the content (off by default), for testing how clients handle deduplication.
+    /// Only consecutive echoes count: any other request in between, or different content, is echoed as usual. Repeats
+    /// are answered by the server without reaching the handler.
+    pub fn suppress_duplicate_echoes(mut self) -> Self {
+        self.config.suppress_duplicate_echoes = true;
+        self
+    }
+
+    /// Logs every frame of each connection, both ways, to `connection-<id>.log` in `dir` (off by default), to be read
+    /// back with `recording::read_log` or sent again with `recording::replay`. The directory must exist; a connection
+    /// whose log can't be created or written is served without one.
+    #[cfg(feature = "recording")]
+    pub fn record_to(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.record_dir = Some(dir.into());
+        self
+    }
+
+    /// Refuses echoes whose content, text or binary, is longer than `bytes` (16 MiB by default) with an `ErrorResponse`
+    /// carrying `ErrorCode::OutOfRange`, before the handler sees them. With `max_frame_size` raised towards its 2 GiB
+    /// ceiling, this keeps the server from copying and re-encoding a pathological payload; the connection stays open.
+    pub fn max_echo_len(mut self, bytes: usize) -> Self {
+        self.config.max_echo_len = bytes;
+        self
+    }
+
+    /// Limits the echo content, text and binary, a client may get back over one connection (no limit by default), so
+    /// the echo service can't be used to amplify traffic. The echo that would go over the limit, and every one after
+    /// it, is answered with an `ErrorResponse` carrying `ErrorCode::QuotaExceeded`; other requests are still served.
+    pub fn max_echo_bytes_per_connection(mut self, bytes: u64) -> Self {
+        self.config.tunables.max_echo_bytes = Some(bytes);
+        self
+    }
+
+    /// Appends a JSON line per request to the file at `path`, creating it if needed (off by default): the time, the
+    /// connection id and peer, the `MessageType`, the `Outcome` and the handling latency in microseconds. Lines are
+    /// written by a background thread, so a slow disk never holds up a connection; if it falls thousands of lines
+    /// behind, new lines are dropped with a warning.
+    #[cfg(feature = "request-log")]
+    pub fn request_log(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.request_log = Some(path.into());
+        self
+    }
+
+    /// Sets how many recent disconnects `Server::recent_disconnects` remembers (32 by default)
+    pub fn disconnect_history(mut self, records: usize) -> Self {
+        self.config.disconnect_history = records;
+        self
+    }
+
+    /// Sets how many pipelined frames a client loop handles before re-checking for shutdown (32 by default)
+    pub fn max_frames_per_iteration(mut self, frames: usize) -> Self {
+        self.config.tunables.max_frames_per_iteration = frames;
+        self
+    }
+
+    /// Caps the bytes a connection may have received but not yet handled (by default one largest frame with its header,
+    /// and never less than that). Once the cap is reached the server stops reading from that client until handling
+    /// catches up, so a client sending faster than its requests are handled is slowed down by TCP instead of
+    /// growing the buffer.
+    pub fn max_buffered_bytes(mut self, bytes: usize) -> Self {
+        self.config.max_buffered_bytes = Some(bytes);
+        self
+    }
+
+    /// Caps the bytes received but not yet handled across every connection combined (no limit by default, and never less
+    /// than one largest frame with its header), where `max_buffered_bytes` only caps each one. Near the cap,
+    /// connections holding more than their share of it stop reading first, while smaller ones may still read what is
+    /// left; unread bytes stay in the socket and TCP slows those clients down. Each read first waits for data to
+    /// arrive, so idle connections hold none of the budget. Upload chunks count until the handler has them. A client
+    /// stalled partway through a frame keeps its bytes until it finishes, or `frame_timeout` closes it; one largest
+    /// frame's worth of the budget is kept for a single connection at a time, so stalled partial frames can't take
+    /// all of it and leave every connection waiting on the others.
+    pub fn max_total_buffered_bytes(mut self, bytes: usize) -> Self {
+        self.config.max_total_buffered_bytes = Some(bytes);
+        self
+    }
+
+    /// Limits the total bytes, frame headers included, a client may send over one connection (no limit by default).
+    /// The read that crosses the limit is discarded unhandled; the client gets an `ErrorResponse` and is disconnected.
+    pub fn max_bytes_per_connection(mut self, bytes: u64) -> Self {
+        self.config.tunables.max_bytes_per_connection = Some(bytes);
+        self
+    }
+
+    /// Logs every decoded request and its response at debug level, with payloads redacted by `rules` (off by default)
+    pub fn log_payloads(mut self, rules: PayloadLogging) -> Self {
+        self.config.payload_logging = Some(rules);
+        self
+    }
+
+    /// Sets how many consecutive undecodable frames a client may send before it gets a final `ErrorResponse` and is
+    /// disconnected (10 by default, 0 for no limit); any successfully decoded message resets the count
+    pub fn max_decode_errors(mut self, errors: u32) -> Self {
+        self.config.tunables.max_decode_errors = errors;
+        self
+    }
+
+    /// Sets `SO_LINGER` on every accepted stream (not set by default, which keeps the OS behavior).
+    ///
+    /// With a non-zero `linger`, closing a connection blocks the closing worker until unsent responses are delivered or
+    /// the time runs out. `Duration::ZERO` discards unsent data and resets the connection, so the client sees a reset
+    /// instead of a clean end of stream. Platforms differ: the time is truncated to whole seconds, what happens to data
+    /// still unsent when it expires varies, and a few systems ignore it for non-blocking sockets.
+    pub fn linger(mut self, linger: Duration) -> Self {
+        self.config.linger = Some(linger);
+        self
+    }
+
+    /// Accepts connections on `acceptors` threads instead of one, for very high connection rates; all of them hand
+    /// connections to the same workers or pool.
+    ///
+    /// The acceptors share the one listening socket through `TcpListener::try_clone`, so this works on every platform
+    /// and they drain a single backlog. `SO_REUSEPORT` is not used: it would need one socket per acceptor, is only
+    /// available on Unix (balanced across sockets on Linux 3.9+, with different semantics on the BSDs and macOS), and
+    /// lets unrelated processes bind the same port.
+    pub fn acceptors(mut self, acceptors: usize) -> Self {
+        self.config.acceptors = acceptors;
+        self
+    }
+
+    /// Sets `SO_RCVBUF` on every accepted stream (not set by default, which keeps the OS default and its autotuning).
+    ///
+    /// A larger receive buffer lets a client keep more data in flight, which speeds up large payloads over links with a
+    /// high bandwidth-delay product. It is also set on the listener, so the window offered during the handshake already
+    /// accounts for it. The OS treats the size as a request: Linux doubles it for bookkeeping and caps it at
+    /// `net.core.rmem_max`, others round or cap it, and setting it at all turns off Linux's receive buffer autotuning.
+    pub fn recv_buffer_size(mut self, bytes: usize) -> Self {
+        self.config.recv_buffer_size = Some(bytes);
+        self
+    }
+
+    /// Sets `SO_SNDBUF` on every accepted stream (not set by default, which keeps the OS default and its autotuning).
+    ///
+    /// A larger send buffer lets a large response be handed to the kernel in fewer blocking writes. Clamped like
+    /// `recv_buffer_size`, against `net.core.wmem_max` on Linux.
+    pub fn send_buffer_size(mut self, bytes: usize) -> Self {
+        self.config.send_buffer_size = Some(bytes);
+        self
+    }
+
+    /// Ramps the accept rate up over the first `period` of every `run` (off by default), so a crowd of clients
+    /// reconnecting at startup arrives gradually instead of all at once. The first connection is taken right away;
+    /// after that the allowed rate climbs steadily from zero to `rate` connections per second, and once `period` is
+    /// over the limit is lifted. Connections over the limit wait in the listen backlog, so a small backlog may turn
+    /// some away.
+    pub fn warmup(mut self, period: Duration, rate: u32) -> Self {
+        self.config.warmup = Some((period, rate));
+        self
+    }
+
+    /// Sets `TCP_NODELAY` on every accepted stream (on by default); `Server::set_nodelay` changes it for one connection.
+    ///
+    /// With it on, each response is sent as soon as it is written, which keeps request/response latency low. Turning it
+    /// off enables Nagle's algorithm: small writes are held back while earlier data is unacknowledged and coalesced into
+    /// fewer, fuller segments, which helps bulk transfers but can delay an interactive response by up to one
+    /// round trip, or by the peer's delayed-ACK timer (often 40 ms) when it delays acknowledgements.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.config.nodelay = nodelay;
+        self
+    }
+
+    /// Runs the message handler on a shared set of `threads` instead of each connection's own thread (the default).
+    /// Connection threads then only read and write frames, so CPU-heavy handlers use at most `threads` cores however
+    /// many clients are connected. Each connection still waits for one response before handling its next request,
+    /// so responses keep their order; server-level requests such as health checks are still answered inline.
+    /// Requests waiting for a thread are taken in `MessageHandler::priority` order, first come first served within a priority.
+    pub fn handler_threads(mut self, threads: usize) -> Self {
+        self.config.handler_threads = Some(threads);
+        self
+    }
+
+    /// Sets the stack size in bytes of every thread that runs handlers: connection threads, pool workers and
+    /// `handler_threads` (the standard library default of 2 MiB unless `RUST_MIN_STACK` is set). Raise it for handlers
+    /// that recurse deeply or keep large values on the stack; acceptor threads keep the default.
+    ///
+    /// The OS rounds the size up to its own minimum and granularity: on Linux to `PTHREAD_STACK_MIN` (16 KiB with
+    /// glibc on x86-64, more on some architectures) and whole pages, on macOS to 16 KiB and whole pages, on Windows to
+    /// the 64 KiB allocation granularity. Zero is rejected by `build`.
+    pub fn worker_stack_size(mut self, bytes: usize) -> Self {
+        self.config.stack_size = Some(bytes);
+        self
+    }
+
+    /// Caps how many requests run the handler at once across every connection (no cap by default), to protect
+    /// resources the handler shares such as a database. A request beyond the cap waits up to `wait` for another to
+    /// finish, then gets an `ErrorResponse` with `ErrorCode::AtCapacity` and the connection carries on. Uploads count
+    /// for as long as the handler reads them; server-level requests such as health checks don't count.
+    pub fn max_in_flight(mut self, limit: usize, wait: Duration) -> Self {
+        self.config.max_in_flight = Some((limit, wait));
+        self
+    }
+
+    /// Caps the connections a single peer IP may have open at once, so one host can't take up every connection. A
+    /// connection beyond the cap gets an `ErrorResponse` with `ErrorCode::AtCapacity` and is closed without being
+    /// served. Unlimited by default.
+    pub fn max_connections_per_ip(mut self, limit: usize) -> Self {
+        self.config.max_connections_per_ip = Some(limit);
+        self
+    }
+
+    /// Replaces the built-in add and echo logic with a custom handler
+    pub fn handler(mut self, handler: impl MessageHandler + 'static) -> Self {
+        self.handler = Arc::new(handler);
+        self
+    }
+
+    /// Delimits frames with `framer` instead of `codec::LengthPrefixed`, to speak an existing wire protocol. Every
+    /// frame in both directions uses it, including the greeting and the error a connection is turned away with;
+    /// `max_frame_size` still bounds payloads, and `record_to` logs and `process` keep length prefixes.
+    pub fn framer(mut self, framer: impl Framer + 'static) -> Self {
+        self.framer = Arc::new(framer);
+        self
+    }
+
+    /// Offers `framer` under `name` to clients, which switch a connection to it with an `UpgradeRequest` naming it.
+    /// Frames after the `UpgradeResponse` use it in both directions; adding a name again replaces its framer.
+    pub fn upgrade_framer(mut self, name: &str, framer: impl Framer + 'static) -> Self {
+        self.upgrades.insert(name.to_string(), Arc::new(framer));
+        self
+    }
+
+    /// Adds a layer around the handler; layers run in the order they are added, so the first one added sees every
+    /// request first. Server-level requests such as version and health checks don't pass through middleware.
+    pub fn middleware(mut self, layer: impl Middleware + 'static) -> Self {
+        self.middleware.push(Box::new(layer));
+        self
+    }
+
+    /// Adds a rule every request must pass before it is handled (none by default, which lets everything through).
+    /// A request the rule returns an `ErrorResponse` for is answered with it and goes no further; rules run in the
+    /// order they are added and the first refusal wins. Rules see server-level requests too, and both a wrapper such
+    /// as `IdempotentRequest` and the request inside it. Uploads aren't checked, and refused one-way messages get no
+    /// answer. Keeps input policy, such as the longest echo or the range of add operands, in one place.
+    pub fn validate(
+        mut self,
+        rule: impl Fn(&client_message::Message) -> Result<(), ErrorResponse> + Send + Sync + 'static,
+    ) -> Self {
+        self.validation.push(Box::new(rule));
+        self
+    }
+
+    /// Counts every request in `metrics` too, next to the server's own counters, so servers built with the same
+    /// `Arc` add up to one set of counters for the process. `Server::metrics` and `Server::drain_metrics` still cover
+    /// this server alone; read or drain the aggregate through `metrics` itself.
+    pub fn shared_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.shared_metrics = Some(metrics);
+        self
+    }
+
+    /// Calls `callback` on the client's worker every time a served connection ends, with its traffic, duration and the
+    /// reason it closed. Connections turned away by the saturation policy or a pause were never served and are not reported.
+    pub fn on_disconnect(mut self, callback: impl Fn(&ConnectionStats) + Send + Sync + 'static) -> Self {
+        self.on_disconnect = Some(Box::new(callback));
+        self
+    }
+
+    /// Calls `callback` once more than `errors` undecodable frames arrive within `window`, counted across every
+    /// connection (off by default). It gets the server's total decode errors so far, as `Server::decode_errors` reports
+    /// them, and runs on the worker of the connection whose frame crossed the threshold. The count starts over each
+    /// time, so a spike that goes on calls it again every `errors + 1` errors within a window.
+    pub fn on_decode_error_spike(
+        mut self,
+        errors: u32,
+        window: Duration,
+        callback: impl Fn(u64) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_decode_error_spike = Some((errors, window, Box::new(callback)));
+        self
+    }
+
+    /// Also pauses the server when `on_decode_error_spike` fires, as `Server::pause` does, so new connections are
+    /// closed while clients already connected keep being served; `Server::resume` takes them again
+    pub fn pause_on_decode_error_spike(mut self) -> Self {
+        self.config.pause_on_decode_error_spike = true;
+        self
+    }
+
+    /// Hands every accepted connection to `spawner` instead of serving it on a thread of the server's own, for
+    /// embedders that manage their threads themselves; the callback runs on the acceptor, so it should hand the
+    /// connection on rather than call `Connection::run` itself. Connections still stop when the server does, but `run`
+    /// doesn't wait for them and the shutdown notifier doesn't cover them. Can't be combined with `workers`.
+    pub fn spawn_with(mut self, spawner: impl Fn(Connection) + Send + Sync + 'static) -> Self {
+        self.spawner = Some(Box::new(spawner));
+        self
+    }
+
+    /// Binds the listener and creates the server
+    pub fn build(mut self) -> io::Result<Server> {
+        if self.config.workers.is_some() && self.spawner.is_some() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "connections go to either the worker pool or the spawner, not both",
+            ));
+        }
+        if self.config.workers == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "worker pool needs at least one worker",
+            ));
+        }
+        if self.config.max_connections_per_ip == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "at least one connection per IP must be allowed",
+            ));
+        }
+        if self.config.acceptors == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "at least one acceptor is needed",
+            ));
+        }
+        if self.config.handler_threads == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "handler executor needs at least one thread",
+            ));
+        }
+        if matches!(self.config.max_in_flight, Some((0, _))) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "at least one request must be allowed in flight",
+            ));
+        }
+        if self.config.idempotency_cache.is_some_and(|(capacity, ttl)| capacity == 0 || ttl.is_zero()) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "idempotency cache needs a non-zero capacity and ttl",
+            ));
+        }
+        if self.config.result_cache.is_some_and(|(capacity, ttl)| capacity == 0 || ttl.is_zero()) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "result cache needs a non-zero capacity and ttl",
+            ));
+        }
+        if self.config.write_buffer == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "write buffer must hold at least one byte",
+            ));
+        }
+        if matches!(self.config.fragment_writes, Some((0, _))) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "fragmented writes must carry at least one byte",
+            ));
+        }
+        if self.config.stack_size == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "worker stack size must be non-zero",
+            ));
+        }
+        if self.config.max_frame_size > codec::MAX_PAYLOAD_LEN {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "max frame size does not fit in the length prefix",
+            ));
+        }
+        self.config.tunables.validate()?;
+        for (message_type, &(per_second, burst)) in &self.config.rate_limits {
+            if per_second == 0 || burst == 0 {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "rate limits need a non-zero rate and burst",
+                ));
+            }
+            if matches!(message_type, MessageType::UploadStart | MessageType::UploadChunk | MessageType::Undecodable) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{} requests can't be rate limited", message_type.name()),
+                ));
+            }
+        }
+        if self.config.idle_reaper == Some(Duration::ZERO) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "idle reaper interval must be non-zero",
+            ));
+        }
+        if self.config.max_echo_len == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "max echo length must be non-zero",
+            ));
+        }
+        if self.on_decode_error_spike.as_ref().is_some_and(|(_, window, _)| window.is_zero()) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "decode error spike window must be non-zero",
+            ));
+        }
+        if self.config.pause_on_decode_error_spike && self.on_decode_error_spike.is_none() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "pausing on a decode error spike needs on_decode_error_spike",
+            ));
+        }
+        if self.config.greeting.as_ref().is_some_and(|greeting| greeting.encoded_len() > self.config.max_frame_size) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "greeting is larger than the max frame size",
+            ));
+        }
+        if self.upgrades.contains_key("") {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "an upgrade framing needs a name"));
+        }
+        // Buffers must hold a largest frame in whichever framing a connection ends up using
+        self.config.max_frame_len = self
+            .upgrades
+            .values()
+            .map(|framer| framer.max_frame_len(self.config.max_frame_size))
+            .fold(self.framer.max_frame_len(self.config.max_frame_size), usize::max);
+        if self.config.max_total_buffered_bytes.is_some_and(|bytes| bytes < self.config.max_frame_len) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "max total buffered bytes must hold at least one largest frame and its header",
+            ));
+        }
+        if self.config.max_buffered_bytes() < self.config.max_frame_len {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "max buffered bytes must hold at least one largest frame and its header",
+            ));
+        }
+        if let Some((min, max)) = self.config.idle_timeout_bounds {
+            if min.is_zero() || min > max {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "idle timeout bounds must be non-zero with min no greater than max",
+                ));
+            }
+        }
+        if self.config.recv_buffer_size == Some(0) || self.config.send_buffer_size == Some(0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "socket buffer sizes must be non-zero",
+            ));
+        }
+        if self.config.warmup.is_some_and(|(period, rate)| period.is_zero() || rate == 0) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "warmup needs a non-zero period and rate",
+            ));
+        }
+        let listener = TcpListener::bind(&self.addr)?;
+        if let Some(bytes) = self.config.recv_buffer_size {
+            SockRef::from(&listener).set_recv_buffer_size(bytes)?;
+        }
+        let executor = match self.config.handler_threads {
+            Some(threads) => Some(Executor::new(threads, self.config.stack_size)?),
+            None => None,
+        };
+        #[cfg(feature = "request-log")]
+        let request_log = match &self.config.request_log {
+            Some(path) => Some(RequestLog::open(path)?),
+            None => None,
+        };
+        let is_running = Arc::new(AtomicBool::new(false)); // Initialize the is_running flag; `stop` flips it with a compare-exchange
+        Ok(Server {
+            listener,
+            is_running,
+            next_connection_id: AtomicU64::new(0),
+            shutdown_notifiers: Mutex::new(Vec::new()),
+            ready_notifiers: Mutex::new(Vec::new()),
+            warmup_accepts: AtomicU64::new(0),
+            shutdown_reason: Mutex::new(None),
+            spawner: self.spawner,
+            shared: Arc::new(Shared {
+                #[cfg(feature = "latency")]
+                latency: LatencyRecorder::new(),
+                #[cfg(feature = "latency")]
+                queue_latency: LatencyRecorder::new(),
+                disconnects: DisconnectLog::new(self.config.disconnect_history),
+                metrics: Arc::new(Metrics::new()),
+                shared_metrics: self.shared_metrics,
+                handler: RwLock::new(self.handler),
+                framer: self.framer,
+                upgrades: self.upgrades,
+                active_clients: AtomicUsize::new(0),
+                on_disconnect: self.on_disconnect,
+                executor,
+                connections: Mutex::new(HashMap::new()),
+                connections_per_ip: Mutex::new(HashMap::new()),
+                middleware: Arc::new(self.middleware),
+                validation: self.validation,
+                in_flight: self.config.max_in_flight.map(|(limit, _)| Semaphore::new(limit)),
+                idempotency: self.config.idempotency_cache.map(|(capacity, ttl)| IdempotencyCache::new(capacity, ttl)),
+                results: self.config.result_cache.map(|(capacity, ttl)| IdempotencyCache::new(capacity, ttl)),
+                started: Mutex::new(None),
+                is_paused: AtomicBool::new(false),
+                decode_errors: DecodeErrorBreaker::new(self.on_decode_error_spike),
+                buffered_total: AtomicUsize::new(0),
+                buffer_leader: AtomicU64::new(NO_LEADER),
+                queued_connections: AtomicUsize::new(0),
+                #[cfg(feature = "request-log")]
+                request_log,
+                tunables: RwLock::new(self.config.tunables),
+                config: self.config,
+            }),
+        })
+    }
+}
+
+pub struct Server {
+    listener: TcpListener,
+    is_running: Arc<AtomicBool>, // Shared with every client, which polls it to notice a shutdown
+    next_connection_id: AtomicU64, // Monotonic counter used to give each connection a unique id
+    shutdown_notifiers: Mutex<Vec<Sender<()>>>, // Observers waiting for `run` to exit and all workers to be joined
+    ready_notifiers: Mutex<Vec<Sender<()>>>, // Observers waiting for `run` to be ready to accept
+    shutdown_reason: Mutex<Option<ShutdownReason>>, // Why the last `run` was stopped, `None` until it is; held while `is_running` changes
+    warmup_accepts: AtomicU64, // Connections accepted since `run` started, paced by `warmup`
+    spawner: Option<Spawner>, // Serves connections on the caller's threads instead of the server's
+    shared: Arc<Shared>, // Configuration and statistics handed to every client
+}
+
+/// The close code for a connection ended by `error`: `TooBig` for a frame over the size limit
+fn frame_close_code(error: &FrameError) -> Option<CloseCode> {
+    match error {
+        FrameError::TooLarge { .. } => Some(CloseCode::TooBig),
+        FrameError::Malformed(_) => None, // `InvalidFrame` already says `ProtocolError`
+    }
+}
+
+/// Shuts the connection down so its worker's read returns, telling the worker it was kicked
+fn kick(id: u64, handle: &ConnectionHandle) -> bool {
+    handle.kicked.store(true, Ordering::SeqCst); // Before the shutdown, so the woken worker sees it
+    let Some(stream) = &handle.stream else {
+        info!("[connection {}] Disconnecting {} at its next read timeout.", id, handle.addr); // No clone to shut down
+        return true;
+    };
+    match stream.shutdown(std::net::Shutdown::Both) {
+        Ok(()) => {
+            info!("[connection {}] Disconnecting {}.", id, handle.addr);
+            true
+        }
+        Err(e) => {
+            warn!("[connection {}] Failed to disconnect {}: {}", id, handle.addr, e); // Most likely already closed
+            false
+        }
+    }
+}
+
+/// Sends a connection that is never served an `ErrorResponse` saying why, then closes it
+fn turn_away(mut stream: TcpStream, addr: SocketAddr, framer: &dyn Framer, code: ErrorCode, message: &str) {
+    info!("Turning away {}: {}.", addr, message);
+    let frame = Frame {
+        stream_id: 0,
+        payload: error_response(code, message).encode_to_vec(),
+    };
+    let mut bytes = Vec::new();
+    framer.write_frame(&frame, &mut bytes);
+    // The stream may inherit the listener's non-blocking mode; bound the blocking write instead
+    let sent = stream
+        .set_nonblocking(false)
+        .and_then(|_| stream.set_write_timeout(Some(POLL_INTERVAL)))
+        .and_then(|_| stream.write_all(&bytes))
+        .and_then(|_| stream.shutdown(std::net::Shutdown::Write));
+    if let Err(e) = sent {
+        debug!("Failed to turn away {}: {}", addr, e); // The client may have gone already
+    }
+}
+
+/// Wait between polls that grows while they find nothing, for `ServerBuilder::adaptive_polling`
+struct Backoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    fn new((min, max): (Duration, Duration)) -> Self {
+        Backoff { min, max, current: min }
+    }
+
+    fn current(&self) -> Duration {
+        self.current
+    }
+
+    /// Doubles the wait after a poll that found nothing; returns whether it changed
+    fn idle(&mut self) -> bool {
+        let next = self.current.saturating_mul(2).min(self.max);
+        std::mem::replace(&mut self.current, next) != next
+    }
+
+    /// Drops the wait back to the shortest after a poll that found work; returns whether it changed
+    fn busy(&mut self) -> bool {
+        std::mem::replace(&mut self.current, self.min) != self.min
+    }
+
+    /// Switches to new bounds, keeping the wait within them; returns whether the wait changed
+    fn reconfigure(&mut self, (min, max): (Duration, Duration)) -> bool {
+        if (min, max) == (self.min, self.max) {
+            return false;
+        }
+        self.min = min;
+        self.max = max;
+        let next = self.current.clamp(min, max);
+        std::mem::replace(&mut self.current, next) != next
+    }
+}
+
+/// Whether `accept` failed because the listening socket itself is gone: its descriptor was closed (`EBADF`) or reused
+/// for something else (`ENOTSOCK`), or it was shut down and no longer listens (`EINVAL`). Retrying can never succeed.
+fn is_listener_broken(error: &io::Error) -> bool {
+    #[cfg(target_os = "linux")]
+    const CODES: &[i32] = &[9, 22, 88]; // EBADF, EINVAL, ENOTSOCK
+    #[cfg(all(unix, not(target_os = "linux")))]
+    const CODES: &[i32] = &[9, 22, 38]; // EBADF, EINVAL, ENOTSOCK on macOS and the BSDs
+    #[cfg(windows)]
+    const CODES: &[i32] = &[10009, 10022, 10038]; // WSAEBADF, WSAEINVAL, WSAENOTSOCK
+    #[cfg(not(any(unix, windows)))]
+    const CODES: &[i32] = &[];
+    error.kind() == ErrorKind::NotConnected || error.raw_os_error().is_some_and(|code| CODES.contains(&code))
+}
+
+/// Whether `accept` failed because the process (`EMFILE`) or the system (`ENFILE`) has no file descriptors left
+fn is_fd_exhaustion(error: &io::Error) -> bool {
+    #[cfg(unix)]
+    const CODES: &[i32] = &[23, 24]; // ENFILE, EMFILE; the same on Linux, macOS and the BSDs
+    #[cfg(windows)]
+    const CODES: &[i32] = &[10024]; // WSAEMFILE
+    #[cfg(not(any(unix, windows)))]
+    const CODES: &[i32] = &[];
+    error.raw_os_error().is_some_and(|code| CODES.contains(&code))
+}
+
+/// Whether `accept` blocks for up to `ACCEPT_TIMEOUT` rather than returning `WouldBlock` right away
+const ACCEPT_BLOCKS: bool = cfg!(target_os = "linux");
+
+/// Readies the listener for the accept loop. Linux honours a receive timeout on `accept`, so the loop blocks until a
+/// client connects or `ACCEPT_TIMEOUT` passes: connections are taken the moment they arrive, and an idle server wakes
+/// only to check for `stop`. Elsewhere the timeout is ignored, so the listener is made non-blocking and the loop sleeps
+/// between polls.
+#[cfg(target_os = "linux")]
+fn prepare_listener(listener: &TcpListener) -> io::Result<()> {
+    listener.set_nonblocking(false)?; // A restarted server finds it non-blocking from draining the backlog
+    SockRef::from(listener).set_read_timeout(Some(ACCEPT_TIMEOUT))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn prepare_listener(listener: &TcpListener) -> io::Result<()> {
+    listener.set_nonblocking(true)
+}
+
+/// For a listening socket, Linux reports the length of its accept queue as `tcpi_unacked`
+#[cfg(target_os = "linux")]
+fn accept_queue_len(listener: &TcpListener) -> Option<usize> {
+    use std::os::fd::AsRawFd;
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() }; // A plain C struct, all zeroes is a valid value
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    // SAFETY: the descriptor stays open while `listener` is borrowed, and `info` and `len` describe a writable buffer
+    let result = unsafe {
+        libc::getsockopt(
+            listener.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    (result == 0).then_some(info.tcpi_unacked as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn accept_queue_len(_listener: &TcpListener) -> Option<usize> {
+    None
+}
+
+/// Reads and parses one environment variable, `None` if it is unset
+fn env_setting<T: FromStr>(name: &str) -> Result<Option<T>, ServerError>
+where
+    T::Err: std::fmt::Display,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|e| ServerError::Config(format!("{}={:?}: {}", name, value, e))),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(_)) => Err(ServerError::Config(format!("{} is not valid unicode", name))),
+    }
+}
+
+impl Server {
+    /// Creates a new server instance
+    pub fn new(addr: &str) -> io::Result<Self> {
+        Server::builder(addr).build()
+    }
+
+    /// Creates a server configured from environment variables; unset variables keep the builder defaults:
+    ///
+    /// - `SERVER_ADDR`: address to bind (`127.0.0.1:8080`)
+    /// - `SERVER_WORKERS`: size of the worker pool (one thread per connection)
+    /// - `SERVER_QUEUE_CAPACITY`: connections allowed to wait for a pool worker (64)
+    /// - `SERVER_MAX_FRAME_SIZE`: largest frame payload in bytes (65536)
+    /// - `SERVER_WRITE_TIMEOUT_MS`: response write timeout in milliseconds, 0 waits forever (5000)
+    /// - `SERVER_MAX_DECODE_ERRORS`: consecutive undecodable frames tolerated, 0 for no limit (10)
+    ///
+    /// Values that don't parse or that the builder rejects return `ServerError::Config`.
+    pub fn from_env() -> Result<Self, ServerError> {
+        let addr = env::var("SERVER_ADDR").unwrap_or_else(|_| DEFAULT_ADDR.to_string());
+        let mut builder = Server::builder(&addr);
+        if let Some(workers) = env_setting("SERVER_WORKERS")? {
+            builder = builder.workers(workers);
+        }
+        if let Some(capacity) = env_setting("SERVER_QUEUE_CAPACITY")? {
+            builder = builder.queue_capacity(capacity);
+        }
+        if let Some(size) = env_setting("SERVER_MAX_FRAME_SIZE")? {
+            builder = builder.max_frame_size(size);
+        }
+        if let Some(millis) = env_setting::<u64>("SERVER_WRITE_TIMEOUT_MS")? {
+            builder = builder.write_timeout(Some(Duration::from_millis(millis)).filter(|timeout| !timeout.is_zero()));
+        }
+        if let Some(errors) = env_setting("SERVER_MAX_DECODE_ERRORS")? {
+            builder = builder.max_decode_errors(errors);
+        }
+        builder.build().map_err(|e| match e.kind() {
+            ErrorKind::InvalidInput => ServerError::Config(e.to_string()), // `build` reports out-of-range settings this way
+            _ => ServerError::Io(e),
+        })
+    }
+
+    /// Starts configuring a server that will listen on `addr`
+    pub fn builder(addr: &str) -> ServerBuilder {
+        ServerBuilder {
+            addr: addr.to_string(),
+            config: ServerConfig::default(),
+            handler: Arc::new(DefaultHandler),
+            framer: Arc::new(LengthPrefixed),
+            upgrades: HashMap::new(),
+            on_disconnect: None,
+            middleware: Vec::new(),
+            validation: Vec::new(),
+            spawner: None,
+            shared_metrics: None,
+            on_decode_error_spike: None,
+        }
+    }
+
+    /// Runs the server, listening for incoming connections and handling them.
+    ///
+    /// If the process runs out of file descriptors, new connections wait in the listen backlog while `accept` retries
+    /// every 500 ms instead of spinning; one error is logged when it starts and an info line when it recovers.
+    pub fn run(&self) -> io::Result<()> {
+        self.run_with(|_| {})
+    }
+
+    /// Runs the server like `run`, first sending the address it listens on to `tx` as soon as it is accepting
+    /// connections. With a server bound to port 0, this is how a test harness running it on another thread learns the
+    /// port the OS picked, and knows it can connect right away.
+    pub fn run_reporting(&self, tx: Sender<SocketAddr>) -> io::Result<()> {
+        self.run_with(move |addr| {
+            let _ = tx.send(addr); // The receiver may have been dropped; the server runs either way
+        })
+    }
+
+    /// Runs the server, calling `ready` with the listening address once it is running and before the first `accept`
+    fn run_with(&self, ready: impl FnOnce(SocketAddr)) -> io::Result<()> {
+        {
+            let mut reason = self.shutdown_reason.lock().unwrap();
+            *reason = None; // A restarted server reports its own stop, not the previous one
+            self.warmup_accepts.store(0, Ordering::SeqCst); // A restarted server warms up again
+            self.shared.is_paused.store(false, Ordering::SeqCst); // A restarted server takes clients, even after a `drain`
+            *self.shared.started.lock().unwrap() = Some((Instant::now(), SystemTime::now())); // Uptime counts from this run
+            self.is_running.store(true, Ordering::SeqCst); // Mark the server as running
+        }
+        let addr = self.listener.local_addr()?;
+        info!("Server is running on {}", addr);
+        
+        prepare_listener(&self.listener)?;
+        // The listener has been accepting into its backlog since `build`, so clients may connect now
+        for notifier in self.ready_notifiers.lock().unwrap().drain(..) {
+            let _ = notifier.send(()); // The observer may have dropped its receiver already
+        }
+        ready(addr);
+        // With a configured pool, connections are queued to a fixed set of workers instead of getting their own thread
+        let pool = match self.shared.config.workers {
+            Some(size) => {
+                let stack_size = self.shared.config.stack_size;
+                let connection_id = |client: &Client| client.id;
+                Some(WorkerPool::new(size, self.shared.config.queue_capacity, stack_size, connection_id, |mut client: Client| {
+                    client.shared.queued_connections.fetch_sub(1, Ordering::Relaxed);
+                    #[cfg(feature = "latency")]
+                    client.shared.queue_latency.record(client.id, client.connected_at.elapsed()); // Created right after `accept`
+                    client.handle();
+                })?)
+            }
+            None => None,
+        };
+
+        // Extra acceptors share the listening socket; each one waits for its own client workers before it returns
+        let listeners: Vec<TcpListener> = (1..self.shared.config.acceptors)
+            .filter_map(|index| {
+                self.listener
+                    .try_clone()
+                    .inspect_err(|e| error!("Failed to clone the listener for acceptor {}: {}", index, e)) // Run with fewer acceptors
+                    .ok()
+            })
+            .collect();
+        thread::scope(|scope| {
+            if let Some(interval) = self.shared.config.idle_reaper {
+                let spawned = thread::Builder::new()
+                    .name("idle-reaper".to_string())
+                    .spawn_scoped(scope, move || self.reap_idle(interval));
+                if let Err(e) = spawned {
+                    error!("Failed to spawn the idle reaper, idle connections stay open: {}", e);
+                }
+            }
+            for (index, listener) in listeners.iter().enumerate() {
+                let pool = pool.as_ref();
+                let spawned = thread::Builder::new()
+                    .name(format!("acceptor-{}", index + 1))
+                    .spawn_scoped(scope, move || self.accept_loop(listener, pool));
+                if let Err(e) = spawned {
+                    error!("Failed to spawn acceptor {}: {}", index + 1, e); // The remaining acceptors still serve
+                }
+            }
+            self.accept_loop(&self.listener, pool.as_ref());
+        });
+        if let Some(pool) = pool {
+            pool.join(); // Queued connections are handed out and see the shutdown right away
+        }
+
+        let uptime = self.shared.uptime();
+        *self.shared.started.lock().unwrap() = None;
+        match self.shutdown_reason() {
+            Some(reason) => info!("Server stopped ({:?}) after {:?}.", reason, uptime),
+            None => info!("Server stopped after {:?}.", uptime),
+        }
+        // Tell observers the shutdown is complete; dropping the senders guarantees each receiver fires only once
+        for notifier in self.shutdown_notifiers.lock().unwrap().drain(..) {
+            let _ = notifier.send(()); // The observer may have dropped its receiver already
+        }
+        Ok(())
+    }
+
+    /// Closes connections idle past their timeout every `interval` until the server stops
+    fn reap_idle(&self, interval: Duration) {
+        let mut next_scan = Instant::now() + interval;
+        while self.is_running.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            if now < next_scan {
+                thread::sleep((next_scan - now).min(POLL_INTERVAL)); // Short enough for `stop` not to wait on a long interval
+                continue;
+            }
+            next_scan = now + interval;
+            let default = self.shared.tunables().idle_timeout;
+            for (id, handle) in self.shared.connections.lock().unwrap().iter() {
+                let counters = &handle.counters;
+                let timeout = match counters.idle_timeout_ms.load(Ordering::Relaxed) {
+                    0 => default,
+                    picked => Some(Duration::from_millis(picked)),
+                };
+                let Some(timeout) = timeout else {
+                    continue;
+                };
+                let last_read = Duration::from_millis(counters.last_read_ms.load(Ordering::Relaxed));
+                if handle.connected_at.elapsed().saturating_sub(last_read) < timeout || counters.reaped.swap(true, Ordering::SeqCst) {
+                    continue;
+                }
+                info!("[connection {}] Idle past {:?}, reaping {}.", id, timeout, handle.addr);
+                if let Some(stream) = &handle.stream {
+                    // A failure means the connection is closing already; otherwise its worker notices at its next read timeout
+                    let _ = stream.shutdown(std::net::Shutdown::Both);
+                }
+            }
+        }
+    }
+
+    /// Accepts connections on `listener` until the server stops, then waits for the client threads it spawned
+    fn accept_loop(&self, listener: &TcpListener, pool: Option<&WorkerPool<Client>>) {
+        let mut workers: Vec<JoinHandle<()>> = Vec::new(); // Handles of the client threads, joined once the server stops
+        let mut fd_exhausted = false; // Set while `accept` fails for lack of file descriptors
+        let mut backoff = Backoff::new(self.shared.tunables().poll_interval); // How long to sleep while no connections arrive
+        while self.is_running.load(Ordering::SeqCst) {
+            workers.retain(|worker| !worker.is_finished()); // Forget workers whose client already disconnected
+            backoff.reconfigure(self.shared.tunables().poll_interval);
+            if self.warming_up() {
+                thread::sleep(WARMUP_STEP); // Connections wait in the backlog meanwhile
+                continue;
+            }
+            match listener.accept() {
+                Ok((stream, addr)) => {
+                    backoff.busy();
+                    self.warmup_accepts.fetch_add(1, Ordering::SeqCst);
+                    if fd_exhausted {
+                        info!("File descriptors available again, accepting connections.");
+                        fd_exhausted = false;
+                    }
+                    // Checked after `accept` so no connection slips through once `pause` returns; dropping the stream closes it
+                    if self.shared.is_paused.load(Ordering::SeqCst) {
+                        info!("Server is paused. Closing new connection from {}.", addr);
+                        continue;
+                    }
+                    // Accepted sockets may inherit the listener's non-blocking mode; use blocking reads bounded by a timeout
+                    // instead, so the worker wakes up periodically to notice a shutdown even if the client stays silent
+                    // The write timeout keeps a client that stops reading responses from stalling the worker forever
+                    if let Err(e) = stream
+                        .set_nonblocking(false)
+                        .and_then(|_| stream.set_read_timeout(Some(self.shared.tunables().poll_interval.0)))
+                        .and_then(|_| stream.set_write_timeout(self.shared.tunables().write_timeout))
+                        .and_then(|_| stream.set_nodelay(self.shared.config.nodelay))
+                        .and_then(|_| match self.shared.config.linger {
+                            Some(linger) => SockRef::from(&stream).set_linger(Some(linger)), // Decides whether closing flushes or resets
+                            None => Ok(()),
+                        })
+                        .and_then(|_| match self.shared.config.recv_buffer_size {
+                            Some(bytes) => SockRef::from(&stream).set_recv_buffer_size(bytes),
+                            None => Ok(()),
+                        })
+                        .and_then(|_| match self.shared.config.send_buffer_size {
+                            Some(bytes) => SockRef::from(&stream).set_send_buffer_size(bytes),
+                            None => Ok(()),
+                        })
+                    {
+                        error!("Failed to configure stream for {}: {}", addr, e);
+                        continue;
+                    }
+                    // Counted until the client is dropped; nothing below can fail before the client exists
+                    if !self.shared.admit_peer(addr.ip()) {
+                        warn!("{} already has the most connections allowed per IP.", addr.ip());
+                        turn_away(stream, addr, &*self.shared.framer, ErrorCode::AtCapacity, "too many connections from this address");
+                        continue;
+                    }
+                    let id = self.next_connection_id.fetch_add(1, Ordering::Relaxed); // Unique id for this connection
+                    info!("[connection {}] New client connected: {}", id, addr); // log the new client address
+                    let is_running_clone = Arc::clone(&self.is_running); // Clone the `is_running` Arc to pass a reference to the client safely
+                    let mut client = Client::new(stream, addr, is_running_clone, id, Arc::clone(&self.shared));  // Create a new Client instance, passing the stream and the cloned `is_running` reference
+
+                    if let Some(pool) = pool {
+                        self.dispatch_to_pool(pool, client, addr);
+                        continue;
+                    }
+                    if let Some(spawner) = &self.spawner {
+                        spawner(Connection { client });
+                        continue;
+                    }
+                    // Spawn a named thread to handle the client independently, so thread dumps and profilers show which connection it serves
+                    let spawned = worker_thread(format!("client-worker-{}", id), self.shared.config.stack_size)
+                        .spawn(move || {
+                            client.handle(); // Call the `handle` method to process the client's requests in the separate thread
+                        });
+                    match spawned {
+                        Ok(worker) => workers.push(worker),
+                        Err(e) => error!("[connection {}] Failed to spawn worker for {}: {}", id, addr, e), // The stream is dropped, closing the connection
+                    }
+                }
+                // A blocking `accept` already waited out its timeout
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock && ACCEPT_BLOCKS => {}
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    // No incoming connections, sleep briefly to reduce CPU usage; longer the longer none arrive
+                    thread::sleep(backoff.current());
+                    backoff.idle();
+                }
+                // Every later `accept` would fail the same way; stop instead of logging the error forever
+                Err(ref e) if is_listener_broken(e) => {
+                    if self.is_running.load(Ordering::SeqCst) {
+                        error!("Listener is no longer usable, stopping the server: {}", e);
+                        self.stop_with(ShutdownReason::ListenerClosed); // Other acceptors see the flag and return too
+                    }
+                    break;
+                }
+                // The connection stays queued in the kernel, so retrying right away would fail again and spin;
+                // rest until clients disconnect and free descriptors
+                Err(ref e) if is_fd_exhaustion(e) => {
+                    if !fd_exhausted {
+                        error!(
+                            "Out of file descriptors, new connections wait in the backlog: {}. Raise the open file limit \
+                             (`ulimit -n`) or lower the client load; retrying every {:?}.",
+                            e, FD_EXHAUSTION_BACKOFF
+                        );
+                        fd_exhausted = true; // Logged once per episode
+                    }
+                    thread::sleep(FD_EXHAUSTION_BACKOFF);
+                }
+                Err(e) => {
+                    error!("Error accepting connection: {}", e);
+                    thread::sleep(POLL_INTERVAL); // Persistent errors would otherwise spin the loop
+                }
+            }
+        }
+
+        // Connections still queued in the backlog would otherwise wait until the listener is dropped; tell them why
+        // they aren't served. Ones arriving after this stay queued until then. A non-blocking listener finds the end of
+        // the backlog without waiting out `ACCEPT_TIMEOUT`; other acceptors sharing it are stopping as well.
+        if let Err(e) = listener.set_nonblocking(true) {
+            warn!("Failed to make the listener non-blocking to drain its backlog: {}", e);
+        }
+        loop {
+            match listener.accept() {
+                Ok((stream, addr)) => turn_away(stream, addr, &*self.shared.framer, ErrorCode::ShuttingDown, "server shutting down"),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => break, // `WouldBlock` once the backlog is empty
+            }
+        }
+
+        // Wait for every client worker to notice the shutdown and finish
+        for worker in workers {
+            if worker.join().is_err() {
+                error!("A client worker panicked.");
+            }
+        }
+    }
+
+    /// Whether `warmup` holds off the next accept: the connections taken so far have used up what the rate allowed
+    /// since `run` started. The rate climbs linearly to the target, so the first connection plus
+    /// `rate * elapsed² / (2 * period)` are allowed by now.
+    fn warming_up(&self) -> bool {
+        let Some((period, rate)) = self.shared.config.warmup else {
+            return false;
+        };
+        let elapsed = self.shared.uptime();
+        if elapsed >= period {
+            return false;
+        }
+        let allowed = 1.0 + rate as f64 * elapsed.as_secs_f64().powi(2) / (2.0 * period.as_secs_f64());
+        self.warmup_accepts.load(Ordering::SeqCst) as f64 >= allowed.floor()
+    }
+
+    /// Replaces the message handler while the server runs. Requests already being handled finish with the old handler;
+    /// every request dispatched afterwards, on any connection, uses the new one. Responses in the `result_cache` came
+    /// from the old handler and are forgotten.
+    pub fn set_handler(&self, handler: impl MessageHandler + 'static) {
+        *self.shared.handler.write().unwrap() = Arc::new(handler);
+        if let Some(results) = &self.shared.results {
+            results.clear();
+        }
+        info!("Message handler replaced.");
+    }
+
+    /// The settings `reload_config` can change, as they are now
+    pub fn tunables(&self) -> Tunables {
+        self.shared.tunables()
+    }
+
+    /// Replaces the settings a running server can change, typically starting from `tunables`. The accept loop and
+    /// every connection pick up the new values as they next use them, so a lowered limit also applies to what an open
+    /// connection already used. Values `build` would refuse are rejected with `ErrorKind::InvalidInput` and change
+    /// nothing; settings outside `Tunables`, such as the address, can only be set by building a new server.
+    pub fn reload_config(&self, tunables: Tunables) -> io::Result<()> {
+        tunables.validate()?;
+        *self.shared.tunables.write().unwrap() = tunables;
+        info!("Configuration reloaded: {:?}", tunables);
+        Ok(())
+    }
+
+    /// Returns the address the listener is bound to, with the actual port when it was bound to port 0
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Returns a receiver that gets exactly one `()` once `run` has exited and all client workers have been joined.
+    /// Subscribe before stopping the server; the sender is dropped after firing.
+    pub fn shutdown_notifier(&self) -> Receiver<()> {
+        let (sender, receiver) = mpsc::channel();
+        self.shutdown_notifiers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Returns a receiver that gets exactly one `()` once `run` is ready, replacing sleeps in test setup and
+    /// supervisors. Subscribe before starting `run`; the sender is dropped after firing, so each subscription covers
+    /// one run.
+    ///
+    /// When it fires, `is_running` is already set and the listener is set up for the accept loop: blocking with a 25ms
+    /// receive timeout on Linux, non-blocking elsewhere. The first `accept` is still to come, so a client connecting
+    /// from then on is served, since it waits in the listen backlog until that `accept`, and `stop` called from then on
+    /// always stops this run.
+    pub fn ready_notifier(&self) -> Receiver<()> {
+        let (sender, receiver) = mpsc::channel();
+        self.ready_notifiers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Returns p50/p99/max request latency, measured from a request being read to its response being written
+    #[cfg(feature = "latency")]
+    pub fn latency_stats(&self) -> LatencyStats {
+        self.shared.latency.stats()
+    }
+
+    /// Returns p50/p99/max of how long connections waited for a pool worker, from being accepted to a worker taking
+    /// them; only connections served by the `workers` pool are counted. A wait that keeps growing means the pool is
+    /// too small for the load.
+    #[cfg(feature = "latency")]
+    pub fn queue_latency_stats(&self) -> LatencyStats {
+        self.shared.queue_latency.stats()
+    }
+
+    /// Returns how long the server has been running, counted from when the current `run` started; zero outside `run`,
+    /// so a restarted server counts from its restart
+    pub fn uptime(&self) -> Duration {
+        self.shared.uptime()
+    }
+
+    /// Returns when the current `run` marked the server running, or `None` outside `run`
+    pub fn started_at(&self) -> Option<SystemTime> {
+        self.shared.started.lock().unwrap().map(|(_, at)| at)
+    }
+
+    /// Returns the request counters, by message type and outcome, of every connection since the server was built or
+    /// the last `drain_metrics`
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.shared.metrics)
+    }
+
+    /// Returns the request counters and resets them to zero at once, so periodic scrapes get windows that neither
+    /// overlap nor miss a request. Only the request counters reset; state such as the open connections, the
+    /// disconnect history and the latency histograms is left alone.
+    pub fn drain_metrics(&self) -> MetricsSnapshot {
+        self.shared.metrics.drain()
+    }
+
+    /// Bytes received but not yet handled across every connection, as counted against `max_total_buffered_bytes`; 0
+    /// when no such cap is set
+    pub fn buffered_bytes(&self) -> usize {
+        self.shared.buffered_total.load(Ordering::SeqCst)
+    }
+
+    /// Undecodable frames received across every connection since the server was built; unlike the request counters,
+    /// `drain_metrics` leaves it alone
+    pub fn decode_errors(&self) -> u64 {
+        self.shared.decode_errors.total()
+    }
+
+    /// Estimates how many connections the OS has completed but `run` hasn't accepted yet, an early sign that the accept
+    /// loop is falling behind. Only Linux reports this (through `TCP_INFO` on the listener); other platforms return `None`,
+    /// as does a failed query.
+    pub fn pending_accepts(&self) -> Option<usize> {
+        accept_queue_len(&self.listener)
+    }
+
+    /// Closes every open connection from `peer`, returning how many were closed; see `disconnect_connection`
+    pub fn disconnect(&self, peer: SocketAddr) -> usize {
+        let connections = self.shared.connections.lock().unwrap();
+        connections
+            .iter()
+            .filter(|(_, handle)| handle.addr == peer)
+            .filter(|(&id, handle)| kick(id, handle))
+            .count()
+    }
+
+    /// Closes the connection with the given id, returning whether it was open. Its worker stops at its next read,
+    /// after finishing the request in progress, and the disconnect is recorded as `DisconnectReason::Kicked`.
+    pub fn disconnect_connection(&self, id: u64) -> bool {
+        let connections = self.shared.connections.lock().unwrap();
+        connections.get(&id).is_some_and(|handle| kick(id, handle))
+    }
+
+    /// Turns `TCP_NODELAY` on or off for the connection with the given id, returning whether it was changed; see
+    /// `ServerBuilder::nodelay` for the tradeoff. Useful to let Nagle coalesce a bulk batch and restore low latency after.
+    /// Fails for a connection that is not open, or whose stream couldn't be cloned when it was accepted.
+    pub fn set_nodelay(&self, id: u64, nodelay: bool) -> bool {
+        let connections = self.shared.connections.lock().unwrap();
+        let Some(handle) = connections.get(&id) else {
+            return false;
+        };
+        let Some(stream) = &handle.stream else {
+            warn!("[connection {}] Can't change TCP_NODELAY without a clone of the stream.", id);
+            return false;
+        };
+        match stream.set_nodelay(nodelay) {
+            Ok(()) => {
+                debug!("[connection {}] TCP_NODELAY set to {}.", id, nodelay);
+                true
+            }
+            Err(e) => {
+                warn!("[connection {}] Failed to set TCP_NODELAY: {}", id, e); // Most likely already closed
+                false
+            }
+        }
+    }
+
+    /// Returns the server's state in one owned value: whether it runs, its uptime, every open connection with its
+    /// counters so far, and the request metrics. The running state and shutdown reason are read together, as are the
+    /// connections and the client count; counters of busy connections and the metrics keep moving while they are copied.
+    pub fn snapshot(&self) -> ServerSnapshot {
+        let (running, shutdown_reason) = {
+            let reason = self.shutdown_reason.lock().unwrap(); // Held while `is_running` changes
+            (self.is_running.load(Ordering::SeqCst), *reason)
+        };
+        let mut connections: Vec<ConnectionSnapshot> = self
+            .shared
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, handle)| ConnectionSnapshot {
+                connection_id: id,
+                peer: handle.addr,
+                bytes_in: handle.counters.bytes_in.load(Ordering::Relaxed),
+                bytes_out: handle.counters.bytes_out.load(Ordering::Relaxed),
+                writes: handle.counters.writes.load(Ordering::Relaxed),
+                messages_handled: handle.counters.messages_handled.load(Ordering::Relaxed),
+                peak_buffered_bytes: handle.counters.peak_buffered_bytes.load(Ordering::Relaxed),
+                connected_for: handle.connected_at.elapsed(),
+            })
+            .collect();
+        connections.sort_by_key(|connection| connection.connection_id);
+        let started = *self.shared.started.lock().unwrap();
+        ServerSnapshot {
+            running,
+            paused: self.shared.is_paused.load(Ordering::SeqCst),
+            shutdown_reason,
+            started_at: started.map(|(_, at)| at),
+            uptime: started.map_or(Duration::ZERO, |(instant, _)| instant.elapsed()),
+            client_count: connections.len(),
+            connections,
+            metrics: self
+                .shared
+                .metrics
+                .snapshot()
+                .into_iter()
+                .map(|((message_type, outcome), count)| (message_type, outcome, count))
+                .collect(),
+        }
+    }
+
+    /// Returns the most recent disconnects, oldest first, with the peer, the reason and when it happened
+    pub fn recent_disconnects(&self) -> Vec<DisconnectRecord> {
+        self.shared.disconnects.snapshot()
+    }
+
+    /// Queues the client for a pool worker, applying the saturation policy when the pool is full
+    fn dispatch_to_pool(&self, pool: &WorkerPool<Client>, client: Client, addr: SocketAddr) {
+        let policy = self.shared.config.saturation_policy;
+        // Counted before it is queued, as a worker may take it right away
+        self.shared.queued_connections.fetch_add(1, Ordering::Relaxed);
+        let queued = match policy {
+            SaturationPolicy::Block => pool.submit(client), // Holds up the accept loop until a worker takes a connection
+            SaturationPolicy::Reject | SaturationPolicy::Close => pool.try_submit(client),
+        };
+        if let Err(mut client) = queued {
+            self.shared.queued_connections.fetch_sub(1, Ordering::Relaxed);
+            warn!("[connection {}] Worker pool saturated, turning away {} ({:?}).", client.id, addr, policy);
+            if policy == SaturationPolicy::Reject {
+                client.reject(ErrorCode::AtCapacity, "server at capacity");
+            }
+            // Dropping the client closes the connection
+        }
+    }
+
+    /// Stops taking new clients without shutting down: connections are accepted and closed at once, while clients already
+    /// connected keep being served. Undo with `resume`; `run` starts unpaused, so a restarted server takes clients again.
+    pub fn pause(&self) {
+        if !self.shared.is_paused.swap(true, Ordering::SeqCst) {
+            info!("Server paused.");
+        }
+    }
+
+    /// Starts taking new clients again after `pause`
+    pub fn resume(&self) {
+        if self.shared.is_paused.swap(false, Ordering::SeqCst) {
+            info!("Server resumed.");
+        }
+    }
+
+    /// Stops taking new clients, as `pause` does, and waits up to `timeout` for the connected ones to leave on their own.
+    /// Returns whether every connection closed in time; the server keeps running either way, call `stop` to end it.
+    /// It stays paused until `resume`, or until `run` is called again after `stop`.
+    pub fn drain(&self, timeout: Duration) -> bool {
+        self.pause();
+        info!("Draining {} connections.", self.shared.active_clients.load(Ordering::SeqCst));
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.shared.active_clients.load(Ordering::SeqCst) == 0 {
+                info!("Drain complete.");
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                warn!("Drain timed out with {} connections open.", self.shared.active_clients.load(Ordering::SeqCst));
+                return false;
+            }
+            thread::sleep(POLL_INTERVAL.min(deadline - now));
+        }
+    }
+
+    /// Runs the server until the process receives `SIGTERM`, then shuts down gracefully. The sequence is:
+    ///
+    /// 1. A `SIGTERM` handler is installed and `run` starts on a separate thread.
+    /// 2. On `SIGTERM` the server drains: new connections are accepted and closed, connected clients keep being served.
+    /// 3. It waits up to `timeout` for the connected clients to disconnect.
+    /// 4. It calls `stop`; clients still connected are closed at their next poll and `run` returns once every worker is joined.
+    /// 5. The handler is removed and the result returned.
+    ///
+    /// Returns `Ok(true)` if every client left within `timeout`, `Ok(false)` if some had to be closed or the server was
+    /// stopped through `stop` instead of the signal, and the error if `run` failed. A second `SIGTERM` during steps 2 to 4
+    /// exits the process at once with status 1. Once the handler is removed `SIGTERM` is ignored, so exit after this returns.
+    pub fn run_until_signal_with_drain(&self, timeout: Duration) -> io::Result<bool> {
+        let terminate = Arc::new(AtomicBool::new(false));
+        // Registered first so it only sees signals that arrive after the flag is already set
+        let signal_ids = [
+            flag::register_conditional_shutdown(SIGTERM, 1, Arc::clone(&terminate))?,
+            flag::register(SIGTERM, Arc::clone(&terminate))?,
+        ];
+        let result = thread::scope(|scope| {
+            let runner = scope.spawn(|| self.run());
+            // Wait for the signal, or for `run` to end on its own; a signal is only acted on once `run` has started,
+            // otherwise `run` would reset the flag `stop` clears and never return
+            while !runner.is_finished() {
+                if terminate.load(Ordering::SeqCst) && self.is_running.load(Ordering::SeqCst) {
+                    break;
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+            let mut drained = false;
+            if !runner.is_finished() {
+                info!("SIGTERM received. Shutting down gracefully.");
+                drained = self.drain(timeout);
+                self.stop_with(if drained { ShutdownReason::DrainComplete } else { ShutdownReason::Signal });
+            }
+            match runner.join() {
+                Ok(result) => result.map(|_| drained),
+                Err(panic) => std::panic::resume_unwind(panic),
+            }
+        });
+        for id in signal_ids {
+            signal_hook::low_level::unregister(id);
+        }
+        result
+    }
+
+    /// Returns why the server was stopped, once it has been; `None` while it runs or if it never ran.
+    /// Set when the stop is requested, so it is available as soon as `run` returns.
+    pub fn shutdown_reason(&self) -> Option<ShutdownReason> {
+        *self.shutdown_reason.lock().unwrap()
+    }
+
+    /// Stops the server by setting the `is_running` flag to `false`. Returns whether this call stopped it: when several
+    /// threads call `stop` at once, exactly one of them sees `true`; the rest, like calls on a server that isn't running,
+    /// get `false`.
+    pub fn stop(&self) -> bool {
+        self.stop_with(ShutdownReason::Stop)
+    }
+
+    /// Stops the server, recording `reason` if this call is the one that stopped it
+    fn stop_with(&self, reason: ShutdownReason) -> bool {
+        // Holding the reason while the flag flips means `run` can't report its stop before the reason is recorded
+        let mut recorded = self.shutdown_reason.lock().unwrap();
+        match self.is_running.compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => {
+                *recorded = Some(reason);
+                info!("Shutdown signal sent ({:?}).", reason);
+                true
+            }
+            Err(_) => {
+                warn!("Server is already stopping or not running.");
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Takes every write, but fails every flush, like a buffering transport that can't push its bytes on
+    #[derive(Default)]
+    struct FailingFlush {
+        written: Vec<u8>,
+        flushes: u32,
+    }
+
+    impl Write for FailingFlush {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            Err(io::Error::other("flush failed"))
+        }
+    }
+
+    #[test]
+    fn test_failed_flush_after_write_is_not_fatal() {
+        let mut stream = FailingFlush::default();
+        stream.write_all(b"response").unwrap();
+        assert!(flush_written(0, &mut stream).is_ok(), "A failed flush ended the connection");
+        assert_eq!(stream.flushes, 1);
+        assert_eq!(stream.written, b"response");
     }
 }
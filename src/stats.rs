@@ -0,0 +1,239 @@
+use crate::error::CloseCode;
+use crate::metrics::{MessageType, Outcome};
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+/// Why a client connection ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client closed its end of the connection
+    ClientClosed,
+    /// The client closed its end of the connection partway through sending a frame or an upload
+    ClosedMidFrame,
+    /// The client sent a frame that can't be accepted, such as one over the size limit
+    InvalidFrame,
+    /// The client interrupted an upload with another message, or sent a chunk that failed to decode
+    InvalidUpload,
+    /// The client sent too many frames in a row that failed to decode
+    TooManyDecodeErrors,
+    /// The client sent nothing for the idle timeout
+    IdleTimeout,
+    /// The client sent part of a frame header and then nothing for the idle timeout
+    HeaderTimeout,
+    /// The client sent a frame header but not the rest of the frame within the frame timeout
+    FrameTimeout,
+    /// The client sent more bytes than its connection is allowed
+    QuotaExceeded,
+    /// Reading from the socket failed
+    ReadError,
+    /// Writing a response failed
+    WriteError,
+    /// Writing a response stalled past the write timeout
+    WriteTimeout,
+    /// Writing a single response took longer than the write progress timeout, though the client kept reading
+    SlowWrite,
+    /// Closed through `Server::disconnect` or `Server::disconnect_connection`
+    Kicked,
+    /// The message handler asked to close the connection after its response
+    ClosedByHandler,
+    /// The server is shutting down
+    Shutdown,
+}
+
+impl DisconnectReason {
+    /// Whether the connection ended with its socket still usable, so responses already written are flushed and
+    /// followed by an orderly close. Otherwise the socket failed or was shut down by `Server::disconnect`, and data not
+    /// yet delivered may be lost.
+    pub fn is_graceful(self) -> bool {
+        !matches!(
+            self,
+            DisconnectReason::ReadError
+                | DisconnectReason::WriteError
+                | DisconnectReason::WriteTimeout
+                | DisconnectReason::SlowWrite
+                | DisconnectReason::Kicked
+        )
+    }
+
+    /// The code of the `CloseNotice` sent for this reason, `None` if nothing is sent: the client closed first, or the
+    /// socket can no longer be written. An oversized frame is `InvalidFrame` too, but its notice says `TooBig`.
+    pub fn close_code(self) -> Option<CloseCode> {
+        match self {
+            DisconnectReason::ClosedByHandler => Some(CloseCode::NormalClose),
+            DisconnectReason::Shutdown => Some(CloseCode::ServerShutdown),
+            DisconnectReason::InvalidFrame | DisconnectReason::InvalidUpload | DisconnectReason::TooManyDecodeErrors => {
+                Some(CloseCode::ProtocolError)
+            }
+            DisconnectReason::QuotaExceeded => Some(CloseCode::PolicyViolation),
+            DisconnectReason::IdleTimeout | DisconnectReason::HeaderTimeout | DisconnectReason::FrameTimeout => {
+                Some(CloseCode::Timeout)
+            }
+            DisconnectReason::ClientClosed
+            | DisconnectReason::ClosedMidFrame
+            | DisconnectReason::ReadError
+            | DisconnectReason::WriteError
+            | DisconnectReason::WriteTimeout
+            | DisconnectReason::SlowWrite
+            | DisconnectReason::Kicked => None,
+        }
+    }
+
+    /// Classifies a failed response write
+    pub(crate) fn from_write_error(error: &io::Error) -> Self {
+        if error.get_ref().is_some_and(|inner| inner.is::<SlowWrite>()) {
+            return DisconnectReason::SlowWrite;
+        }
+        match error.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => DisconnectReason::WriteTimeout,
+            _ => DisconnectReason::WriteError,
+        }
+    }
+}
+
+/// Carried by the write error of a response that took longer than `ServerBuilder::write_progress_timeout`
+#[derive(Debug)]
+pub(crate) struct SlowWrite;
+
+impl fmt::Display for SlowWrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("slow client write")
+    }
+}
+
+impl Error for SlowWrite {}
+
+/// Why the server stopped, reported by `Server::shutdown_reason`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ShutdownReason {
+    /// `Server::stop` was called
+    Stop,
+    /// `SIGTERM` was received and clients still connected when the drain timed out were closed
+    Signal,
+    /// `SIGTERM` was received and every client left before the drain timed out
+    DrainComplete,
+    /// The listening socket was closed or shut down from outside the server, so no connection can be accepted again
+    ListenerClosed,
+}
+
+/// One entry of `Server::recent_disconnects`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisconnectRecord {
+    pub connection_id: u64,
+    pub peer: SocketAddr,
+    pub reason: DisconnectReason,
+    pub at: SystemTime,
+}
+
+/// Everything known about a connection once it has ended, passed to `ServerBuilder::on_disconnect`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub connection_id: u64, // Assigned at accept in increasing order, matches the `[connection N]` log prefix
+    pub peer: SocketAddr,
+    pub bytes_in: u64,  // Raw bytes read, including frame headers
+    pub bytes_out: u64, // Raw bytes written, including frame headers and any greeting
+    pub writes: u64, // Socket writes it took, one per response unless `write_buffer` batches them
+    pub messages_handled: u64, // Requests that decoded, whether or not they got a response
+    pub peak_buffered_bytes: usize, // Most bytes received but not yet handled at any one time
+    pub duration: Duration, // From accept to close
+    pub reason: DisconnectReason,
+}
+
+/// Counters of a connection, written by its worker and read by `Server::snapshot` while it is open
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionCounters {
+    pub(crate) bytes_in: AtomicU64,
+    pub(crate) bytes_out: AtomicU64,
+    pub(crate) writes: AtomicU64,
+    pub(crate) messages_handled: AtomicU64,
+    pub(crate) peak_buffered_bytes: AtomicUsize,
+    pub(crate) last_read_ms: AtomicU64, // From accept to the last read that got bytes, for the idle reaper
+    pub(crate) idle_timeout_ms: AtomicU64, // Picked by the client with a `SetIdleTimeoutRequest`, 0 while it follows the server's
+    pub(crate) reaped: AtomicBool, // Set by the idle reaper before it shuts the stream down
+}
+
+impl ConnectionCounters {
+    /// Adds `bytes` read from the client, returning the new total
+    pub(crate) fn read(&self, bytes: usize) -> u64 {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed) + bytes as u64
+    }
+
+    /// Records how many bytes are buffered but not yet handled
+    pub(crate) fn buffered(&self, bytes: usize) {
+        self.peak_buffered_bytes.fetch_max(bytes, Ordering::Relaxed);
+    }
+}
+
+/// An open connection as seen by `Server::snapshot`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ConnectionSnapshot {
+    pub connection_id: u64,
+    pub peer: SocketAddr,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub writes: u64,
+    pub messages_handled: u64,
+    pub peak_buffered_bytes: usize,
+    pub connected_for: Duration, // From accept to the snapshot
+}
+
+/// The state of a server at one moment, returned by `Server::snapshot`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ServerSnapshot {
+    pub running: bool,
+    pub paused: bool,
+    pub shutdown_reason: Option<ShutdownReason>, // Why the last `run` stopped, `None` while it runs
+    pub started_at: Option<SystemTime>, // When the current `run` started, `None` outside `run`
+    pub uptime: Duration,
+    pub client_count: usize, // The number of `connections`
+    pub connections: Vec<ConnectionSnapshot>, // Ordered by connection id
+    pub metrics: Vec<(MessageType, Outcome, u64)>, // Every non-zero request counter, in no particular order
+}
+
+/// Keeps the most recent disconnects, dropping the oldest once full
+pub(crate) struct DisconnectLog {
+    records: Mutex<VecDeque<DisconnectRecord>>,
+    capacity: usize,
+}
+
+impl DisconnectLog {
+    pub(crate) fn new(capacity: usize) -> Self {
+        DisconnectLog {
+            records: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    pub(crate) fn push(&self, connection_id: u64, peer: SocketAddr, reason: DisconnectReason) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut records = self.records.lock().unwrap();
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        records.push_back(DisconnectRecord {
+            connection_id,
+            peer,
+            reason,
+            at: SystemTime::now(),
+        });
+    }
+
+    /// Copies the records, oldest first
+    pub(crate) fn snapshot(&self) -> Vec<DisconnectRecord> {
+        self.records.lock().unwrap().iter().cloned().collect()
+    }
+}
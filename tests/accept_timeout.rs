@@ -0,0 +1,72 @@
+// Runs in its own test binary because it measures the CPU time of the whole process
+#![cfg(target_os = "linux")]
+
+use embedded_recruitment_task::{
+    codec,
+    message::{client_message, server_message, ClientMessage, EchoMessage, ServerMessage},
+    server::Server,
+};
+use prost::Message;
+use std::{
+    io::Write,
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+mod common;
+use common::cpu_time;
+
+#[test]
+fn test_blocking_accept_is_idle_and_stops_quickly() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let addr: SocketAddr = "127.0.0.1:9084".parse().unwrap();
+    let server = Arc::new(Server::new(&addr.to_string()).expect("Failed to start server"));
+    let handle = {
+        let server = Arc::clone(&server);
+        thread::spawn(move || server.run().expect("Server encountered an error"))
+    };
+    thread::sleep(Duration::from_millis(200)); // Let the accept loop start
+
+    // Waiting in `accept` costs next to nothing
+    let cpu_before = cpu_time();
+    thread::sleep(Duration::from_secs(1));
+    let cpu_used = cpu_time() - cpu_before;
+    assert!(cpu_used < Duration::from_millis(50), "Idle accept loop used {:?} of CPU time", cpu_used);
+
+    // A client arriving after the quiet spell is taken at once, not after the 100ms poll interval
+    let started = Instant::now();
+    let mut stream = TcpStream::connect(addr).expect("Failed to connect to the server");
+    let request = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "right away".to_string(),
+        })),
+    });
+    stream.write_all(&request).expect("Failed to send request");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(3)))
+        .expect("Failed to set read timeout");
+    let frame = codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing echo response");
+    match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "right away"),
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+    let latency = started.elapsed();
+    assert!(latency < Duration::from_millis(50), "First response took {:?}", latency);
+    drop(stream);
+    thread::sleep(Duration::from_millis(200)); // Let the worker see the disconnect
+
+    // `stop` is noticed within the accept timeout rather than the poll interval
+    let started = Instant::now();
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+    let shutdown = started.elapsed();
+    assert!(shutdown < Duration::from_millis(80), "Shutdown took {:?}", shutdown);
+}
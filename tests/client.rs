@@ -1,9 +1,10 @@
-use embedded_recruitment_task::message::{client_message, ServerMessage};
+use embedded_recruitment_task::codec::{self, DEFAULT_MAX_FRAME_SIZE};
+use embedded_recruitment_task::error::ErrorCode;
+use embedded_recruitment_task::handler;
+use embedded_recruitment_task::message::{AddRequest, ClientMessage, EchoMessage, client_message, server_message, ServerMessage};
 use log::error;
 use log::info;
 use prost::Message;
-use std::io::Read;
-use std::io::Write;
 use std::{
     io,
     net::{SocketAddr, TcpStream, ToSocketAddrs},
@@ -61,18 +62,27 @@ impl Client {
         Ok(())
     }
 
+    // set how long `receive` waits for a response (`None` waits forever)
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        match self.stream {
+            Some(ref stream) => stream.set_read_timeout(timeout),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "No active connection",
+            )),
+        }
+    }
+
     // generic message to send message to the server
     pub fn send(&mut self, message: client_message::Message) -> io::Result<()> {
         if let Some(ref mut stream) = self.stream {
-            // Encode the message to a buffer
-            let mut buffer = Vec::new();
-            message.encode(&mut buffer);
-
-            // Send the buffer to the server
-            stream.write_all(&buffer)?;
-            stream.flush()?;
+            // Wrap the message, then send it to the server as a length-prefixed frame
+            let message = ClientMessage {
+                message: Some(message),
+            };
+            codec::write_frame(stream, &message)?;
 
-            println!("Sent message: {:?}", message);
+            println!("Sent message: {:?}", message.message);
             Ok(())
         } else {
             Err(io::Error::new(
@@ -82,23 +92,34 @@ impl Client {
         }
     }
 
+    // send the message and wait for its response, or return `None` right away for one-way messages
+    pub fn request(&mut self, message: client_message::Message) -> io::Result<Option<ServerMessage>> {
+        let expects_response = handler::expects_response(&message);
+        self.send(message)?;
+        if expects_response {
+            self.receive().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
     pub fn receive(&mut self) -> io::Result<ServerMessage> {
         if let Some(ref mut stream) = self.stream {
             info!("Receiving message from the server");
-            let mut buffer = vec![0u8; 1024];
-            let bytes_read = stream.read(&mut buffer)?;
-            if bytes_read == 0 {
-                info!("Server disconnected.");
-                return Err(io::Error::new(
-                    io::ErrorKind::ConnectionAborted,
-                    "Server disconnected",
-                ));
-            }
+            // Read exactly one length-prefixed frame
+            let frame = codec::read_frame(stream, DEFAULT_MAX_FRAME_SIZE).map_err(|e| {
+                if e.kind() == io::ErrorKind::UnexpectedEof {
+                    info!("Server disconnected.");
+                    io::Error::new(io::ErrorKind::ConnectionAborted, "Server disconnected")
+                } else {
+                    e
+                }
+            })?;
 
-            info!("Received {} bytes from the server", bytes_read);
+            info!("Received {} bytes from the server", frame.payload.len());
 
             // Decode the received message
-            ServerMessage::decode(&buffer[..bytes_read]).map_err(|e| {
+            ServerMessage::decode(frame.payload.as_slice()).map_err(|e| {
                 io::Error::new(
                     io::ErrorKind::InvalidData,
                     format!("Failed to decode ServerMessage: {}", e),
@@ -112,4 +133,75 @@ impl Client {
             ))
         }
     }
+
+    // send an add request and return the sum, failing with `TimedOut` if no response arrives within `timeout`
+    pub fn add(&mut self, a: i32, b: i32, timeout: Duration) -> io::Result<i32> {
+        let message = client_message::Message::AddRequest(AddRequest { a, b });
+        match self.request_within(message, timeout)?.message {
+            Some(server_message::Message::AddResponse(add)) => Ok(add.result),
+            other => Err(unexpected_response("AddResponse", other)),
+        }
+    }
+
+    // send an echo request and return the echoed content, failing with `TimedOut` if no response arrives within `timeout`
+    pub fn echo(&mut self, content: &str, timeout: Duration) -> io::Result<String> {
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: content.to_string(),
+        });
+        match self.request_within(message, timeout)?.message {
+            Some(server_message::Message::EchoMessage(echo)) => Ok(echo.content),
+            other => Err(unexpected_response("EchoMessage", other)),
+        }
+    }
+
+    // send the message and wait up to `timeout` for its response. A late response would be read by the next call
+    // as its own, so on timeout the connection is dropped and must be re-established with `connect`
+    fn request_within(&mut self, message: client_message::Message, timeout: Duration) -> io::Result<ServerMessage> {
+        let previous = match self.stream {
+            Some(ref stream) => stream.read_timeout()?,
+            None => None, // `send` reports the missing connection
+        };
+        self.send(message)?;
+        self.set_read_timeout(Some(timeout))?;
+        let response = self.receive();
+        match response {
+            // `WouldBlock` on Unix, `TimedOut` on Windows
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                error!("No response within {:?}, dropping the connection", timeout);
+                self.stream = None;
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no response within {:?}", timeout),
+                ))
+            }
+            response => {
+                self.set_read_timeout(previous)?; // The timeout only applies to this call
+                response
+            }
+        }
+    }
+
+    // receive a response that must be an `ErrorResponse` with a known code
+    pub fn receive_error(&mut self) -> io::Result<(ErrorCode, String)> {
+        match self.receive()?.message {
+            Some(server_message::Message::ErrorResponse(error)) => match error.error_code() {
+                Some(code) => Ok((code, error.message)),
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown error code {}", error.code),
+                )),
+            },
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Expected ErrorResponse, got {:?}", other),
+            )),
+        }
+    }
+}
+
+fn unexpected_response(expected: &str, received: Option<server_message::Message>) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Expected {}, got {:?}", expected, received),
+    )
 }
@@ -1,9 +1,27 @@
 use embedded_recruitment_task::{
-    message::{client_message, server_message, AddRequest, EchoMessage},
-    server::Server,
+    codec,
+    error::{CloseCode, ErrorCode, ServerError},
+    handler::{BuiltinHandler, CancellationToken, CloseAfter, DefaultHandler, MessageHandler, Next, OverflowPolicy, Priority},
+    message::{
+        client_message, server_message, AddRequest, BinaryEchoMessage, CancelRequest, CapabilitiesRequest, ClientMessage,
+        DeadlineRequest, DuplicateSuppressed, EchoMessage, EchoMetadataRequest, ErrorResponse, Greeting, HealthRequest,
+        IdempotentRequest, LogEvent, ServerLoad, ServerMessage, SetIdleTimeoutRequest, SetIdleTimeoutResponse, UploadChunk,
+        UploadResponse, UploadStart, UpgradeRequest, VersionRequest,
+    },
+    metrics::{MessageType, Metrics, Outcome},
+    redact::{PayloadLogging, Redaction},
+    server::{process, Connection, MissingResponsePolicy, SaturationPolicy, Server, Tunables},
+    stats::{ConnectionStats, DisconnectReason, ShutdownReason},
 };
+use prost::Message;
 use std::{
-    sync::Arc,
+    io::{Read, Write},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        mpsc::{self, TryRecvError},
+        Arc,
+    },
     thread::{self, JoinHandle},
 	time::Duration,
 };
@@ -11,6 +29,7 @@ use std::{
 mod client;
 
 // This ensures each test or call gets a unique port by incrementing the port number after each use
+#[allow(clippy::useless_conversion)] // Kept as originally written, like the tests below
 fn get_unique_port() -> u32 {
     // Local static variable to track the next port number
     static mut NEXT_PORT: u32 = 8080;
@@ -61,6 +80,7 @@ fn test_client_connection() {
 }
 
 #[test]
+#[allow(clippy::field_reassign_with_default)]
 fn test_client_echo_message() {
     let _ = env_logger::builder()
             .is_test(true) // Configures logger for tests
@@ -116,6 +136,7 @@ fn test_client_echo_message() {
 }
 
 #[test]
+#[allow(clippy::field_reassign_with_default)]
 fn test_multiple_echo_messages() {
     let _ = env_logger::builder()
             .is_test(true) // Configures logger for tests
@@ -180,6 +201,7 @@ fn test_multiple_echo_messages() {
 }
 
 #[test]
+#[allow(clippy::field_reassign_with_default, clippy::useless_vec)]
 fn test_multiple_clients() {
     let _ = env_logger::builder()
             .is_test(true) // Configures logger for tests
@@ -258,6 +280,7 @@ fn test_multiple_clients() {
 }
 
 #[test]
+#[allow(clippy::field_reassign_with_default, clippy::clone_on_copy)]
 fn test_client_add_request() {
     let _ = env_logger::builder()
     .is_test(true) // Configures logger for tests
@@ -313,3 +336,6277 @@ fn test_client_add_request() {
         "Server thread panicked or failed to join"
     );
 }
+
+
+#[test]
+fn test_shutdown_notifier_fires_once() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread and subscribe to its shutdown; it closes without waiting for the
+    // client, which stays connected until the end
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .graceful_close_timeout(None)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let notifier = server.shutdown_notifier();
+    let handle = setup_server_thread(server.clone());
+
+    // Keep a client connected so the worker still has to be joined during shutdown
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(
+        notifier.try_recv(),
+        Err(TryRecvError::Empty),
+        "Notifier fired before the server was stopped"
+    );
+
+    // Stop the server and wait for the notification
+    server.stop();
+    assert!(
+        notifier.recv_timeout(Duration::from_secs(2)).is_ok(),
+        "Shutdown notifier did not fire"
+    );
+    assert_eq!(
+        notifier.try_recv(),
+        Err(TryRecvError::Disconnected),
+        "Shutdown notifier fired more than once"
+    );
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+}
+
+#[test]
+fn test_client_binary_echo_message() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = create_server(port);
+    let handle = setup_server_thread(server.clone());
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Prepare a payload that is not valid UTF-8
+    let payload = vec![0xff, 0xfe, 0x00, 0xc3, 0x28, 0x80];
+    assert!(String::from_utf8(payload.clone()).is_err());
+    let message = client_message::Message::BinaryEchoMessage(BinaryEchoMessage {
+        payload: payload.clone(),
+    });
+
+    // Send the message to the server
+    assert!(client.send(message).is_ok(), "Failed to send message");
+
+    // Receive the echoed bytes
+    let response = client.receive();
+    assert!(
+        response.is_ok(),
+        "Failed to receive response for BinaryEchoMessage"
+    );
+
+    match response.unwrap().message {
+        Some(server_message::Message::BinaryEchoMessage(echo)) => {
+            assert_eq!(echo.payload, payload, "Echoed bytes do not match");
+        }
+        _ => panic!("Expected BinaryEchoMessage, but received a different message"),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+// Starts a one-worker pool with room for one queued connection, then fills both: the first client is
+// being served and the second waits in the queue, so the next connection hits the saturation policy
+fn setup_saturated_server(
+    port: u32,
+    policy: SaturationPolicy,
+) -> (Arc<Server>, JoinHandle<()>, client::Client, client::Client) {
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .workers(1)
+            .queue_capacity(1)
+            .saturation_policy(policy)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // The first client occupies the only worker; a round trip proves it is being served
+    let mut busy = client::Client::new("localhost", port, 1000);
+    assert!(busy.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut busy, "busy");
+
+    // The second client fills the queue; give the accept loop time to pick it up
+    let mut queued = client::Client::new("localhost", port, 1000);
+    assert!(queued.connect().is_ok(), "Failed to connect to the server");
+    thread::sleep(Duration::from_millis(300));
+
+    (server, handle, busy, queued)
+}
+
+// Sends an echo request and checks the reply
+fn assert_echo(client: &mut client::Client, content: &str) {
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: content.to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response for EchoMessage").message {
+        Some(server_message::Message::EchoMessage(echo)) => {
+            assert_eq!(echo.content, content, "Echoed message content does not match");
+        }
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+}
+
+#[test]
+fn test_saturation_policy_block() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+    let (server, handle, mut busy, mut queued) = setup_saturated_server(port, SaturationPolicy::Block);
+
+    // The extra client connects, but is not served while the pool is saturated
+    let mut blocked = client::Client::new("localhost", port, 1000);
+    assert!(blocked.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "blocked".to_string(),
+    });
+    assert!(blocked.send(message).is_ok(), "Failed to send message");
+    assert!(blocked.set_read_timeout(Some(Duration::from_millis(300))).is_ok());
+    assert!(
+        blocked.receive().is_err(),
+        "Blocked client was served while the pool was saturated"
+    );
+
+    // Freeing the worker lets the queued clients through, in order
+    assert!(busy.disconnect().is_ok(), "Failed to disconnect from the server");
+    assert_echo(&mut queued, "queued");
+    assert!(queued.disconnect().is_ok(), "Failed to disconnect from the server");
+    assert!(blocked.set_read_timeout(Some(Duration::from_secs(2))).is_ok());
+    match blocked.receive().expect("Blocked client was never served").message {
+        Some(server_message::Message::EchoMessage(echo)) => {
+            assert_eq!(echo.content, "blocked", "Echoed message content does not match");
+        }
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+    assert!(blocked.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_saturation_policy_reject() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+    let (server, handle, mut busy, mut queued) = setup_saturated_server(port, SaturationPolicy::Reject);
+
+    // The extra client gets an error response, then the connection closes
+    let mut rejected = client::Client::new("localhost", port, 1000);
+    assert!(rejected.connect().is_ok(), "Failed to connect to the server");
+    let (code, message) = rejected.receive_error().expect("Failed to receive the rejection");
+    assert_eq!(code, ErrorCode::AtCapacity, "Unexpected error code");
+    assert_eq!(message, "server at capacity");
+    assert!(rejected.receive().is_err(), "Rejected connection was left open");
+
+    // The clients already admitted are unaffected
+    assert_echo(&mut busy, "still served");
+    assert!(busy.disconnect().is_ok(), "Failed to disconnect from the server");
+    assert_echo(&mut queued, "queued");
+    assert!(queued.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_saturation_policy_close() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+    let (server, handle, mut busy, mut queued) = setup_saturated_server(port, SaturationPolicy::Close);
+
+    // The extra client is disconnected without any reply
+    let mut shed = client::Client::new("localhost", port, 1000);
+    assert!(shed.connect().is_ok(), "Failed to connect to the server");
+    assert!(shed.set_read_timeout(Some(Duration::from_secs(2))).is_ok());
+    let error = shed.receive().expect_err("Shed connection received a reply");
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionAborted, "Shed connection was not closed");
+
+    // The clients already admitted are unaffected
+    assert_echo(&mut busy, "still served");
+    assert!(busy.disconnect().is_ok(), "Failed to disconnect from the server");
+    assert_echo(&mut queued, "queued");
+    assert!(queued.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[cfg(feature = "latency")]
+#[test]
+fn test_latency_stats() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = create_server(port);
+    let handle = setup_server_thread(server.clone());
+    assert_eq!(server.latency_stats().count, 0, "Latency recorded before any request");
+
+    // Create and connect the client, then issue a few requests
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    for i in 0..5 {
+        assert_echo(&mut client, &format!("request {}", i));
+    }
+
+    // Every request is recorded once its response is written, which can land just after the client reads it
+    thread::sleep(Duration::from_millis(100));
+    let stats = server.latency_stats();
+    assert_eq!(stats.count, 5, "Not every request was recorded");
+    // The percentiles are ordered and plausible
+    assert!(stats.p50 <= stats.p99 && stats.p99 <= stats.max, "Percentiles out of order: {:?}", stats);
+    assert!(stats.max > Duration::ZERO && stats.max < Duration::from_secs(1), "Implausible latency: {:?}", stats);
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_pipelining_client_that_never_reads_is_dropped() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up a server that gives up quickly on stalled writes
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .write_timeout(Some(Duration::from_millis(500)))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Pipeline large echo requests without ever reading a response, until the server hangs up
+    let mut stream = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+    stream
+        .set_write_timeout(Some(Duration::from_secs(10)))
+        .expect("Failed to set write timeout");
+    let frame = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::BinaryEchoMessage(BinaryEchoMessage {
+            payload: vec![0xab; 32 * 1024],
+        })),
+    });
+    let writer = thread::spawn(move || {
+        for _ in 0..(256 * 1024 * 1024 / frame.len()) {
+            stream.write_all(&frame)?;
+        }
+        Ok::<(), std::io::Error>(())
+    });
+    let result = writer.join().expect("Writer thread panicked");
+    assert!(result.is_err(), "Server kept serving a client that never reads");
+
+    // The worker recovered, so the server still serves other clients and stops cleanly
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut client, "still alive");
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_greeting_arrives_before_any_request() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up a server that greets every client
+    let greeting = Greeting {
+        banner: "Welcome!".to_string(),
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+    };
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .greeting(ServerMessage {
+                message: Some(server_message::Message::Greeting(greeting.clone())),
+                load: None,
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // The greeting is the first thing received, without sending anything
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert!(client.set_read_timeout(Some(Duration::from_secs(2))).is_ok());
+    match client.receive().expect("No greeting received").message {
+        Some(server_message::Message::Greeting(received)) => {
+            assert_eq!(received, greeting, "Greeting does not match");
+        }
+        _ => panic!("Expected Greeting, but received a different message"),
+    }
+
+    // Requests are served normally afterwards
+    assert_echo(&mut client, "after greeting");
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_recent_disconnects_records_reasons() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up a server that only remembers the last two disconnects
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .disconnect_history(2)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let address = format!("localhost:{}", port);
+
+    // A client that simply hangs up
+    let closed = TcpStream::connect(&address).expect("Failed to connect to the server");
+    let closed_peer = closed.local_addr().unwrap();
+    drop(closed);
+    thread::sleep(Duration::from_millis(300));
+    let recent = server.recent_disconnects();
+    assert_eq!(recent.len(), 1, "Expected one disconnect record");
+    assert_eq!(recent[0].peer, closed_peer);
+    assert_eq!(recent[0].reason, DisconnectReason::ClientClosed);
+
+    // A client that declares a frame far over the size limit
+    let mut invalid = TcpStream::connect(&address).expect("Failed to connect to the server");
+    let invalid_peer = invalid.local_addr().unwrap();
+    invalid
+        .write_all(&(u32::MAX).to_be_bytes())
+        .expect("Failed to send frame header");
+    thread::sleep(Duration::from_millis(300));
+
+    // A client still connected when the server stops
+    let connected = TcpStream::connect(&address).expect("Failed to connect to the server");
+    let connected_peer = connected.local_addr().unwrap();
+    thread::sleep(Duration::from_millis(300));
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+
+    // Only the two most recent disconnects are kept, oldest first
+    let recent = server.recent_disconnects();
+    assert_eq!(recent.len(), 2, "Disconnect history is not bounded");
+    assert_eq!(recent[0].peer, invalid_peer);
+    assert_eq!(recent[0].reason, DisconnectReason::InvalidFrame);
+    assert_eq!(recent[1].peer, connected_peer);
+    assert_eq!(recent[1].reason, DisconnectReason::Shutdown);
+    assert!(recent[0].at <= recent[1].at, "Records are out of order");
+}
+
+#[test]
+fn test_pipelined_batch_larger_than_frame_cap() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up a server that handles only a few frames per loop iteration
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .max_frames_per_iteration(3)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Send a whole batch of requests in a single write
+    let mut batch = Vec::new();
+    for i in 0..50 {
+        batch.extend(codec::encode_frame(&ClientMessage {
+            message: Some(client_message::Message::AddRequest(AddRequest { a: i, b: 1 })),
+        }));
+    }
+    let mut stream = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+    stream.write_all(&batch).expect("Failed to send batch");
+
+    // Every request is still answered, in order, without waiting for more input
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .expect("Failed to set read timeout");
+    for i in 0..50 {
+        let frame = codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing response");
+        match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+            Some(server_message::Message::AddResponse(add_response)) => {
+                assert_eq!(add_response.result, i + 1, "Responses are out of order");
+            }
+            _ => panic!("Expected AddResponse, but received a different message"),
+        }
+    }
+    drop(stream);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_version_request() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = create_server(port);
+    let handle = setup_server_thread(server.clone());
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Ask for the version
+    let message = client_message::Message::VersionRequest(VersionRequest {});
+    assert!(client.send(message).is_ok(), "Failed to send message");
+
+    let response = client.receive();
+    assert!(
+        response.is_ok(),
+        "Failed to receive response for VersionRequest"
+    );
+
+    match response.unwrap().message {
+        Some(server_message::Message::VersionResponse(version)) => {
+            assert_eq!(version.server_version, env!("CARGO_PKG_VERSION"), "Server version does not match the package");
+            assert_eq!(version.protocol_version, codec::PROTOCOL_VERSION, "Protocol version does not match");
+        }
+        _ => panic!("Expected VersionResponse, but received a different message"),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+// Behaves like the built-in handler, but ends the connection after echoing "goodbye"
+struct GoodbyeHandler;
+
+impl MessageHandler for GoodbyeHandler {
+    fn handle(&self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)> {
+        let goodbye = matches!(&message, client_message::Message::EchoMessage(echo) if echo.content == "goodbye");
+        let (response, _) = DefaultHandler.handle(message)?;
+        Some((response, if goodbye { CloseAfter::Yes } else { CloseAfter::No }))
+    }
+}
+
+#[test]
+fn test_handler_closes_connection_after_response() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up a server with the custom handler
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .handler(GoodbyeHandler)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Ordinary requests keep the connection open
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut client, "hello");
+    assert_echo(&mut client, "still here");
+
+    // The flagged response still arrives, then the server hangs up
+    assert_echo(&mut client, "goodbye");
+    assert!(client.set_read_timeout(Some(Duration::from_secs(2))).is_ok());
+    let error = client.receive().expect_err("Connection stayed open after the goodbye");
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionAborted, "Connection was not closed");
+    let recent = server.recent_disconnects();
+    assert_eq!(recent.last().map(|record| record.reason), Some(DisconnectReason::ClosedByHandler));
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_interleaved_streams_on_one_connection() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = create_server(port);
+    let handle = setup_server_thread(server.clone());
+
+    // Interleave requests from two logical streams, plus the default stream, in a single write
+    let mut stream = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+    let requests = [(1, 10), (2, 20), (1, 11), (0, 30), (2, 21)];
+    let mut batch = Vec::new();
+    for (stream_id, a) in requests {
+        batch.extend(codec::encode_stream_frame(
+            stream_id,
+            &ClientMessage {
+                message: Some(client_message::Message::AddRequest(AddRequest { a, b: 0 })),
+            },
+        ));
+    }
+    stream.write_all(&batch).expect("Failed to send batch");
+
+    // Each response is tagged with the stream of the request it answers
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .expect("Failed to set read timeout");
+    for (stream_id, a) in requests {
+        let frame = codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing response");
+        assert_eq!(frame.stream_id, stream_id, "Response tagged with the wrong stream");
+        match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+            Some(server_message::Message::AddResponse(add_response)) => {
+                assert_eq!(add_response.result, a, "Response does not match its request");
+            }
+            _ => panic!("Expected AddResponse, but received a different message"),
+        }
+    }
+    drop(stream);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+// Frames a payload that is not a valid protobuf message
+fn garbage_frame() -> Vec<u8> {
+    let payload = [0xff, 0xff, 0xff];
+    let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+#[test]
+fn test_too_many_decode_errors_closes_connection() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up a server that tolerates only three bad frames in a row
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .max_decode_errors(3)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut stream = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .expect("Failed to set read timeout");
+
+    // A valid request in between resets the count, so the connection survives four bad frames in total
+    let echo = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "valid".to_string(),
+        })),
+    });
+    for frame in [garbage_frame(), garbage_frame(), echo, garbage_frame(), garbage_frame()] {
+        stream.write_all(&frame).expect("Failed to send frame");
+    }
+    let frame = codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing echo response");
+    match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "valid"),
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+
+    // The third consecutive bad frame gets a final error, then the connection closes
+    stream.write_all(&garbage_frame()).expect("Failed to send frame");
+    let frame = codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing final error");
+    match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.error_code(), Some(ErrorCode::DecodeFailed), "Unexpected error code");
+            assert_eq!(error.message, "too many decode errors");
+        }
+        _ => panic!("Expected ErrorResponse, but received a different message"),
+    }
+    let error = codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect_err("Connection stayed open");
+    assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof, "Connection was not closed");
+    let recent = server.recent_disconnects();
+    assert_eq!(recent.last().map(|record| record.reason), Some(DisconnectReason::TooManyDecodeErrors));
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+// Sends a health probe and returns the reported client count
+fn health_client_count(client: &mut client::Client) -> u32 {
+    let message = client_message::Message::HealthRequest(HealthRequest {});
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response for HealthRequest").message {
+        Some(server_message::Message::HealthResponse(health)) => {
+            assert!(health.ok, "Server reported unhealthy");
+            health.client_count
+        }
+        _ => panic!("Expected HealthResponse, but received a different message"),
+    }
+}
+
+#[test]
+fn test_health_request_reports_client_count() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = create_server(port);
+    let handle = setup_server_thread(server.clone());
+
+    // Connect the probe first; connections are accepted in order, so a served echo on the second means both are counted
+    let mut probe = client::Client::new("localhost", port, 1000);
+    assert!(probe.connect().is_ok(), "Failed to connect to the server");
+    let mut other = client::Client::new("localhost", port, 1000);
+    assert!(other.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut other, "counted");
+
+    assert_eq!(health_client_count(&mut probe), 2, "Both connections should be counted");
+
+    // Once the other client leaves, only the probe remains
+    assert!(
+        other.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+    thread::sleep(Duration::from_millis(300)); // Give the worker time to see the disconnect
+    assert_eq!(health_client_count(&mut probe), 1, "Closed connection is still counted");
+
+    // Disconnect the client
+    assert!(
+        probe.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_zero_linger_resets_connection_on_close() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up a server whose connections reset instead of closing cleanly
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .linger(Duration::ZERO)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Create and connect the client; requests are served as usual
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut client, "before shutdown");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+
+    // The worker closed the stream on shutdown, which resets it instead of sending a FIN
+    let error = client.receive().expect_err("Connection stayed open after shutdown");
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionReset, "Connection was not reset");
+}
+
+#[test]
+fn test_pause_and_resume_accepting() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = create_server(port);
+    let handle = setup_server_thread(server.clone());
+
+    // A client connected before the pause keeps being served
+    let mut existing = client::Client::new("localhost", port, 1000);
+    assert!(existing.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut existing, "before pause");
+
+    // While paused, new connections are closed without being served
+    server.pause();
+    let mut refused = client::Client::new("localhost", port, 1000);
+    assert!(refused.connect().is_ok(), "Failed to connect to the server");
+    assert!(refused.set_read_timeout(Some(Duration::from_secs(2))).is_ok());
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "while paused".to_string(),
+    });
+    let _ = refused.send(message); // May already fail if the close arrived first
+    let error = refused.receive().expect_err("Paused server served a new connection");
+    assert!(
+        matches!(error.kind(), std::io::ErrorKind::ConnectionAborted | std::io::ErrorKind::ConnectionReset),
+        "Connection was not closed: {:?}",
+        error
+    );
+    assert_echo(&mut existing, "during pause");
+
+    // After resuming, new connections are served again
+    server.resume();
+    let mut accepted = client::Client::new("localhost", port, 1000);
+    assert!(accepted.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut accepted, "after resume");
+
+    // Disconnect the clients
+    for client in [&mut existing, &mut accepted] {
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_on_disconnect_reports_connection_stats() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up a server that forwards every connection's statistics to the test
+    let (sender, receiver) = mpsc::channel::<ConnectionStats>();
+    let sender = std::sync::Mutex::new(sender); // The callback must be `Sync`
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .on_disconnect(move |stats| {
+                let _ = sender.lock().unwrap().send(stats.clone());
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Create and connect the client
+    let started = std::time::Instant::now();
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Send a known sequence and add up the frame sizes on both sides
+    let mut bytes_in = 0;
+    let mut bytes_out = 0;
+    let requests = [
+        client_message::Message::EchoMessage(EchoMessage {
+            content: "first".to_string(),
+        }),
+        client_message::Message::AddRequest(AddRequest { a: 2, b: 40 }),
+        client_message::Message::EchoMessage(EchoMessage {
+            content: "second, a little longer".to_string(),
+        }),
+    ];
+    for message in requests {
+        bytes_in += codec::encode_frame(&ClientMessage {
+            message: Some(message.clone()),
+        })
+        .len() as u64;
+        assert!(client.send(message).is_ok(), "Failed to send message");
+        let response = client.receive().expect("Failed to receive response");
+        bytes_out += codec::encode_frame(&response).len() as u64;
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    let stats = receiver
+        .recv_timeout(Duration::from_secs(2))
+        .expect("on_disconnect was not called");
+    assert!(stats.peer.ip().is_loopback(), "Unexpected peer address");
+    assert_eq!(stats.bytes_in, bytes_in, "Bytes read do not match");
+    assert_eq!(stats.bytes_out, bytes_out, "Bytes written do not match");
+    assert_eq!(stats.messages_handled, 3, "Handled message count does not match");
+    assert_eq!(stats.reason, DisconnectReason::ClientClosed);
+    assert!(stats.duration <= started.elapsed(), "Duration exceeds the connection's lifetime");
+    assert!(receiver.try_recv().is_err(), "on_disconnect was called more than once");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_server_from_env() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Every case lives in this one test because the environment is shared by all test threads
+    const VARIABLES: [&str; 6] = [
+        "SERVER_ADDR",
+        "SERVER_WORKERS",
+        "SERVER_QUEUE_CAPACITY",
+        "SERVER_MAX_FRAME_SIZE",
+        "SERVER_WRITE_TIMEOUT_MS",
+        "SERVER_MAX_DECODE_ERRORS",
+    ];
+    for variable in VARIABLES {
+        std::env::remove_var(variable);
+    }
+
+    // Unset variables fall back to the defaults, including the default address
+    match Server::from_env() {
+        Ok(_) => {}
+        Err(ServerError::Io(e)) if e.kind() == std::io::ErrorKind::AddrInUse => {} // Something else owns the default port
+        Err(e) => panic!("Defaults were rejected: {}", e),
+    }
+
+    // Malformed and out-of-range values are configuration errors
+    for (variable, value) in [("SERVER_WORKERS", "two"), ("SERVER_WORKERS", "0"), ("SERVER_WRITE_TIMEOUT_MS", "-1")] {
+        std::env::set_var(variable, value);
+        assert!(
+            matches!(Server::from_env(), Err(ServerError::Config(_))),
+            "{}={} was accepted",
+            variable,
+            value
+        );
+        std::env::remove_var(variable);
+    }
+
+    // A fully configured server serves clients
+    let port = get_unique_port();
+    std::env::set_var("SERVER_ADDR", format!("localhost:{}", port));
+    std::env::set_var("SERVER_WORKERS", "2");
+    std::env::set_var("SERVER_QUEUE_CAPACITY", "4");
+    std::env::set_var("SERVER_WRITE_TIMEOUT_MS", "0");
+    let server = Server::from_env();
+    for variable in VARIABLES {
+        std::env::remove_var(variable);
+    }
+    let server = Arc::new(server.expect("Failed to start server"));
+    let handle = setup_server_thread(server.clone());
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut client, "configured from the environment");
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+// Echoes text back in upper case, to tell it apart from the default handler
+struct ShoutHandler;
+
+impl MessageHandler for ShoutHandler {
+    fn handle(&self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)> {
+        let message = match message {
+            client_message::Message::EchoMessage(echo) => client_message::Message::EchoMessage(EchoMessage {
+                content: echo.content.to_uppercase(),
+            }),
+            other => other,
+        };
+        DefaultHandler.handle(message)
+    }
+}
+
+#[test]
+fn test_set_handler_mid_traffic() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = create_server(port);
+    let handle = setup_server_thread(server.clone());
+
+    // Keep a client sending requests while the handler is swapped; once it sees the new handler it never sees the old one
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut client, "quiet");
+    let traffic = thread::spawn(move || {
+        let mut swapped = false;
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while std::time::Instant::now() < deadline {
+            let message = client_message::Message::EchoMessage(EchoMessage {
+                content: "quiet".to_string(),
+            });
+            assert!(client.send(message).is_ok(), "Failed to send message");
+            match client.receive().expect("Failed to receive response").message {
+                Some(server_message::Message::EchoMessage(echo)) if echo.content == "QUIET" => swapped = true,
+                Some(server_message::Message::EchoMessage(echo)) if echo.content == "quiet" => {
+                    assert!(!swapped, "Old handler answered after the new one")
+                }
+                other => panic!("Unexpected response: {:?}", other),
+            }
+            if swapped {
+                break;
+            }
+        }
+        swapped
+    });
+    thread::sleep(Duration::from_millis(20));
+    server.set_handler(ShoutHandler);
+    assert!(traffic.join().expect("Traffic thread panicked"), "New handler never answered");
+
+    // New connections get the new handler too
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "new".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "NEW"),
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_run_until_signal_with_drain() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Both cases live in this one test because the signal reaches the whole test process
+    for (drain_timeout, client_leaves) in [(Duration::from_secs(5), true), (Duration::from_millis(300), false)] {
+        let port = get_unique_port();
+
+        // Set up the server in a separate thread
+        let server = create_server(port);
+        let runner = {
+            let server = Arc::clone(&server);
+            thread::spawn(move || server.run_until_signal_with_drain(drain_timeout))
+        };
+
+        // A served request proves `run` is up, and with it the signal handler
+        let mut client = client::Client::new("localhost", port, 1000);
+        assert!(client.connect().is_ok(), "Failed to connect to the server");
+        assert_echo(&mut client, "before signal");
+
+        signal_hook::low_level::raise(signal_hook::consts::SIGTERM).expect("Failed to raise SIGTERM");
+        thread::sleep(Duration::from_millis(200)); // Give the server a poll interval to start draining
+
+        // While draining, new connections are closed but the existing one is still served
+        let mut late = client::Client::new("localhost", port, 1000);
+        assert!(late.connect().is_ok(), "Failed to connect to the server");
+        assert!(late.set_read_timeout(Some(Duration::from_secs(2))).is_ok());
+        assert!(late.receive().is_err(), "Draining server served a new connection");
+        assert_echo(&mut client, "during drain");
+
+        if client_leaves {
+            assert!(
+                client.disconnect().is_ok(),
+                "Failed to disconnect from the server"
+            );
+        }
+        let drained = runner
+            .join()
+            .expect("Server thread panicked")
+            .expect("Server encountered an error");
+        assert_eq!(drained, client_leaves, "Drain result does not match");
+        let expected = if client_leaves { ShutdownReason::DrainComplete } else { ShutdownReason::Signal };
+        assert_eq!(server.shutdown_reason(), Some(expected), "Wrong shutdown reason");
+        if !client_leaves {
+            // The client that outstayed the timeout was closed by the shutdown
+            assert!(client.receive().is_err(), "Connection stayed open after shutdown");
+        }
+    }
+}
+
+// Takes a millisecond per request, so a pipelining client easily outpaces it
+struct SlowHandler;
+
+impl MessageHandler for SlowHandler {
+    fn handle(&self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)> {
+        thread::sleep(Duration::from_millis(1));
+        DefaultHandler.handle(message)
+    }
+}
+
+#[test]
+fn test_buffered_bytes_stay_bounded_for_fast_sender() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up a slow server with a backlog cap smaller than a single socket read
+    let (sender, receiver) = mpsc::channel::<ConnectionStats>();
+    let sender = std::sync::Mutex::new(sender); // The callback must be `Sync`
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .handler(SlowHandler)
+            .max_frame_size(256)
+            .max_buffered_bytes(300)
+            .on_disconnect(move |stats| {
+                let _ = sender.lock().unwrap().send(stats.clone());
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Send every request at once from another thread while reading the responses here
+    const REQUESTS: usize = 500;
+    let mut stream = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .expect("Failed to set read timeout");
+    let mut writer = stream.try_clone().expect("Failed to clone stream");
+    let sending = thread::spawn(move || {
+        let mut batch = Vec::new();
+        for index in 0..REQUESTS {
+            batch.extend(codec::encode_frame(&ClientMessage {
+                message: Some(client_message::Message::EchoMessage(EchoMessage {
+                    content: format!("request {}", index),
+                })),
+            }));
+        }
+        writer.write_all(&batch).expect("Failed to send requests");
+    });
+    for index in 0..REQUESTS {
+        let frame = codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing response");
+        match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+            Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, format!("request {}", index)),
+            _ => panic!("Expected EchoMessage, but received a different message"),
+        }
+    }
+    sending.join().expect("Sender thread panicked");
+    drop(stream); // Close the connection so the statistics are reported
+
+    let stats = receiver
+        .recv_timeout(Duration::from_secs(2))
+        .expect("on_disconnect was not called");
+    assert_eq!(stats.messages_handled, REQUESTS as u64, "Handled message count does not match");
+    assert!(stats.peak_buffered_bytes <= 300, "Backlog grew to {} bytes", stats.peak_buffered_bytes);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_fire_and_forget_then_request() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = create_server(port);
+    let handle = setup_server_thread(server.clone());
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // The event gets no response, and the client helper doesn't wait for one
+    let event = client_message::Message::LogEvent(LogEvent {
+        message: "client started".to_string(),
+    });
+    let response = client.request(event).expect("Failed to send LogEvent");
+    assert!(response.is_none(), "One-way message got a response");
+
+    // The next response on the connection belongs to the next request
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "after the event".to_string(),
+    });
+    match client.request(message).expect("Failed to receive response for EchoMessage") {
+        Some(ServerMessage {
+            message: Some(server_message::Message::EchoMessage(echo)),
+            ..
+        }) => assert_eq!(echo.content, "after the event"),
+        other => panic!("Expected EchoMessage, but received {:?}", other),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_connection_ids_unique_and_increasing() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = create_server(port);
+    let handle = setup_server_thread(server.clone());
+
+    // Serve clients one after another, waiting for each disconnect to be recorded
+    for index in 0..5 {
+        let mut client = client::Client::new("localhost", port, 1000);
+        assert!(client.connect().is_ok(), "Failed to connect to the server");
+        assert_echo(&mut client, &format!("client {}", index));
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+        let deadline = std::time::Instant::now() + Duration::from_secs(2);
+        while server.recent_disconnects().len() <= index && std::time::Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Connections were served in order, so their ids must be strictly increasing
+    let ids: Vec<u64> = server.recent_disconnects().iter().map(|record| record.connection_id).collect();
+    assert_eq!(ids.len(), 5, "Missing disconnect records");
+    assert!(ids.windows(2).all(|pair| pair[0] < pair[1]), "Connection ids are not increasing: {:?}", ids);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_pending_accepts() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // The listener is bound but nothing accepts yet, so the kernel queues completed connections
+    let server = create_server(port);
+    let waiting: Vec<TcpStream> = (0..3)
+        .map(|_| TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server"))
+        .collect();
+    if cfg!(target_os = "linux") {
+        assert_eq!(server.pending_accepts(), Some(3), "Queued connections were not counted");
+    } else {
+        assert_eq!(server.pending_accepts(), None, "Platform without support reported a depth");
+    }
+
+    // Once the server runs, the queue empties
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut client, "after the queue");
+    if cfg!(target_os = "linux") {
+        assert_eq!(server.pending_accepts(), Some(0), "Accepted connections are still counted");
+    }
+    drop(waiting);
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+// Takes half a second for echoes of "slow", answers everything else at once
+struct SometimesSlowHandler;
+
+impl MessageHandler for SometimesSlowHandler {
+    fn handle(&self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)> {
+        if matches!(&message, client_message::Message::EchoMessage(echo) if echo.content == "slow") {
+            thread::sleep(Duration::from_millis(500));
+        }
+        DefaultHandler.handle(message)
+    }
+}
+
+#[test]
+fn test_handler_threads_keep_other_connections_responsive() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up a server that runs handlers on a separate executor
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .handler(SometimesSlowHandler)
+            .handler_threads(2)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // One client pipelines a slow request and a fast one
+    let mut busy = client::Client::new("localhost", port, 1000);
+    assert!(busy.connect().is_ok(), "Failed to connect to the server");
+    for content in ["slow", "after slow"] {
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: content.to_string(),
+        });
+        assert!(busy.send(message).is_ok(), "Failed to send message");
+    }
+    thread::sleep(Duration::from_millis(50)); // Let the slow request reach the executor
+
+    // Another client is served while the slow handler runs
+    let mut other = client::Client::new("localhost", port, 1000);
+    assert!(other.connect().is_ok(), "Failed to connect to the server");
+    let started = std::time::Instant::now();
+    assert_echo(&mut other, "fast");
+    assert!(started.elapsed() < Duration::from_millis(300), "Slow handler held up another connection");
+
+    // The busy client's responses arrive in request order
+    for expected in ["slow", "after slow"] {
+        match busy.receive().expect("Failed to receive response").message {
+            Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, expected, "Responses out of order"),
+            _ => panic!("Expected EchoMessage, but received a different message"),
+        }
+    }
+
+    // Disconnect the clients
+    for client in [&mut busy, &mut other] {
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_disconnect_mid_frame_is_reported() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = create_server(port);
+    let handle = setup_server_thread(server.clone());
+
+    // A clean close after a whole request, then a close partway through the second frame's payload
+    for partial in [false, true] {
+        let mut stream = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+        let mut frame = codec::encode_frame(&ClientMessage {
+            message: Some(client_message::Message::EchoMessage(EchoMessage {
+                content: "cut short".to_string(),
+            })),
+        });
+        if partial {
+            frame.truncate(frame.len() - 3);
+        }
+        stream.write_all(&frame).expect("Failed to send frame");
+        if !partial {
+            stream
+                .set_read_timeout(Some(Duration::from_secs(2)))
+                .expect("Failed to set read timeout");
+            codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing echo response");
+        }
+        drop(stream);
+    }
+
+    // Both disconnects are recorded, each with its own reason
+    let deadline = std::time::Instant::now() + Duration::from_secs(2);
+    while server.recent_disconnects().len() < 2 && std::time::Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let reasons: Vec<DisconnectReason> = server.recent_disconnects().iter().map(|record| record.reason).collect();
+    assert_eq!(reasons.len(), 2, "Missing disconnect records");
+    assert!(reasons.contains(&DisconnectReason::ClientClosed), "Clean close was not recorded: {:?}", reasons);
+    assert!(reasons.contains(&DisconnectReason::ClosedMidFrame), "Partial frame was not recorded: {:?}", reasons);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+// Adds take a while; echoes of "ping" are control messages that skip the queue
+struct PingFirstHandler;
+
+impl MessageHandler for PingFirstHandler {
+    fn handle(&self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)> {
+        if matches!(message, client_message::Message::AddRequest(_)) {
+            thread::sleep(Duration::from_millis(25));
+        }
+        DefaultHandler.handle(message)
+    }
+
+    fn priority(&self, message: &client_message::Message) -> Priority {
+        match message {
+            client_message::Message::EchoMessage(echo) if echo.content == "ping" => Priority::High,
+            _ => Priority::Normal,
+        }
+    }
+}
+
+#[test]
+fn test_high_priority_request_skips_queued_work() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up a server with a single handler thread, so requests from different clients queue up
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .handler(PingFirstHandler)
+            .handler_threads(1)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Queue about half a second of adds from many clients
+    let mut adders: Vec<client::Client> = (0..20)
+        .map(|_| {
+            let mut client = client::Client::new("localhost", port, 1000);
+            assert!(client.connect().is_ok(), "Failed to connect to the server");
+            client
+        })
+        .collect();
+    for adder in adders.iter_mut() {
+        let message = client_message::Message::AddRequest(AddRequest { a: 1, b: 2 });
+        assert!(adder.send(message).is_ok(), "Failed to send message");
+    }
+    thread::sleep(Duration::from_millis(50)); // Let the adds reach the queue
+
+    // The ping only waits for the add already running
+    let mut pinger = client::Client::new("localhost", port, 1000);
+    assert!(pinger.connect().is_ok(), "Failed to connect to the server");
+    let started = std::time::Instant::now();
+    assert_echo(&mut pinger, "ping");
+    assert!(started.elapsed() < Duration::from_millis(200), "Ping waited behind the queued adds: {:?}", started.elapsed());
+
+    // Every add is still answered
+    for adder in adders.iter_mut() {
+        assert!(adder.set_read_timeout(Some(Duration::from_secs(5))).is_ok());
+        match adder.receive().expect("Failed to receive response").message {
+            Some(server_message::Message::AddResponse(add)) => assert_eq!(add.result, 3),
+            _ => panic!("Expected AddResponse, but received a different message"),
+        }
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_connection_byte_quota() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up a server that allows each connection 100 bytes
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .max_bytes_per_connection(100)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Create and connect the client; two requests of about 40 bytes fit in the quota
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let content = "x".repeat(30);
+    assert_echo(&mut client, &content);
+    assert_echo(&mut client, &content);
+
+    // The third one crosses it
+    let message = client_message::Message::EchoMessage(EchoMessage { content });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    let (code, message) = client.receive_error().expect("Missing quota error");
+    assert_eq!(code, ErrorCode::QuotaExceeded, "Unexpected error code");
+    assert_eq!(message, "quota exceeded");
+    assert!(client.set_read_timeout(Some(Duration::from_secs(2))).is_ok());
+    let error = client.receive().expect_err("Connection stayed open past the quota");
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionAborted, "Connection was not closed");
+    let recent = server.recent_disconnects();
+    assert_eq!(recent.last().map(|record| record.reason), Some(DisconnectReason::QuotaExceeded));
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_payload_logging_redaction() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Each message type follows its own rule
+    let rules = PayloadLogging {
+        echo: Redaction::Truncate(5),
+        binary_echo: Redaction::Show,
+        log_event: Redaction::Hide,
+        ..PayloadLogging::default()
+    };
+    let echo = client_message::Message::EchoMessage(EchoMessage {
+        content: "hello, secret world".to_string(),
+    });
+    assert_eq!(rules.describe_request(&echo), r#"EchoMessage { content: "hello"... (14 more bytes) }"#);
+    let binary = client_message::Message::BinaryEchoMessage(BinaryEchoMessage {
+        payload: vec![0x00, 0xab, 0xff],
+    });
+    assert_eq!(rules.describe_request(&binary), "BinaryEchoMessage { payload: 00abff }");
+    let event = client_message::Message::LogEvent(LogEvent {
+        message: "password=hunter2".to_string(),
+    });
+    assert_eq!(rules.describe_request(&event), "LogEvent { message: <16 bytes redacted> }");
+    let add = client_message::Message::AddRequest(AddRequest { a: 1, b: 2 });
+    assert_eq!(rules.describe_request(&add), "AddRequest(AddRequest { a: 1, b: 2 })");
+    let response = ServerMessage {
+        message: Some(server_message::Message::EchoMessage(EchoMessage {
+            content: "short".to_string(),
+        })),
+        load: None,
+    };
+    assert_eq!(rules.describe_response(&response), r#"EchoMessage { content: "short" }"#);
+
+    // A server with payload logging on serves requests as usual
+    let port = get_unique_port();
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .log_payloads(rules)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut client, "logged, but only partly");
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_disconnect_one_client() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = create_server(port);
+    let handle = setup_server_thread(server.clone());
+
+    // Connect two clients; the raw stream is the one to kick, since its address is known
+    let mut staying = client::Client::new("localhost", port, 1000);
+    assert!(staying.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut staying, "staying");
+    let mut kicked = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+    kicked
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .expect("Failed to set read timeout");
+    let echo = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "leaving".to_string(),
+        })),
+    });
+    kicked.write_all(&echo).expect("Failed to send frame");
+    codec::read_frame(&mut kicked, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing echo response");
+
+    // Only the addressed connection is closed
+    assert_eq!(server.disconnect(kicked.local_addr().unwrap()), 1, "Connection was not found");
+    let error = codec::read_frame(&mut kicked, codec::DEFAULT_MAX_FRAME_SIZE).expect_err("Connection stayed open");
+    assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof, "Connection was not closed");
+    assert_echo(&mut staying, "still staying");
+    let deadline = std::time::Instant::now() + Duration::from_secs(2);
+    while server.recent_disconnects().is_empty() && std::time::Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let recent = server.recent_disconnects();
+    assert_eq!(recent.len(), 1, "Only one connection should have ended");
+    assert_eq!(recent[0].reason, DisconnectReason::Kicked);
+    assert!(!server.disconnect_connection(recent[0].connection_id), "Closed connection is still registered");
+
+    // Disconnect the client
+    assert!(
+        staying.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+// Connects `clients` clients at once, each sending `requests` mixed add and echo requests, and checks every response
+fn run_concurrent_clients(clients: u32, requests: i32) {
+    // Bind to an ephemeral port so this never collides with another test
+    let server = Arc::new(Server::new("localhost:0").expect("Failed to start server"));
+    let port = server.local_addr().expect("Server has no address").port() as u32;
+    let handle = setup_server_thread(server.clone());
+
+    let threads: Vec<JoinHandle<()>> = (0..clients)
+        .map(|index| {
+            thread::spawn(move || {
+                let mut client = client::Client::new("localhost", port, 5000);
+                assert!(client.connect().is_ok(), "Failed to connect to the server");
+                assert!(client.set_read_timeout(Some(Duration::from_secs(10))).is_ok());
+                for request in 0..requests {
+                    // Values unique to this client and request, so a response meant for anyone else can't match
+                    if request % 2 == 0 {
+                        let (a, b) = (index as i32 * 10_000, request);
+                        let message = client_message::Message::AddRequest(AddRequest { a, b });
+                        assert!(client.send(message).is_ok(), "Failed to send message");
+                        match client.receive().expect("Failed to receive response").message {
+                            Some(server_message::Message::AddResponse(add)) => {
+                                assert_eq!(add.result, a + b, "Client {} got another add result", index)
+                            }
+                            other => panic!("Client {} expected AddResponse, got {:?}", index, other),
+                        }
+                    } else {
+                        let content = format!("client {} request {}", index, request);
+                        let message = client_message::Message::EchoMessage(EchoMessage {
+                            content: content.clone(),
+                        });
+                        assert!(client.send(message).is_ok(), "Failed to send message");
+                        match client.receive().expect("Failed to receive response").message {
+                            Some(server_message::Message::EchoMessage(echo)) => {
+                                assert_eq!(echo.content, content, "Client {} got another echo", index)
+                            }
+                            other => panic!("Client {} expected EchoMessage, got {:?}", index, other),
+                        }
+                    }
+                }
+                assert!(
+                    client.disconnect().is_ok(),
+                    "Failed to disconnect from the server"
+                );
+            })
+        })
+        .collect();
+    for thread in threads {
+        assert!(thread.join().is_ok(), "A client thread failed");
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_concurrent_clients_round_trip() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    run_concurrent_clients(16, 50);
+}
+
+#[test]
+fn test_concurrent_clients_stress() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    run_concurrent_clients(200, 10);
+}
+
+#[test]
+fn test_error_code_round_trip() {
+    // Every code survives the trip through its wire value, and the wire values don't change
+    let wire: Vec<u32> = ErrorCode::ALL.iter().map(|code| code.code()).collect();
+    assert_eq!(wire, [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12], "Wire values changed");
+    for code in ErrorCode::ALL {
+        assert_eq!(ErrorCode::from_code(code.code()), Some(code));
+        assert_eq!(ErrorCode::try_from(u32::from(code)), Ok(code));
+    }
+
+    // Unknown values are reported rather than guessed
+    assert_eq!(ErrorCode::from_code(0), None);
+    assert_eq!(ErrorCode::try_from(99), Err(99));
+    let response = ErrorResponse::new(ErrorCode::Timeout, "too slow");
+    assert_eq!(response.code, 6);
+    assert_eq!(response.error_code(), Some(ErrorCode::Timeout));
+}
+
+#[test]
+fn test_multiple_acceptors_high_connection_rate() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Zero acceptors can't accept anything
+    assert!(
+        Server::builder("localhost:0").acceptors(0).build().is_err(),
+        "Server without acceptors was built"
+    );
+
+    // Set up a server with four acceptors on an ephemeral port
+    let server = Arc::new(
+        Server::builder("localhost:0")
+            .acceptors(4)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let port = server.local_addr().expect("Server has no address").port() as u32;
+    let handle = setup_server_thread(server.clone());
+
+    // Open short connections from several threads as fast as they are served
+    const THREADS: usize = 8;
+    const CONNECTIONS: usize = 50;
+    let started = std::time::Instant::now();
+    let threads: Vec<JoinHandle<()>> = (0..THREADS)
+        .map(|_| {
+            thread::spawn(move || {
+                for index in 0..CONNECTIONS {
+                    let mut client = client::Client::new("localhost", port, 5000);
+                    assert!(client.connect().is_ok(), "Failed to connect to the server");
+                    assert_echo(&mut client, &format!("connection {}", index));
+                    assert!(
+                        client.disconnect().is_ok(),
+                        "Failed to disconnect from the server"
+                    );
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        assert!(thread.join().is_ok(), "A client thread failed");
+    }
+    let elapsed = started.elapsed();
+    assert!(elapsed < Duration::from_secs(20), "Accepting {} connections took {:?}", THREADS * CONNECTIONS, elapsed);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_middleware_rejects_unauthenticated_messages() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // The outer layer counts every request, the inner one only lets echoes carrying the token through, without it
+    let seen = Arc::new(AtomicU32::new(0));
+    let counted = Arc::clone(&seen);
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .middleware(move |message: client_message::Message, next: Next<'_>| {
+                counted.fetch_add(1, Ordering::Relaxed);
+                next.run(message)
+            })
+            .middleware(|message: client_message::Message, next: Next<'_>| match message {
+                client_message::Message::EchoMessage(echo) if echo.content.starts_with("token:") => {
+                    let content = echo.content["token:".len()..].to_string();
+                    next.run(client_message::Message::EchoMessage(EchoMessage { content }))
+                }
+                _ => Some((
+                    ServerMessage {
+                        message: Some(server_message::Message::ErrorResponse(ErrorResponse::new(
+                            ErrorCode::Unsupported,
+                            "unauthenticated",
+                        ))),
+                        load: None,
+                    },
+                    CloseAfter::No,
+                )),
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Create and connect the client
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // Without the token the handler never sees the request
+    let message = client_message::Message::AddRequest(AddRequest { a: 1, b: 2 });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    let (code, message) = client.receive_error().expect("Unauthenticated request was not rejected");
+    assert_eq!(code, ErrorCode::Unsupported);
+    assert_eq!(message, "unauthenticated");
+
+    // With it the request reaches the handler, as rewritten by the middleware
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "token:hello".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "hello"),
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+
+    // Server-level requests skip the chain
+    let message = client_message::Message::VersionRequest(VersionRequest {});
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    assert!(client.receive().is_ok(), "Failed to receive response for VersionRequest");
+    assert_eq!(seen.load(Ordering::Relaxed), 2, "Outer middleware saw the wrong requests");
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_set_nodelay_per_connection() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Start with Nagle enabled on every connection
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .nodelay(false)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut client, "bulk");
+
+    // The first connection of a fresh server has id 0; toggling it keeps the connection serving
+    assert!(server.set_nodelay(0, true), "Failed to turn TCP_NODELAY on");
+    assert_echo(&mut client, "interactive");
+    assert!(server.set_nodelay(0, false), "Failed to turn TCP_NODELAY off");
+    assert_echo(&mut client, "bulk again");
+    assert!(!server.set_nodelay(1, true), "No connection has id 1");
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_shutdown_reason_after_stop() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = create_server(port);
+    assert_eq!(server.shutdown_reason(), None, "Server that never ran has a shutdown reason");
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut client, "running");
+    assert_eq!(server.shutdown_reason(), None, "Running server has a shutdown reason");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+    assert_eq!(server.shutdown_reason(), Some(ShutdownReason::Stop));
+
+    // A second stop changes nothing
+    server.stop();
+    assert_eq!(server.shutdown_reason(), Some(ShutdownReason::Stop));
+}
+
+// Byte at `offset` of the test upload, so the handler can check every byte arrived in order
+fn upload_byte(offset: u64) -> u8 {
+    (offset % 251) as u8
+}
+
+// Reads the upload in small pieces, answering with its length only if every byte matches
+struct VerifyingUpload;
+
+impl MessageHandler for VerifyingUpload {
+    fn handle(&self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)> {
+        DefaultHandler.handle(message)
+    }
+
+    fn upload(&self, _start: UploadStart, body: &mut dyn Read) -> Option<(ServerMessage, CloseAfter)> {
+        let mut buffer = [0u8; 1000];
+        let mut offset = 0u64;
+        loop {
+            let read = body.read(&mut buffer).ok()?;
+            if read == 0 {
+                break;
+            }
+            if buffer[..read].iter().enumerate().any(|(index, &byte)| byte != upload_byte(offset + index as u64)) {
+                return DefaultHandler.handle(client_message::Message::EchoMessage(EchoMessage {
+                    content: format!("corrupt near {}", offset),
+                }));
+            }
+            offset += read as u64;
+        }
+        let response = ServerMessage {
+            message: Some(server_message::Message::UploadResponse(UploadResponse { bytes_received: offset })),
+            load: None,
+        };
+        Some((response, CloseAfter::No))
+    }
+}
+
+#[test]
+fn test_streamed_upload_keeps_memory_bounded() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread, reporting its backlog once the connection ends
+    let (sender, receiver) = mpsc::channel::<ConnectionStats>();
+    let sender = std::sync::Mutex::new(sender); // The callback must be `Sync`
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .handler(VerifyingUpload)
+            .on_disconnect(move |stats| {
+                let _ = sender.lock().unwrap().send(stats.clone());
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Stream 32 MiB, far more than any buffer the server holds, in 32 KiB chunks
+    const CHUNK: u64 = 32 * 1024;
+    const TOTAL: u64 = 32 * 1024 * 1024;
+    let mut stream = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .expect("Failed to set read timeout");
+    let start = ClientMessage {
+        message: Some(client_message::Message::UploadStart(UploadStart {
+            name: "large.bin".to_string(),
+            size: TOTAL,
+        })),
+    };
+    codec::write_frame(&mut stream, &start).expect("Failed to send upload start");
+    for offset in (0..TOTAL).step_by(CHUNK as usize) {
+        let chunk = ClientMessage {
+            message: Some(client_message::Message::UploadChunk(UploadChunk {
+                data: (offset..offset + CHUNK).map(upload_byte).collect(),
+                last: offset + CHUNK == TOTAL,
+            })),
+        };
+        codec::write_frame(&mut stream, &chunk).expect("Failed to send upload chunk");
+    }
+    let frame = codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing upload response");
+    match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+        Some(server_message::Message::UploadResponse(upload)) => assert_eq!(upload.bytes_received, TOTAL),
+        other => panic!("Expected UploadResponse, got {:?}", other),
+    }
+
+    // The connection is back to ordinary requests after the last chunk
+    let echo = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "after upload".to_string(),
+        })),
+    });
+    stream.write_all(&echo).expect("Failed to send request");
+    let frame = codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing echo response");
+    match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "after upload"),
+        other => panic!("Expected EchoMessage, got {:?}", other),
+    }
+    drop(stream); // Close the connection so the statistics are reported
+
+    let stats = receiver
+        .recv_timeout(Duration::from_secs(2))
+        .expect("on_disconnect was not called");
+    assert!(stats.bytes_in > TOTAL, "Upload bytes were not counted");
+    let cap = codec::DEFAULT_MAX_FRAME_SIZE + codec::MAX_HEADER_LEN;
+    assert!(stats.peak_buffered_bytes <= cap, "Backlog grew to {} bytes", stats.peak_buffered_bytes);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+// Keeps a 4 MiB buffer on the stack, more than the default 2 MiB thread stack holds
+struct LargeStackHandler;
+
+impl MessageHandler for LargeStackHandler {
+    fn handle(&self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)> {
+        let scratch = [1u8; 4 * 1024 * 1024];
+        let sum: u64 = std::hint::black_box(&scratch).iter().map(|&byte| byte as u64).sum();
+        assert_eq!(sum, scratch.len() as u64);
+        DefaultHandler.handle(message)
+    }
+}
+
+#[test]
+fn test_worker_stack_size() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    assert!(
+        Server::builder("localhost:0").worker_stack_size(0).build().is_err(),
+        "A zero stack size was accepted"
+    );
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread, with room for the handler's stack buffer
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .handler(LargeStackHandler)
+            .worker_stack_size(16 * 1024 * 1024)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut client, "deep stack");
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+// Decodes every response frame `process` produced
+fn process_responses(input: &[u8]) -> Vec<ServerMessage> {
+    let Some(output) = process(input) else {
+        return Vec::new();
+    };
+    let mut reader = output.as_slice();
+    let mut responses = Vec::new();
+    while !reader.is_empty() {
+        let frame = codec::read_frame(&mut reader, codec::DEFAULT_MAX_FRAME_SIZE).expect("Invalid response frame");
+        responses.push(ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response"));
+    }
+    responses
+}
+
+#[test]
+fn test_process_never_panics() {
+    // Well-formed requests are answered as a connection would answer them
+    let mut input = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "fuzz".to_string(),
+        })),
+    });
+    input.extend(codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::AddRequest(AddRequest { a: i32::MAX, b: 1 })),
+    }));
+    let responses = process_responses(&input);
+    assert_eq!(responses.len(), 2, "Expected one response per request");
+    match &responses[0].message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "fuzz"),
+        other => panic!("Expected EchoMessage, got {:?}", other),
+    }
+    match &responses[1].message {
+        Some(server_message::Message::ErrorResponse(error)) => assert_eq!(error.error_code(), Some(ErrorCode::Overflow)),
+        other => panic!("Expected ErrorResponse, got {:?}", other),
+    }
+
+    // Crafted inputs: empty, partial headers, oversized and maximal lengths, empty and garbage payloads
+    let crafted: Vec<Vec<u8>> = vec![
+        Vec::new(),
+        vec![0],
+        vec![0, 0, 0],
+        vec![0, 0, 0, 0],
+        u32::MAX.to_be_bytes().to_vec(),
+        (codec::STREAM_ID_FLAG | 4).to_be_bytes().to_vec(),
+        [&5u32.to_be_bytes()[..], &[0xff; 5]].concat(),
+        [&3u32.to_be_bytes()[..], &[0x0a, 0x7f, 0x00]].concat(),
+    ];
+    for input in &crafted {
+        let _ = process(input);
+    }
+
+    // Ten consecutive undecodable frames get the final error, as on a real connection
+    let garbage: Vec<u8> = (0..10).flat_map(|_| [&2u32.to_be_bytes()[..], &[0xff, 0xff]].concat()).collect();
+    match process_responses(&garbage).last().and_then(|response| response.message.clone()) {
+        Some(server_message::Message::ErrorResponse(error)) => assert_eq!(error.error_code(), Some(ErrorCode::DecodeFailed)),
+        other => panic!("Expected ErrorResponse, got {:?}", other),
+    }
+
+    // Pseudo-random inputs and random corruptions of a valid request, reproducible from a fixed seed
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    for _ in 0..20_000 {
+        let len = (next() % 64) as usize;
+        let mut input: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+        if next() % 2 == 0 {
+            // Keep a plausible header so the payload reaches the decoder
+            input.splice(0..0, (len as u32).to_be_bytes());
+        }
+        let _ = process(&input);
+
+        let mut corrupted = valid_add_frame();
+        let position = (next() as usize) % corrupted.len();
+        corrupted[position] = next() as u8;
+        let _ = process(&corrupted);
+    }
+}
+
+// A valid add request to corrupt
+fn valid_add_frame() -> Vec<u8> {
+    codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::AddRequest(AddRequest { a: 7, b: 35 })),
+    })
+}
+
+#[test]
+fn test_concurrent_stop_single_transition() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = create_server(port);
+    assert!(!server.stop(), "Stopping a server that never ran reported a transition");
+    let handle = setup_server_thread(server.clone());
+
+    // A served request proves `run` is up
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut client, "before stop");
+
+    // Every thread calls `stop` at the same moment; exactly one of them stops the server
+    const CALLERS: usize = 8;
+    let barrier = Arc::new(std::sync::Barrier::new(CALLERS));
+    let callers: Vec<JoinHandle<bool>> = (0..CALLERS)
+        .map(|_| {
+            let server = Arc::clone(&server);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                server.stop()
+            })
+        })
+        .collect();
+    let transitions = callers
+        .into_iter()
+        .map(|caller| caller.join().expect("Stop caller panicked"))
+        .filter(|&stopped| stopped)
+        .count();
+    assert_eq!(transitions, 1, "Expected exactly one caller to stop the server");
+
+    // Wait for the server thread to finish
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+    assert_eq!(server.shutdown_reason(), Some(ShutdownReason::Stop));
+}
+
+#[test]
+fn test_response_delivered_when_client_closes_after_request() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = create_server(port);
+    let handle = setup_server_thread(server.clone());
+
+    // Send a request and close the sending side right away, before the response can have been written
+    let mut stream = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .expect("Failed to set read timeout");
+    let request = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "last words".to_string(),
+        })),
+    });
+    stream.write_all(&request).expect("Failed to send request");
+    stream.shutdown(std::net::Shutdown::Write).expect("Failed to close the sending side");
+
+    // The response arrives in full, followed by an orderly end of stream
+    let frame = codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing echo response");
+    match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "last words"),
+        other => panic!("Expected EchoMessage, got {:?}", other),
+    }
+    let mut rest = Vec::new();
+    assert_eq!(stream.read_to_end(&mut rest).expect("Connection was reset"), 0, "Unexpected bytes after the response");
+
+    let deadline = std::time::Instant::now() + Duration::from_secs(2);
+    while server.recent_disconnects().is_empty() && std::time::Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let reasons: Vec<_> = server.recent_disconnects().iter().map(|record| record.reason).collect();
+    assert_eq!(reasons, vec![DisconnectReason::ClientClosed]);
+    assert!(DisconnectReason::ClientClosed.is_graceful());
+    assert!(!DisconnectReason::WriteTimeout.is_graceful());
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_metrics_by_message_type_and_outcome() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = create_server(port);
+    let handle = setup_server_thread(server.clone());
+
+    // Mixed traffic: echoes, additions that fit and one that overflows, a health check and an undecodable frame
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    for index in 0..3 {
+        assert_echo(&mut client, &format!("echo {}", index));
+    }
+    for (a, b) in [(1, 2), (-5, 5)] {
+        let message = client_message::Message::AddRequest(AddRequest { a, b });
+        assert!(client.send(message).is_ok(), "Failed to send message");
+        assert!(client.receive().is_ok(), "Failed to receive response for AddRequest");
+    }
+    let overflow = client_message::Message::AddRequest(AddRequest { a: i32::MAX, b: 1 });
+    assert!(client.send(overflow).is_ok(), "Failed to send message");
+    let (code, _) = client.receive_error().expect("Expected an ErrorResponse for the overflow");
+    assert_eq!(code, ErrorCode::Overflow);
+    assert!(client.send(client_message::Message::HealthRequest(HealthRequest {})).is_ok());
+    assert!(client.receive().is_ok(), "Failed to receive response for HealthRequest");
+    let mut raw = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+    raw.write_all(&[0, 0, 0, 2, 0xff, 0xff]).expect("Failed to send garbage frame");
+    drop(raw);
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+    let deadline = std::time::Instant::now() + Duration::from_secs(2);
+    while server.recent_disconnects().len() < 2 && std::time::Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let metrics = server.metrics();
+    assert_eq!(metrics.count(MessageType::EchoMessage, Outcome::Ok), 3);
+    assert_eq!(metrics.count(MessageType::AddRequest, Outcome::Ok), 2);
+    assert_eq!(metrics.count(MessageType::AddRequest, Outcome::Error), 1);
+    assert_eq!(metrics.count(MessageType::HealthRequest, Outcome::Ok), 1);
+    assert_eq!(metrics.count(MessageType::Undecodable, Outcome::Error), 1);
+    assert_eq!(metrics.snapshot().values().sum::<u64>(), 8, "Unexpected buckets: {:?}", metrics.snapshot());
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+// Takes 20 ms per request and records the most requests it ever ran at once
+struct ConcurrencyTracker {
+    running: Arc<std::sync::atomic::AtomicUsize>,
+    peak: Arc<std::sync::atomic::AtomicUsize>,
+}
+
+impl MessageHandler for ConcurrencyTracker {
+    fn handle(&self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)> {
+        let now = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(now, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(20));
+        self.running.fetch_sub(1, Ordering::SeqCst);
+        DefaultHandler.handle(message)
+    }
+}
+
+#[test]
+fn test_max_in_flight_bounds_concurrent_handlers() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread, letting two requests run at once and the rest wait their turn
+    let peak = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .handler(ConcurrencyTracker {
+                running: Arc::new(std::sync::atomic::AtomicUsize::new(0)),
+                peak: Arc::clone(&peak),
+            })
+            .max_in_flight(2, Duration::from_secs(5))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Many clients at once, each waiting for every response
+    let clients: Vec<JoinHandle<()>> = (0..8)
+        .map(|index| {
+            thread::spawn(move || {
+                let mut client = client::Client::new("localhost", port, 1000);
+                assert!(client.connect().is_ok(), "Failed to connect to the server");
+                assert!(client.set_read_timeout(Some(Duration::from_secs(10))).is_ok());
+                for request in 0..5 {
+                    assert_echo(&mut client, &format!("client {} request {}", index, request));
+                }
+                assert!(
+                    client.disconnect().is_ok(),
+                    "Failed to disconnect from the server"
+                );
+            })
+        })
+        .collect();
+    for client in clients {
+        assert!(client.join().is_ok(), "A client thread failed");
+    }
+    let peak = peak.load(Ordering::SeqCst);
+    assert!(peak <= 2, "{} handlers ran at once", peak);
+    assert_eq!(peak, 2, "Requests never ran concurrently");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+// Waits the given time before answering each request
+struct SlowEcho(Duration);
+
+impl MessageHandler for SlowEcho {
+    fn handle(&self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)> {
+        thread::sleep(self.0);
+        DefaultHandler.handle(message)
+    }
+}
+
+#[test]
+fn test_max_in_flight_rejects_after_wait() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    assert!(
+        Server::builder("localhost:0").max_in_flight(0, Duration::ZERO).build().is_err(),
+        "A zero in-flight limit was accepted"
+    );
+
+    let port = get_unique_port();
+
+    // One request at a time, and no waiting for the slot
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .handler(SlowEcho(Duration::from_millis(500)))
+            .max_in_flight(1, Duration::ZERO)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // The first client holds the only slot while the second asks
+    let mut busy = client::Client::new("localhost", port, 1000);
+    assert!(busy.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "holding the slot".to_string(),
+    });
+    assert!(busy.send(message).is_ok(), "Failed to send message");
+    thread::sleep(Duration::from_millis(100)); // Let the handler start
+    let mut turned_away = client::Client::new("localhost", port, 1000);
+    assert!(turned_away.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "no room".to_string(),
+    });
+    assert!(turned_away.send(message).is_ok(), "Failed to send message");
+    let (code, _) = turned_away.receive_error().expect("Expected an ErrorResponse");
+    assert_eq!(code, ErrorCode::AtCapacity);
+
+    // Both connections keep working once the slot frees up
+    assert!(busy.set_read_timeout(Some(Duration::from_secs(2))).is_ok());
+    assert!(busy.receive().is_ok(), "Failed to receive the slow response");
+    assert_echo(&mut turned_away, "room again");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_client_request_timeout() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up a server that takes half a second to answer
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .handler(SlowEcho(Duration::from_millis(500)))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // A call with a shorter timeout fails instead of hanging, and drops the connection
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let started = std::time::Instant::now();
+    let error = client.echo("too slow", Duration::from_millis(100)).expect_err("Call did not time out");
+    assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+    assert!(started.elapsed() < Duration::from_millis(450), "Call waited for the response");
+    let error = client.add(1, 2, Duration::from_secs(2)).expect_err("Timed out connection was kept");
+    assert_eq!(error.kind(), std::io::ErrorKind::NotConnected);
+
+    // Calls with room for the delay succeed on a new connection
+    assert!(client.connect().is_ok(), "Failed to reconnect to the server");
+    assert_eq!(client.echo("patient", Duration::from_secs(2)).expect("Echo failed"), "patient");
+    assert_eq!(client.add(20, 22, Duration::from_secs(2)).expect("Add failed"), 42);
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_message_with_no_message_set() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = create_server(port);
+    let handle = setup_server_thread(server.clone());
+
+    let mut stream = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .expect("Failed to set read timeout");
+    let mut expect_error = |frame: &[u8], expected: ErrorCode| {
+        stream.write_all(frame).expect("Failed to send frame");
+        let response = codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing error response");
+        match ServerMessage::decode(response.payload.as_slice()).expect("Invalid response").message {
+            Some(server_message::Message::ErrorResponse(error)) => assert_eq!(error.error_code(), Some(expected)),
+            other => panic!("Expected ErrorResponse, got {:?}", other),
+        }
+    };
+
+    // A `ClientMessage` with nothing set encodes to an empty payload
+    expect_error(&codec::encode_frame(&ClientMessage { message: None }), ErrorCode::EmptyMessage);
+    // A message in a field this version doesn't know (field 99, empty length-delimited) is unsupported instead
+    expect_error(&[0, 0, 0, 3, 0x9a, 0x06, 0x00], ErrorCode::Unsupported);
+
+    // The connection keeps serving afterwards
+    let echo = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "still here".to_string(),
+        })),
+    });
+    stream.write_all(&echo).expect("Failed to send request");
+    let frame = codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing echo response");
+    match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "still here"),
+        other => panic!("Expected EchoMessage, got {:?}", other),
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_spawn_with_caller_threads() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    assert!(
+        Server::builder("localhost:0").workers(2).spawn_with(|_| {}).build().is_err(),
+        "A worker pool and a spawner were both accepted"
+    );
+
+    let port = get_unique_port();
+
+    // The caller collects accepted connections and serves them on threads it owns
+    let (sender, receiver) = mpsc::channel::<Connection>();
+    let sender = std::sync::Mutex::new(sender); // The callback must be `Sync`
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .spawn_with(move |connection| {
+                let _ = sender.lock().unwrap().send(connection);
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let runtime = thread::spawn(move || {
+        let mut served = Vec::new();
+        for connection in receiver.iter().take(2) {
+            let peer = connection.peer();
+            served.push(thread::spawn(move || (peer, connection.run())));
+        }
+        served
+            .into_iter()
+            .map(|thread| thread.join().expect("Caller thread panicked"))
+            .collect::<Vec<_>>()
+    });
+
+    // Both clients are served as usual
+    let mut first = client::Client::new("localhost", port, 1000);
+    assert!(first.connect().is_ok(), "Failed to connect to the server");
+    let mut second = client::Client::new("localhost", port, 1000);
+    assert!(second.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut first, "first");
+    assert_echo(&mut second, "second");
+    assert!(first.disconnect().is_ok(), "Failed to disconnect from the server");
+    assert!(second.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    // The caller's threads see each connection end
+    let results = runtime.join().expect("Caller runtime panicked");
+    assert_eq!(results.len(), 2);
+    assert!(results.iter().all(|(_, reason)| *reason == DisconnectReason::ClientClosed), "{:?}", results);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_write_buffer_batches_pipelined_responses() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    assert!(
+        Server::builder("localhost:0").write_buffer(0).build().is_err(),
+        "An empty write buffer was accepted"
+    );
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread, reporting its writes once the connection ends
+    let (sender, receiver) = mpsc::channel::<ConnectionStats>();
+    let sender = std::sync::Mutex::new(sender); // The callback must be `Sync`
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .write_buffer(16 * 1024)
+            .on_disconnect(move |stats| {
+                let _ = sender.lock().unwrap().send(stats.clone());
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // A lone request is still answered right away
+    let mut stream = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .expect("Failed to set read timeout");
+    let lone = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "alone".to_string(),
+        })),
+    });
+    stream.write_all(&lone).expect("Failed to send request");
+    codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Buffered response was not flushed");
+
+    // Pipelined requests are answered in far fewer writes than responses
+    const REQUESTS: usize = 500;
+    let mut batch = Vec::new();
+    for index in 0..REQUESTS {
+        batch.extend(codec::encode_frame(&ClientMessage {
+            message: Some(client_message::Message::EchoMessage(EchoMessage {
+                content: format!("request {}", index),
+            })),
+        }));
+    }
+    stream.write_all(&batch).expect("Failed to send requests");
+    for index in 0..REQUESTS {
+        let frame = codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing response");
+        match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+            Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, format!("request {}", index)),
+            _ => panic!("Expected EchoMessage, but received a different message"),
+        }
+    }
+    drop(stream); // Close the connection so the statistics are reported
+
+    let stats = receiver
+        .recv_timeout(Duration::from_secs(2))
+        .expect("on_disconnect was not called");
+    assert!(stats.writes < REQUESTS as u64 / 4, "{} writes for {} responses", stats.writes, REQUESTS + 1);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_late_connection_told_server_is_shutting_down() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = create_server(port);
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut client, "before shutdown");
+
+    // Connect right as shutdown begins; the accept loop is still between polls, so the connection is queued
+    server.stop();
+    let mut late = client::Client::new("localhost", port, 1000);
+    assert!(late.connect().is_ok(), "Failed to connect to the server");
+    assert!(late.set_read_timeout(Some(Duration::from_secs(2))).is_ok());
+    let (code, message) = late.receive_error().expect("Late connection got no shutdown error");
+    assert_eq!(code, ErrorCode::ShuttingDown);
+    assert_eq!(message, "server shutting down");
+
+    // Wait for the server thread to finish
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_suppress_duplicate_echoes() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .suppress_duplicate_echoes()
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let echo = |client: &mut client::Client, content: &str| {
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: content.to_string(),
+        });
+        assert!(client.send(message).is_ok(), "Failed to send message");
+        client.receive().expect("Failed to receive response").message
+    };
+    let echoed = |content: &str| Some(server_message::Message::EchoMessage(EchoMessage { content: content.to_string() }));
+    let suppressed = Some(server_message::Message::DuplicateSuppressed(DuplicateSuppressed {}));
+
+    // Repeats right after the first echo are suppressed, however many there are
+    assert_eq!(echo(&mut client, "same"), echoed("same"));
+    assert_eq!(echo(&mut client, "same"), suppressed);
+    assert_eq!(echo(&mut client, "same"), suppressed);
+    // New content is echoed, and so is a repeat once another request came in between
+    assert_eq!(echo(&mut client, "other"), echoed("other"));
+    let add = client_message::Message::AddRequest(AddRequest { a: 1, b: 1 });
+    assert!(client.send(add).is_ok(), "Failed to send message");
+    assert!(client.receive().is_ok(), "Failed to receive response for AddRequest");
+    assert_eq!(echo(&mut client, "other"), echoed("other"));
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_uptime_and_health_report() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = create_server(port);
+    assert_eq!(server.uptime(), Duration::ZERO, "Server that never ran has an uptime");
+    assert_eq!(server.started_at(), None, "Server that never ran has a start time");
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut client, "running");
+    let started_at = server.started_at().expect("Running server has no start time");
+    thread::sleep(Duration::from_millis(200));
+    assert!(server.uptime() >= Duration::from_millis(200), "Uptime doesn't count from the start");
+
+    // The health check reports the same start time and an uptime at least as long
+    let uptime = server.uptime();
+    let message = client_message::Message::HealthRequest(HealthRequest {});
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response for HealthRequest").message {
+        Some(server_message::Message::HealthResponse(health)) => {
+            assert!(health.uptime_ms >= uptime.as_millis() as u64, "Health check uptime is behind");
+            let expected = started_at.duration_since(std::time::UNIX_EPOCH).unwrap();
+            assert_eq!(health.started_at_ms, expected.as_millis() as u64, "Health check start time differs");
+        }
+        _ => panic!("Expected HealthResponse, but received a different message"),
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+    assert_eq!(server.uptime(), Duration::ZERO, "Stopped server still has an uptime");
+    assert_eq!(server.started_at(), None, "Stopped server still has a start time");
+
+    // A restarted server counts from its restart
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to reconnect to the server");
+    assert_echo(&mut client, "restarted");
+    assert!(server.started_at().expect("Restarted server has no start time") > started_at);
+    assert!(server.uptime() < Duration::from_millis(200), "Uptime carried over from the previous run");
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_stalled_header_and_slow_frame_are_disconnected() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .idle_timeout(Duration::from_millis(400))
+            .frame_timeout(Duration::from_millis(600))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let frame = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "x".repeat(64),
+        })),
+    });
+    let connect = || {
+        let stream = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(3)))
+            .expect("Failed to set read timeout");
+        stream
+    };
+    let closed = |stream: &mut TcpStream| match stream.read(&mut [0; 16]) {
+        Ok(0) => true,
+        Err(e) => e.kind() == std::io::ErrorKind::ConnectionReset, // Bytes sent after the close reset the connection
+        Ok(_) => false,
+    };
+
+    // Half a length prefix, then nothing
+    let mut header = connect();
+    header.write_all(&frame[..2]).expect("Failed to send partial header");
+    assert!(closed(&mut header), "Stalled header was not disconnected");
+
+    // The whole header, then the body a byte at a time, too slowly to finish but never idle for long
+    let mut slow = connect();
+    slow.write_all(&frame[..codec::HEADER_LEN]).expect("Failed to send header");
+    for byte in &frame[codec::HEADER_LEN..] {
+        if slow.write_all(&[*byte]).is_err() {
+            break; // Disconnected mid-body, as expected
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(closed(&mut slow), "Slow frame was not disconnected");
+
+    // Nothing at all
+    let mut idle = connect();
+    assert!(closed(&mut idle), "Idle client was not disconnected");
+
+    // Each stall is recorded with its own reason
+    let deadline = std::time::Instant::now() + Duration::from_secs(2);
+    while server.recent_disconnects().len() < 3 && std::time::Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let reasons: Vec<DisconnectReason> = server.recent_disconnects().iter().map(|record| record.reason).collect();
+    assert_eq!(
+        reasons,
+        vec![DisconnectReason::HeaderTimeout, DisconnectReason::FrameTimeout, DisconnectReason::IdleTimeout]
+    );
+
+    // A client that keeps sending whole frames is never cut off
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    for _ in 0..4 {
+        assert_echo(&mut client, "active");
+        thread::sleep(Duration::from_millis(200));
+    }
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_stalled_uploads_are_disconnected() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .handler(VerifyingUpload)
+            .idle_timeout(Duration::from_millis(400))
+            .frame_timeout(Duration::from_millis(600))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let connect = || {
+        let mut stream = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(3)))
+            .expect("Failed to set read timeout");
+        let start = ClientMessage {
+            message: Some(client_message::Message::UploadStart(UploadStart { name: "stalled.bin".to_string(), size: 64 })),
+        };
+        codec::write_frame(&mut stream, &start).expect("Failed to send upload start");
+        stream
+    };
+    let chunk = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::UploadChunk(UploadChunk {
+            data: (0..64).map(upload_byte).collect(),
+            last: true,
+        })),
+    });
+    let closed = |stream: &mut TcpStream| match stream.read(&mut [0; 16]) {
+        Ok(0) => true,
+        Err(e) => e.kind() == std::io::ErrorKind::ConnectionReset, // Bytes sent after the close reset the connection
+        Ok(_) => false,
+    };
+
+    // An upload that starts, then sends nothing
+    let mut quiet = connect();
+    assert!(closed(&mut quiet), "Quiet upload was not disconnected");
+
+    // One whose chunk trickles in a byte at a time, too slowly to finish but never idle for long
+    let mut slow = connect();
+    slow.write_all(&chunk[..codec::HEADER_LEN]).expect("Failed to send chunk header");
+    for byte in &chunk[codec::HEADER_LEN..] {
+        if slow.write_all(&[*byte]).is_err() {
+            break; // Disconnected mid-chunk, as expected
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(closed(&mut slow), "Slow upload chunk was not disconnected");
+
+    // Each stall is recorded with its own reason
+    let deadline = std::time::Instant::now() + Duration::from_secs(2);
+    while server.recent_disconnects().len() < 2 && std::time::Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let reasons: Vec<DisconnectReason> = server.recent_disconnects().iter().map(|record| record.reason).collect();
+    assert_eq!(reasons, vec![DisconnectReason::IdleTimeout, DisconnectReason::FrameTimeout]);
+
+    // An upload taking longer than the idle timeout, but never idle for long, leaves the connection usable
+    let mut steady = connect();
+    for half in [&chunk[..chunk.len() / 2], &chunk[chunk.len() / 2..]] {
+        thread::sleep(Duration::from_millis(300));
+        steady.write_all(half).expect("Failed to send upload chunk");
+    }
+    let frame = codec::read_frame(&mut steady, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing upload response");
+    match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+        Some(server_message::Message::UploadResponse(upload)) => assert_eq!(upload.bytes_received, 64),
+        other => panic!("Expected UploadResponse, got {:?}", other),
+    }
+    let echo = ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage { content: "after upload".to_string() })),
+    };
+    codec::write_frame(&mut steady, &echo).expect("Failed to send request");
+    let frame = codec::read_frame(&mut steady, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing echo response");
+    match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "after upload"),
+        other => panic!("Expected EchoMessage, got {:?}", other),
+    }
+    drop(steady);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_snapshot_reflects_live_state() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = create_server(port);
+    let snapshot = server.snapshot();
+    assert!(!snapshot.running, "Server that never ran is running");
+    assert_eq!(snapshot.client_count, 0);
+    let handle = setup_server_thread(server.clone());
+
+    // One busy client and one that only connects; echoes on the second prove both were accepted
+    let mut busy = client::Client::new("localhost", port, 1000);
+    assert!(busy.connect().is_ok(), "Failed to connect to the server");
+    let mut quiet = client::Client::new("localhost", port, 1000);
+    assert!(quiet.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut busy, "one");
+    assert_echo(&mut busy, "two");
+    thread::sleep(Duration::from_millis(100)); // Counters are updated just after the response is written
+
+    let snapshot = server.snapshot();
+    assert!(snapshot.running && !snapshot.paused);
+    assert_eq!(snapshot.shutdown_reason, None);
+    assert!(snapshot.started_at.is_some() && snapshot.uptime > Duration::ZERO);
+    assert_eq!(snapshot.client_count, 2);
+    let ids: Vec<u64> = snapshot.connections.iter().map(|connection| connection.connection_id).collect();
+    assert_eq!(ids, vec![0, 1], "Connections missing or out of order");
+    let (first, second) = (&snapshot.connections[0], &snapshot.connections[1]);
+    assert_eq!(first.messages_handled, 2);
+    assert!(first.bytes_in > 0 && first.bytes_out > 0 && first.writes == 2, "Busy connection: {:?}", first);
+    assert_eq!((second.messages_handled, second.bytes_in, second.bytes_out), (0, 0, 0), "Quiet connection: {:?}", second);
+    assert_eq!(snapshot.metrics, vec![(MessageType::EchoMessage, Outcome::Ok, 2)]);
+
+    // Closed connections leave the snapshot
+    assert!(
+        quiet.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+    thread::sleep(Duration::from_millis(300)); // Give the worker time to see the disconnect
+    assert_eq!(server.snapshot().client_count, 1);
+
+    // Disconnect the client
+    assert!(
+        busy.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+    let snapshot = server.snapshot();
+    assert!(!snapshot.running);
+    assert_eq!(snapshot.shutdown_reason, Some(ShutdownReason::Stop));
+    assert_eq!(snapshot.client_count, 0);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_snapshot_serializes_as_json() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = create_server(port);
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut client, "serialized");
+
+    let json = serde_json::to_value(server.snapshot()).expect("Snapshot doesn't serialize");
+    assert_eq!(json["running"], true);
+    assert_eq!(json["client_count"], 1);
+    assert_eq!(json["connections"][0]["messages_handled"], 1);
+    assert_eq!(json["metrics"][0], serde_json::json!(["EchoMessage", "Ok", 1]));
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_client_sets_its_own_idle_timeout() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread; by default nobody is closed for idling
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .idle_timeout_bounds(Duration::from_millis(200), Duration::from_secs(60))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let set_idle_timeout = |client: &mut client::Client, timeout_ms: u64| {
+        let message = client_message::Message::SetIdleTimeoutRequest(SetIdleTimeoutRequest { timeout_ms });
+        assert!(client.send(message).is_ok(), "Failed to send message");
+    };
+
+    // Values out of bounds are refused and leave the connection as it was
+    for timeout_ms in [100, 61_000] {
+        set_idle_timeout(&mut client, timeout_ms);
+        let (code, _) = client.receive_error().expect("Failed to receive error response");
+        assert_eq!(code, ErrorCode::OutOfRange, "Unexpected error code for {} ms", timeout_ms);
+    }
+    thread::sleep(Duration::from_millis(400));
+    assert_echo(&mut client, "still connected");
+
+    // A value in bounds is confirmed and applies from then on
+    set_idle_timeout(&mut client, 300);
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::SetIdleTimeoutResponse(response)) => {
+            assert_eq!(response, SetIdleTimeoutResponse { timeout_ms: 300 })
+        }
+        other => panic!("Expected SetIdleTimeoutResponse, got {:?}", other),
+    }
+    assert!(client.set_read_timeout(Some(Duration::from_secs(2))).is_ok());
+    let closed = client.receive();
+    assert!(closed.is_err(), "Connection stayed open while idle: {:?}", closed);
+    thread::sleep(Duration::from_millis(100)); // The disconnect is recorded just after the connection closes
+    assert_eq!(
+        server.recent_disconnects().last().map(|record| record.reason),
+        Some(DisconnectReason::IdleTimeout)
+    );
+
+    // Other connections keep the server's setting
+    let mut other = client::Client::new("localhost", port, 1000);
+    assert!(other.connect().is_ok(), "Failed to connect to the server");
+    thread::sleep(Duration::from_millis(500));
+    assert_echo(&mut other, "no idle timeout");
+
+    // Disconnect the client
+    assert!(
+        other.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+// Echoes "slow" only after five seconds unless cancelled first, recording whether it saw the cancellation
+struct CancellableEcho {
+    saw_cancel: Arc<AtomicBool>,
+}
+
+impl MessageHandler for CancellableEcho {
+    fn handle(&self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)> {
+        DefaultHandler.handle(message)
+    }
+
+    fn handle_cancellable(&self, message: client_message::Message, cancel: &CancellationToken) -> Option<(ServerMessage, CloseAfter)> {
+        if matches!(&message, client_message::Message::EchoMessage(echo) if echo.content == "slow") {
+            let deadline = std::time::Instant::now() + Duration::from_secs(5);
+            while std::time::Instant::now() < deadline {
+                if cancel.is_cancelled() {
+                    self.saw_cancel.store(true, Ordering::SeqCst);
+                    return None; // Give up; the server answers for us
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        self.handle(message)
+    }
+}
+
+#[test]
+fn test_cancel_request_aborts_slow_handler() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread; cancellation needs the handler off the connection thread
+    let saw_cancel = Arc::new(AtomicBool::new(false));
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .handler_threads(1)
+            .handler(CancellableEcho { saw_cancel: Arc::clone(&saw_cancel) })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert!(client.set_read_timeout(Some(Duration::from_secs(3))).is_ok());
+
+    // A slow request, its cancellation, and a request pipelined behind both
+    let echo = |content: &str| client_message::Message::EchoMessage(EchoMessage { content: content.to_string() });
+    let started = std::time::Instant::now();
+    assert!(client.send(echo("slow")).is_ok(), "Failed to send message");
+    thread::sleep(Duration::from_millis(100)); // Let the handler start
+    let cancel = client_message::Message::CancelRequest(CancelRequest { request_id: 0 });
+    assert!(client.send(cancel).is_ok(), "Failed to send message");
+    assert!(client.send(echo("after")).is_ok(), "Failed to send message");
+
+    // The slow request is answered as cancelled long before it would have finished, then the next one as usual
+    let (code, _) = client.receive_error().expect("Failed to receive error response");
+    assert_eq!(code, ErrorCode::Cancelled);
+    assert!(started.elapsed() < Duration::from_secs(2), "Cancellation took {:?}", started.elapsed());
+    assert!(saw_cancel.load(Ordering::SeqCst), "Handler never saw the cancellation");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "after"),
+        other => panic!("Expected EchoMessage, got {:?}", other),
+    }
+
+    // A cancellation with nothing in flight is ignored
+    let cancel = client_message::Message::CancelRequest(CancelRequest { request_id: 0 });
+    assert!(client.send(cancel).is_ok(), "Failed to send message");
+    assert_echo(&mut client, "in sync");
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[cfg(feature = "recording")]
+#[test]
+fn test_recorded_connection_replays() {
+    use embedded_recruitment_task::recording::{self, Direction};
+
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+    let dir = std::env::temp_dir().join(format!("recording-test-{}", port));
+    std::fs::create_dir_all(&dir).expect("Failed to create the recording directory");
+
+    // Set up the server in a separate thread
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .record_to(&dir)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // An echo and an add, then close
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut client, "recorded");
+    let add = client_message::Message::AddRequest(AddRequest { a: 2, b: 3 });
+    assert!(client.send(add).is_ok(), "Failed to send message");
+    assert!(client.receive().is_ok(), "Failed to receive response for AddRequest");
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+    let deadline = std::time::Instant::now() + Duration::from_secs(2);
+    while server.recent_disconnects().is_empty() && std::time::Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    thread::sleep(Duration::from_millis(100)); // The log is flushed as the connection is dropped, just after the record
+
+    // The log holds both requests and both responses, in order
+    let path = recording::log_path(&dir, 0);
+    let log = recording::read_log(&path).expect("Failed to read the recording");
+    let directions: Vec<Direction> = log.iter().map(|(direction, _)| *direction).collect();
+    assert_eq!(directions, [Direction::Inbound, Direction::Outbound, Direction::Inbound, Direction::Outbound]);
+
+    // Replaying the requests gets the same responses again
+    let recorded: Vec<codec::Frame> = log
+        .into_iter()
+        .filter(|(direction, _)| *direction == Direction::Outbound)
+        .map(|(_, frame)| frame)
+        .collect();
+    let replayed = recording::replay(&path, format!("localhost:{}", port)).expect("Failed to replay the recording");
+    assert_eq!(replayed, recorded);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[cfg(feature = "recording")]
+#[test]
+fn test_long_recording_replays() {
+    use embedded_recruitment_task::recording::{self, Direction};
+
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+    let dir = std::env::temp_dir().join(format!("recording-test-{}", port));
+    std::fs::create_dir_all(&dir).expect("Failed to create the recording directory");
+
+    // Set up the server in a separate thread; a replay that stops reading is dropped within a second
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .record_to(&dir)
+            .write_timeout(Some(Duration::from_secs(1)))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Record 16 MiB of echoes, far more than the socket buffers of both sides hold
+    const ECHOES: usize = 512;
+    let mut client = client::Client::new("localhost", port, 5000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let content = "r".repeat(32 * 1024);
+    for _ in 0..ECHOES {
+        assert_echo(&mut client, &content);
+    }
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+    let deadline = std::time::Instant::now() + Duration::from_secs(2);
+    while server.recent_disconnects().is_empty() && std::time::Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    thread::sleep(Duration::from_millis(100)); // The log is flushed as the connection is dropped, just after the record
+
+    // Every request is sent in one go, and every response still comes back
+    let path = recording::log_path(&dir, 0);
+    let recorded: Vec<codec::Frame> = recording::read_log(&path)
+        .expect("Failed to read the recording")
+        .into_iter()
+        .filter(|(direction, _)| *direction == Direction::Outbound)
+        .map(|(_, frame)| frame)
+        .collect();
+    assert_eq!(recorded.len(), ECHOES);
+    let replayed = recording::replay(&path, format!("localhost:{}", port)).expect("Failed to replay the recording");
+    assert!(replayed == recorded, "Replayed {} of {} responses, or different ones", replayed.len(), recorded.len());
+    assert!(server.recent_disconnects().iter().all(|record| record.reason == DisconnectReason::ClientClosed));
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_max_echo_bytes_per_connection() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+
+    // Set up the server in a separate thread
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .max_echo_bytes_per_connection(10_000)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let large = "x".repeat(4_000);
+
+    // Echoes are served until the next one would go over the cap
+    assert_echo(&mut client, &large);
+    assert_echo(&mut client, &large);
+    let echo = |content: &str| client_message::Message::EchoMessage(EchoMessage { content: content.to_string() });
+    assert!(client.send(echo(&large)).is_ok(), "Failed to send message");
+    let (code, _) = client.receive_error().expect("Failed to receive error response");
+    assert_eq!(code, ErrorCode::QuotaExceeded, "Unexpected error code");
+
+    // From then on even echoes that would fit are refused, while other requests are still served
+    assert!(client.send(echo("x")).is_ok(), "Failed to send message");
+    let (code, _) = client.receive_error().expect("Failed to receive error response");
+    assert_eq!(code, ErrorCode::QuotaExceeded, "Unexpected error code");
+    assert_eq!(client.add(1, 2, Duration::from_secs(1)).expect("Failed to add"), 3);
+
+    // Every connection has its own allowance
+    let mut other = client::Client::new("localhost", port, 1000);
+    assert!(other.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut other, &large);
+
+    // Disconnect the clients
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+    assert!(
+        other.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_run_reporting_sends_connectable_address() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Bind to an ephemeral port and learn it only from the report
+    let server = Arc::new(Server::new("127.0.0.1:0").expect("Failed to start server"));
+    let (tx, rx) = mpsc::channel();
+    let handle = {
+        let server = server.clone();
+        thread::spawn(move || {
+            server.run_reporting(tx).expect("Server encountered an error");
+        })
+    };
+    let addr = rx.recv_timeout(Duration::from_secs(2)).expect("Server never reported its address");
+    assert_eq!(Some(addr), server.local_addr().ok());
+    assert_ne!(addr.port(), 0, "Reported the port asked for, not the one bound");
+
+    // Connectable right away, without sleeping first
+    let mut client = client::Client::new(&addr.ip().to_string(), addr.port() as u32, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the reported address");
+    assert_echo(&mut client, "reported");
+
+    // Disconnect the client
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_ready_notifier_fires_before_accepting() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Connect and stop the moment each server says it's ready, with no sleeps, many times over
+    for _ in 0..20 {
+        let server = Arc::new(Server::new("localhost:0").expect("Failed to start server"));
+        let port = server.local_addr().expect("Server has no address").port() as u32;
+        let ready = server.ready_notifier();
+        let handle = setup_server_thread(server.clone());
+        ready.recv_timeout(Duration::from_secs(2)).expect("Server never became ready");
+        assert!(ready.recv().is_err(), "Ready notifier fired twice"); // The sender is dropped after firing
+
+        let mut client = client::Client::new("localhost", port, 1000);
+        assert!(client.connect().is_ok(), "Failed to connect to the server");
+        assert_echo(&mut client, "ready");
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+
+        // Stop the server and wait for thread to finish; a ready server always stops
+        assert!(server.stop(), "Ready server was not running");
+        assert!(
+            handle.join().is_ok(),
+            "Server thread panicked or failed to join"
+        );
+    }
+}
+
+#[cfg(feature = "request-log")]
+#[test]
+fn test_request_log_has_a_line_per_request() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+    let path = std::env::temp_dir().join(format!("request-log-test-{}.jsonl", port));
+    let _ = std::fs::remove_file(&path);
+
+    // Set up the server in a separate thread
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .request_log(&path)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Three echoes and an add
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    for i in 0..3 {
+        assert_echo(&mut client, &format!("logged {}", i));
+    }
+    let add = client_message::Message::AddRequest(AddRequest { a: 2, b: 3 });
+    assert!(client.send(add).is_ok(), "Failed to send message");
+    assert!(client.receive().is_ok(), "Failed to receive response for AddRequest");
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // The writer thread flushes once its queue runs empty
+    let read_lines = || std::fs::read_to_string(&path).unwrap_or_default().lines().map(str::to_string).collect::<Vec<_>>();
+    let deadline = std::time::Instant::now() + Duration::from_secs(2);
+    while read_lines().len() < 4 && std::time::Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let lines = read_lines();
+    assert_eq!(lines.len(), 4, "Expected one line per request, got {:?}", lines);
+    for line in &lines[..3] {
+        assert!(line.starts_with("{\"timestamp_ms\":"), "Unexpected line {}", line);
+        assert!(line.contains("\"message_type\":\"EchoMessage\""), "Unexpected line {}", line);
+        assert!(line.contains("\"outcome\":\"Ok\""), "Unexpected line {}", line);
+        assert!(line.contains("\"latency_us\":"), "Unexpected line {}", line);
+    }
+    assert!(lines[3].contains("\"message_type\":\"AddRequest\""), "Unexpected line {}", lines[3]);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_adaptive_polling_answers_after_backing_off() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // The shortest wait must be non-zero and no longer than the longest
+    for (min, max) in [(Duration::ZERO, Duration::from_millis(10)), (Duration::from_millis(20), Duration::from_millis(10))] {
+        assert!(
+            Server::builder("localhost:0").adaptive_polling(min, max).build().is_err(),
+            "Server with poll intervals {:?}..{:?} was built",
+            min,
+            max
+        );
+    }
+
+    // Set up the server in a separate thread
+    let port = get_unique_port();
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .adaptive_polling(Duration::from_millis(1), Duration::from_millis(200))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Requests in quick succession are answered, and so is one after the connection has backed off to its longest wait
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    for i in 0..10 {
+        assert_echo(&mut client, &format!("busy {}", i));
+    }
+    thread::sleep(Duration::from_millis(500));
+    let started = std::time::Instant::now();
+    assert_echo(&mut client, "after idling");
+    assert!(started.elapsed() < Duration::from_millis(100), "Data arriving waited for the poll interval");
+
+    // A new connection after the accept loop backed off is still served
+    let mut late = client::Client::new("localhost", port, 1000);
+    assert!(late.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut late, "late");
+    assert!(late.disconnect().is_ok(), "Failed to disconnect from the server");
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_capabilities_match_handled_types() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Set up the server in a separate thread
+    let port = get_unique_port();
+    let server = create_server(port);
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let capabilities = |client: &mut client::Client| {
+        let request = client_message::Message::CapabilitiesRequest(CapabilitiesRequest {});
+        assert!(client.send(request).is_ok(), "Failed to send message");
+        match client.receive().expect("Failed to receive response").message {
+            Some(server_message::Message::CapabilitiesResponse(response)) => response.message_types,
+            other => panic!("Expected CapabilitiesResponse, got {:?}", other),
+        }
+    };
+
+    // The default handler's types, sorted, next to the server's own; idle timeouts are fixed, so not listed
+    let listed = capabilities(&mut client);
+    assert_eq!(
+        listed,
+        [
+            "AddRequest", "BinaryEchoMessage", "CancelRequest", "CapabilitiesRequest", "DeadlineRequest", "EchoMessage",
+            "EchoMetadataRequest", "HealthRequest", "IdempotentRequest", "LogEvent", "UploadChunk", "UploadStart",
+            "VersionRequest",
+        ]
+    );
+
+    // Every listed request that gets a response is answered with something other than an error
+    for name in &listed {
+        let request = match name.as_str() {
+            "AddRequest" => client_message::Message::AddRequest(AddRequest { a: 1, b: 2 }),
+            "BinaryEchoMessage" => client_message::Message::BinaryEchoMessage(BinaryEchoMessage { payload: vec![1, 2] }),
+            "CapabilitiesRequest" => client_message::Message::CapabilitiesRequest(CapabilitiesRequest {}),
+            "EchoMessage" => client_message::Message::EchoMessage(EchoMessage { content: "probe".to_string() }),
+            "EchoMetadataRequest" => client_message::Message::EchoMetadataRequest(EchoMetadataRequest {}),
+            "HealthRequest" => client_message::Message::HealthRequest(HealthRequest {}),
+            "VersionRequest" => client_message::Message::VersionRequest(VersionRequest {}),
+            _ => continue, // One-way, or only answered as part of an upload or another request
+        };
+        assert!(client.send(request).is_ok(), "Failed to send message");
+        let response = client.receive().expect("Failed to receive response");
+        assert!(
+            !matches!(response.message, Some(server_message::Message::ErrorResponse(_))),
+            "{} is listed but answered with {:?}",
+            name,
+            response
+        );
+    }
+
+    // A type left out is refused
+    let request = client_message::Message::SetIdleTimeoutRequest(SetIdleTimeoutRequest { timeout_ms: 1000 });
+    assert!(client.send(request).is_ok(), "Failed to send message");
+    let (code, _) = client.receive_error().expect("Failed to receive error response");
+    assert_eq!(code, ErrorCode::Unsupported);
+
+    // A handler that lists nothing leaves only the server's own types
+    struct Unlisted;
+    impl MessageHandler for Unlisted {
+        fn handle(&self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)> {
+            DefaultHandler.handle(message)
+        }
+    }
+    server.set_handler(Unlisted);
+    let listed = capabilities(&mut client);
+    assert!(!listed.iter().any(|name| name == "EchoMessage"), "Unlisted handler types reported: {:?}", listed);
+    assert!(listed.iter().any(|name| name == "VersionRequest"));
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+/// Answers everything like `DefaultHandler` except echoes, which it drops
+struct SilentEcho;
+
+impl MessageHandler for SilentEcho {
+    fn handle(&self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)> {
+        match message {
+            client_message::Message::EchoMessage(_) => None,
+            other => DefaultHandler.handle(other),
+        }
+    }
+}
+
+#[test]
+fn test_missing_response_policy() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    for policy in [None, Some(MissingResponsePolicy::Error)] {
+        // Set up the server in a separate thread
+        let port = get_unique_port();
+        let mut builder = Server::builder(&format!("localhost:{}", port)).handler(SilentEcho);
+        if let Some(policy) = policy {
+            builder = builder.missing_response(policy);
+        }
+        let server = Arc::new(builder.build().expect("Failed to start server"));
+        let handle = setup_server_thread(server.clone());
+
+        let mut client = client::Client::new("localhost", port, 1000);
+        assert!(client.connect().is_ok(), "Failed to connect to the server");
+        let echo = client_message::Message::EchoMessage(EchoMessage { content: "dropped".to_string() });
+        assert!(client.send(echo).is_ok(), "Failed to send message");
+        match policy {
+            // Lenient by default: nothing is sent, so the next response is the add's
+            None => {}
+            // Otherwise the dropped echo is answered with an error first
+            Some(_) => {
+                let (code, _) = client.receive_error().expect("Failed to receive error response");
+                assert_eq!(code, ErrorCode::Internal);
+            }
+        }
+        let add = client_message::Message::AddRequest(AddRequest { a: 2, b: 3 });
+        assert!(client.send(add).is_ok(), "Failed to send message");
+        match client.receive().expect("Failed to receive response").message {
+            Some(server_message::Message::AddResponse(response)) => assert_eq!(response.result, 5),
+            other => panic!("Expected AddResponse with {:?}, got {:?}", policy, other),
+        }
+
+        // One-way messages are never answered, whatever the policy
+        let event = client_message::Message::LogEvent(LogEvent { message: "one-way".to_string() });
+        assert!(client.send(event).is_ok(), "Failed to send message");
+        let version = client_message::Message::VersionRequest(VersionRequest {});
+        assert!(client.send(version).is_ok(), "Failed to send message");
+        match client.receive().expect("Failed to receive response").message {
+            Some(server_message::Message::VersionResponse(_)) => {}
+            other => panic!("Expected VersionResponse with {:?}, got {:?}", policy, other),
+        }
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+
+        // Stop the server and wait for thread to finish
+        server.stop();
+        assert!(
+            handle.join().is_ok(),
+            "Server thread panicked or failed to join"
+        );
+    }
+}
+
+#[test]
+fn test_socket_buffer_sizes_are_applied() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    assert!(
+        Server::builder("localhost:0").recv_buffer_size(0).build().is_err(),
+        "An empty receive buffer was accepted"
+    );
+
+    // Small enough to differ from any OS default, so seeing it proves it was set
+    const BUFFER: usize = 8 * 1024;
+    let port = get_unique_port();
+    let (sender, receiver) = mpsc::channel::<Connection>();
+    let sender = std::sync::Mutex::new(sender); // The callback must be `Sync`
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .recv_buffer_size(BUFFER)
+            .send_buffer_size(BUFFER)
+            .spawn_with(move |connection| {
+                let _ = sender.lock().unwrap().send(connection);
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let connection = receiver.recv_timeout(Duration::from_secs(2)).expect("No connection accepted");
+
+    // Linux reports double the size it was given, for its bookkeeping; other systems report it as given
+    let socket = socket2::SockRef::from(connection.stream());
+    for (option, size) in [
+        ("SO_RCVBUF", socket.recv_buffer_size().expect("Failed to read SO_RCVBUF")),
+        ("SO_SNDBUF", socket.send_buffer_size().expect("Failed to read SO_SNDBUF")),
+    ] {
+        assert!((BUFFER..=2 * BUFFER).contains(&size), "{} is {} bytes, expected {}", option, size, BUFFER);
+    }
+
+    // The connection is served as usual
+    let served = thread::spawn(move || connection.run());
+    assert_echo(&mut client, "small buffers");
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+    assert_eq!(served.join().expect("Connection thread panicked"), DisconnectReason::ClientClosed);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_warmup_throttles_accepts_then_lifts() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    assert!(
+        Server::builder("localhost:0").warmup(Duration::from_secs(1), 0).build().is_err(),
+        "A warmup to no connections at all was accepted"
+    );
+
+    // Set up the server in a separate thread, ramping up to 5 connections a second over its first second
+    let port = get_unique_port();
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .warmup(Duration::from_secs(1), 5)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let ready = server.ready_notifier();
+    let handle = setup_server_thread(server.clone());
+    ready.recv_timeout(Duration::from_secs(2)).expect("Server never became ready");
+    let started = std::time::Instant::now();
+
+    // A burst right at startup: the first connection is taken at once, the rest wait in the backlog
+    let mut early: Vec<client::Client> = (0..4)
+        .map(|_| {
+            let mut client = client::Client::new("localhost", port, 3000);
+            assert!(client.connect().is_ok(), "Failed to connect to the server");
+            client
+        })
+        .collect();
+    thread::sleep(Duration::from_millis(300));
+    assert!(started.elapsed() < Duration::from_millis(600), "Test too slow to observe the warmup");
+    assert_eq!(server.snapshot().client_count, 1, "Accepts were not throttled during warmup");
+
+    // Once warmed up every waiting client is served
+    for (i, client) in early.iter_mut().enumerate() {
+        assert_echo(client, &format!("early {}", i));
+    }
+
+    // And after the warmup a burst is taken all at once
+    thread::sleep(Duration::from_millis(1100).saturating_sub(started.elapsed()));
+    let mut late: Vec<client::Client> = (0..8)
+        .map(|_| {
+            let mut client = client::Client::new("localhost", port, 1000);
+            assert!(client.connect().is_ok(), "Failed to connect to the server");
+            client
+        })
+        .collect();
+    let deadline = std::time::Instant::now() + Duration::from_millis(500);
+    while server.snapshot().client_count < 12 && std::time::Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(server.snapshot().client_count, 12, "Accepts were still throttled after warmup");
+    for (i, client) in late.iter_mut().enumerate() {
+        assert_echo(client, &format!("late {}", i));
+    }
+    for client in early.iter_mut().chain(late.iter_mut()) {
+        assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+/// Frames each payload as a line of hex digits, for protocols that are read line by line
+struct HexLines;
+
+impl codec::Framer for HexLines {
+    fn read_frame(&self, bytes: &[u8], max_payload: usize) -> Result<Option<(codec::Frame, usize)>, codec::FrameError> {
+        let Some(end) = bytes.iter().position(|&byte| byte == b'\n') else {
+            if bytes.len() > 2 * max_payload {
+                return Err(codec::FrameError::TooLarge { declared: bytes.len() / 2, max: max_payload });
+            }
+            return Ok(None);
+        };
+        let line = std::str::from_utf8(&bytes[..end]).map_err(|e| codec::FrameError::Malformed(e.to_string()))?;
+        if line.len() % 2 != 0 {
+            return Err(codec::FrameError::Malformed("odd number of hex digits".to_string()));
+        }
+        let payload = (0..line.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&line[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|e| codec::FrameError::Malformed(e.to_string()))?;
+        Ok(Some((codec::Frame { stream_id: 0, payload }, end + 1)))
+    }
+
+    fn write_frame(&self, frame: &codec::Frame, out: &mut Vec<u8>) {
+        for byte in &frame.payload {
+            out.extend_from_slice(format!("{:02x}", byte).as_bytes());
+        }
+        out.push(b'\n');
+    }
+
+    fn max_frame_len(&self, max_payload: usize) -> usize {
+        2 * max_payload + 1
+    }
+}
+
+#[test]
+fn test_custom_framer_alongside_default() {
+    use embedded_recruitment_task::codec::Framer;
+    use std::io::{BufRead, BufReader};
+
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Set up a server with the default framing and one with hex lines
+    let default_port = get_unique_port();
+    let default_server = create_server(default_port);
+    let default_handle = setup_server_thread(default_server.clone());
+    let lines_port = get_unique_port();
+    let lines_server = Arc::new(
+        Server::builder(&format!("localhost:{}", lines_port))
+            .framer(HexLines)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let lines_handle = setup_server_thread(lines_server.clone());
+
+    // The default server speaks length prefixes as always
+    let mut client = client::Client::new("localhost", default_port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut client, "length-prefixed");
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // The other reads and answers one line per message, even with two requests in one write
+    let mut stream = TcpStream::connect(format!("localhost:{}", lines_port)).expect("Failed to connect to the server");
+    stream.set_read_timeout(Some(Duration::from_secs(2))).expect("Failed to set read timeout");
+    let mut request = Vec::new();
+    for content in ["first line", "second line"] {
+        let message = ClientMessage {
+            message: Some(client_message::Message::EchoMessage(EchoMessage { content: content.to_string() })),
+        };
+        HexLines.write_frame(&codec::Frame { stream_id: 0, payload: message.encode_to_vec() }, &mut request);
+    }
+    stream.write_all(&request).expect("Failed to send requests");
+    let mut reader = BufReader::new(stream.try_clone().expect("Failed to clone stream"));
+    for content in ["first line", "second line"] {
+        let mut line = String::new();
+        reader.read_line(&mut line).expect("Failed to read response line");
+        let (frame, _) = HexLines
+            .read_frame(line.as_bytes(), codec::DEFAULT_MAX_FRAME_SIZE)
+            .expect("Invalid response line")
+            .expect("Incomplete response line");
+        match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+            Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, content),
+            other => panic!("Expected EchoMessage, got {:?}", other),
+        }
+    }
+
+    // A line that isn't hex can't be framed, so the connection closes after saying why
+    stream.write_all(b"not hex\n").expect("Failed to send line");
+    let mut line = String::new();
+    reader.read_line(&mut line).expect("Failed to read error line");
+    let (frame, _) = HexLines
+        .read_frame(line.as_bytes(), codec::DEFAULT_MAX_FRAME_SIZE)
+        .expect("Invalid error line")
+        .expect("Incomplete error line");
+    match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+        Some(server_message::Message::ErrorResponse(error)) => assert_eq!(error.error_code(), Some(ErrorCode::DecodeFailed)),
+        other => panic!("Expected ErrorResponse, got {:?}", other),
+    }
+    let mut rest = String::new();
+    assert!(matches!(reader.read_line(&mut rest), Ok(0) | Err(_)), "Connection stayed open: {:?}", rest);
+
+    // Stop the servers and wait for their threads to finish
+    for (server, handle) in [(default_server, default_handle), (lines_server, lines_handle)] {
+        server.stop();
+        assert!(
+            handle.join().is_ok(),
+            "Server thread panicked or failed to join"
+        );
+    }
+}
+
+#[test]
+fn test_upgrade_to_another_framing() {
+    use embedded_recruitment_task::codec::Framer;
+    use std::io::{BufRead, BufReader};
+
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Set up a server that speaks length prefixes and offers hex lines
+    let port = get_unique_port();
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .upgrade_framer("hex-lines", HexLines)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut stream = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+    stream.set_read_timeout(Some(Duration::from_secs(2))).expect("Failed to set read timeout");
+    let upgrade = |framing: &str| ClientMessage {
+        message: Some(client_message::Message::UpgradeRequest(UpgradeRequest { framing: framing.to_string() })),
+    };
+    let echo = |content: &str| ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage { content: content.to_string() })),
+    };
+    let read_prefixed = |stream: &mut TcpStream| {
+        let frame = codec::read_frame(stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Failed to read frame");
+        ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message
+    };
+    let refused = |message: Option<server_message::Message>| match message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.error_code(), Some(ErrorCode::Unsupported));
+            error
+        }
+        other => panic!("Expected ErrorResponse, got {:?}", other),
+    };
+
+    // A framing the server doesn't offer is refused, listing the ones it does, and the connection carries on
+    codec::write_frame(&mut stream, &upgrade("tls")).expect("Failed to send upgrade");
+    let error = refused(read_prefixed(&mut stream));
+    assert!(error.detail.contains("hex-lines"), "Offered framings not listed: {:?}", error.detail);
+
+    // An upgrade with a request right behind it is refused too, and the request is answered in the old framing
+    let mut request = codec::encode_frame(&upgrade("hex-lines"));
+    request.extend_from_slice(&codec::encode_frame(&echo("too early")));
+    stream.write_all(&request).expect("Failed to send requests");
+    refused(read_prefixed(&mut stream));
+    match read_prefixed(&mut stream) {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "too early"),
+        other => panic!("Expected EchoMessage, got {:?}", other),
+    }
+
+    // The upgrade is answered in the old framing, everything after it in the new one
+    codec::write_frame(&mut stream, &upgrade("hex-lines")).expect("Failed to send upgrade");
+    match read_prefixed(&mut stream) {
+        Some(server_message::Message::UpgradeResponse(response)) => assert_eq!(response.framing, "hex-lines"),
+        other => panic!("Expected UpgradeResponse, got {:?}", other),
+    }
+    let mut request = Vec::new();
+    HexLines.write_frame(&codec::Frame { stream_id: 0, payload: echo("hex line").encode_to_vec() }, &mut request);
+    stream.write_all(&request).expect("Failed to send request");
+    let mut reader = BufReader::new(stream.try_clone().expect("Failed to clone stream"));
+    let mut line = String::new();
+    reader.read_line(&mut line).expect("Failed to read response line");
+    let (frame, _) = HexLines
+        .read_frame(line.as_bytes(), codec::DEFAULT_MAX_FRAME_SIZE)
+        .expect("Invalid response line")
+        .expect("Incomplete response line");
+    match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "hex line"),
+        other => panic!("Expected EchoMessage, got {:?}", other),
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+/// Counts every add it handles, as a stand-in for a side effect that must not happen twice
+struct CountingAdder {
+    adds: Arc<AtomicU32>,
+}
+
+impl MessageHandler for CountingAdder {
+    fn handle(&self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)> {
+        if let client_message::Message::AddRequest(_) = message {
+            self.adds.fetch_add(1, Ordering::SeqCst);
+        }
+        DefaultHandler.handle(message)
+    }
+}
+
+#[test]
+fn test_idempotency_key_returns_cached_response() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Set up the server in a separate thread
+    let port = get_unique_port();
+    let adds = Arc::new(AtomicU32::new(0));
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .handler(CountingAdder { adds: Arc::clone(&adds) })
+            .idempotency_cache(16, Duration::from_millis(500))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let idempotent_add = |key: &str, a: i32| {
+        client_message::Message::IdempotentRequest(Box::new(IdempotentRequest {
+            key: key.to_string(),
+            request: Some(Box::new(ClientMessage {
+                message: Some(client_message::Message::AddRequest(AddRequest { a, b: 1 })),
+            })),
+        }))
+    };
+    let add_result = |client: &mut client::Client| match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::AddResponse(response)) => response.result,
+        other => panic!("Expected AddResponse, got {:?}", other),
+    };
+
+    // A retry with the same key, even on another connection and with a different body, gets the first result
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert!(client.send(idempotent_add("retry-me", 1)).is_ok(), "Failed to send message");
+    assert_eq!(add_result(&mut client), 2);
+    let mut retrying = client::Client::new("localhost", port, 1000);
+    assert!(retrying.connect().is_ok(), "Failed to connect to the server");
+    assert!(retrying.send(idempotent_add("retry-me", 41)).is_ok(), "Failed to send message");
+    assert_eq!(add_result(&mut retrying), 2, "Retry was not answered from the cache");
+    assert_eq!(adds.load(Ordering::SeqCst), 1, "Retried add ran twice");
+
+    // Another key runs, and so does the first key once its result expired
+    assert!(client.send(idempotent_add("another", 2)).is_ok(), "Failed to send message");
+    assert_eq!(add_result(&mut client), 3);
+    thread::sleep(Duration::from_millis(600));
+    assert!(client.send(idempotent_add("retry-me", 9)).is_ok(), "Failed to send message");
+    assert_eq!(add_result(&mut client), 10);
+    assert_eq!(adds.load(Ordering::SeqCst), 3);
+
+    // Errors aren't stored, and an empty key is refused
+    let overflow = client_message::Message::IdempotentRequest(Box::new(IdempotentRequest {
+        key: "overflow".to_string(),
+        request: Some(Box::new(ClientMessage {
+            message: Some(client_message::Message::AddRequest(AddRequest { a: i32::MAX, b: 1 })),
+        })),
+    }));
+    assert!(client.send(overflow).is_ok(), "Failed to send message");
+    let (code, _) = client.receive_error().expect("Failed to receive error response");
+    assert_eq!(code, ErrorCode::Overflow);
+    assert!(client.send(idempotent_add("overflow", 1)).is_ok(), "Failed to send message");
+    assert_eq!(add_result(&mut client), 2, "A failed request's result was stored");
+    assert!(client.send(idempotent_add("", 1)).is_ok(), "Failed to send message");
+    let (code, _) = client.receive_error().expect("Failed to receive error response");
+    assert_eq!(code, ErrorCode::OutOfRange);
+    for client in [&mut client, &mut retrying] {
+        assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_write_progress_timeout_closes_slow_reader() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Small socket buffers on the server, so a large response can't be handed to the kernel all at once
+    let port = get_unique_port();
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .send_buffer_size(8 * 1024)
+            .write_progress_timeout(Duration::from_millis(300))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let payload = vec![7u8; 60 * 1024];
+    let request = client_message::Message::BinaryEchoMessage(BinaryEchoMessage { payload: payload.clone() });
+
+    // A client reading at full speed gets the whole response
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert!(client.send(request.clone()).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::BinaryEchoMessage(echo)) => assert_eq!(echo.payload, payload),
+        other => panic!("Expected BinaryEchoMessage, got {:?}", other),
+    }
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // A client with a small receive buffer reading 1 KiB every 50 ms keeps the writes moving, but too slowly
+    let socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).expect("Failed to create socket");
+    socket.set_recv_buffer_size(4 * 1024).expect("Failed to set SO_RCVBUF");
+    let addr: std::net::SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+    socket.connect(&addr.into()).expect("Failed to connect to the server");
+    let mut stream: TcpStream = socket.into();
+    let frame = codec::encode_frame(&ClientMessage { message: Some(request) });
+    stream.write_all(&frame).expect("Failed to send request");
+    stream.set_read_timeout(Some(Duration::from_secs(1))).expect("Failed to set read timeout");
+    let started = std::time::Instant::now();
+    let mut received = 0;
+    let mut chunk = [0u8; 1024];
+    while started.elapsed() < Duration::from_secs(5) {
+        match stream.read(&mut chunk) {
+            Ok(0) | Err(_) => break, // Closed by the server
+            Ok(read) => received += read,
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(received < payload.len(), "Slow reader got the whole response");
+    assert!(started.elapsed() < Duration::from_secs(5), "Slow reader was never disconnected");
+    assert_eq!(
+        server.recent_disconnects().last().map(|record| record.reason),
+        Some(DisconnectReason::SlowWrite)
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_max_connections_per_ip() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    assert!(
+        Server::builder("localhost:0").max_connections_per_ip(0).build().is_err(),
+        "A limit of zero connections per IP was accepted"
+    );
+
+    // Every test client connects from the loopback address, so they all count against one IP
+    let port = get_unique_port();
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .max_connections_per_ip(2)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut first = client::Client::new("localhost", port, 1000);
+    assert!(first.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut first, "first");
+    let mut second = client::Client::new("localhost", port, 1000);
+    assert!(second.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut second, "second");
+
+    // The connection over the cap is told why and closed
+    let mut rejected = client::Client::new("localhost", port, 1000);
+    assert!(rejected.connect().is_ok(), "Failed to connect to the server");
+    let (code, message) = rejected.receive_error().expect("Failed to receive the rejection");
+    assert_eq!(code, ErrorCode::AtCapacity, "Unexpected error code");
+    assert_eq!(message, "too many connections from this address");
+    assert!(rejected.receive().is_err(), "Rejected connection was left open");
+    assert_echo(&mut second, "still served");
+
+    // Closing a connection frees its slot once the server notices
+    assert!(first.disconnect().is_ok(), "Failed to disconnect from the server");
+    let mut admitted = None;
+    for _ in 0..20 {
+        let mut client = client::Client::new("localhost", port, 1000);
+        assert!(client.connect().is_ok(), "Failed to connect to the server");
+        let message = client_message::Message::EchoMessage(EchoMessage {
+            content: "third".to_string(),
+        });
+        assert!(client.send(message).is_ok(), "Failed to send message");
+        if let Ok(ServerMessage { message: Some(server_message::Message::EchoMessage(echo)), .. }) = client.receive() {
+            assert_eq!(echo.content, "third");
+            admitted = Some(client);
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    let mut admitted = admitted.expect("Slot of the closed connection was never freed");
+    assert!(admitted.disconnect().is_ok(), "Failed to disconnect from the server");
+    assert!(second.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+// Echoes "slow" only after ten seconds unless the server stops first, recording whether it saw the shutdown
+struct ShutdownAwareEcho {
+    saw_shutdown: Arc<AtomicBool>,
+}
+
+impl MessageHandler for ShutdownAwareEcho {
+    fn handle(&self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)> {
+        DefaultHandler.handle(message)
+    }
+
+    fn handle_cancellable(&self, message: client_message::Message, cancel: &CancellationToken) -> Option<(ServerMessage, CloseAfter)> {
+        if matches!(&message, client_message::Message::EchoMessage(echo) if echo.content == "slow") {
+            let deadline = std::time::Instant::now() + Duration::from_secs(10);
+            while std::time::Instant::now() < deadline {
+                if cancel.is_shutting_down() {
+                    self.saw_shutdown.store(true, Ordering::SeqCst);
+                    return None; // Give up; the server answers for us
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        self.handle(message)
+    }
+}
+
+#[test]
+fn test_stop_cancels_cooperative_handler() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Only the server can cancel here, a token made outside one never shuts down
+    assert!(!CancellationToken::new().is_shutting_down());
+
+    let port = get_unique_port();
+    let saw_shutdown = Arc::new(AtomicBool::new(false));
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .handler(ShutdownAwareEcho {
+                saw_shutdown: Arc::clone(&saw_shutdown),
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", port, 5000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "slow".to_string(),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    thread::sleep(Duration::from_millis(200)); // Let the handler start
+
+    // Stopping doesn't wait out the handler, which gives up and leaves the server to tell the client why
+    let stopping = std::time::Instant::now();
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+    assert!(stopping.elapsed() < Duration::from_secs(5), "Stop waited for the handler to finish");
+    assert!(saw_shutdown.load(Ordering::SeqCst), "Handler never saw the shutdown");
+    let (code, message) = client.receive_error().expect("Failed to receive the shutdown error");
+    assert_eq!(code, ErrorCode::ShuttingDown, "Unexpected error code");
+    assert_eq!(message, "server shutting down");
+}
+
+// The code and detail of the only response `process` gives to `input`
+fn process_error(input: &[u8]) -> (Option<ErrorCode>, String) {
+    match process_responses(input).pop().and_then(|response| response.message) {
+        Some(server_message::Message::ErrorResponse(error)) => (error.error_code(), error.detail),
+        other => panic!("Expected ErrorResponse, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_error_responses_carry_details() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // A response built without a detail has an empty one
+    assert_eq!(ErrorResponse::new(ErrorCode::Timeout, "too slow").detail, "");
+
+    // Requests the default handler and the server answer with an error
+    let overflow = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::AddRequest(AddRequest { a: i32::MAX, b: 1 })),
+    });
+    assert_eq!(
+        process_error(&overflow),
+        (Some(ErrorCode::Overflow), "2147483647 + 1 = 2147483648 is outside -2147483648..=2147483647".to_string())
+    );
+    let empty = codec::encode_frame(&ClientMessage { message: None });
+    assert_eq!(process_error(&empty), (Some(ErrorCode::EmptyMessage), "payload is empty".to_string()));
+    let unknown = [&3u32.to_be_bytes()[..], &[0xf8, 0x06, 0x01]].concat(); // Field 111, which no version defines
+    assert_eq!(
+        process_error(&unknown),
+        (Some(ErrorCode::Unsupported), "3 byte payload has no field this server knows".to_string())
+    );
+    let wrapped_cancel = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::IdempotentRequest(Box::new(IdempotentRequest {
+            key: "cancel".to_string(),
+            request: Some(Box::new(ClientMessage {
+                message: Some(client_message::Message::CancelRequest(CancelRequest { request_id: 1 })),
+            })),
+        }))),
+    });
+    assert_eq!(
+        process_error(&wrapped_cancel),
+        (Some(ErrorCode::Unsupported), "a CancelRequest can only be sent unwrapped".to_string())
+    );
+
+    // Framing and decoding errors that close the connection say what was wrong
+    let (code, detail) = process_error(&u32::MAX.to_be_bytes());
+    assert_eq!(code, Some(ErrorCode::DecodeFailed));
+    assert!(detail.starts_with("declared length "), "Unexpected detail {:?}", detail);
+    let garbage: Vec<u8> = (0..10).flat_map(|_| [&2u32.to_be_bytes()[..], &[0xff, 0xff]].concat()).collect();
+    let (code, detail) = process_error(&garbage);
+    assert_eq!(code, Some(ErrorCode::DecodeFailed));
+    assert!(detail.starts_with("10 consecutive frames failed to decode, the last on stream 0: "), "Unexpected detail {:?}", detail);
+
+    // Limits enforced by a running server report the values involved
+    let port = get_unique_port();
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .max_frame_size(1024)
+            .max_bytes_per_connection(64)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut oversized = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+    oversized.write_all(&4096u32.to_be_bytes()).expect("Failed to send frame header");
+    let frame = codec::read_frame(&mut oversized, codec::DEFAULT_MAX_FRAME_SIZE).expect("Failed to receive the error");
+    match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.error_code(), Some(ErrorCode::DecodeFailed));
+            assert_eq!(error.message, "invalid frame");
+            assert_eq!(error.detail, "declared length 4096 exceeds max 1024");
+        }
+        other => panic!("Expected ErrorResponse, got {:?}", other),
+    }
+    let mut greedy = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+    let request = ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage { content: "x".repeat(100) })),
+    };
+    greedy.write_all(&codec::encode_frame(&request)).expect("Failed to send request");
+    let frame = codec::read_frame(&mut greedy, codec::DEFAULT_MAX_FRAME_SIZE).expect("Failed to receive the error");
+    match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.error_code(), Some(ErrorCode::QuotaExceeded));
+            assert!(error.detail.ends_with("bytes received, quota is 64"), "Unexpected detail {:?}", error.detail);
+        }
+        other => panic!("Expected ErrorResponse, got {:?}", other),
+    }
+
+    // The same goes for a quota crossed partway through an upload
+    let mut uploader = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+    let start = ClientMessage {
+        message: Some(client_message::Message::UploadStart(UploadStart { name: "big.bin".to_string(), size: 100 })),
+    };
+    codec::write_frame(&mut uploader, &start).expect("Failed to send upload start");
+    thread::sleep(Duration::from_millis(100)); // Let the upload begin before the chunk crosses the quota
+    let chunk = ClientMessage {
+        message: Some(client_message::Message::UploadChunk(UploadChunk { data: vec![0; 100], last: true })),
+    };
+    uploader.write_all(&codec::encode_frame(&chunk)).expect("Failed to send upload chunk");
+    let frame = codec::read_frame(&mut uploader, codec::DEFAULT_MAX_FRAME_SIZE).expect("Failed to receive the error");
+    match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.error_code(), Some(ErrorCode::QuotaExceeded));
+            assert!(error.detail.ends_with("bytes received, quota is 64"), "Unexpected detail {:?}", error.detail);
+        }
+        other => panic!("Expected ErrorResponse, got {:?}", other),
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[cfg(feature = "latency")]
+#[test]
+fn test_queue_latency_stats() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .workers(1)
+            .queue_capacity(4)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    assert_eq!(server.queue_latency_stats().count, 0, "Queue latency recorded before any connection");
+
+    // One connection at a time finds the worker free
+    for i in 0..5 {
+        let mut client = client::Client::new("localhost", port, 1000);
+        assert!(client.connect().is_ok(), "Failed to connect to the server");
+        assert_echo(&mut client, &format!("connection {}", i));
+        assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+        thread::sleep(Duration::from_millis(50)); // Let the worker notice the disconnect
+    }
+    let stats = server.queue_latency_stats();
+    assert_eq!(stats.count, 5, "Not every connection was recorded");
+    assert!(stats.p99 < Duration::from_millis(50), "Queue latency without load: {:?}", stats);
+
+    // A connection queued behind a busy worker waits until the worker is free
+    let mut busy = client::Client::new("localhost", port, 1000);
+    assert!(busy.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut busy, "busy");
+    let mut queued = client::Client::new("localhost", port, 2000);
+    assert!(queued.connect().is_ok(), "Failed to connect to the server");
+    thread::sleep(Duration::from_millis(500));
+    assert!(busy.disconnect().is_ok(), "Failed to disconnect from the server");
+    assert_echo(&mut queued, "queued");
+    let stats = server.queue_latency_stats();
+    assert_eq!(stats.count, 7, "Not every connection was recorded");
+    assert!(stats.max >= Duration::from_millis(400), "Queued connection didn't wait: {:?}", stats);
+    assert!(stats.p50 <= stats.p99 && stats.p99 <= stats.max, "Percentiles out of order: {:?}", stats);
+    assert!(queued.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+// Waits for the server to report the end of the connection from `peer`, returning how long that took from `started`
+fn wait_for_disconnect(closed: &mpsc::Receiver<std::net::SocketAddr>, peer: std::net::SocketAddr, started: std::time::Instant) -> Duration {
+    while let Ok(closed_peer) = closed.recv_timeout(Duration::from_secs(5)) {
+        if closed_peer == peer {
+            return started.elapsed();
+        }
+    }
+    panic!("Disconnect of {} was never reported", peer);
+}
+
+#[test]
+fn test_graceful_close_timeout() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    assert!(
+        Server::builder("localhost:0").graceful_close_timeout(Some(Duration::ZERO)).build().is_err(),
+        "A zero graceful close timeout was accepted"
+    );
+
+    // Idle clients are closed after 200ms, then get 800ms to close their side; `on_disconnect` runs once they have
+    let port = get_unique_port();
+    let (sender, closed) = mpsc::channel();
+    let sender = std::sync::Mutex::new(sender); // The callback must be `Sync`
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .on_disconnect(move |stats| {
+                let _ = sender.lock().unwrap().send(stats.peer);
+            })
+            .idle_timeout(Duration::from_millis(200))
+            .graceful_close_timeout(Some(Duration::from_millis(800)))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // A client that reads to the end of stream and closes lets the server finish right away
+    let started = std::time::Instant::now();
+    let mut responsive = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+    let responsive_peer = responsive.local_addr().unwrap();
+    let reader = thread::spawn(move || {
+        let mut buffer = [0; 64];
+        while matches!(responsive.read(&mut buffer), Ok(read) if read > 0) {}
+        // Dropping the stream closes it
+    });
+    let elapsed = wait_for_disconnect(&closed, responsive_peer, started);
+    assert!(elapsed < Duration::from_millis(700), "Server waited {:?} for a client that closed", elapsed);
+    assert!(reader.join().is_ok(), "Reader thread panicked");
+
+    // A client that never closes holds the connection for the timeout, then has it reset
+    let started = std::time::Instant::now();
+    let mut unresponsive = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+    let unresponsive_peer = unresponsive.local_addr().unwrap();
+    let elapsed = wait_for_disconnect(&closed, unresponsive_peer, started);
+    assert!(elapsed >= Duration::from_millis(900), "Server didn't wait for the client to close: {:?}", elapsed);
+    thread::sleep(Duration::from_millis(200)); // Let the reset arrive
+    assert!(unresponsive.write_all(b"late").is_err(), "Connection was closed without a reset");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_echo_metadata_describes_connection() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+    let server = create_server(port);
+    let handle = setup_server_thread(server.clone());
+
+    // Raw socket, so the bytes sent and received are known exactly
+    let mut stream = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+    let echo = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage { content: "hello".to_string() })),
+    });
+    stream.write_all(&echo).expect("Failed to send request");
+    let echoed = codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Failed to receive response");
+    thread::sleep(Duration::from_millis(100));
+    let request = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMetadataRequest(EchoMetadataRequest {})),
+    });
+    stream.write_all(&request).expect("Failed to send request");
+    let frame = codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Failed to receive response");
+    let metadata = match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+        Some(server_message::Message::EchoMetadataResponse(metadata)) => metadata,
+        other => panic!("Expected EchoMetadataResponse, got {:?}", other),
+    };
+
+    // The server's view matches what this side knows about the connection
+    assert_eq!(metadata.peer, stream.local_addr().unwrap().to_string());
+    assert_eq!(metadata.bytes_in, (echo.len() + request.len()) as u64);
+    assert_eq!(metadata.bytes_out, echoed.encode().len() as u64);
+    assert_eq!(metadata.messages_handled, 2);
+    assert!(metadata.connected_ms >= 100, "Connection age too low: {}ms", metadata.connected_ms);
+    let snapshot = server.snapshot();
+    let open = snapshot
+        .connections
+        .iter()
+        .find(|connection| connection.peer == stream.local_addr().unwrap())
+        .expect("Connection missing from the snapshot");
+    assert_eq!(metadata.connection_id, open.connection_id);
+
+    // Nothing about the connection changed by asking
+    assert_eq!(server.metrics().count(MessageType::EchoMetadataRequest, Outcome::Ok), 1);
+    let again = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage { content: "again".to_string() })),
+    });
+    stream.write_all(&again).expect("Failed to send request");
+    assert!(codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).is_ok(), "Connection stopped answering");
+    drop(stream);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+// Echoes text back with a prefix, so a response is a few bytes longer than its request
+struct PrefixedEcho;
+
+impl MessageHandler for PrefixedEcho {
+    fn handle(&self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)> {
+        match message {
+            client_message::Message::EchoMessage(echo) => DefaultHandler.handle(client_message::Message::EchoMessage(EchoMessage {
+                content: format!("echo: {}", echo.content),
+            })),
+            other => DefaultHandler.handle(other),
+        }
+    }
+}
+
+#[test]
+fn test_response_over_frame_limit_is_refused() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    const MAX_FRAME_SIZE: usize = 1024;
+    assert!(
+        Server::builder("localhost:0")
+            .max_frame_size(MAX_FRAME_SIZE)
+            .greeting(ServerMessage {
+                message: Some(server_message::Message::Greeting(Greeting {
+                    banner: "x".repeat(MAX_FRAME_SIZE),
+                    server_version: String::new(),
+                })),
+                load: None,
+            })
+            .build()
+            .is_err(),
+        "A greeting over the frame limit was accepted"
+    );
+
+    let port = get_unique_port();
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .max_frame_size(MAX_FRAME_SIZE)
+            .handler(PrefixedEcho)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+    // The longest content whose response still fits, and one byte more
+    let response_size = |content: &str| {
+        ServerMessage {
+            message: Some(server_message::Message::EchoMessage(EchoMessage { content: format!("echo: {}", content) })),
+            load: None,
+        }
+        .encoded_len()
+    };
+    let mut fitting = String::new();
+    while response_size(&format!("{}x", fitting)) <= MAX_FRAME_SIZE {
+        fitting.push('x');
+    }
+    let over = format!("{}x", fitting);
+    match client.echo(&fitting, Duration::from_secs(1)) {
+        Ok(echoed) => assert_eq!(echoed, format!("echo: {}", fitting)),
+        Err(e) => panic!("Response at the frame limit was refused: {}", e),
+    }
+    let message = client_message::Message::EchoMessage(EchoMessage { content: over.clone() });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    let (code, message) = client.receive_error().expect("Failed to receive the error");
+    assert_eq!(code, ErrorCode::OutOfRange, "Unexpected error code");
+    assert_eq!(message, "response too large");
+    assert_eq!(server.metrics().count(MessageType::EchoMessage, Outcome::Error), 1);
+
+    // The connection carries on
+    assert_eq!(client.echo("short", Duration::from_secs(1)).expect("Failed to echo"), "echo: short");
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_drain_metrics_partitions_requests() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    const REQUESTS: u64 = 200;
+    let port = get_unique_port();
+    let server = create_server(port);
+    let handle = setup_server_thread(server.clone());
+
+    // A client keeps sending while the metrics are drained over and over
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let sender = thread::spawn(move || {
+        for i in 0..REQUESTS {
+            assert_echo(&mut client, &format!("request {}", i));
+            if i % 10 == 0 {
+                assert!(client.add(i as i32, 1, Duration::from_secs(1)).is_ok(), "Failed to add");
+            }
+            if i == REQUESTS / 2 {
+                thread::sleep(Duration::from_millis(50)); // A fast machine could otherwise finish within one window
+            }
+        }
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+    });
+    let mut drains = Vec::new();
+    while !sender.is_finished() {
+        drains.push(server.drain_metrics());
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert!(sender.join().is_ok(), "Sender thread panicked");
+    thread::sleep(Duration::from_millis(100)); // The last request is counted once its response is written
+    drains.push(server.drain_metrics());
+
+    // Every request shows up in exactly one window
+    let echoes: u64 = drains.iter().map(|drain| drain.count(MessageType::EchoMessage, Outcome::Ok)).sum();
+    let adds: u64 = drains.iter().map(|drain| drain.count(MessageType::AddRequest, Outcome::Ok)).sum();
+    assert_eq!(echoes, REQUESTS, "Echoes lost or counted twice");
+    assert_eq!(adds, REQUESTS / 10, "Adds lost or counted twice");
+    assert_eq!(drains.iter().map(|drain| drain.total()).sum::<u64>(), REQUESTS + REQUESTS / 10);
+    assert!(drains.iter().filter(|drain| drain.total() > 0).count() > 1, "Every request landed in one window");
+
+    // Draining left the counters at zero
+    assert_eq!(server.metrics().count(MessageType::EchoMessage, Outcome::Ok), 0);
+    assert_eq!(server.drain_metrics().total(), 0);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+// Wraps `message` in a `DeadlineRequest` expiring `offset_ms` from now, negative for one already past
+fn with_deadline(message: client_message::Message, offset_ms: i64) -> client_message::Message {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as i64;
+    client_message::Message::DeadlineRequest(Box::new(DeadlineRequest {
+        deadline_unix_ms: (now + offset_ms) as u64,
+        request: Some(Box::new(ClientMessage { message: Some(message) })),
+    }))
+}
+
+// Echoes "slow" only after five seconds unless cancelled first, recording whether it saw its deadline pass
+struct DeadlineAwareEcho {
+    saw_deadline: Arc<AtomicBool>,
+}
+
+impl MessageHandler for DeadlineAwareEcho {
+    fn handle(&self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)> {
+        DefaultHandler.handle(message)
+    }
+
+    fn handle_cancellable(&self, message: client_message::Message, cancel: &CancellationToken) -> Option<(ServerMessage, CloseAfter)> {
+        if matches!(&message, client_message::Message::EchoMessage(echo) if echo.content == "slow") {
+            let deadline = std::time::Instant::now() + Duration::from_secs(5);
+            while std::time::Instant::now() < deadline {
+                if cancel.is_cancelled() {
+                    self.saw_deadline.store(cancel.is_past_deadline(), Ordering::SeqCst);
+                    return None; // Give up; the server answers for us
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        self.handle(message)
+    }
+}
+
+#[test]
+fn test_request_deadlines() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+    let saw_deadline = Arc::new(AtomicBool::new(false));
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .handler(DeadlineAwareEcho {
+                saw_deadline: Arc::clone(&saw_deadline),
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let mut client = client::Client::new("localhost", port, 2000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let echo = |content: &str| client_message::Message::EchoMessage(EchoMessage { content: content.to_string() });
+
+    // A request whose deadline already passed is answered without being handled
+    assert!(client.send(with_deadline(echo("late"), -1000)).is_ok(), "Failed to send message");
+    let (code, message) = client.receive_error().expect("Failed to receive the error");
+    assert_eq!(code, ErrorCode::Timeout, "Unexpected error code");
+    assert_eq!(message, "deadline exceeded");
+    assert_eq!(server.metrics().count(MessageType::DeadlineRequest, Outcome::Error), 1);
+
+    // One-way requests past their deadline are dropped silently, so the next response is the next request's
+    let event = client_message::Message::LogEvent(LogEvent { message: "late".to_string() });
+    assert!(client.send(with_deadline(event, -1000)).is_ok(), "Failed to send message");
+
+    // With time to spare the wrapped request is answered as usual
+    assert!(client.send(with_deadline(echo("in time"), 5000)).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::EchoMessage(echoed)) => assert_eq!(echoed.content, "in time"),
+        other => panic!("Expected EchoMessage, got {:?}", other),
+    }
+
+    // A handler still running at the deadline sees it through its token and gives up
+    let started = std::time::Instant::now();
+    assert!(client.send(with_deadline(echo("slow"), 300)).is_ok(), "Failed to send message");
+    let (code, _) = client.receive_error().expect("Failed to receive the error");
+    assert_eq!(code, ErrorCode::Timeout, "Unexpected error code");
+    assert!(started.elapsed() < Duration::from_secs(2), "Handler ran past its deadline: {:?}", started.elapsed());
+    assert!(saw_deadline.load(Ordering::SeqCst), "Handler never saw the deadline pass");
+
+    // Uploads span frames, so they can't be given a deadline
+    let upload = client_message::Message::UploadStart(UploadStart { name: "file".to_string(), size: 0 });
+    assert!(client.send(with_deadline(upload, 5000)).is_ok(), "Failed to send message");
+    let (code, _) = client.receive_error().expect("Failed to receive the error");
+    assert_eq!(code, ErrorCode::Unsupported, "Unexpected error code");
+
+    assert!(
+        client.disconnect().is_ok(),
+        "Failed to disconnect from the server"
+    );
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_servers_share_metrics() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Two endpoints counting into the same aggregate
+    let aggregate = Arc::new(Metrics::new());
+    let ports = [get_unique_port(), get_unique_port()];
+    let servers: Vec<Arc<Server>> = ports
+        .iter()
+        .map(|port| {
+            Arc::new(
+                Server::builder(&format!("localhost:{}", port))
+                    .shared_metrics(Arc::clone(&aggregate))
+                    .build()
+                    .expect("Failed to start server"),
+            )
+        })
+        .collect();
+    let handles: Vec<JoinHandle<()>> = servers.iter().map(|server| setup_server_thread(server.clone())).collect();
+
+    // Three echoes on the first server, two on the second, and an add on each
+    for (port, echoes) in ports.iter().zip([3, 2]) {
+        let mut client = client::Client::new("localhost", *port, 1000);
+        assert!(client.connect().is_ok(), "Failed to connect to the server");
+        for i in 0..echoes {
+            assert_echo(&mut client, &format!("request {}", i));
+        }
+        assert_eq!(client.add(1, 2, Duration::from_secs(1)).expect("Failed to add"), 3);
+        assert!(
+            client.disconnect().is_ok(),
+            "Failed to disconnect from the server"
+        );
+    }
+    thread::sleep(Duration::from_millis(100)); // The last request is counted once its response is written
+
+    // Each server counts its own requests, the aggregate counts both
+    assert_eq!(servers[0].metrics().count(MessageType::EchoMessage, Outcome::Ok), 3);
+    assert_eq!(servers[1].metrics().count(MessageType::EchoMessage, Outcome::Ok), 2);
+    assert_eq!(aggregate.count(MessageType::EchoMessage, Outcome::Ok), 5);
+    assert_eq!(aggregate.count(MessageType::AddRequest, Outcome::Ok), 2);
+
+    // Draining one server leaves the aggregate alone
+    assert_eq!(servers[0].drain_metrics().total(), 4);
+    assert_eq!(aggregate.count(MessageType::EchoMessage, Outcome::Ok), 5);
+    assert_eq!(aggregate.drain().total(), 7);
+
+    // Stop the servers and wait for threads to finish
+    for (server, handle) in servers.iter().zip(handles) {
+        server.stop();
+        assert!(
+            handle.join().is_ok(),
+            "Server thread panicked or failed to join"
+        );
+    }
+}
+
+#[test]
+fn test_length_prefix_split_across_reads() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // The buffer holds back a frame until every byte of its header and payload is in, whatever the split
+    let request = ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage { content: "split".to_string() })),
+    };
+    for bytes in [codec::encode_frame(&request), codec::encode_stream_frame(7, &request)] {
+        let mut frames = codec::FrameBuffer::new(codec::DEFAULT_MAX_FRAME_SIZE);
+        let (last, rest) = bytes.split_last().unwrap();
+        for byte in rest {
+            frames.extend(&[*byte]);
+            assert_eq!(frames.next_frame(), Ok(None), "Frame returned before it was complete");
+        }
+        frames.extend(&[*last]);
+        let frame = frames.next_frame().expect("Valid frame rejected").expect("Complete frame not returned");
+        assert_eq!(ClientMessage::decode(frame.payload.as_slice()).unwrap(), request);
+        assert!(frames.is_empty(), "Bytes left over after the frame");
+    }
+
+    let port = get_unique_port();
+    let server = create_server(port);
+    let handle = setup_server_thread(server.clone());
+
+    // Over a socket, each byte of the prefix goes out in its own segment, then the body, then a second request
+    // pipelined behind the first so the next prefix starts mid-read
+    let mut stream = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+    stream.set_nodelay(true).expect("Failed to disable Nagle"); // Or the kernel would coalesce the bytes again
+    let first = codec::encode_frame(&request);
+    for byte in &first[..4] {
+        stream.write_all(&[*byte]).expect("Failed to send prefix byte");
+        thread::sleep(Duration::from_millis(50));
+    }
+    let second = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage { content: "second".to_string() })),
+    });
+    stream.write_all(&[&first[4..], &second[..2]].concat()).expect("Failed to send body");
+    thread::sleep(Duration::from_millis(50));
+    stream.write_all(&second[2..]).expect("Failed to send the rest");
+    for expected in ["split", "second"] {
+        let frame = codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Failed to receive response");
+        match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+            Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, expected),
+            other => panic!("Expected EchoMessage, got {:?}", other),
+        }
+    }
+    drop(stream);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_overflow_policies() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Sums past either end of the range, and one that fits, under each policy
+    let cases = [
+        (OverflowPolicy::Error, None, None),
+        (OverflowPolicy::Saturate, Some(i32::MAX), Some(i32::MIN)),
+        (OverflowPolicy::Wrap, Some(i32::MIN), Some(i32::MAX)),
+    ];
+    for (policy, above, below) in cases {
+        let port = get_unique_port();
+        let server = Arc::new(
+            Server::builder(&format!("localhost:{}", port))
+                .handler(BuiltinHandler::new(policy))
+                .build()
+                .expect("Failed to start server"),
+        );
+        let handle = setup_server_thread(server.clone());
+
+        let mut client = client::Client::new("localhost", port, 1000);
+        assert!(client.connect().is_ok(), "Failed to connect to the server");
+        for ((a, b), expected) in [((i32::MAX, 1), above), ((i32::MIN, -1), below)] {
+            match expected {
+                Some(expected) => {
+                    assert_eq!(client.add(a, b, Duration::from_secs(2)).expect("Add failed"), expected, "{:?}", policy)
+                }
+                None => {
+                    assert!(client.send(client_message::Message::AddRequest(AddRequest { a, b })).is_ok());
+                    let (code, _) = client.receive_error().expect("Expected an overflow error");
+                    assert_eq!(code, ErrorCode::Overflow, "{:?}", policy);
+                }
+            }
+        }
+        assert_eq!(client.add(20, 22, Duration::from_secs(2)).expect("Add failed"), 42, "{:?}", policy);
+        assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+
+        // Stop the server and wait for thread to finish
+        server.stop();
+        assert!(
+            handle.join().is_ok(),
+            "Server thread panicked or failed to join"
+        );
+    }
+
+    // The default handler keeps to the default policy
+    assert_eq!(OverflowPolicy::default(), OverflowPolicy::Error);
+    match DefaultHandler.handle(client_message::Message::AddRequest(AddRequest { a: i32::MAX, b: 1 })) {
+        Some((ServerMessage { message: Some(server_message::Message::ErrorResponse(error)), .. }, _)) => {
+            assert_eq!(error.error_code(), Some(ErrorCode::Overflow))
+        }
+        other => panic!("Expected ErrorResponse, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_reload_config_at_runtime() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+    let server = create_server(port);
+    let handle = setup_server_thread(server.clone());
+    assert_eq!(server.tunables(), Tunables::default());
+
+    // Echoes are unlimited until a reload caps them; the connection already open counts what it was sent before
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut client, "hello");
+    let tunables = Tunables {
+        max_echo_bytes: Some(8),
+        poll_interval: (Duration::from_millis(10), Duration::from_millis(200)),
+        ..server.tunables()
+    };
+    assert!(server.reload_config(tunables).is_ok(), "Valid configuration rejected");
+    assert_eq!(server.tunables(), tunables);
+    assert!(client.send(client_message::Message::EchoMessage(EchoMessage { content: "hello".to_string() })).is_ok());
+    let (code, _) = client.receive_error().expect("Expected the echo to be refused");
+    assert_eq!(code, ErrorCode::QuotaExceeded);
+    assert_eq!(client.add(20, 22, Duration::from_secs(2)).expect("Add failed"), 42);
+
+    // New connections get the new limit from the start
+    let mut other = client::Client::new("localhost", port, 1000);
+    assert!(other.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(other.echo("12345678", Duration::from_secs(2)).expect("Echo failed"), "12345678");
+    assert!(other.send(client_message::Message::EchoMessage(EchoMessage { content: "!".to_string() })).is_ok());
+    assert_eq!(other.receive_error().expect("Expected the echo to be refused").0, ErrorCode::QuotaExceeded);
+
+    // Values the builder would refuse leave the configuration as it was
+    let invalid = Tunables {
+        max_frames_per_iteration: 0,
+        ..server.tunables()
+    };
+    let error = server.reload_config(invalid).expect_err("Invalid configuration accepted");
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    assert_eq!(server.tunables(), tunables);
+
+    // Lifting the cap serves echoes of any size again, even to a connection that was refused
+    assert!(server.reload_config(Tunables { max_echo_bytes: None, ..tunables }).is_ok());
+    assert_echo(&mut client, "a longer message than eight bytes");
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+    assert!(other.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_decode_error_spike_fires_callback() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // More than three undecodable frames within the window, across all connections, set off the alert and pause
+    let port = get_unique_port();
+    let (tx, rx) = mpsc::channel();
+    let tx = std::sync::Mutex::new(tx);
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .on_decode_error_spike(3, Duration::from_secs(10), move |total| {
+                let _ = tx.lock().unwrap().send(total);
+            })
+            .pause_on_decode_error_spike()
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Two bad frames on each of two connections: neither reaches its own limit, but together they cross the threshold
+    let garbage = [&2u32.to_be_bytes()[..], &[0xff, 0xff]].concat();
+    let mut streams: Vec<TcpStream> = (0..2)
+        .map(|_| TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server"))
+        .collect();
+    streams[0].write_all(&garbage.repeat(2)).expect("Failed to send garbage");
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(rx.try_recv(), Err(TryRecvError::Empty), "Alert fired below the threshold");
+    assert_eq!(server.decode_errors(), 2);
+    streams[1].write_all(&garbage.repeat(2)).expect("Failed to send garbage");
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).expect("Alert never fired"), 4);
+    assert_eq!(server.decode_errors(), 4);
+
+    // The connections stay open, but new ones are closed until the server resumes
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert!(client.set_read_timeout(Some(Duration::from_secs(2))).is_ok());
+    let _ = client.send(client_message::Message::EchoMessage(EchoMessage { content: "refused".to_string() }));
+    assert!(client.receive().is_err(), "Paused server served a new connection");
+    assert!(server.snapshot().paused, "Server was not paused");
+    server.resume();
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut client, "after resume");
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+    drop(streams);
+
+    // A zero window is refused, as is pausing without a threshold
+    let result = Server::builder("localhost:0").on_decode_error_spike(3, Duration::ZERO, |_| {}).build();
+    assert_eq!(result.err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidInput));
+    let result = Server::builder("localhost:0").pause_on_decode_error_spike().build();
+    assert_eq!(result.err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidInput));
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_echo_over_max_length_is_refused() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .max_echo_len(16)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Content at the limit is echoed; one byte more, text or binary, gets an error and the connection stays open
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut client, &"a".repeat(16));
+    let message = client_message::Message::EchoMessage(EchoMessage {
+        content: "a".repeat(17),
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    assert_eq!(client.receive_error().expect("Expected an error").0, ErrorCode::OutOfRange);
+    let message = client_message::Message::BinaryEchoMessage(BinaryEchoMessage {
+        payload: vec![0; 17],
+    });
+    assert!(client.send(message).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.error_code(), Some(ErrorCode::OutOfRange));
+            assert_eq!(error.detail, "echo of 17 bytes exceeds max echo length 16");
+        }
+        other => panic!("Expected ErrorResponse, got {:?}", other),
+    }
+    assert_echo(&mut client, "still served");
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    // A zero limit would refuse every echo
+    let result = Server::builder("localhost:0").max_echo_len(0).build();
+    assert_eq!(result.err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidInput));
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_idle_reaper_closes_idle_connections() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    assert!(
+        Server::builder("localhost:0").idle_reaper(Duration::ZERO).build().is_err(),
+        "A zero reaper interval was accepted"
+    );
+
+    // Workers read with a 1s timeout, so only the reaper can close a client 300ms after it went quiet
+    let port = get_unique_port();
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .idle_timeout(Duration::from_millis(300))
+            .idle_reaper(Duration::from_millis(100))
+            .adaptive_polling(Duration::from_secs(1), Duration::from_secs(4))
+            .graceful_close_timeout(None)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut idle = client::Client::new("localhost", port, 1000);
+    assert!(idle.connect().is_ok(), "Failed to connect to the server");
+    let mut chatty = client::Client::new("localhost", port, 1000);
+    assert!(chatty.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut idle, "then quiet");
+    let started = std::time::Instant::now();
+
+    // A client that keeps talking is left alone while the quiet one is reaped
+    while started.elapsed() < Duration::from_millis(700) {
+        assert_echo(&mut chatty, "still here");
+        thread::sleep(Duration::from_millis(100));
+    }
+    let recent = server.recent_disconnects();
+    assert_eq!(recent.len(), 1, "Expected only the idle client to be reaped: {:?}", recent);
+    assert_eq!(recent[0].reason, DisconnectReason::IdleTimeout);
+    assert!(idle.receive().is_err(), "Reaped connection stayed open");
+    assert_eq!(server.snapshot().connections.len(), 1);
+    assert!(chatty.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_idle_reaper_spares_streaming_uploads() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .handler(VerifyingUpload)
+            .idle_timeout(Duration::from_millis(300))
+            .idle_reaper(Duration::from_millis(100))
+            .graceful_close_timeout(None)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let connect = || {
+        let stream = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(3)))
+            .expect("Failed to set read timeout");
+        stream
+    };
+    let start = |size: u64| ClientMessage {
+        message: Some(client_message::Message::UploadStart(UploadStart { name: "slow.bin".to_string(), size })),
+    };
+
+    // An upload that keeps sending chunks for well over the idle timeout is never reaped
+    const CHUNK: u64 = 1024;
+    const CHUNKS: u64 = 10;
+    let mut streaming = connect();
+    codec::write_frame(&mut streaming, &start(CHUNK * CHUNKS)).expect("Failed to send upload start");
+    for index in 0..CHUNKS {
+        thread::sleep(Duration::from_millis(100));
+        let offset = index * CHUNK;
+        let chunk = ClientMessage {
+            message: Some(client_message::Message::UploadChunk(UploadChunk {
+                data: (offset..offset + CHUNK).map(upload_byte).collect(),
+                last: index + 1 == CHUNKS,
+            })),
+        };
+        codec::write_frame(&mut streaming, &chunk).expect("Failed to send upload chunk");
+    }
+    let frame = codec::read_frame(&mut streaming, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing upload response");
+    match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+        Some(server_message::Message::UploadResponse(upload)) => assert_eq!(upload.bytes_received, CHUNK * CHUNKS),
+        other => panic!("Expected UploadResponse, got {:?}", other),
+    }
+    assert!(server.recent_disconnects().is_empty(), "Streaming upload was reaped");
+
+    // One that goes quiet after its start is reaped as idle, not as a client that closed mid-frame
+    let mut quiet = connect();
+    codec::write_frame(&mut quiet, &start(CHUNK)).expect("Failed to send upload start");
+    assert!(matches!(quiet.read(&mut [0; 16]), Ok(0) | Err(_)), "Quiet upload was not reaped");
+    let started = std::time::Instant::now();
+    while server.recent_disconnects().is_empty() && started.elapsed() < Duration::from_secs(2) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let recent = server.recent_disconnects();
+    assert_eq!(recent.len(), 1, "Expected only the quiet upload to be reaped: {:?}", recent);
+    assert_eq!(recent[0].reason, DisconnectReason::IdleTimeout);
+    drop(streaming);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_rate_limit_per_message_type() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Adds are limited to a burst of two and one per second after that; echoes are unlimited
+    let port = get_unique_port();
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .rate_limit(MessageType::AddRequest, 1, 2)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(client.add(1, 2, Duration::from_secs(2)).expect("Add failed"), 3);
+    assert_eq!(client.add(3, 4, Duration::from_secs(2)).expect("Add failed"), 7);
+    assert!(client.send(client_message::Message::AddRequest(AddRequest { a: 5, b: 6 })).is_ok());
+    assert_eq!(client.receive_error().expect("Expected the add to be limited").0, ErrorCode::RateLimited);
+
+    // The limited type doesn't hold back any other
+    for index in 0..20 {
+        assert_echo(&mut client, &format!("echo {}", index));
+    }
+
+    // Wrapping a request doesn't get it past its type's limit
+    let wrapped = client_message::Message::IdempotentRequest(Box::new(IdempotentRequest {
+        key: "wrapped".to_string(),
+        request: Some(Box::new(ClientMessage {
+            message: Some(client_message::Message::AddRequest(AddRequest { a: 1, b: 1 })),
+        })),
+    }));
+    assert!(client.send(wrapped).is_ok());
+    assert_eq!(client.receive_error().expect("Expected the add to be limited").0, ErrorCode::RateLimited);
+
+    // Each connection has its own allowance, and it refills over time
+    let mut other = client::Client::new("localhost", port, 1000);
+    assert!(other.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(other.add(20, 22, Duration::from_secs(2)).expect("Add failed"), 42);
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(client.add(5, 6, Duration::from_secs(2)).expect("Add failed"), 11);
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+    assert!(other.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    // Uploads and empty limits are refused
+    let result = Server::builder("localhost:0").rate_limit(MessageType::UploadStart, 1, 1).build();
+    assert_eq!(result.err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidInput));
+    let result = Server::builder("localhost:0").rate_limit(MessageType::EchoMessage, 0, 1).build();
+    assert_eq!(result.err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidInput));
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+/// Reads responses until the `CloseNotice` the server sends before closing, returning its code
+fn read_close_code(stream: &mut TcpStream) -> CloseCode {
+    stream.set_read_timeout(Some(Duration::from_secs(5))).expect("Failed to set read timeout");
+    loop {
+        let frame = codec::read_frame(stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Connection closed without a notice");
+        if let Some(server_message::Message::CloseNotice(notice)) = ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+            let code = notice.close_code().expect("Unknown close code");
+            assert!(codec::read_frame(stream, codec::DEFAULT_MAX_FRAME_SIZE).is_err(), "Frame sent after the close notice");
+            return code;
+        }
+    }
+}
+
+#[test]
+fn test_close_notices_carry_the_reason() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let port = get_unique_port();
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .handler(GoodbyeHandler)
+            .close_notices()
+            .max_frame_size(1024)
+            .max_decode_errors(2)
+            .max_bytes_per_connection(4096)
+            .idle_timeout(Duration::from_millis(500))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let connect = || TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+    let echo = |content: &str| {
+        codec::encode_frame(&ClientMessage {
+            message: Some(client_message::Message::EchoMessage(EchoMessage { content: content.to_string() })),
+        })
+    };
+
+    // The handler asks to close after its response
+    let mut stream = connect();
+    stream.write_all(&echo("goodbye")).expect("Failed to send request");
+    assert_eq!(read_close_code(&mut stream), CloseCode::NormalClose);
+
+    // Frames that don't decode, and one over the size limit
+    let mut stream = connect();
+    stream.write_all(&[&2u32.to_be_bytes()[..], &[0xff, 0xff]].concat().repeat(2)).expect("Failed to send garbage");
+    assert_eq!(read_close_code(&mut stream), CloseCode::ProtocolError);
+    let mut stream = connect();
+    stream.write_all(&2048u32.to_be_bytes()).expect("Failed to send header");
+    assert_eq!(read_close_code(&mut stream), CloseCode::TooBig);
+
+    // Going over the byte quota breaks the server's policy
+    let mut stream = connect();
+    for _ in 0..8 {
+        let _ = stream.write_all(&echo(&"q".repeat(1000))); // The server may close before the last ones
+    }
+    assert_eq!(read_close_code(&mut stream), CloseCode::PolicyViolation);
+
+    // Saying nothing until the idle timeout
+    let mut stream = connect();
+    assert_eq!(read_close_code(&mut stream), CloseCode::Timeout);
+
+    // Stopping the server closes the clients still connected
+    let mut stream = connect();
+    stream.write_all(&echo("waiting")).expect("Failed to send request");
+    let frame = codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Failed to receive response");
+    assert!(matches!(
+        ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message,
+        Some(server_message::Message::EchoMessage(_))
+    ));
+    server.stop();
+    assert_eq!(read_close_code(&mut stream), CloseCode::ServerShutdown);
+
+    drop(stream);
+
+    // Nothing is sent once the client has closed, or after the socket was shut down
+    assert_eq!(DisconnectReason::ClientClosed.close_code(), None);
+    assert_eq!(DisconnectReason::Kicked.close_code(), None);
+    assert_eq!(CloseCode::from_code(CloseCode::TooBig.code()), Some(CloseCode::TooBig));
+
+    // Wait for the server thread to finish
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_max_total_buffered_bytes_holds_across_connections() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    assert!(
+        Server::builder("localhost:0").max_frame_size(1024).max_total_buffered_bytes(512).build().is_err(),
+        "A budget smaller than one frame was accepted"
+    );
+
+    // Eight clients each send most of a 16 KiB frame, together far more than the 32 KiB budget
+    let port = get_unique_port();
+    let budget = 32 * 1024;
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .max_frame_size(16 * 1024)
+            .max_total_buffered_bytes(budget)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    let partial = [&(16 * 1024u32).to_be_bytes()[..], &[0u8; 15 * 1024]].concat();
+    let streams: Vec<TcpStream> = (0..8)
+        .map(|_| {
+            let mut stream = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+            stream.write_all(&partial).expect("Failed to send partial frame");
+            stream
+        })
+        .collect();
+
+    // The server reads until the budget is used up, and never past it
+    let started = std::time::Instant::now();
+    let mut peak = 0;
+    while started.elapsed() < Duration::from_secs(1) {
+        let buffered = server.buffered_bytes();
+        assert!(buffered <= budget, "{} bytes buffered with a budget of {}", buffered, budget);
+        peak = peak.max(buffered);
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(peak > budget / 2, "Server read only {} bytes of the budget", peak);
+    let held: usize = server.snapshot().connections.iter().map(|connection| connection.peak_buffered_bytes).sum();
+    assert!(held <= budget, "Connections held {} bytes with a budget of {}", held, budget);
+
+    // Closed connections give their share back, and the server still serves
+    drop(streams);
+    let started = std::time::Instant::now();
+    while server.buffered_bytes() > 0 && started.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(server.buffered_bytes(), 0, "Budget not returned by closed connections");
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut client, "after the flood");
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_max_total_buffered_bytes_holds_during_uploads() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Eight uploads each send most of a 14 KiB chunk, together far more than the 32 KiB budget
+    let port = get_unique_port();
+    let budget = 32 * 1024;
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .handler(VerifyingUpload)
+            .max_frame_size(16 * 1024)
+            .max_total_buffered_bytes(budget)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+    const SIZE: u64 = 14 * 1024;
+    let start = ClientMessage {
+        message: Some(client_message::Message::UploadStart(UploadStart { name: "budgeted.bin".to_string(), size: SIZE })),
+    };
+    let chunk = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::UploadChunk(UploadChunk {
+            data: (0..SIZE).map(upload_byte).collect(),
+            last: true,
+        })),
+    });
+    let (head, tail) = chunk.split_at(chunk.len() - 100);
+    let mut streams: Vec<TcpStream> = (0..8)
+        .map(|_| {
+            let mut stream = TcpStream::connect(format!("localhost:{}", port)).expect("Failed to connect to the server");
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .expect("Failed to set read timeout");
+            codec::write_frame(&mut stream, &start).expect("Failed to send upload start");
+            stream.write_all(head).expect("Failed to send partial chunk");
+            stream
+        })
+        .collect();
+
+    // The uploads read until the budget is used up, and never past it
+    let started = std::time::Instant::now();
+    let mut peak = 0;
+    while started.elapsed() < Duration::from_secs(1) {
+        let buffered = server.buffered_bytes();
+        assert!(buffered <= budget, "{} bytes buffered with a budget of {}", buffered, budget);
+        peak = peak.max(buffered);
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(peak > budget / 2, "Uploads read only {} bytes of the budget", peak);
+    let held: usize = server.snapshot().connections.iter().map(|connection| connection.peak_buffered_bytes).sum();
+    assert!(held <= budget, "Uploads held {} bytes with a budget of {}", held, budget);
+
+    // Finished chunks give their share back, so every upload completes in turn
+    for stream in &mut streams {
+        stream.write_all(tail).expect("Failed to send the rest of the chunk");
+    }
+    for stream in &mut streams {
+        let frame = codec::read_frame(stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing upload response");
+        match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+            Some(server_message::Message::UploadResponse(upload)) => assert_eq!(upload.bytes_received, SIZE),
+            other => panic!("Expected UploadResponse, got {:?}", other),
+        }
+    }
+    assert_eq!(server.buffered_bytes(), 0, "Budget not returned by finished uploads");
+    drop(streams);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+/// `CountingAdder` that lets its sums be cached
+struct PureAdder {
+    adds: Arc<AtomicU32>,
+}
+
+impl MessageHandler for PureAdder {
+    fn handle(&self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)> {
+        CountingAdder { adds: Arc::clone(&self.adds) }.handle(message)
+    }
+
+    fn is_pure(&self, message: &client_message::Message) -> bool {
+        matches!(message, client_message::Message::AddRequest(_))
+    }
+}
+
+#[test]
+fn test_result_cache_answers_repeated_requests() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Set up the server in a separate thread
+    let port = get_unique_port();
+    let adds = Arc::new(AtomicU32::new(0));
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .handler(PureAdder { adds: Arc::clone(&adds) })
+            .result_cache(16, Duration::from_millis(500))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // A repeated add, even on another connection, is answered without reaching the handler
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(client.add(1, 2, Duration::from_secs(1)).expect("Failed to add"), 3);
+    assert_eq!(client.add(1, 2, Duration::from_secs(1)).expect("Failed to add"), 3);
+    let mut other = client::Client::new("localhost", port, 1000);
+    assert!(other.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(other.add(1, 2, Duration::from_secs(1)).expect("Failed to add"), 3);
+    assert_eq!(adds.load(Ordering::SeqCst), 1, "Repeated add reached the handler");
+
+    // Other operands run, and so does the first add once its response expired
+    assert_eq!(client.add(2, 2, Duration::from_secs(1)).expect("Failed to add"), 4);
+    assert_eq!(adds.load(Ordering::SeqCst), 2);
+    thread::sleep(Duration::from_millis(600));
+    assert_eq!(client.add(1, 2, Duration::from_secs(1)).expect("Failed to add"), 3);
+    assert_eq!(adds.load(Ordering::SeqCst), 3);
+
+    // Errors aren't stored, and replacing the handler forgets what the old one answered
+    for _ in 0..2 {
+        assert!(client.send(client_message::Message::AddRequest(AddRequest { a: i32::MAX, b: 1 })).is_ok(), "Failed to send message");
+        let (code, _) = client.receive_error().expect("Failed to receive error response");
+        assert_eq!(code, ErrorCode::Overflow);
+    }
+    assert_eq!(adds.load(Ordering::SeqCst), 5, "A failed add was answered from the cache");
+    server.set_handler(PureAdder { adds: Arc::clone(&adds) });
+    assert_eq!(client.add(1, 2, Duration::from_secs(1)).expect("Failed to add"), 3);
+    assert_eq!(adds.load(Ordering::SeqCst), 6, "Cached response outlived the handler that gave it");
+    for client in [&mut client, &mut other] {
+        assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_fragmented_writes_are_reassembled() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Set up the server in a separate thread
+    let port = get_unique_port();
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .nodelay(true)
+            .fragment_writes(4, Duration::from_millis(5))
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Each response arrives 4 bytes at a time, yet reads back whole through the framing
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let content = "x".repeat(40);
+    let started = std::time::Instant::now();
+    assert_eq!(client.echo(&content, Duration::from_secs(2)).expect("Failed to echo"), content);
+    // At least 11 fragments of the 40 bytes of content alone, so 10 pauses
+    assert!(started.elapsed() >= Duration::from_millis(50), "Response was not fragmented");
+    for (a, b) in [(1, 2), (300, 400)] {
+        assert_eq!(client.add(a, b, Duration::from_secs(2)).expect("Failed to add"), a + b);
+    }
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_responses_report_server_load() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Set up the server in a separate thread
+    let port = get_unique_port();
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .workers(2)
+            .report_load()
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let echo_load = |client: &mut client::Client| {
+        let echo = client_message::Message::EchoMessage(EchoMessage { content: "load".to_string() });
+        assert!(client.send(echo).is_ok(), "Failed to send message");
+        let response = client.receive().expect("Failed to receive response");
+        assert!(matches!(response.message, Some(server_message::Message::EchoMessage(_))), "Expected EchoMessage, got {:?}", response);
+        response.load.expect("Response carries no load")
+    };
+    let load = |active: u32, busy: u32, queued: u32| ServerLoad {
+        active_connections: active,
+        pool_size: 2,
+        busy_workers: busy,
+        queued_connections: queued,
+        queued_requests: 0,
+    };
+
+    // Each of the first two clients gets a worker
+    let mut first = client::Client::new("localhost", port, 1000);
+    assert!(first.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(echo_load(&mut first), load(1, 1, 0));
+    let mut second = client::Client::new("localhost", port, 1000);
+    assert!(second.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(echo_load(&mut second), load(2, 2, 0));
+
+    // A third waits in the queue until a worker is free
+    let mut third = client::Client::new("localhost", port, 1000);
+    assert!(third.connect().is_ok(), "Failed to connect to the server");
+    thread::sleep(Duration::from_millis(200)); // Let the server accept it
+    assert_eq!(echo_load(&mut first), load(3, 2, 1));
+    assert!(first.disconnect().is_ok(), "Failed to disconnect from the server");
+    assert_eq!(echo_load(&mut third), load(2, 2, 0));
+    for client in [&mut second, &mut third] {
+        assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_validation_rules_refuse_requests() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Set up the server in a separate thread
+    let port = get_unique_port();
+    let adds = Arc::new(AtomicU32::new(0));
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .handler(CountingAdder { adds: Arc::clone(&adds) })
+            .validate(|message| match message {
+                client_message::Message::EchoMessage(echo) if echo.content.len() > 8 => {
+                    Err(ErrorResponse::new(ErrorCode::OutOfRange, "echo too long"))
+                }
+                _ => Ok(()),
+            })
+            .validate(|message| match message {
+                client_message::Message::AddRequest(add) if [add.a, add.b].iter().any(|operand| !(-100..=100).contains(operand)) => {
+                    Err(ErrorResponse::new(ErrorCode::OutOfRange, "operand out of range").with_detail("operands must be within -100..=100"))
+                }
+                _ => Ok(()),
+            })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Requests within the rules are handled as usual
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_eq!(client.echo("short", Duration::from_secs(1)).expect("Failed to echo"), "short");
+    assert_eq!(client.add(-100, 100, Duration::from_secs(1)).expect("Failed to add"), 0);
+
+    // Breaking a rule gets its error, and the request never reaches the handler
+    let long_echo = client_message::Message::EchoMessage(EchoMessage { content: "far too long".to_string() });
+    assert!(client.send(long_echo).is_ok(), "Failed to send message");
+    assert_eq!(client.receive_error().expect("Failed to receive error response"), (ErrorCode::OutOfRange, "echo too long".to_string()));
+    assert!(client.send(client_message::Message::AddRequest(AddRequest { a: 1, b: 101 })).is_ok(), "Failed to send message");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::ErrorResponse(error)) => {
+            assert_eq!(error.error_code(), Some(ErrorCode::OutOfRange));
+            assert_eq!(error.detail, "operands must be within -100..=100");
+        }
+        other => panic!("Expected ErrorResponse, got {:?}", other),
+    }
+    assert_eq!(adds.load(Ordering::SeqCst), 1, "A refused add reached the handler");
+
+    // The request inside a wrapper is checked as well
+    let wrapped = client_message::Message::IdempotentRequest(Box::new(IdempotentRequest {
+        key: "wrapped".to_string(),
+        request: Some(Box::new(ClientMessage {
+            message: Some(client_message::Message::AddRequest(AddRequest { a: -101, b: 0 })),
+        })),
+    }));
+    assert!(client.send(wrapped).is_ok(), "Failed to send message");
+    let (code, _) = client.receive_error().expect("Failed to receive error response");
+    assert_eq!(code, ErrorCode::OutOfRange);
+    assert_eq!(adds.load(Ordering::SeqCst), 1, "A refused add reached the handler");
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_restart_after_drain() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Set up the server in a separate thread
+    let port = get_unique_port();
+    let server = create_server(port);
+    let handle = setup_server_thread(server.clone());
+
+    // Drain the server once its only client has left, then stop it
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut client, "before drain");
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+    assert!(server.drain(Duration::from_secs(2)), "Drain timed out");
+    assert!(server.snapshot().paused, "Drained server is not paused");
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+
+    // The restarted server is no longer paused and serves new clients
+    let ready = server.ready_notifier();
+    let handle = setup_server_thread(server.clone());
+    assert!(ready.recv_timeout(Duration::from_secs(2)).is_ok(), "Server did not restart");
+    assert!(!server.snapshot().paused, "Restarted server is still paused");
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to reconnect to the server");
+    assert_echo(&mut client, "after restart");
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+/// Remembers the connection id each request's token names
+struct ConnectionIdRecorder {
+    seen: Arc<std::sync::Mutex<Vec<Option<u64>>>>,
+}
+
+impl MessageHandler for ConnectionIdRecorder {
+    fn handle(&self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)> {
+        DefaultHandler.handle(message)
+    }
+
+    fn handle_cancellable(&self, message: client_message::Message, cancel: &CancellationToken) -> Option<(ServerMessage, CloseAfter)> {
+        self.seen.lock().unwrap().push(cancel.connection_id());
+        DefaultHandler.handle_cancellable(message, cancel)
+    }
+}
+
+#[test]
+fn test_handlers_see_the_connection_id() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // A token made outside a server belongs to no connection
+    assert_eq!(CancellationToken::new().connection_id(), None);
+
+    let port = get_unique_port();
+    let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .handler(ConnectionIdRecorder { seen: Arc::clone(&seen) })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Every request of a connection carries the id the server reports for it
+    let mut client = client::Client::new("localhost", port, 1000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    assert_echo(&mut client, "which connection");
+    assert_eq!(client.add(1, 2, Duration::from_secs(2)).expect("Failed to add"), 3);
+    let connections = server.snapshot().connections;
+    assert_eq!(connections.len(), 1);
+    let id = connections[0].connection_id;
+    assert_eq!(*seen.lock().unwrap(), vec![Some(id), Some(id)]);
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+#[test]
+fn test_reading_ahead_during_a_handler_is_accounted() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Handlers run on their own thread, so the connection reads ahead while one runs
+    let port = get_unique_port();
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .handler_threads(1)
+            .handler(SlowEcho(Duration::from_millis(500)))
+            .max_bytes_per_connection(100)
+            .max_frame_size(512)
+            .max_total_buffered_bytes(1000)
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    let mut client = client::Client::new("localhost", port, 2000);
+    assert!(client.connect().is_ok(), "Failed to connect to the server");
+    let echo = |content: &str| client_message::Message::EchoMessage(EchoMessage { content: content.to_string() });
+    let frame_len = |content: &str| codec::encode_frame(&ClientMessage { message: Some(echo(content)) }).len();
+    let content = "x".repeat(30);
+
+    // A request sent while the handler runs is held against the buffer budget like any other
+    assert!(client.send(echo(&content)).is_ok(), "Failed to send message");
+    thread::sleep(Duration::from_millis(100)); // Let the handler start
+    assert!(client.send(echo(&content)).is_ok(), "Failed to send message");
+    thread::sleep(Duration::from_millis(100)); // Let the connection read it
+    assert_eq!(server.buffered_bytes(), 2 * frame_len(&content), "Bytes read ahead escaped the budget");
+
+    // and counts against the quota, which the third crosses: the client is told at once, gets the response of the
+    // running handler, and then the connection closes
+    assert!(client.send(echo(&content)).is_ok(), "Failed to send message");
+    let (code, _) = client.receive_error().expect("Missing quota error");
+    assert_eq!(code, ErrorCode::QuotaExceeded, "Unexpected error code");
+    match client.receive().expect("Failed to receive response").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, content),
+        other => panic!("Expected EchoMessage, got {:?}", other),
+    }
+    let error = client.receive().expect_err("Connection stayed open past the quota");
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionAborted, "Connection was not closed");
+    let recent = server.recent_disconnects();
+    assert_eq!(recent.last().map(|record| record.reason), Some(DisconnectReason::QuotaExceeded));
+    assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+    thread::sleep(Duration::from_millis(100)); // Let the worker finish closing
+    assert_eq!(server.buffered_bytes(), 0, "The closed connection still holds budget");
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
+
+/// Remembers the name of the thread each request is handled on
+struct ThreadNameRecorder {
+    names: Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+impl MessageHandler for ThreadNameRecorder {
+    fn handle(&self, message: client_message::Message) -> Option<(ServerMessage, CloseAfter)> {
+        let name = thread::current().name().unwrap_or_default().to_string();
+        self.names.lock().unwrap().push(name);
+        DefaultHandler.handle(message)
+    }
+}
+
+#[test]
+fn test_worker_threads_are_named_after_their_connection() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    // Handlers run on the connection's own thread unless `handler_threads` is set
+    let port = get_unique_port();
+    let names = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let server = Arc::new(
+        Server::builder(&format!("localhost:{}", port))
+            .handler(ThreadNameRecorder { names: Arc::clone(&names) })
+            .build()
+            .expect("Failed to start server"),
+    );
+    let handle = setup_server_thread(server.clone());
+
+    // Each connection is served on a thread of its own, named with the connection's id
+    let mut clients: Vec<client::Client> = (0..3).map(|_| client::Client::new("localhost", port, 1000)).collect();
+    for (index, client) in clients.iter_mut().enumerate() {
+        assert!(client.connect().is_ok(), "Failed to connect to the server");
+        assert_echo(client, &format!("client {}", index));
+    }
+    let expected: Vec<String> = server
+        .snapshot()
+        .connections
+        .iter()
+        .map(|connection| format!("client-worker-{}", connection.connection_id))
+        .collect();
+    assert_eq!(*names.lock().unwrap(), expected);
+    let mut unique = expected.clone();
+    unique.dedup();
+    assert_eq!(unique.len(), 3, "Connections share a worker name: {:?}", expected);
+
+    for client in clients.iter_mut() {
+        assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
@@ -0,0 +1,10 @@
+// Helpers shared by the test binaries that measure the whole process
+use std::time::Duration;
+
+// CPU time used by the whole process so far
+pub fn cpu_time() -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    assert_eq!(unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) }, 0, "getrusage failed");
+    let micros = |time: libc::timeval| Duration::from_micros(time.tv_sec as u64 * 1_000_000 + time.tv_usec as u64);
+    micros(usage.ru_utime) + micros(usage.ru_stime)
+}
@@ -0,0 +1,101 @@
+// Runs in its own test binary because it lowers the open file limit of the whole process
+#![cfg(target_os = "linux")]
+
+use embedded_recruitment_task::{
+    codec,
+    message::{client_message, server_message, ClientMessage, EchoMessage, ServerMessage},
+    server::Server,
+};
+use prost::Message;
+use socket2::{Domain, Socket, Type};
+use std::{
+    fs::File,
+    io::Write,
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+mod common;
+use common::cpu_time;
+
+#[test]
+fn test_accept_backs_off_when_out_of_file_descriptors() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let addr: SocketAddr = "127.0.0.1:9081".parse().unwrap();
+    let server = Arc::new(Server::new(&addr.to_string()).expect("Failed to start server"));
+    let handle = {
+        let server = Arc::clone(&server);
+        thread::spawn(move || server.run().expect("Server encountered an error"))
+    };
+    thread::sleep(Duration::from_millis(200)); // Let the accept loop start
+
+    // Create the client sockets while descriptors are still available; connecting needs no new one
+    let sockets: Vec<Socket> = (0..2)
+        .map(|_| Socket::new(Domain::IPV4, Type::STREAM, None).expect("Failed to create socket"))
+        .collect();
+
+    // Lower the limit to the highest descriptor in use and fill every free slot below it
+    let mut original = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    assert_eq!(unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut original) }, 0, "getrlimit failed");
+    let highest_fd = std::fs::read_dir("/proc/self/fd")
+        .expect("Failed to list descriptors")
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u64>().ok())
+        .max()
+        .expect("No open descriptors");
+    let lowered = libc::rlimit {
+        rlim_cur: highest_fd + 1,
+        rlim_max: original.rlim_max,
+    };
+    assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &lowered) }, 0, "setrlimit failed");
+    let mut fillers = Vec::new();
+    while let Ok(file) = File::open("/dev/null") {
+        fillers.push(file);
+    }
+
+    // The connections complete in the kernel but can't all be accepted; the accept loop must rest instead of spinning.
+    // Linux reserves a descriptor for a blocked `accept` before it waits, so the first connection may still be taken.
+    let request = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "waited in the backlog".to_string(),
+        })),
+    });
+    let mut streams = Vec::new();
+    for socket in sockets {
+        socket.connect(&addr.into()).expect("Failed to connect to the server");
+        let mut stream: TcpStream = socket.into();
+        stream.write_all(&request).expect("Failed to send request");
+        streams.push(stream);
+    }
+    let cpu_before = cpu_time();
+    thread::sleep(Duration::from_secs(1));
+    let cpu_used = cpu_time() - cpu_before;
+    let pending = server.pending_accepts();
+    assert!(pending.is_some_and(|pending| pending >= 1), "A connection should still be waiting: {:?}", pending);
+    assert!(cpu_used < Duration::from_millis(300), "Accept loop spun for {:?} of CPU time", cpu_used);
+
+    // Once descriptors are free again the waiting clients are served
+    drop(fillers);
+    assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &original) }, 0, "Failed to restore the limit");
+    for mut stream in streams {
+        stream
+            .set_read_timeout(Some(Duration::from_secs(3)))
+            .expect("Failed to set read timeout");
+        let frame = codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing echo response");
+        match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+            Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "waited in the backlog"),
+            _ => panic!("Expected EchoMessage, but received a different message"),
+        }
+    }
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
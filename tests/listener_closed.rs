@@ -0,0 +1,78 @@
+// Runs in its own test binary because it shuts down a socket found among every descriptor of the process
+#![cfg(target_os = "linux")]
+
+use embedded_recruitment_task::{
+    codec,
+    message::{client_message, server_message, ClientMessage, EchoMessage, ServerMessage},
+    server::Server,
+    stats::ShutdownReason,
+};
+use prost::Message;
+use socket2::SockRef;
+use std::{
+    io::Write,
+    net::{SocketAddr, TcpStream},
+    os::fd::{BorrowedFd, RawFd},
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+// The descriptor of the only socket bound to `addr`; run before any connection is accepted, since those share it
+fn socket_bound_to(addr: SocketAddr) -> RawFd {
+    std::fs::read_dir("/proc/self/fd")
+        .expect("Failed to list descriptors")
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<RawFd>().ok())
+        .find(|&fd| {
+            // SAFETY: the descriptor is only borrowed for the call; one closed meanwhile just fails it
+            let socket = unsafe { BorrowedFd::borrow_raw(fd) };
+            SockRef::from(&socket).local_addr().ok().and_then(|local| local.as_socket()) == Some(addr)
+        })
+        .expect("No socket bound to the server address")
+}
+
+#[test]
+fn test_run_returns_when_listener_is_shut_down() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let addr: SocketAddr = "127.0.0.1:9083".parse().unwrap();
+    let server = Arc::new(Server::new(&addr.to_string()).expect("Failed to start server"));
+    let listener = socket_bound_to(addr);
+    let (returned, run_returned) = mpsc::channel();
+    let handle = {
+        let server = Arc::clone(&server);
+        thread::spawn(move || {
+            server.run().expect("Server encountered an error");
+            let _ = returned.send(());
+        })
+    };
+    thread::sleep(Duration::from_millis(200)); // Let the accept loop start
+
+    // The server serves until then
+    let mut stream = TcpStream::connect(addr).expect("Failed to connect to the server");
+    let request = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "before".to_string(),
+        })),
+    });
+    stream.write_all(&request).expect("Failed to send request");
+    let frame = codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing echo response");
+    match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "before"),
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+
+    // Shutting the listener down makes every later `accept` fail; `run` stops instead of retrying forever
+    assert_eq!(unsafe { libc::shutdown(listener, libc::SHUT_RDWR) }, 0, "shutdown failed");
+    assert!(
+        run_returned.recv_timeout(Duration::from_secs(3)).is_ok(),
+        "run kept going after its listener was shut down"
+    );
+    assert_eq!(server.shutdown_reason(), Some(ShutdownReason::ListenerClosed));
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}
@@ -0,0 +1,90 @@
+// Runs in its own test binary because it lowers the open file limit of the whole process
+#![cfg(target_os = "linux")]
+
+use embedded_recruitment_task::{
+    codec,
+    message::{client_message, server_message, ClientMessage, EchoMessage, ServerMessage},
+    server::Server,
+    stats::DisconnectReason,
+};
+use prost::Message;
+use std::{
+    fs::File,
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+#[test]
+fn test_connection_is_served_when_stream_clone_fails() {
+    let _ = env_logger::builder()
+        .is_test(true) // Configures logger for tests
+        .try_init(); // Avoids reinitializing if already initialized
+
+    let addr: SocketAddr = "127.0.0.1:9082".parse().unwrap();
+    let server = Arc::new(Server::new(&addr.to_string()).expect("Failed to start server"));
+    let handle = {
+        let server = Arc::clone(&server);
+        thread::spawn(move || server.run().expect("Server encountered an error"))
+    };
+    thread::sleep(Duration::from_millis(200)); // Let the accept loop start
+
+    // Connect while descriptors are still available
+    let mut stream = TcpStream::connect(addr).expect("Failed to connect to the server");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(3)))
+        .expect("Failed to set read timeout");
+
+    // Fill every free descriptor but one, so the accept succeeds and the clone of the accepted stream fails
+    let mut original = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    assert_eq!(unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut original) }, 0, "getrlimit failed");
+    let highest_fd = std::fs::read_dir("/proc/self/fd")
+        .expect("Failed to list descriptors")
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u64>().ok())
+        .max()
+        .expect("No open descriptors");
+    let lowered = libc::rlimit {
+        rlim_cur: highest_fd + 1,
+        rlim_max: original.rlim_max,
+    };
+    assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &lowered) }, 0, "setrlimit failed");
+    let mut fillers = Vec::new();
+    while let Ok(file) = File::open("/dev/null") {
+        fillers.push(file);
+    }
+    fillers.pop(); // The one slot left for the accept
+
+    // The connection is served without its clone
+    let request = codec::encode_frame(&ClientMessage {
+        message: Some(client_message::Message::EchoMessage(EchoMessage {
+            content: "no clone needed".to_string(),
+        })),
+    });
+    stream.write_all(&request).expect("Failed to send request");
+    let frame = codec::read_frame(&mut stream, codec::DEFAULT_MAX_FRAME_SIZE).expect("Missing echo response");
+    match ServerMessage::decode(frame.payload.as_slice()).expect("Invalid response").message {
+        Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "no clone needed"),
+        _ => panic!("Expected EchoMessage, but received a different message"),
+    }
+
+    // Disconnecting it falls back to the worker's read timeout instead of a shutdown through the clone
+    let peer = stream.local_addr().expect("Failed to read local address");
+    assert_eq!(server.disconnect(peer), 1, "Connection should still be disconnectable");
+    let mut rest = Vec::new();
+    assert_eq!(stream.read_to_end(&mut rest).expect("Connection was not closed"), 0);
+
+    drop(fillers);
+    assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &original) }, 0, "Failed to restore the limit");
+    thread::sleep(Duration::from_millis(100)); // The disconnect is recorded once the worker has unwound
+    let reasons: Vec<_> = server.recent_disconnects().iter().map(|record| record.reason).collect();
+    assert_eq!(reasons, vec![DisconnectReason::Kicked]);
+
+    // Stop the server and wait for thread to finish
+    server.stop();
+    assert!(
+        handle.join().is_ok(),
+        "Server thread panicked or failed to join"
+    );
+}